@@ -0,0 +1,140 @@
+use actix::*;
+
+use std::mem::size_of;
+
+use node::{Node, NeighbourData};
+use nc::NCNodeData;
+use stats::Distribution;
+use world;
+
+/// Routing state held by a single node.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeState {
+    pub neighbours: usize,
+    pub neighbours_map: usize,
+    pub dht_entries: usize,
+    pub dht_buckets_used: usize,
+    // sum of the lengths of all routes stored in the DHT table
+    pub dht_route_hops: usize,
+    pub dht_values: usize,
+    pub nc_floats: usize,
+    pub baseline_entries: usize,
+    pub addresses: usize,
+    pub readdressed: u64,
+    pub lookups: u64,
+    pub lookup_failures: u64,
+}
+
+impl NodeState {
+    /// Rough estimate of the memory used, ignoring allocator and hash map overhead.
+    pub fn bytes(&self) -> usize {
+        self.neighbours * size_of::<NeighbourData>() +
+            self.neighbours_map * 2 * size_of::<u64>() +
+            self.dht_entries * (size_of::<u64>() + size_of::<Vec<u64>>()) +
+            self.dht_route_hops * size_of::<u64>() +
+            self.dht_values * size_of::<u64>() +
+            self.nc_floats * size_of::<f32>()
+    }
+}
+
+impl Node {
+    pub fn routing_state(&self) -> NodeState {
+        let buckets = self.dht.table().buckets();
+        NodeState {
+            neighbours: self.neighbours.len(),
+            neighbours_map: self.neighbours_map.len(),
+            dht_entries: buckets.iter().map(|b| b.data().len()).sum(),
+            dht_buckets_used: buckets.iter().filter(|b| !b.data().is_empty()).count(),
+            dht_route_hops: buckets.iter().flat_map(|b| b.data()).map(|n| n.route.len()).sum(),
+            dht_values: self.dht.value_count(),
+            nc_floats: nc_floats(&self.nc),
+            baseline_entries: self.baseline.as_ref().map_or(0, |b| b.table_size()),
+            addresses: self.addresses.len(),
+            readdressed: self.readdressed,
+            lookups: self.lookups,
+            lookup_failures: self.lookup_failures,
+        }
+    }
+}
+
+fn nc_floats(nc: &NCNodeData) -> usize {
+    nc.outgoing_vec.len() + nc.incoming_vec.len() + 1
+}
+
+// sent by the world to ask every node for its state
+pub struct ReportState;
+
+message!(ReportState);
+
+impl Handler<ReportState> for Node {
+    fn handle(&mut self, _msg: ReportState, _ctx: &mut Context<Self>) -> Response<Self, ReportState> {
+        self.world.send(world::StateReport { id: self.id, state: self.routing_state() });
+        self.world.send(world::QueueStatsReport { id: self.id, report: self.queue_report() });
+        self.world.send(world::NCReport {
+            id: self.id,
+            error: self.nc_error,
+            rank: self.nc_rank,
+            learn_rate: self.nc.learn_rate,
+            malicious: self.malicious.is_some(),
+            rejected: self.nc_rejected,
+        });
+        if self.config.adverts.is_some() {
+            self.world.send(world::ReputationReport { id: self.id, liar: self.metric_liar, reputations: self.reputations() });
+        }
+        Self::reply(())
+    }
+}
+
+/// Network wide distributions of per node state.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StateSummary {
+    pub nodes: usize,
+    pub neighbours: Distribution,
+    pub dht_entries: Distribution,
+    pub dht_route_hops: Distribution,
+    pub dht_values: Distribution,
+    pub baseline_entries: Distribution,
+    pub addresses: Distribution,
+    pub readdressed: Distribution,
+    // fraction of the sampled lookups which failed, of nodes which made any
+    pub lookup_failures: Distribution,
+    pub bytes: Distribution,
+}
+
+impl StateSummary {
+    pub fn new<'a, I: Iterator<Item=&'a NodeState> + Clone>(states: I) -> StateSummary {
+        let collect = |f: &dyn Fn(&NodeState) -> usize| Distribution::new(states.clone().map(|s| f(s) as f32).collect());
+        StateSummary {
+            nodes: states.clone().count(),
+            neighbours: collect(&|s| s.neighbours),
+            dht_entries: collect(&|s| s.dht_entries),
+            dht_route_hops: collect(&|s| s.dht_route_hops),
+            dht_values: collect(&|s| s.dht_values),
+            baseline_entries: collect(&|s| s.baseline_entries),
+            addresses: collect(&|s| s.addresses),
+            readdressed: collect(&|s| s.readdressed as usize),
+            lookup_failures: Distribution::new(states.clone().filter(|s| s.lookups > 0)
+                .map(|s| s.lookup_failures as f32 / s.lookups as f32).collect()),
+            bytes: collect(&|s| s.bytes()),
+        }
+    }
+
+    pub fn csv_header() -> String {
+        format!("nodes,{},{},{},{},{},{},{},{},{}\n",
+                Distribution::csv_header("neighbours"),
+                Distribution::csv_header("dht_entries"),
+                Distribution::csv_header("dht_route_hops"),
+                Distribution::csv_header("dht_values"),
+                Distribution::csv_header("baseline_entries"),
+                Distribution::csv_header("addresses"),
+                Distribution::csv_header("readdressed"),
+                Distribution::csv_header("lookup_failures"),
+                Distribution::csv_header("bytes"))
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{},{},{},{},{},{},{}\n", self.nodes, self.neighbours.to_csv(), self.dht_entries.to_csv(),
+                self.dht_route_hops.to_csv(), self.dht_values.to_csv(), self.baseline_entries.to_csv(),
+                self.addresses.to_csv(), self.readdressed.to_csv(), self.lookup_failures.to_csv(), self.bytes.to_csv())
+    }
+}
@@ -0,0 +1,210 @@
+//! Hierarchical addresses.
+//!
+//! Core nodes pick their own label, every other node is delegated a label by
+//! each of its upstream providers (neighbours on a lower level) and so has one
+//! address per path to the core. Addresses are rebuilt every round from the
+//! last offer of each upstream, so a node re-addresses when its upstreams
+//! change, and is left without an address once none of them has one to
+//! delegate. An upstream which stops answering keeps its place for
+//! `MISSED_ROUNDS` rounds, a lost answer does not re-address the node. The
+//! current addresses are published in the DHT under a key derived from the
+//! node's stable ID.
+
+use actix::*;
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use directory::{self, Record, Value};
+use nc::NCNodeData;
+use node::{Node, NodeFuture};
+use packet::*;
+
+/// How nodes derive their addresses.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum AddressScheme {
+    /// Prefixes delegated by upstream providers.
+    #[default]
+    Provider,
+    /// A quadtree cell of the first two coordinate dimensions, `depth` labels long.
+    Coordinates { depth: usize },
+    /// Provider prefixes with the coordinate cell as the last label, at most `HYBRID_DEPTH` deep.
+    Hybrid { depth: usize },
+}
+
+/// Labels from the core down.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Address(pub Vec<u16>);
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let labels: Vec<String> = self.0.iter().map(|l| format!("{:x}", l)).collect();
+        write!(f, "{}", labels.join("."))
+    }
+}
+
+impl Address {
+    /// Address delegated to a child with `label`.
+    pub fn child(&self, label: u16) -> Address {
+        let mut labels = self.0.clone();
+        labels.push(label);
+        Address(labels)
+    }
+}
+
+// simulated seconds between address rounds
+static ADDRESS_INTERVAL: f32 = 10.;
+// bound on the addresses of a multi homed node
+pub static MAX_ADDRESSES: usize = 8;
+// coordinates are roughly within this range in every dimension
+static CELL_RANGE: f32 = 2.;
+// a hybrid cell packs two bits per level into one label
+pub static HYBRID_DEPTH: usize = 8;
+// rounds an upstream may leave unanswered before its offer is dropped
+static MISSED_ROUNDS: u32 = 3;
+
+/// DHT key of the addresses of `id`, kept apart from the coordinate stored under `id` itself.
+pub fn address_key(id: u64) -> u64 {
+    id.rotate_left(32) ^ 0x9e37_79b9_7f4a_7c15
+}
+
+/// Quadtree cell of a coordinate, one label per level.
+pub fn cell(nc: &NCNodeData, depth: usize) -> Vec<u16> {
+    let v = &nc.outgoing_vec;
+    let (mut x, mut y) = (v[0], if v.len() > 1 { v[1] } else { 0. });
+    let (mut lo, mut size) = ((-CELL_RANGE, -CELL_RANGE), 2. * CELL_RANGE);
+    let mut labels = Vec::new();
+    x = x.max(-CELL_RANGE).min(CELL_RANGE);
+    y = y.max(-CELL_RANGE).min(CELL_RANGE);
+    for _ in 0..depth {
+        size /= 2.;
+        let qx = if x >= lo.0 + size { 1 } else { 0 };
+        let qy = if y >= lo.1 + size { 1 } else { 0 };
+        lo = (lo.0 + qx as f32 * size, lo.1 + qy as f32 * size);
+        labels.push(qx + 2 * qy);
+    }
+    labels
+}
+
+impl Node {
+    /// Settle the offers of the last round, then ask our upstreams again.
+    pub fn address_tick(&mut self, ctx: &mut Context<Self>) {
+        if self.address_round.is_some_and(|t| self.now - t < ADDRESS_INTERVAL) {
+            return
+        }
+        self.address_round = Some(self.now);
+
+        let upstreams: Vec<u64> = self.neighbours.iter().filter(|n| n.level < self.level && !n.failed).map(|n| n.id).collect();
+        self.address_offers.retain(|u, o| upstreams.contains(u) && o.1 < MISSED_ROUNDS);
+        let provider = if self.level == 0 {
+            // core labels are unique as long as the graph index fits
+            match u16::try_from(self.graph_index.index()) {
+                Ok(label) => vec![Address(vec![label])],
+                Err(_) => Vec::new()
+            }
+        } else {
+            self.address_offers.values().flat_map(|o| o.0.iter().cloned()).collect()
+        };
+        // cut off from the core once every upstream answered without an offer or stayed silent for too
+        // long, a missing answer alone may just be a lost packet
+        let orphaned = self.level > 0 && provider.is_empty()
+            && upstreams.iter().all(|u| self.address_offers.get(u).is_none_or(|o| o.1 == 0));
+        for o in self.address_offers.values_mut() {
+            o.1 += 1;
+        }
+        let mut addresses = match self.config.addressing {
+            AddressScheme::Provider => provider,
+            AddressScheme::Coordinates { depth } => vec![Address(cell(&self.nc, depth))],
+            AddressScheme::Hybrid { depth } => {
+                let c = cell(&self.nc, depth);
+                let label = c.iter().fold(0u16, |a, l| a.wrapping_mul(4).wrapping_add(*l));
+                provider.iter().map(|a| a.child(label)).collect()
+            }
+        };
+        addresses.sort();
+        addresses.dedup();
+        addresses.truncate(MAX_ADDRESSES);
+
+        if addresses != self.addresses && (!addresses.is_empty() || orphaned) {
+            if !self.addresses.is_empty() {
+                self.readdressed += 1;
+            }
+            self.addresses = addresses;
+            self.address_version += 1;
+        }
+        if self.address_version > 0 {
+            // republished every round so the record does not expire, empty once we lost our addresses
+            let record = Record {
+                owner: self.id,
+                version: self.address_version,
+                expires: self.now + directory::RECORD_TTL,
+                value: Value::Addresses(self.addresses.clone()),
+            };
+            let key = address_key(self.id);
+            self.dht_store(key, record, ctx);
+        }
+
+        for u in upstreams {
+            let f = self.send_packet(Packet {
+                from: self.id,
+                des: u,
+                route: vec![u],
+                data: AddressRequest,
+                hops: Vec::new(),
+                sent_at: 0.,
+                elapsed: 0.,
+                trace: 0,
+            });
+            ctx.spawn(ActorFuture::then(f, move |res, actor: &mut Node, _ctx| {
+                if let Ok(Ok(offer)) = res {
+                    actor.address_offers.insert(u, (offer.addresses, 0));
+                }
+                fut::ok::<(), (), Node>(())
+            }));
+        }
+    }
+
+    /// Current addresses of `id` from the DHT.
+    pub fn address_lookup(&mut self, id: u64) -> directory::LookupFuture<Vec<Address>> {
+        let key = address_key(id);
+        NodeFuture::new(self.dht_find_value(key).then(|res, _actor, _ctx| {
+            match res {
+                Ok(Record { value: Value::Addresses(ref a), .. }) if !a.is_empty() => fut::ok(a.clone()),
+                _ => fut::err(())
+            }
+        }))
+    }
+}
+
+/// Ask an upstream provider to delegate us a prefix under each of its addresses.
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
+#[Message(AddressOffer)]
+pub struct AddressRequest;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddressOffer {
+    pub addresses: Vec<Address>,
+}
+
+impl PacketData for AddressRequest {
+    fn kind() -> &'static str {
+        "address_request"
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        // labels stay the same for as long as the child keeps asking, once they run out new children get none
+        let label = match node.address_children.get(&packet.from) {
+            Some(&l) => l,
+            None => match u16::try_from(node.address_children.len() + 1) {
+                Ok(l) => *node.address_children.entry(packet.from).or_insert(l),
+                Err(_) => return Node::reply(AddressOffer { addresses: Vec::new() })
+            }
+        };
+        let addresses = node.addresses.iter().map(|a| a.child(label)).collect();
+        Node::reply(AddressOffer { addresses })
+    }
+}
@@ -0,0 +1,283 @@
+//! Malicious nodes in the coordinate system and the defences against them.
+//!
+//! A fraction of the nodes lie about their coordinates and about the latency
+//! they measured for probes they receive. A liar answering a probe scales its
+//! coordinate relative to the prober's, so the lie holds whoever asks. Honest
+//! nodes filter what they learn from neighbours before it reaches the NC
+//! model.
+
+use rand::{Rng, SeedableRng, StdRng};
+
+use std::collections::VecDeque;
+use std::mem;
+
+use nc::{NC, NCModel, NCNodeData};
+use node::Node;
+
+/// What a malicious node lies about, and how.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum Attack {
+    /// Claim to be `factor` times further away than we are.
+    Inflate { factor: f32 },
+    /// Claim to be `factor` times closer than we are.
+    Deflate { factor: f32 },
+    /// Random coordinates and latencies, fresh for every reply.
+    Random,
+    /// Every colluding node claims the same coordinate, derived from `seed`, and to be close to everyone.
+    Collude { seed: usize },
+    /// Inflate by `factor` and make up witnesses which back the claim.
+    Forge { factor: f32 },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdversaryConfig {
+    // fraction of the nodes which are malicious
+    pub fraction: f32,
+    pub attack: Attack,
+}
+
+/// How honest nodes filter neighbour coordinates before updating their own.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Defence {
+    #[default]
+    None,
+    /// Ignore samples with a relative prediction error above `threshold`, once our own coordinate has settled.
+    OutlierRejection { threshold: f32 },
+    /// Scale updates by a per neighbour reputation built from past prediction errors.
+    Reputation { weight: f32 },
+    /// Veracity style, check the claimed coordinate against measurements other nodes made of the claimant.
+    Veracity { witnesses: usize, threshold: f32 },
+}
+
+impl Defence {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Defence::None => "none",
+            Defence::OutlierRejection { .. } => "outlier",
+            Defence::Reputation { .. } => "reputation",
+            Defence::Veracity { .. } => "veracity",
+        }
+    }
+}
+
+/// A probe received by a node, kept to vouch for its coordinate.
+///
+/// The claimant reports the latency itself and nothing is signed, so a liar
+/// can make up witnesses which fit its claim, as `Attack::Forge` does.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Witness {
+    pub id: u64,
+    pub coords: NCNodeData,
+    pub latency: f32,
+}
+
+impl Witness {
+    /// Encoded size in bytes.
+    pub fn wire_size(&self) -> usize {
+        mem::size_of::<u64>() + self.coords.wire_size() + mem::size_of::<f32>()
+    }
+}
+
+// our own running error below which outlier rejection starts
+static SETTLED_ERROR: f32 = 0.5;
+// latency range used by random attackers
+static RANDOM_LATENCY: f32 = 1.;
+
+impl Attack {
+    fn scale(&self) -> f32 {
+        match *self {
+            Attack::Inflate { factor } | Attack::Forge { factor } => factor,
+            Attack::Deflate { factor } => 1. / factor,
+            _ => 1.,
+        }
+    }
+
+    /// The coordinate a malicious node advertises instead of `own` to `peer`, random lies are drawn from `rng`.
+    ///
+    /// Scaled lies are relative to the peer when we know its coordinate, to the origin otherwise.
+    pub fn coords<R: Rng>(&self, own: &NCNodeData, peer: Option<&NCNodeData>, model: &dyn NCModel, rng: &mut R) -> NCNodeData {
+        let mut lie = own.clone();
+        match *self {
+            Attack::Inflate { .. } | Attack::Deflate { .. } | Attack::Forge { .. } => {
+                let mut origin = own.clone();
+                origin.outgoing_vec *= 0.;
+                origin.incoming_vec *= 0.;
+                origin.height = 0.;
+                lie = model.scaled(own, peer.unwrap_or(&origin), self.scale());
+            }
+            Attack::Random => {
+                lie.outgoing_vec = NC::from_fn(own.outgoing_vec.len(), |_, _| rng.next_f32());
+                lie.incoming_vec = NC::from_fn(own.incoming_vec.len(), |_, _| rng.next_f32());
+            }
+            Attack::Collude { seed } => {
+                let mut rng: StdRng = SeedableRng::from_seed(&[seed][..]);
+                lie.outgoing_vec = NC::from_fn(own.outgoing_vec.len(), |_, _| rng.next_f32() * 0.1);
+                lie.incoming_vec = lie.outgoing_vec.clone();
+                lie.height = 0.;
+            }
+        }
+        // always claim to be confident
+        lie.error = 0.01;
+        lie
+    }
+
+    /// The latency a malicious node reports for a probe which took `latency`.
+    pub fn latency<R: Rng>(&self, latency: f32, rng: &mut R) -> f32 {
+        match *self {
+            Attack::Random => rng.next_f32() * RANDOM_LATENCY,
+            Attack::Collude { .. } => latency * 0.1,
+            _ => latency * self.scale(),
+        }
+    }
+
+    /// The witnesses a malicious node attaches to `claimed`, forgers make their latencies fit it.
+    pub fn witnesses(&self, recorded: &Witnesses, claimed: &NCNodeData, model: &dyn NCModel) -> Vec<Witness> {
+        let forge = matches!(*self, Attack::Forge { .. });
+        recorded.iter().map(|w| {
+            let mut w = w.clone();
+            if forge {
+                w.latency = model.predict(&w.coords, claimed);
+            }
+            w
+        }).collect()
+    }
+}
+
+// median relative error of the latencies `witnesses` measured against the ones `claimed` predicts, none without any
+fn witness_error(model: &dyn NCModel, claimed: &NCNodeData, witnesses: &[Witness]) -> Option<f32> {
+    let mut errors: Vec<f32> = witnesses.iter()
+        .filter(|w| w.latency > 0.)
+        .map(|w| (model.predict(&w.coords, claimed) - w.latency).abs() / w.latency)
+        .collect();
+    if errors.is_empty() {
+        return None
+    }
+    errors.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    Some(errors[errors.len() / 2])
+}
+
+impl Node {
+    /// Our coordinate as we tell it to `peer`, or to anyone.
+    pub fn advertised_coords(&mut self, peer: Option<&NCNodeData>) -> NCNodeData {
+        match self.malicious {
+            Some(a) => a.coords(&self.nc, peer, &*self.nc_model, &mut self.rng),
+            None => self.nc.clone(),
+        }
+    }
+
+    /// The latency we report for a probe which took `latency`.
+    pub fn reported_latency(&mut self, latency: f32) -> f32 {
+        match self.malicious {
+            Some(a) => a.latency(latency, &mut self.rng),
+            None => latency,
+        }
+    }
+
+    /// Remember a probe we received, it vouches for the sender's coordinate.
+    pub fn witness(&mut self, id: u64, coords: &NCNodeData, latency: f32) {
+        let max = match self.config.defence {
+            Defence::Veracity { witnesses, .. } => witnesses,
+            _ => return,
+        };
+        self.nc_witnesses.retain(|w| w.id != id);
+        self.nc_witnesses.push_back(Witness { id, coords: coords.clone(), latency });
+        while self.nc_witnesses.len() > max {
+            self.nc_witnesses.pop_front();
+        }
+    }
+
+    /// Weight to give to a sample from `from`, `None` if it should be discarded.
+    pub fn nc_accept(&mut self, from: u64, other: &NCNodeData, latency: f32, predicted: f32, witnesses: &[Witness]) -> Option<f32> {
+        let error = if latency > 0. { (predicted - latency).abs() / latency } else { 0. };
+        let weight = match self.config.defence {
+            Defence::None => Some(1.),
+            Defence::OutlierRejection { threshold } => {
+                if self.nc_error < SETTLED_ERROR && error > threshold { None } else { Some(1.) }
+            }
+            Defence::Reputation { weight } => {
+                let r = self.nc_reputation.entry(from).or_insert(1.);
+                *r = (1. - weight) * *r + weight * (1. - error.min(1.));
+                Some(*r)
+            }
+            Defence::Veracity { threshold, .. } => {
+                // no witnesses means no trust
+                match witness_error(&*self.nc_model, other, witnesses) {
+                    Some(e) if e <= threshold => Some(1.),
+                    _ => None
+                }
+            }
+        };
+        if weight.is_none() {
+            self.nc_rejected += 1;
+        }
+        weight
+    }
+
+    /// The witnesses we send along with `claimed`, our advertised coordinate.
+    pub fn witnesses(&self, claimed: &NCNodeData) -> Vec<Witness> {
+        match self.malicious {
+            Some(a) => a.witnesses(&self.nc_witnesses, claimed, &*self.nc_model),
+            None => self.nc_witnesses.iter().cloned().collect(),
+        }
+    }
+}
+
+/// Pick which nodes are malicious.
+pub fn choose<R: Rng>(rng: &mut R, config: &Option<AdversaryConfig>) -> Option<Attack> {
+    match *config {
+        Some(c) if rng.next_f32() < c.fraction => Some(c.attack),
+        _ => None,
+    }
+}
+
+pub type Witnesses = VecDeque<Witness>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nc::{ModelKind, NCParams};
+    use rng::SimRng;
+
+    #[test]
+    fn scaled_coordinates_predict_the_claimed_latency() {
+        let params = NCParams::default();
+        let mut rng = SimRng::derive(5, 0);
+        for kind in &[ModelKind::Factorisation, ModelKind::Dmfsgd, ModelKind::Vivaldi] {
+            let model = kind.create(&params);
+            let own = NCNodeData::new(&params, &mut rng);
+            // any peer, not only one at the origin
+            let mut peer = NCNodeData::new(&params, &mut rng);
+            peer.outgoing_vec = NC::from_fn(params.dimension, |i, _| 0.3 + 0.1 * i as f32);
+            peer.incoming_vec = NC::from_fn(params.dimension, |i, _| 0.2 + 0.05 * i as f32);
+            // heights add, a deflating liar cannot take off the peer's
+            peer.height = 0.;
+            for &(attack, factor) in &[(Attack::Inflate { factor: 3. }, 3.), (Attack::Deflate { factor: 2. }, 0.5)] {
+                let lie = attack.coords(&own, Some(&peer), &*model, &mut rng);
+                for &(honest, claimed) in &[(model.predict(&own, &peer), model.predict(&lie, &peer)),
+                                            (model.predict(&peer, &own), model.predict(&peer, &lie))] {
+                    assert!((claimed - factor * honest).abs() < 1e-4 * honest, "{:?} {:?}: {} for {}", kind, attack, claimed, honest);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn forged_witnesses_pass_veracity() {
+        let params = NCParams::default();
+        let model = ModelKind::Vivaldi.create(&params);
+        let mut rng = SimRng::derive(6, 0);
+        let own = NCNodeData::new(&params, &mut rng);
+        let peer = NCNodeData::new(&params, &mut rng);
+        // what the liar really measured from three nodes
+        let recorded: Witnesses = (0..3).map(|i| {
+            let coords = NCNodeData::new(&params, &mut rng);
+            let latency = model.predict(&coords, &own);
+            Witness { id: i, coords, latency }
+        }).collect();
+        for &(attack, passes) in &[(Attack::Inflate { factor: 3. }, false), (Attack::Forge { factor: 3. }, true)] {
+            let lie = attack.coords(&own, Some(&peer), &*model, &mut rng);
+            let error = witness_error(&*model, &lie, &attack.witnesses(&recorded, &lie, &*model)).unwrap();
+            assert_eq!(error < 0.5, passes, "{:?}: {}", attack, error);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Advertised link metrics and the reputation of their advertisers.
+//!
+//! Nodes periodically tell their neighbours the metrics of their own links.
+//! A neighbour checks an advertisement by probing through the advertiser to
+//! one of the advertised links and comparing the latency it sees with the one
+//! claimed. The resulting per neighbour reputation weights NC updates and
+//! steers the first hop away from untrusted neighbours.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::mem;
+
+use node::Node;
+use packet::*;
+use stats::Distribution;
+
+#[derive(Clone, Copy, Debug)]
+pub struct AdvertConfig {
+    // fraction of nodes which advertise better links than they have
+    pub liars: f32,
+    // liars claim `factor` times the latency of their links
+    pub factor: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinkMetric {
+    pub neighbour: u64,
+    pub latency: f32,
+    pub bandwidth: f32,
+    pub loss: f32,
+}
+
+// simulated seconds between advertisements
+static ADVERT_INTERVAL: f32 = 5.;
+// weight of a new check in the reputation
+static REPUTATION_WEIGHT: f32 = 0.2;
+// below this a neighbour is avoided as a first hop
+pub static TRUST_THRESHOLD: f32 = 0.5;
+// relative latency error which is not held against the advertiser
+static TOLERANCE: f32 = 0.1;
+
+impl Node {
+    /// Advertise our links and check one advertisement received.
+    pub fn advert_tick(&mut self, ctx: &mut Context<Self>) {
+        let config = match self.config.adverts {
+            Some(c) => c,
+            None => return
+        };
+        if self.advert_round.is_some_and(|t| self.now - t < ADVERT_INTERVAL) {
+            return
+        }
+        self.advert_round = Some(self.now);
+
+        let scale = if self.metric_liar { config.factor } else { 1. };
+        let metrics: Vec<LinkMetric> = self.neighbours.iter().map(|n| LinkMetric {
+            neighbour: n.id,
+            latency: n.connection.latency * scale,
+            bandwidth: n.connection.bandwidth / scale,
+            loss: if self.metric_liar { 0. } else { n.connection.packet_loss },
+        }).collect();
+        let ids: Vec<u64> = self.neighbours.iter().map(|n| n.id).collect();
+        for n in ids {
+            let _ = self.send_packet(Packet {
+                from: self.id,
+                des: n,
+                route: vec![n],
+                data: MetricAdvert { metrics: metrics.clone() },
+                hops: Vec::new(),
+                sent_at: 0.,
+                elapsed: 0.,
+                trace: 0,
+            });
+        }
+
+        // check a random advertised link of a random advertiser
+        let mut advertisers: Vec<u64> = self.adverts.keys().cloned().collect();
+        // in a fixed order, so the draw does not depend on how the map iterates
+        advertisers.sort();
+        if advertisers.is_empty() {
+            return
+        }
+        let x = advertisers[self.rng.gen_range(0, advertisers.len())];
+        let links: Vec<LinkMetric> = self.adverts[&x].iter().filter(|m| m.neighbour != self.id).cloned().collect();
+        if links.is_empty() {
+            return
+        }
+        let claimed = links[self.rng.gen_range(0, links.len())].clone();
+        let own = match self.neighbours_map.get(&x) {
+            Some(&i) => self.neighbours[i].connection.latency,
+            None => return
+        };
+        let y = claimed.neighbour;
+        let f = self.send_packet(Packet {
+            from: self.id,
+            des: y,
+            route: vec![y, x],
+            data: MetricProbe,
+            hops: Vec::new(),
+            sent_at: 0.,
+            elapsed: 0.,
+            trace: 0,
+        });
+        ctx.spawn(ActorFuture::then(f, move |res, actor: &mut Node, _ctx| {
+            let experienced = match res {
+                Ok(Ok(elapsed)) => (elapsed - own).max(1e-6),
+                // a single loss says little about the claims
+                _ => return fut::ok::<(), (), Node>(())
+            };
+            // queueing and transmission add to what we see, so some error is tolerated
+            let error = (claimed.latency - experienced).abs() / experienced - TOLERANCE;
+            let score = 1. - error.clamp(0., 1.);
+            let r = actor.metric_reputation.entry(x).or_insert(1.);
+            *r = (1. - REPUTATION_WEIGHT) * *r + REPUTATION_WEIGHT * score;
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+
+    /// Our trust in the advertisements of `id`, 1 if unknown.
+    pub fn metric_trust(&self, id: u64) -> f32 {
+        *self.metric_reputation.get(&id).unwrap_or(&1.)
+    }
+
+    /// Replace an untrusted first hop with a trusted neighbour advertising a link to the second.
+    pub fn trusted_first_hop(&self, route: &mut [u64]) {
+        if self.config.adverts.is_none() || route.len() < 2 {
+            return
+        }
+        let first = route[route.len() - 1];
+        let second = route[route.len() - 2];
+        if self.metric_trust(first) >= TRUST_THRESHOLD {
+            return
+        }
+        // the trusted advertiser with the fastest link to the second hop, the lowest ID among equals
+        let alternative = self.adverts.iter()
+            .filter(|&(id, _)| *id != first && self.metric_trust(*id) >= TRUST_THRESHOLD)
+            .filter_map(|(id, m)| m.iter().find(|l| l.neighbour == second).map(|l| (l.latency, *id)))
+            .min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        if let Some((_, a)) = alternative {
+            let last = route.len() - 1;
+            route[last] = a;
+        }
+    }
+}
+
+/// Link metrics of the sender.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MetricAdvert {
+    pub metrics: Vec<LinkMetric>,
+}
+
+message!(MetricAdvert);
+
+impl PacketData for MetricAdvert {
+    fn kind() -> &'static str {
+        "metric_advert"
+    }
+
+    fn size(&self) -> usize {
+        self.metrics.len() * mem::size_of::<LinkMetric>()
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        node.adverts.insert(packet.from, packet.data.metrics.clone());
+        Node::reply(())
+    }
+}
+
+/// Sent through an advertiser to one of its neighbours, replies with the one way latency.
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
+#[Message(f32)]
+pub struct MetricProbe;
+
+impl PacketData for MetricProbe {
+    fn kind() -> &'static str {
+        "metric_probe"
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn process(packet: &Packet<Self>, _node: &mut Node) -> Response<Node, Packet<Self>> {
+        Node::reply(packet.elapsed)
+    }
+}
+
+/// Reputations as seen by the other nodes, kept by the world.
+#[derive(Default)]
+pub struct ReputationStats {
+    liars: HashMap<u64, bool>,
+    // every score given to a node by its neighbours
+    scores: HashMap<u64, HashMap<u64, f32>>,
+}
+
+impl ReputationStats {
+    pub fn record(&mut self, id: u64, liar: bool, reputations: Vec<(u64, f32)>) {
+        self.liars.insert(id, liar);
+        for (other, r) in reputations {
+            self.scores.entry(other).or_default().insert(id, r);
+        }
+    }
+
+    fn mean(&self, id: u64) -> Option<f32> {
+        self.scores.get(&id).map(|s| s.values().sum::<f32>() / s.len() as f32)
+    }
+
+    pub fn summary(&self) -> String {
+        let (mut liars, mut honest) = (Vec::new(), Vec::new());
+        let mut caught = 0;
+        let mut accused = 0;
+        for (&id, &liar) in &self.liars {
+            if let Some(m) = self.mean(id) {
+                if liar { liars.push(m) } else { honest.push(m) }
+                if m < TRUST_THRESHOLD {
+                    if liar { caught += 1 } else { accused += 1 }
+                }
+            }
+        }
+        let total_liars = liars.len();
+        format!("metric reputation: liars {}, honest {}, {}/{} liars identified, {} honest nodes accused",
+                Distribution::new(liars), Distribution::new(honest), caught, total_liars, accused)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("id,liar,observers,mean_reputation\n");
+        for (&id, &liar) in &self.liars {
+            if let Some(m) = self.mean(id) {
+                out.push_str(&format!("{},{},{},{}\n", id, liar, self.scores[&id].len(), m));
+            }
+        }
+        out
+    }
+}
+
+impl Node {
+    /// Our reputations of our neighbours, for the world's statistics.
+    pub fn reputations(&self) -> Vec<(u64, f32)> {
+        self.metric_reputation.iter().map(|(k, v)| (*k, *v)).collect()
+    }
+}
@@ -0,0 +1,313 @@
+//! Thorup-Zwick compact routing.
+//!
+//! Each node keeps routes to every landmark plus its cluster, the nodes that
+//! are closer to it than to their own nearest landmark. A destination outside
+//! the cluster is at least as far as its landmark is from it, so going via
+//! the landmark in the destination's label gives stretch at most 3 once the
+//! tables have converged, with roughly `sqrt(n)` entries per node.
+
+use rand::Rng;
+
+use std::collections::{HashMap, HashSet};
+use std::f32;
+
+use super::{Local, Label, BaselineMessage, RoutingProtocol, new_neighbours};
+
+/// Distance vector announcement, `path` starts at the announcing node and ends at `des`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DistanceEntry {
+    pub des: u64,
+    pub landmark: bool,
+    pub distance: f32,
+    // distance from `des` to its nearest landmark, bounds its cluster
+    pub radius: f32,
+    pub path: Vec<u64>,
+}
+
+struct Entry {
+    landmark: bool,
+    distance: f32,
+    radius: f32,
+    // hops to destination, empty for ourselves
+    path: Vec<u64>,
+}
+
+pub struct Compact {
+    is_landmark: bool,
+    entries: HashMap<u64, Entry>,
+    pending: HashSet<u64>,
+    // entries dropped since the last tick, passed on so the neighbours drop them too
+    withdrawn: Vec<DistanceEntry>,
+    known: HashSet<u64>,
+    changed: bool,
+    outbox: Vec<(u64, BaselineMessage)>,
+}
+
+impl Compact {
+    pub fn new<R: Rng>(landmark_probability: f32, rng: &mut R) -> Compact {
+        Compact {
+            is_landmark: rng.next_f32() < landmark_probability,
+            entries: HashMap::new(),
+            pending: HashSet::new(),
+            withdrawn: Vec::new(),
+            known: HashSet::new(),
+            changed: false,
+            outbox: Vec::new(),
+        }
+    }
+
+    fn nearest_landmark(&self) -> Option<(u64, f32)> {
+        self.entries.iter()
+            .filter(|&(_, e)| e.landmark)
+            .map(|(id, e)| (*id, e.distance))
+            .fold(None, |best, (id, d)| match best {
+                Some((_, bd)) if bd <= d => best,
+                _ => Some((id, d))
+            })
+    }
+
+    // only landmarks and the nodes whose cluster we are in are kept
+    fn accept(landmark: bool, distance: f32, radius: f32) -> bool {
+        landmark || distance < radius
+    }
+
+    fn announce<'a, I: Iterator<Item=&'a u64>>(&self, id: u64, to: u64, des: I) -> Vec<DistanceEntry> {
+        let mut out = Vec::new();
+        for d in des {
+            if let Some(e) = self.entries.get(d) {
+                if !e.path.contains(&to) {
+                    let mut path = vec![id];
+                    path.extend(e.path.iter().cloned());
+                    out.push(DistanceEntry { des: *d, landmark: e.landmark, distance: e.distance, radius: e.radius, path });
+                }
+            }
+        }
+        for w in &self.withdrawn {
+            if !w.path.contains(&to) {
+                out.push(w.clone());
+            }
+        }
+        out
+    }
+
+    // queue announcements, to new neighbours everything, to all of them what changed
+    fn advertise(&mut self, id: u64, fresh: &[u64], neighbours: &[u64]) {
+        let radius = if self.is_landmark { 0. } else { self.nearest_landmark().map_or(f32::MAX, |(_, d)| d) };
+        let own = self.entries.entry(id).or_insert(Entry { landmark: self.is_landmark, distance: 0., radius: f32::NAN, path: Vec::new() });
+        if own.radius != radius {
+            // a closer landmark shrinks our cluster
+            own.radius = radius;
+            self.pending.insert(id);
+        }
+
+        for &n in fresh {
+            let entries = self.announce(id, n, self.entries.keys());
+            if !entries.is_empty() {
+                self.outbox.push((n, BaselineMessage::Distances(entries)));
+            }
+        }
+
+        if !self.pending.is_empty() || !self.withdrawn.is_empty() {
+            let pending: Vec<u64> = self.pending.drain().collect();
+            for &n in neighbours.iter().filter(|n| !fresh.contains(n)) {
+                let entries = self.announce(id, n, pending.iter());
+                if !entries.is_empty() {
+                    self.outbox.push((n, BaselineMessage::Distances(entries)));
+                }
+            }
+            self.withdrawn.clear();
+        }
+    }
+
+    fn learn(&mut self, id: u64, from: u64, latency: f32, entries: Vec<DistanceEntry>) {
+        for e in entries {
+            if e.des == id || e.path.contains(&id) {
+                continue
+            }
+            let distance = e.distance + latency;
+            let (better, radius) = match self.entries.get(&e.des) {
+                Some(old) if old.path.first() == Some(&from) => (true, e.radius),
+                Some(old) => (distance < old.distance, e.radius.min(old.radius)),
+                None => (true, e.radius)
+            };
+            if better && Compact::accept(e.landmark, distance, radius) {
+                self.entries.insert(e.des, Entry { landmark: e.landmark, distance, radius, path: e.path });
+                self.pending.insert(e.des);
+                self.changed = true;
+                continue
+            }
+            let keep = match self.entries.get_mut(&e.des) {
+                Some(_) if better => false,
+                Some(old) if radius < old.radius => {
+                    old.radius = radius;
+                    Compact::accept(old.landmark, old.distance, radius)
+                }
+                _ => continue
+            };
+            if keep {
+                self.pending.insert(e.des);
+            } else {
+                self.withdraw(id, e.des, radius);
+            }
+        }
+    }
+
+    // the destination found a closer landmark and we left its cluster
+    fn withdraw(&mut self, id: u64, des: u64, radius: f32) {
+        if let Some(old) = self.entries.remove(&des) {
+            let mut path = vec![id];
+            path.extend(old.path);
+            self.withdrawn.push(DistanceEntry { des, landmark: false, distance: old.distance, radius, path });
+            self.pending.remove(&des);
+            self.changed = true;
+        }
+    }
+}
+
+impl RoutingProtocol for Compact {
+    fn tick(&mut self, local: Local) {
+        let fresh = new_neighbours(&local, &mut self.known);
+        let neighbours: Vec<u64> = local.neighbours.iter().map(|n| n.id).collect();
+        self.advertise(local.id, &fresh, &neighbours);
+    }
+
+    fn receive(&mut self, local: Local, from: u64, msg: BaselineMessage) {
+        let entries = match msg {
+            BaselineMessage::Distances(e) => e,
+            _ => return
+        };
+        let latency = match local.neighbour(from) {
+            Some(n) => n.connection.latency,
+            None => return
+        };
+        self.learn(local.id, from, latency, entries);
+    }
+
+    fn route(&self, des: u64, label: Option<&Label>) -> Option<Vec<u64>> {
+        if let Some(e) = self.entries.get(&des) {
+            return Some(e.path.clone())
+        }
+        let label = label?;
+        let mut path = self.entries.get(&label.landmark)?.path.clone();
+        path.extend(label.path.iter().cloned());
+        Some(path)
+    }
+
+    fn label(&self, id: u64) -> Option<Label> {
+        let (landmark, _) = self.nearest_landmark()?;
+        // links are symmetric, so the way back from the landmark is our route reversed
+        let mut path = vec![id];
+        path.extend(self.entries[&landmark].path.iter().cloned());
+        path.pop();
+        path.reverse();
+        Some(Label { landmark, path })
+    }
+
+    fn table_size(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn outbox(&mut self) -> Vec<(u64, BaselineMessage)> {
+        self.outbox.drain(..).collect()
+    }
+
+    fn take_changed(&mut self) -> bool {
+        let c = self.changed;
+        self.changed = false;
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rng::SimRng;
+
+    // a grid with random latencies, every node knows its neighbours from the start
+    fn grid(side: usize, rng: &mut SimRng) -> Vec<Vec<(u64, f32)>> {
+        let mut links = vec![Vec::new(); side * side];
+        for i in 0..side * side {
+            let mut right_and_down = Vec::new();
+            if i % side + 1 < side {
+                right_and_down.push(i + 1);
+            }
+            if i + side < side * side {
+                right_and_down.push(i + side);
+            }
+            for j in right_and_down {
+                let latency = 0.001 + rng.next_f32() * 0.05;
+                links[i].push((j as u64, latency));
+                links[j].push((i as u64, latency));
+            }
+        }
+        links
+    }
+
+    fn shortest(links: &[Vec<(u64, f32)>], from: usize) -> Vec<f32> {
+        let mut distance = vec![f32::MAX; links.len()];
+        let mut done = vec![false; links.len()];
+        distance[from] = 0.;
+        while let Some(u) = (0..links.len()).filter(|&u| !done[u] && distance[u] < f32::MAX)
+            .min_by(|&a, &b| distance[a].partial_cmp(&distance[b]).unwrap()) {
+            done[u] = true;
+            for &(v, latency) in &links[u] {
+                let d = distance[u] + latency;
+                if d < distance[v as usize] {
+                    distance[v as usize] = d;
+                }
+            }
+        }
+        distance
+    }
+
+    #[test]
+    fn converged_routes_have_stretch_at_most_3() {
+        let mut rng = SimRng::derive(3, 0);
+        let links = grid(8, &mut rng);
+        let mut nodes: Vec<Compact> = (0..links.len()).map(|_| Compact::new(0.1, &mut rng)).collect();
+        nodes[0].is_landmark = true;
+
+        for round in 0..1000 {
+            let mut quiet = true;
+            for (i, node) in nodes.iter_mut().enumerate() {
+                let neighbours: Vec<u64> = links[i].iter().map(|&(n, _)| n).collect();
+                let fresh = if round == 0 { neighbours.clone() } else { Vec::new() };
+                node.advertise(i as u64, &fresh, &neighbours);
+            }
+            for i in 0..nodes.len() {
+                for (to, msg) in nodes[i].outbox() {
+                    quiet = false;
+                    let latency = links[i].iter().find(|&&(n, _)| n == to).unwrap().1;
+                    if let BaselineMessage::Distances(entries) = msg {
+                        nodes[to as usize].learn(to, i as u64, latency, entries);
+                    }
+                }
+            }
+            if quiet {
+                break
+            }
+        }
+
+        let mut via_landmark = 0;
+        for u in 0..nodes.len() {
+            let distance = shortest(&links, u);
+            for v in (0..nodes.len()).filter(|&v| v != u) {
+                let label = nodes[v].label(v as u64);
+                let path = nodes[u].route(v as u64, label.as_ref()).unwrap();
+                assert_eq!(path.last(), Some(&(v as u64)));
+                let mut at = u;
+                let mut length = 0.;
+                for &hop in &path {
+                    length += links[at].iter().find(|&&(n, _)| n == hop).unwrap().1;
+                    at = hop as usize;
+                }
+                assert!(length <= 3. * distance[v] + 1e-4, "{} to {}: {} over {}", u, v, length, distance[v]);
+                if !nodes[u].entries.contains_key(&(v as u64)) {
+                    via_landmark += 1;
+                }
+            }
+        }
+        // the tables are compact, most routes go via a landmark
+        assert!(via_landmark > nodes.len() * nodes.len() / 2);
+    }
+}
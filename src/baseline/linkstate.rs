@@ -0,0 +1,168 @@
+//! OSPF-like link state routing.
+//!
+//! Every node floods its adjacencies to the whole network and runs Dijkstra
+//! over the resulting database, so state grows with the size of the network.
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+
+use super::{Local, Label, BaselineMessage, RoutingProtocol};
+
+/// Link state advertisement.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lsa {
+    pub origin: u64,
+    pub seq: u64,
+    pub links: Vec<(u64, f32)>,
+}
+
+pub struct LinkState {
+    seq: u64,
+    // links of our last advertisement
+    advertised: Vec<(u64, f32)>,
+    lsdb: HashMap<u64, Lsa>,
+    routes: HashMap<u64, Vec<u64>>,
+    dirty: bool,
+    changed: bool,
+    outbox: Vec<(u64, BaselineMessage)>,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct State {
+    cost: f32,
+    node: u64,
+}
+
+impl Eq for State {}
+
+impl Ord for State {
+    fn cmp(&self, other: &State) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &State) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl LinkState {
+    pub fn new() -> LinkState {
+        LinkState {
+            seq: 0,
+            advertised: Vec::new(),
+            lsdb: HashMap::new(),
+            routes: HashMap::new(),
+            dirty: false,
+            changed: false,
+            outbox: Vec::new(),
+        }
+    }
+
+    fn flood(&mut self, local: &Local, lsa: &Lsa, except: Option<u64>) {
+        for n in local.neighbours {
+            if Some(n.id) != except {
+                self.outbox.push((n.id, BaselineMessage::Lsa(lsa.clone())));
+            }
+        }
+    }
+
+    fn compute_routes(&mut self, source: u64) {
+        let mut cost: HashMap<u64, f32> = HashMap::new();
+        let mut predecessor: HashMap<u64, u64> = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        cost.insert(source, 0.);
+        heap.push(State { cost: 0., node: source });
+
+        while let Some(State { cost: c, node }) = heap.pop() {
+            if c > cost[&node] {
+                continue
+            }
+            if let Some(lsa) = self.lsdb.get(&node) {
+                for &(next, latency) in &lsa.links {
+                    let nc = c + latency;
+                    if cost.get(&next).is_none_or(|&old| nc < old) {
+                        cost.insert(next, nc);
+                        predecessor.insert(next, node);
+                        heap.push(State { cost: nc, node: next });
+                    }
+                }
+            }
+        }
+
+        let mut routes = HashMap::new();
+        for &des in predecessor.keys() {
+            let mut path = vec![des];
+            let mut current = des;
+            while predecessor[&current] != source {
+                current = predecessor[&current];
+                path.push(current);
+            }
+            path.reverse();
+            routes.insert(des, path);
+        }
+        if routes.len() != self.routes.len() || routes.iter().any(|(k, v)| self.routes.get(k) != Some(v)) {
+            self.changed = true;
+        }
+        self.routes = routes;
+    }
+}
+
+impl RoutingProtocol for LinkState {
+    fn tick(&mut self, local: Local) {
+        // failed links are left out, any change to the others is advertised again
+        let links: Vec<(u64, f32)> = local.neighbours.iter()
+            .filter(|n| !n.failed)
+            .map(|n| (n.id, n.connection.latency))
+            .collect();
+        if links != self.advertised {
+            self.seq += 1;
+            self.advertised = links.clone();
+            let lsa = Lsa { origin: local.id, seq: self.seq, links };
+            self.lsdb.insert(local.id, lsa.clone());
+            self.flood(&local, &lsa, None);
+            self.dirty = true;
+        }
+        // recomputed once per tick rather than per advertisement
+        if self.dirty {
+            self.compute_routes(local.id);
+            self.dirty = false;
+        }
+    }
+
+    fn receive(&mut self, local: Local, from: u64, msg: BaselineMessage) {
+        if let BaselineMessage::Lsa(lsa) = msg {
+            if lsa.origin == local.id {
+                return
+            }
+            if self.lsdb.get(&lsa.origin).is_none_or(|old| old.seq < lsa.seq) {
+                self.flood(&local, &lsa, Some(from));
+                self.lsdb.insert(lsa.origin, lsa);
+                self.dirty = true;
+            }
+        }
+    }
+
+    fn route(&self, des: u64, _label: Option<&Label>) -> Option<Vec<u64>> {
+        self.routes.get(&des).cloned()
+    }
+
+    fn label(&self, _id: u64) -> Option<Label> {
+        None
+    }
+
+    fn table_size(&self) -> usize {
+        self.routes.len()
+    }
+
+    fn outbox(&mut self) -> Vec<(u64, BaselineMessage)> {
+        self.outbox.drain(..).collect()
+    }
+
+    fn take_changed(&mut self) -> bool {
+        let c = self.changed;
+        self.changed = false;
+        c
+    }
+}
@@ -0,0 +1,326 @@
+//! Baseline routing protocols, run alongside the DHT for comparison.
+//!
+//! Every protocol computes full source routes, so data packets are forwarded
+//! by the same `Node::fwd` path as everything else and their stretch is
+//! measured by the world oracle.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use node::{Node, NeighbourData};
+use packet::*;
+use world;
+
+pub use self::linkstate::{LinkState, Lsa};
+pub use self::pathvector::{PathVector, PathRoute};
+pub use self::compact::{Compact, DistanceEntry};
+
+mod linkstate;
+mod pathvector;
+mod compact;
+
+/// Which baseline to run.
+#[derive(Clone, Copy, Debug)]
+pub enum Protocol {
+    /// OSPF-like flooding of link state and Dijkstra per node.
+    LinkState,
+    /// BGP-like with customer/provider/peer policies from the hierarchy levels.
+    PathVector,
+    /// Thorup-Zwick landmarks, each node becomes a landmark with this probability.
+    Compact { landmarks: f32 },
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Protocol::LinkState => "link_state",
+            Protocol::PathVector => "path_vector",
+            Protocol::Compact { .. } => "compact",
+        }
+    }
+
+    /// A fresh protocol instance, landmarks are chosen with `rng`.
+    pub fn create<R: Rng>(&self, rng: &mut R) -> Box<dyn RoutingProtocol> {
+        match *self {
+            Protocol::LinkState => Box::new(LinkState::new()),
+            Protocol::PathVector => Box::new(PathVector::new()),
+            Protocol::Compact { landmarks } => Box::new(Compact::new(landmarks, rng)),
+        }
+    }
+}
+
+/// Address of a node in name dependent schemes, the sender is assumed to know it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Label {
+    pub landmark: u64,
+    // route from the landmark, ending at the labelled node
+    pub path: Vec<u64>,
+}
+
+/// The part of a node a protocol can see.
+pub struct Local<'a> {
+    pub id: u64,
+    pub level: usize,
+    pub neighbours: &'a [NeighbourData],
+}
+
+impl<'a> Local<'a> {
+    pub fn neighbour(&self, id: u64) -> Option<&NeighbourData> {
+        self.neighbours.iter().find(|n| n.id == id)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BaselineMessage {
+    Lsa(Lsa),
+    Routes(Vec<PathRoute>),
+    Distances(Vec<DistanceEntry>),
+}
+
+impl BaselineMessage {
+    /// Encoded size in bytes.
+    pub fn wire_size(&self) -> usize {
+        let id = mem::size_of::<u64>();
+        match *self {
+            BaselineMessage::Lsa(ref lsa) => 2 * id + lsa.links.len() * (id + mem::size_of::<f32>()),
+            BaselineMessage::Routes(ref routes) => routes.iter().map(|r| id * (1 + r.path.len())).sum(),
+            BaselineMessage::Distances(ref entries) => entries.iter()
+                .map(|e| id * (1 + e.path.len()) + 1 + 2 * mem::size_of::<f32>()).sum(),
+        }
+    }
+}
+
+pub trait RoutingProtocol {
+    /// Called every tick, may queue control messages.
+    fn tick(&mut self, local: Local);
+    /// Process a control message from a neighbour.
+    fn receive(&mut self, local: Local, from: u64, msg: BaselineMessage);
+    /// Hops after this node up to and including `des`.
+    fn route(&self, des: u64, label: Option<&Label>) -> Option<Vec<u64>>;
+    /// Label others need to reach this node, if the protocol is name dependent.
+    fn label(&self, id: u64) -> Option<Label>;
+    /// Number of destinations with a stored route.
+    fn table_size(&self) -> usize;
+    /// Queued control messages, as (neighbour, message).
+    fn outbox(&mut self) -> Vec<(u64, BaselineMessage)>;
+    /// Whether the routing table changed since the last call.
+    fn take_changed(&mut self) -> bool;
+}
+
+/// Network wide baseline statistics kept by the world.
+#[derive(Default)]
+pub struct BaselineStats {
+    pub tables: HashMap<u64, usize>,
+    pub messages: HashMap<u64, u64>,
+    pub failures: HashMap<u64, u64>,
+    pub labels: HashMap<u64, Label>,
+    pub last_change: u64,
+    pub converged: Option<(u64, u64)>,
+}
+
+impl BaselineStats {
+    pub fn total_messages(&self) -> u64 {
+        self.messages.values().sum()
+    }
+
+    pub fn record(&mut self, report: world::BaselineReport, epoch: u64) {
+        if report.changed {
+            self.last_change = epoch;
+            self.converged = None;
+        }
+        self.tables.insert(report.id, report.table_size);
+        self.messages.insert(report.id, report.messages);
+        self.failures.insert(report.id, report.failures);
+        if let Some(label) = report.label {
+            self.labels.insert(report.id, label);
+        }
+    }
+
+    // considered converged once no table changed for `quiet` epochs
+    pub fn check_converged(&mut self, epoch: u64, quiet: u64) {
+        if self.converged.is_none() && epoch >= self.last_change + quiet && !self.tables.is_empty() {
+            self.converged = Some((self.last_change, self.total_messages()));
+        }
+    }
+
+    pub fn summary(&self, protocol: Protocol) -> String {
+        let sizes: Vec<usize> = self.tables.values().cloned().collect();
+        let max = sizes.iter().cloned().max().unwrap_or(0);
+        let mean = if !sizes.is_empty() { sizes.iter().sum::<usize>() as f32 / sizes.len() as f32 } else { 0. };
+        let convergence = match self.converged {
+            Some((epoch, messages)) => format!("converged at epoch {} after {} messages", epoch, messages),
+            None => String::from("not converged"),
+        };
+        format!("{}: table size mean {:.1} max {}, {} messages, {} unroutable probes, {}",
+                protocol.name(), mean, max, self.total_messages(), self.failures.values().sum::<u64>(), convergence)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("id,table_size,messages,failures\n");
+        for (id, size) in &self.tables {
+            out.push_str(&format!("{},{},{},{}\n", id, size,
+                                  self.messages.get(id).cloned().unwrap_or(0),
+                                  self.failures.get(id).cloned().unwrap_or(0)));
+        }
+        out
+    }
+}
+
+impl Node {
+    pub fn baseline_tick(&mut self) {
+        if let Some(ref mut b) = self.baseline {
+            b.tick(Local { id: self.id, level: self.level, neighbours: &self.neighbours });
+        } else {
+            return
+        }
+        self.baseline_flush();
+    }
+
+    fn baseline_receive(&mut self, from: u64, msg: BaselineMessage) {
+        if let Some(ref mut b) = self.baseline {
+            b.receive(Local { id: self.id, level: self.level, neighbours: &self.neighbours }, from, msg);
+        }
+        self.baseline_flush();
+    }
+
+    // send queued control messages and report the table to the world
+    fn baseline_flush(&mut self) {
+        let (outbox, changed, table_size, label) = match self.baseline {
+            Some(ref mut b) => (b.outbox(), b.take_changed(), b.table_size(), b.label(self.id)),
+            None => return
+        };
+        let sent = outbox.len();
+        for (to, msg) in outbox {
+            if !self.neighbours_map.contains_key(&to) {
+                continue
+            }
+            self.send_packet(Packet {
+                from: self.id,
+                des: to,
+                route: vec![to],
+                data: BaselineUpdate { msg },
+                hops: Vec::new(),
+                sent_at: 0.,
+                elapsed: 0.,
+                trace: 0,
+            });
+            self.baseline_messages += 1;
+        }
+        if changed || sent > 0 {
+            self.world.send(world::BaselineReport {
+                id: self.id,
+                table_size,
+                messages: self.baseline_messages,
+                failures: self.baseline_failures,
+                changed,
+                label,
+            });
+        }
+    }
+}
+
+// sent by the world to ask a node to route a data packet with its baseline protocol
+pub struct Probe {
+    pub des: u64,
+    pub label: Option<Label>,
+}
+
+message!(Probe);
+
+impl Handler<Probe> for Node {
+    fn handle(&mut self, msg: Probe, _ctx: &mut Context<Self>) -> Response<Self, Probe> {
+        let route = match self.baseline {
+            Some(ref b) => b.route(msg.des, msg.label.as_ref()),
+            None => None
+        }.map(|r| {
+            let mut full = vec![self.id];
+            full.extend(r);
+            let mut r = remove_loops(full);
+            r.remove(0);
+            r
+        });
+        match route {
+            Some(mut route) if !route.is_empty() && self.neighbours_map.contains_key(&route[0]) => {
+                route.reverse();
+                self.send_packet(Packet {
+                    from: self.id,
+                    des: msg.des,
+                    route,
+                    data: BaselineData,
+                    hops: Vec::new(),
+                    sent_at: 0.,
+                    elapsed: 0.,
+                    trace: 0,
+                });
+            }
+            _ => {
+                self.baseline_failures += 1;
+                self.world.send(world::BaselineReport {
+                    id: self.id,
+                    table_size: self.baseline.as_ref().map_or(0, |b| b.table_size()),
+                    messages: self.baseline_messages,
+                    failures: self.baseline_failures,
+                    changed: false,
+                    label: None,
+                });
+            }
+        }
+        Self::reply(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaselineUpdate {
+    pub msg: BaselineMessage,
+}
+
+message!(BaselineUpdate);
+
+impl PacketData for BaselineUpdate {
+    fn kind() -> &'static str {
+        "baseline_update"
+    }
+
+    fn size(&self) -> usize {
+        self.msg.wire_size()
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        node.baseline_receive(packet.from, packet.data.msg.clone());
+        Node::reply(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaselineData;
+
+message!(BaselineData);
+
+impl PacketData for BaselineData {
+    fn kind() -> &'static str {
+        "baseline_data"
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
+    fn process(_packet: &Packet<Self>, _node: &mut Node) -> Response<Node, Packet<Self>> {
+        Node::reply(())
+    }
+}
+
+// neighbours we have not yet sent our full table to
+fn new_neighbours(local: &Local, known: &mut HashSet<u64>) -> Vec<u64> {
+    let mut out = Vec::new();
+    for n in local.neighbours {
+        if known.insert(n.id) {
+            out.push(n.id);
+        }
+    }
+    out
+}
@@ -0,0 +1,169 @@
+//! BGP-like path vector routing with Gao-Rexford policies.
+//!
+//! Relationships are derived from the world hierarchy: a neighbour closer to
+//! the core is a provider, one further away is a customer and one on the same
+//! level is a peer.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{Local, Label, BaselineMessage, RoutingProtocol, new_neighbours};
+
+/// Relationship to a neighbour, ordered by preference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Relation {
+    Customer,
+    Peer,
+    Provider,
+}
+
+impl Relation {
+    pub fn between(own_level: usize, neighbour_level: usize) -> Relation {
+        if neighbour_level > own_level {
+            Relation::Customer
+        } else if neighbour_level == own_level {
+            Relation::Peer
+        } else {
+            Relation::Provider
+        }
+    }
+}
+
+/// Route announcement, `path` starts at the announcing node and ends at `des`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PathRoute {
+    pub des: u64,
+    pub path: Vec<u64>,
+}
+
+struct Entry {
+    // first hop to destination, empty for our own prefix
+    path: Vec<u64>,
+    // None for routes we originate
+    relation: Option<Relation>,
+}
+
+impl Entry {
+    fn better_than(&self, other: &Entry) -> bool {
+        (self.relation, self.path.len()) < (other.relation, other.path.len())
+    }
+}
+
+pub struct PathVector {
+    rib: HashMap<u64, Entry>,
+    pending: HashSet<u64>,
+    known: HashSet<u64>,
+    changed: bool,
+    outbox: Vec<(u64, BaselineMessage)>,
+}
+
+impl PathVector {
+    pub fn new() -> PathVector {
+        PathVector {
+            rib: HashMap::new(),
+            pending: HashSet::new(),
+            known: HashSet::new(),
+            changed: false,
+            outbox: Vec::new(),
+        }
+    }
+
+    // routes learned from customers (or our own) go to everyone, the rest only to customers
+    fn exportable(entry: &Entry, to: Relation) -> bool {
+        match entry.relation {
+            None | Some(Relation::Customer) => true,
+            _ => to == Relation::Customer,
+        }
+    }
+
+    fn announce<'a, I: Iterator<Item=&'a u64>>(&self, local: &Local, to: u64, to_level: usize, des: I) -> Vec<PathRoute> {
+        let relation = Relation::between(local.level, to_level);
+        let mut routes = Vec::new();
+        for d in des {
+            if let Some(e) = self.rib.get(d) {
+                if PathVector::exportable(e, relation) && !e.path.contains(&to) {
+                    let mut path = vec![local.id];
+                    path.extend(e.path.iter().cloned());
+                    routes.push(PathRoute { des: *d, path });
+                }
+            }
+        }
+        routes
+    }
+}
+
+impl RoutingProtocol for PathVector {
+    fn tick(&mut self, local: Local) {
+        if let std::collections::hash_map::Entry::Vacant(e) = self.rib.entry(local.id) {
+            e.insert(Entry { path: Vec::new(), relation: None });
+            self.pending.insert(local.id);
+        }
+
+        // full table to neighbours that have just come up
+        for n in new_neighbours(&local, &mut self.known) {
+            let level = local.neighbour(n).unwrap().level;
+            let routes = self.announce(&local, n, level, self.rib.keys());
+            if !routes.is_empty() {
+                self.outbox.push((n, BaselineMessage::Routes(routes)));
+            }
+        }
+
+        if !self.pending.is_empty() {
+            let pending: Vec<u64> = self.pending.drain().collect();
+            for n in local.neighbours {
+                let routes = self.announce(&local, n.id, n.level, pending.iter());
+                if !routes.is_empty() {
+                    self.outbox.push((n.id, BaselineMessage::Routes(routes)));
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self, local: Local, from: u64, msg: BaselineMessage) {
+        let routes = match msg {
+            BaselineMessage::Routes(r) => r,
+            _ => return
+        };
+        let relation = match local.neighbour(from) {
+            Some(n) => Relation::between(local.level, n.level),
+            None => return
+        };
+        for r in routes {
+            if r.des == local.id || r.path.contains(&local.id) {
+                continue
+            }
+            let candidate = Entry { path: r.path, relation: Some(relation) };
+            let replace = match self.rib.get(&r.des) {
+                // implicit withdrawal, the neighbour we use changed its route
+                Some(old) => candidate.better_than(old) || old.path.first() == Some(&from),
+                None => true
+            };
+            if replace {
+                self.rib.insert(r.des, candidate);
+                self.pending.insert(r.des);
+                self.changed = true;
+            }
+        }
+    }
+
+    fn route(&self, des: u64, _label: Option<&Label>) -> Option<Vec<u64>> {
+        self.rib.get(&des).map(|e| e.path.clone())
+    }
+
+    fn label(&self, _id: u64) -> Option<Label> {
+        None
+    }
+
+    fn table_size(&self) -> usize {
+        self.rib.len()
+    }
+
+    fn outbox(&mut self) -> Vec<(u64, BaselineMessage)> {
+        self.outbox.drain(..).collect()
+    }
+
+    fn take_changed(&mut self) -> bool {
+        let c = self.changed;
+        self.changed = false;
+        c
+    }
+}
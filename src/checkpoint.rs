@@ -0,0 +1,346 @@
+//! Saving and restoring a whole simulation.
+//!
+//! A checkpoint is taken at the start of an epoch, once every packet of the
+//! previous one has been processed, so nothing is in flight. It holds the
+//! world graph with node positions and thread assignment, the links taken
+//! down by scenarios or churn, and the state of every started node including
+//! its random stream. Neighbour lists are rebuilt from the saved graph when
+//! the nodes start again, with empty link queues. Baseline routing state is
+//! not saved and reconverges after a restore. The statistics the world
+//! gathered so far are saved too, so the summaries of a restored run cover it
+//! from the start.
+
+use actix::*;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use serde_json;
+
+use petgraph::graph::NodeIndex;
+
+use address::{Address, AddressRequest};
+use accounting::{NodeState, StateSummary};
+use adversary::{Attack, Witnesses};
+use advert::{LinkMetric, MetricAdvert, MetricProbe};
+use baseline::{BaselineData, BaselineUpdate};
+use connection::Connection;
+use dht::service::{DHT, DHTLookup, DHTLookupReply, Ping};
+use directory::{DHTFindValue, DHTStore};
+use ledger::{Accounts, Invoice};
+use link::{KindStats, QueueStats};
+use nc::{NCNodeData, NCProbe};
+use node::{Node, NodeConfig};
+use onion::Onion;
+use oracle::StretchStats;
+use packet::PacketData;
+use rng::SimRng;
+use traffic::{Data, TrafficStats};
+use world::{self, NCSummary, World};
+
+// every packet kind, statistics are keyed by their static names
+fn kinds() -> Vec<&'static str> {
+    vec![NCProbe::kind(), AddressRequest::kind(), Invoice::kind(), MetricAdvert::kind(), MetricProbe::kind(),
+         Data::kind(), BaselineUpdate::kind(), BaselineData::kind(), DHTStore::kind(), DHTFindValue::kind(),
+         Onion::kind(), Ping::kind(), DHTLookup::kind(), DHTLookupReply::kind()]
+}
+
+/// Statistics keyed by packet kind, names read back are matched to the static ones.
+pub mod kind_map {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use std::collections::HashMap;
+
+    pub fn serialize<S: Serializer, V: Serialize>(map: &HashMap<&'static str, V>, s: S) -> Result<S::Ok, S::Error> {
+        map.serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, V: Deserialize<'de>>(d: D) -> Result<HashMap<&'static str, V>, D::Error> {
+        let kinds = super::kinds();
+        HashMap::<String, V>::deserialize(d)?.into_iter()
+            .map(|(k, v)| match kinds.iter().find(|&&n| n == k) {
+                Some(&n) => Ok((n, v)),
+                None => Err(D::Error::custom(format!("unknown packet kind {}", k)))
+            })
+            .collect()
+    }
+}
+
+/// Everything a node needs to carry on where it stopped.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub id: u64,
+    pub graph_index: usize,
+    pub level: usize,
+    pub now: f32,
+    pub rng: SimRng,
+    pub dht: DHT,
+    pub dht_init: bool,
+    pub nc: NCNodeData,
+    pub nc_error: f32,
+    pub nc_last: Option<(f32, f32)>,
+    pub nc_rank: (u64, u64),
+    pub malicious: Option<Attack>,
+    pub nc_reputation: HashMap<u64, f32>,
+    pub nc_witnesses: Witnesses,
+    pub nc_rejected: u64,
+    pub published: Option<f32>,
+    pub publish_version: u64,
+    pub addresses: Vec<Address>,
+    pub address_offers: HashMap<u64, (Vec<Address>, u32)>,
+    pub address_children: HashMap<u64, u16>,
+    pub address_version: u64,
+    pub address_round: Option<f32>,
+    pub readdressed: u64,
+    pub lookups: u64,
+    pub lookup_failures: u64,
+    pub metric_liar: bool,
+    pub adverts: HashMap<u64, Vec<LinkMetric>>,
+    pub metric_reputation: HashMap<u64, f32>,
+    pub advert_round: Option<f32>,
+    pub accounts: Accounts,
+    pub known_keys: HashMap<u64, u64>,
+    #[serde(with = "kind_map")]
+    pub link_stats: HashMap<&'static str, KindStats>,
+}
+
+impl Node {
+    pub fn snapshot(&self) -> NodeSnapshot {
+        NodeSnapshot {
+            id: self.id,
+            graph_index: self.graph_index.index(),
+            level: self.level,
+            now: self.now,
+            rng: self.rng.clone(),
+            dht: self.dht.clone(),
+            dht_init: self.dht_init,
+            nc: self.nc.clone(),
+            nc_error: self.nc_error,
+            nc_last: self.nc_last,
+            nc_rank: self.nc_rank,
+            malicious: self.malicious,
+            nc_reputation: self.nc_reputation.clone(),
+            nc_witnesses: self.nc_witnesses.clone(),
+            nc_rejected: self.nc_rejected,
+            published: self.published,
+            publish_version: self.publish_version,
+            addresses: self.addresses.clone(),
+            address_offers: self.address_offers.clone(),
+            address_children: self.address_children.clone(),
+            address_version: self.address_version,
+            address_round: self.address_round,
+            readdressed: self.readdressed,
+            lookups: self.lookups,
+            lookup_failures: self.lookup_failures,
+            metric_liar: self.metric_liar,
+            adverts: self.adverts.clone(),
+            metric_reputation: self.metric_reputation.clone(),
+            advert_round: self.advert_round,
+            accounts: self.accounts.clone(),
+            known_keys: self.known_keys.clone(),
+            link_stats: self.link_stats.clone(),
+        }
+    }
+
+    /// A node continuing from `s`, the configuration is the one of the restoring run.
+    pub fn restore(s: NodeSnapshot, config: NodeConfig, world: SyncAddress<World>) -> Node {
+        let mut node = Node::new(NodeIndex::new(s.graph_index), s.level, config, world);
+        node.id = s.id;
+        node.now = s.now;
+        node.rng = s.rng;
+        node.dht = s.dht;
+        node.dht_init = s.dht_init;
+        node.nc = s.nc;
+        node.nc_error = s.nc_error;
+        node.nc_last = s.nc_last;
+        node.nc_rank = s.nc_rank;
+        node.malicious = s.malicious;
+        node.nc_reputation = s.nc_reputation;
+        node.nc_witnesses = s.nc_witnesses;
+        node.nc_rejected = s.nc_rejected;
+        node.published = s.published;
+        node.publish_version = s.publish_version;
+        node.addresses = s.addresses;
+        node.address_offers = s.address_offers;
+        node.address_children = s.address_children;
+        node.address_version = s.address_version;
+        node.address_round = s.address_round;
+        node.readdressed = s.readdressed;
+        node.lookups = s.lookups;
+        node.lookup_failures = s.lookup_failures;
+        node.metric_liar = s.metric_liar;
+        node.adverts = s.adverts;
+        node.metric_reputation = s.metric_reputation;
+        node.advert_round = s.advert_round;
+        node.accounts = s.accounts;
+        node.known_keys = s.known_keys;
+        node.link_stats = s.link_stats;
+        node
+    }
+}
+
+// sent by the world at an epoch boundary, answered with a `world::SnapshotReport`
+pub struct TakeSnapshot;
+
+message!(TakeSnapshot);
+
+impl Handler<TakeSnapshot> for Node {
+    fn handle(&mut self, _msg: TakeSnapshot, _ctx: &mut Context<Self>) -> Response<Self, TakeSnapshot> {
+        self.world.send(world::SnapshotReport { snapshot: self.snapshot() });
+        Self::reply(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GraphNodeState {
+    pub level: usize,
+    pub thread: usize,
+    pub position: [f32; 2],
+}
+
+/// Both directions of a link between two graph indices.
+pub type LinkState = (usize, usize, Connection, Connection);
+
+/// What the world recorded before the checkpoint.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct WorldStats {
+    // route stretch of delivered packets, by packet type
+    #[serde(with = "kind_map")]
+    pub stretch: HashMap<&'static str, StretchStats>,
+    pub nc_history: Vec<NCSummary>,
+    pub nc_converged: Option<u64>,
+    pub traffic: TrafficStats,
+    // ID the traffic generator gives the next flow
+    pub next_flow: u64,
+    pub queues: QueueStats,
+    // latest routing state reported by each node, and the summaries taken as the network grew
+    pub states: HashMap<u64, NodeState>,
+    pub state_history: Vec<StateSummary>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub seed: u64,
+    // the epoch about to start
+    pub epoch: u64,
+    // by graph index
+    pub nodes: Vec<GraphNodeState>,
+    // links which are up
+    pub links: Vec<LinkState>,
+    // links taken down by the scenario, and nodes taken down by churn with the epoch they come back
+    pub failed_links: Vec<LinkState>,
+    pub down: Vec<(usize, u64, Vec<LinkState>)>,
+    pub churned: u64,
+    // every started node, by graph index
+    pub states: Vec<NodeSnapshot>,
+    #[serde(default)]
+    pub stats: WorldStats,
+}
+
+impl Checkpoint {
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        File::create(path).map_err(|e| e.to_string())
+            .and_then(|f| serde_json::to_writer(BufWriter::new(f), self).map_err(|e| e.to_string()))
+            .map_err(|e| format!("cannot write checkpoint {:?}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Checkpoint, String> {
+        File::open(path).map_err(|e| e.to_string())
+            .and_then(|f| serde_json::from_reader(BufReader::new(f)).map_err(|e| e.to_string()))
+            .map_err(|e| format!("cannot read checkpoint {}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dht::service::DHTParams;
+    use nc::NCParams;
+    use rand::Rng;
+
+    fn snapshot() -> NodeSnapshot {
+        let mut rng = SimRng::derive(7, 3);
+        let nc = NCNodeData::new(&NCParams::default(), &mut rng);
+        let mut link_stats = HashMap::new();
+        link_stats.insert(Data::kind(), KindStats { sent: 5, dropped: 1, queueing: 0.25 });
+        NodeSnapshot {
+            id: 42,
+            graph_index: 3,
+            level: 1,
+            now: 12.5,
+            rng,
+            dht: DHT::with_params(42, DHTParams::default()),
+            dht_init: true,
+            nc,
+            nc_error: 0.3,
+            nc_last: Some((0.02, 0.03)),
+            nc_rank: (4, 6),
+            malicious: None,
+            nc_reputation: HashMap::new(),
+            nc_witnesses: Witnesses::new(),
+            nc_rejected: 2,
+            published: Some(10.),
+            publish_version: 3,
+            addresses: vec![Address(vec![1, 2])],
+            address_offers: vec![(7, (vec![Address(vec![1, 3])], 1))].into_iter().collect(),
+            address_children: HashMap::new(),
+            address_version: 1,
+            address_round: None,
+            readdressed: 0,
+            lookups: 4,
+            lookup_failures: 1,
+            metric_liar: false,
+            adverts: HashMap::new(),
+            metric_reputation: HashMap::new(),
+            advert_round: None,
+            accounts: Accounts::default(),
+            known_keys: HashMap::new(),
+            link_stats,
+        }
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut stats = WorldStats::default();
+        stats.stretch.insert(DHTLookup::kind(), StretchStats { delivered: 9, total_stretch: 12., ..StretchStats::default() });
+        stats.next_flow = 17;
+        stats.states.insert(42, NodeState { neighbours: 3, ..NodeState::default() });
+        let checkpoint = Checkpoint {
+            seed: 1,
+            epoch: 20,
+            nodes: vec![GraphNodeState { level: 1, thread: 0, position: [0.5, 0.5] }],
+            links: Vec::new(),
+            failed_links: Vec::new(),
+            down: Vec::new(),
+            churned: 0,
+            states: vec![snapshot()],
+            stats,
+        };
+        let restored: Checkpoint = serde_json::from_str(&serde_json::to_string(&checkpoint).unwrap()).unwrap();
+
+        let s = &restored.states[0];
+        assert_eq!(s.id, 42);
+        assert_eq!(s.nc_last, Some((0.02, 0.03)));
+        assert_eq!(s.address_offers[&7], (vec![Address(vec![1, 3])], 1));
+        assert_eq!(s.link_stats[Data::kind()].sent, 5);
+        // the stream carries on where it stopped
+        let (mut a, mut b) = (checkpoint.states[0].rng.clone(), s.rng.clone());
+        assert_eq!(a.next_u64(), b.next_u64());
+
+        assert_eq!(restored.stats.stretch[DHTLookup::kind()].delivered, 9);
+        assert_eq!(restored.stats.next_flow, 17);
+        assert_eq!(restored.stats.states[&42].neighbours, 3);
+    }
+
+    #[test]
+    fn unknown_packet_kinds_are_rejected() {
+        let mut json = serde_json::to_value(snapshot()).unwrap();
+        let mut kinds = serde_json::Map::new();
+        kinds.insert(String::from("bogus"), json["link_stats"]["data"].clone());
+        json["link_stats"] = serde_json::Value::Object(kinds);
+        assert!(serde_json::from_value::<NodeSnapshot>(json).is_err());
+    }
+}
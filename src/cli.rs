@@ -0,0 +1,307 @@
+//! Command line interface.
+//!
+//! `run` simulates, `generate` only builds and exports the graph, `inspect`
+//! summarises the statistics a run exported, `sweep` repeats a run over a
+//! grid of parameters and consecutive seeds, `udp` runs every node of a
+//! topology as its own process and `trace` replays a trace file.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use logging::Level;
+use partition::Partitioner;
+use sweep::Setting;
+use trace::{self, Filter, Subsystem};
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub scenario: Option<String>,
+    pub seed: u64,
+    // arbiter threads nodes are spread over
+    pub threads: usize,
+    // stop after this many epochs, run forever if unset
+    pub epochs: Option<u64>,
+    pub output_dir: Option<String>,
+    pub log_level: Level,
+    // trace levels by subsystem, the events go to trace.jsonl in the output directory
+    pub trace: Vec<(Subsystem, Level)>,
+    // runs of a sweep for every data point, each with the next seed
+    pub runs: u64,
+    // parameters set for every run, and the values a sweep varies
+    pub set: Vec<(String, String)>,
+    pub vary: Vec<(String, Vec<String>)>,
+    // epochs between checkpoints written to the output directory, and a checkpoint to continue from
+    pub checkpoint_interval: Option<u64>,
+    pub restore: Option<String>,
+    pub partitioner: Partitioner,
+    // processes the nodes are spread over, and the shard this one runs with the coordinator it reports to
+    pub shards: usize,
+    pub shard: Option<usize>,
+    pub coordinator: Option<String>,
+    // topology file of a udp run, and the node this process runs
+    pub topology: Option<String>,
+    pub node: Option<usize>,
+    // stop at the start of this epoch and take queries on stdin
+    pub pause: Option<u64>,
+    // refuse to start on a disconnected graph
+    pub require_connected: bool,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            scenario: None,
+            seed: 0,
+            threads: 1,
+            epochs: None,
+            output_dir: None,
+            log_level: Level::Quiet,
+            trace: Vec::new(),
+            runs: 1,
+            set: Vec::new(),
+            vary: Vec::new(),
+            checkpoint_interval: None,
+            restore: None,
+            partitioner: Partitioner::default(),
+            shards: 1,
+            shard: None,
+            coordinator: None,
+            topology: None,
+            node: None,
+            pause: None,
+            require_connected: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Command {
+    Run(Options),
+    Generate(Options),
+    Inspect(String),
+    Trace(String, Filter),
+    Journey(String, u64),
+    Sweep(Options),
+    Udp(Options),
+    Help,
+}
+
+pub fn usage() -> &'static str {
+    "usage: routing-actor <command> [options]
+
+commands:
+  run                 simulate until the epoch limit, forever without one
+  generate            build the graph from the seed, export it and exit
+  inspect <dir>       summarise the statistics exported to a directory
+  trace <file>        print the events of a trace file in order of simulated time, only
+                      those matching --node <id>, --subsystem <s>, --kind <k>, --packet <p>,
+                      --goal <id> and --epochs <a>-<b> if given, or with --journey <id>
+                      the timeline of one packet or lookup with the packets it sent
+  sweep               repeat `run` for every combination of --vary and --runs seeds,
+                      one directory each, and combine the results in sweep.csv
+  udp                 run every node of --topology as a process exchanging UDP datagrams
+                      on localhost, each exporting to node-<i> under the output directory
+                      (default udp), epochs last their length in real time
+  help                print this message
+
+options:
+  --scenario <file>   timeline of events to execute
+  --seed <n>          seed of the generated graph (default 0)
+  --threads <n>       arbiter threads to spread nodes over (default 1)
+  --partition <p>     label (balanced label propagation, default) or upstream
+  --epochs <n>        stop after this many epochs
+  --output <dir>      directory statistics are exported to
+  --log <level>       quiet, info or debug (default quiet), the final summary is always printed
+  --trace <levels>    trace events to trace.jsonl in the output directory, a level for every
+                      subsystem or <subsystem>=<level>,.. for world, dht, nc and forward
+                      (default quiet)
+  --runs <n>          seeds of a sweep for every combination (default 1)
+  --set <key>=<v>     set a parameter for every run
+  --vary <key>=<v,..> values of a parameter for a sweep
+  --checkpoint <n>    write a checkpoint every n epochs
+  --restore <file>    continue from a checkpoint instead of generating a graph,
+                      scenario events before it are skipped
+  --shards <n>        run the nodes in n processes synchronised by this one, each
+                      exporting to shard-<k> under the output directory (default shards)
+  --pause <epoch>     stop at the start of the epoch and read queries on stdin, answered
+                      as JSON (type help once paused), run only
+  --require-connected do not start nodes if the generated graph is disconnected
+  --topology <file>   nodes with their ports and links with their delay and loss, as
+                      exported to topology.txt by generate
+parameters: nodes, bucket_size, max_node_count, dimension, loss, churn and, with the numbers
+after a variant optional,
+  model               factorisation, vivaldi or dmfsgd
+  schedule            constant, decay:<factor>:<min> or adaptive:<min>:<max>
+  baseline            none, link_state, path_vector or compact:<landmark probability>
+  queue               drop_tail, red:<min>:<max>:<max p> or codel:<target>:<interval>
+  defence             none, outlier:<threshold>, reputation:<weight> or veracity:<witnesses>:<threshold>
+  addressing          provider, coordinates:<depth> or hybrid:<depth up to 8>
+  private_routes      off, on or rendezvous
+  adverts             off or on:<liars>:<factor>
+  billing             off or on"
+}
+
+fn value<T, F: Fn(&str) -> Option<T>>(args: &mut dyn Iterator<Item=&String>, name: &str, parse: F) -> Result<T, String> {
+    match args.next() {
+        Some(v) => parse(v).ok_or_else(|| format!("invalid value for {}: {}", name, v)),
+        None => Err(format!("missing value for {}", name))
+    }
+}
+
+// `key=value`, checked against a default setting
+fn assignment(arg: &str) -> Option<(String, Vec<String>)> {
+    let mut parts = arg.splitn(2, '=');
+    let (key, values) = match (parts.next(), parts.next()) {
+        (Some(k), Some(v)) => (k.to_string(), v.split(',').map(|v| v.to_string()).collect::<Vec<String>>()),
+        _ => return None
+    };
+    let mut setting = Setting::default();
+    if values.iter().all(|v| setting.apply(&key, v).is_ok()) {
+        Some((key, values))
+    } else {
+        None
+    }
+}
+
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+    let command = match args.next() {
+        Some(c) => c.clone(),
+        None => return Ok(Command::Help)
+    };
+    if command == "inspect" {
+        return match args.next() {
+            Some(dir) => Ok(Command::Inspect(dir.clone())),
+            None => Err(String::from("inspect needs a directory"))
+        }
+    }
+
+    if command == "trace" {
+        return parse_trace(&mut args)
+    }
+
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scenario" => options.scenario = Some(value(&mut args, arg, |v| Some(v.to_string()))?),
+            "--seed" => options.seed = value(&mut args, arg, |v| v.parse().ok())?,
+            "--threads" => options.threads = value(&mut args, arg, |v| v.parse::<usize>().ok().and_then(|n| if n > 0 { Some(n) } else { None }))?,
+            "--partition" => options.partitioner = value(&mut args, arg, Partitioner::parse)?,
+            "--epochs" => options.epochs = Some(value(&mut args, arg, |v| v.parse().ok())?),
+            "--output" => options.output_dir = Some(value(&mut args, arg, |v| Some(v.to_string()))?),
+            "--log" => options.log_level = value(&mut args, arg, Level::parse)?,
+            "--trace" => options.trace.extend(value(&mut args, arg, trace::parse_levels)?),
+            "--runs" => options.runs = value(&mut args, arg, |v| v.parse().ok())?,
+            "--set" => {
+                let (key, mut values) = value(&mut args, arg, |v| assignment(v).and_then(|a| if a.1.len() == 1 { Some(a) } else { None }))?;
+                options.set.push((key, values.remove(0)));
+            }
+            "--vary" => options.vary.push(value(&mut args, arg, assignment)?),
+            "--checkpoint" => options.checkpoint_interval = Some(value(&mut args, arg, |v| v.parse::<u64>().ok().and_then(|n| if n > 0 { Some(n) } else { None }))?),
+            "--restore" => options.restore = Some(value(&mut args, arg, |v| Some(v.to_string()))?),
+            "--shards" => options.shards = value(&mut args, arg, |v| v.parse::<usize>().ok().and_then(|n| if n > 0 { Some(n) } else { None }))?,
+            // set by the coordinator on the processes it starts
+            "--shard" => options.shard = Some(value(&mut args, arg, |v| v.parse().ok())?),
+            "--coordinator" => options.coordinator = Some(value(&mut args, arg, |v| Some(v.to_string()))?),
+            "--pause" => options.pause = Some(value(&mut args, arg, |v| v.parse().ok())?),
+            "--require-connected" => options.require_connected = true,
+            "--topology" => options.topology = Some(value(&mut args, arg, |v| Some(v.to_string()))?),
+            // set by `udp` on the processes it starts
+            "--node" => options.node = Some(value(&mut args, arg, |v| v.parse().ok())?),
+            _ => return Err(format!("unknown option {}", arg))
+        }
+    }
+
+    if options.shards > 1 {
+        if command != "run" {
+            return Err(String::from("--shards only applies to run"))
+        }
+        if options.checkpoint_interval.is_some() || options.restore.is_some() {
+            return Err(String::from("checkpoints are not supported with --shards"))
+        }
+    }
+
+    if options.pause.is_some() && (command != "run" || options.shards > 1) {
+        return Err(String::from("--pause only applies to run in a single process"))
+    }
+
+    if command == "udp" {
+        if options.topology.is_none() {
+            return Err(String::from("udp needs --topology"))
+        }
+        if options.scenario.is_some() || options.checkpoint_interval.is_some() || options.restore.is_some() || options.shards > 1 {
+            return Err(String::from("scenarios, checkpoints and --shards are not supported with udp"))
+        }
+    }
+
+    match command.as_str() {
+        "run" => Ok(Command::Run(options)),
+        "generate" => Ok(Command::Generate(options)),
+        "sweep" if options.epochs.is_none() => Err(String::from("sweep needs --epochs")),
+        "sweep" => Ok(Command::Sweep(options)),
+        "udp" => Ok(Command::Udp(options)),
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        _ => Err(format!("unknown command {}", command))
+    }
+}
+
+fn parse_trace(args: &mut dyn Iterator<Item=&String>) -> Result<Command, String> {
+    let path = match args.next() {
+        Some(p) => p.clone(),
+        None => return Err(String::from("trace needs a file"))
+    };
+    let mut filter = Filter::default();
+    let mut journey = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--node" => filter.node = Some(value(args, arg, |v| v.parse().ok())?),
+            "--subsystem" => filter.subsystem = Some(value(args, arg, Subsystem::parse)?),
+            "--kind" => filter.kind = Some(value(args, arg, |v| Some(v.to_string()))?),
+            "--packet" => filter.packet = Some(value(args, arg, |v| Some(v.to_string()))?),
+            "--goal" => filter.goal = Some(value(args, arg, |v| v.parse().ok())?),
+            "--journey" => journey = Some(value(args, arg, |v| v.parse().ok())?),
+            "--epochs" => filter.epochs = Some(value(args, arg, |v| {
+                let mut range = v.splitn(2, '-').map(|e| e.parse::<u64>().ok());
+                match (range.next(), range.next()) {
+                    (Some(Some(a)), Some(Some(b))) => Some((a, b)),
+                    (Some(Some(a)), None) => Some((a, a)),
+                    _ => None
+                }
+            })?),
+            _ => return Err(format!("unknown option {}", arg))
+        }
+    }
+    match journey {
+        Some(_) if filter.node.is_some() || filter.subsystem.is_some() || filter.kind.is_some() || filter.packet.is_some()
+            || filter.goal.is_some() || filter.epochs.is_some() => Err(String::from("--journey takes no other filter")),
+        Some(id) => Ok(Command::Journey(path, id)),
+        None => Ok(Command::Trace(path, filter))
+    }
+}
+
+/// Print the header and last row of every csv file in `dir`, the latest value of each statistic.
+pub fn inspect(dir: &str) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("cannot read {}: {}", dir, e))?;
+    let mut paths: Vec<_> = entries.filter_map(|e| e.ok()).map(|e| e.path())
+        .filter(|p| p.extension().is_some_and(|x| x == "csv"))
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        return Err(format!("no statistics in {}", dir))
+    }
+    for path in paths {
+        let mut contents = String::new();
+        File::open(&path).and_then(|mut f| f.read_to_string(&mut contents))
+            .map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+        let lines: Vec<&str> = contents.lines().filter(|l| !l.is_empty()).collect();
+        let name = Path::new(&path).file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+        println!("{}: {} rows", name, lines.len().saturating_sub(1));
+        if lines.len() > 1 {
+            for (h, v) in lines[0].split(',').zip(lines[lines.len() - 1].split(',')) {
+                println!("  {} = {}", h, v);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,230 @@
+//! Encoding of packets which leave the process.
+//!
+//! A packet crossing to another process travels as a `Frame` holding the
+//! name of its type and its JSON encoding, and the reply comes back in a
+//! frame with the same id. The receiving side finds the type by name, so
+//! every packet type has to be listed in `deliver`.
+
+use actix::*;
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::sync::oneshot;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+
+use petgraph::graph::NodeIndex;
+
+use node::{Node, Pipe};
+use packet::{Packet, PacketData};
+use shard;
+use udp;
+use world::{self, World};
+
+use address::AddressRequest;
+use advert::{MetricAdvert, MetricProbe};
+use baseline::{BaselineData, BaselineUpdate};
+use dht::service::{DHTLookup, DHTLookupReply, Ping};
+use directory::{DHTFindValue, DHTStore};
+use ledger::Invoice;
+use nc::NCProbe;
+use onion::Onion;
+use traffic::Data;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Frame {
+    // matches a reply to its request, unique per sending process
+    pub id: u64,
+    // graph index of the node the packet is for
+    pub to: usize,
+    pub kind: String,
+    // the packet, or the reply as a result
+    pub body: Value,
+}
+
+impl Frame {
+    pub fn request<T>(id: u64, to: usize, packet: &Packet<T>) -> Result<Frame, String>
+        where T: PacketData + Clone + Send + ResponseType + Serialize {
+        serde_json::to_value(packet)
+            .map(|body| Frame { id, to, kind: String::from(T::kind()), body })
+            .map_err(|e| format!("cannot encode {} packet: {}", T::kind(), e))
+    }
+}
+
+/// Decode the reply to a packet of type `T`, a reply which does not decode is an error.
+pub fn decode_reply<T>(body: Value) -> Result<T::Item, T::Error>
+    where T: ResponseType, T::Item: DeserializeOwned, T::Error: DeserializeOwned + Default {
+    match serde_json::from_value(body) {
+        Ok(r) => r,
+        Err(_) => Err(T::Error::default())
+    }
+}
+
+// when each request was sent, and where its reply goes
+type Replies = HashMap<u64, (Instant, oneshot::Sender<Value>)>;
+
+/// Requests sent to another process, waiting for their reply.
+#[derive(Clone, Default)]
+pub struct Waiting {
+    next_id: Arc<AtomicUsize>,
+    replies: Arc<Mutex<Replies>>,
+}
+
+impl Waiting {
+    /// Id of a new request, and the future of its reply.
+    pub fn register(&self) -> (u64, oneshot::Receiver<Value>) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) as u64;
+        let (tx, rx) = oneshot::channel();
+        self.replies.lock().unwrap().insert(id, (Instant::now(), tx));
+        (id, rx)
+    }
+
+    pub fn resolve(&self, id: u64, body: Value) {
+        if let Some((_, tx)) = self.replies.lock().unwrap().remove(&id) {
+            let _ = tx.send(body);
+        }
+    }
+
+    pub fn cancel(&self, id: u64) {
+        self.replies.lock().unwrap().remove(&id);
+    }
+
+    /// Give up on requests sent more than `timeout` ago, they fail like lost packets.
+    pub fn expire(&self, timeout: Duration) {
+        self.replies.lock().unwrap().retain(|_, &mut (sent, _)| sent.elapsed() < timeout);
+    }
+
+    pub fn len(&self) -> usize {
+        self.replies.lock().unwrap().len()
+    }
+}
+
+/// What a forwarder answers for a packet sent on to another process, resolves with the decoded reply.
+pub fn await_reply<T>(reply: oneshot::Receiver<Value>) -> Response<Forwarder, Packet<T>>
+    where T: PacketData + Clone + Send + ResponseType + 'static,
+          T::Item: Send + DeserializeOwned, T::Error: Send + Default + DeserializeOwned {
+    Forwarder::async_reply(fut::wrap_future::<_, Forwarder>(reply).then(|body, _, _| {
+        fut::result(match body {
+            Ok(body) => decode_reply::<T>(body),
+            Err(_) => Err(T::Error::default())
+        })
+    }))
+}
+
+/// How a forwarder reaches the node it stands in for.
+#[derive(Clone)]
+pub enum Proxy {
+    /// Run by another shard, through the coordinator.
+    Shard(shard::Remote),
+    /// A process of its own, over UDP.
+    Udp(udp::Socket),
+}
+
+impl Proxy {
+    /// Hand a packet for node `to` on, answered with the reply that comes back.
+    pub fn forward<T>(&self, to: usize, packet: Packet<T>) -> Response<Forwarder, Packet<T>>
+        where T: PacketData + Clone + Send + ResponseType + Serialize + 'static + Debug,
+              T::Item: Send + DeserializeOwned, T::Error: Send + Default + DeserializeOwned {
+        match *self {
+            Proxy::Shard(ref remote) => remote.forward(to, packet),
+            Proxy::Udp(ref socket) => socket.forward(to, packet),
+        }
+    }
+}
+
+/// Stands in for a node run by another process, every packet it is handed goes on to that process.
+pub struct Forwarder {
+    pub id: u64,
+    pub graph_index: NodeIndex,
+    pub proxy: Proxy,
+    pub world: SyncAddress<World>,
+}
+
+impl Actor for Forwarder {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.world.send(world::HelloWorld { pipe: Pipe::Forwarder(ctx.address()), graph_index: self.graph_index, id: self.id });
+    }
+}
+
+impl<T> Handler<Packet<T>> for Forwarder
+    where T: PacketData + Clone + Send + ResponseType + Serialize + 'static + Debug,
+          T::Item: Send + DeserializeOwned, T::Error: Send + Default + DeserializeOwned {
+    fn handle(&mut self, msg: Packet<T>, _ctx: &mut Context<Self>) -> Response<Self, Packet<T>> {
+        self.proxy.forward(self.graph_index.index(), msg)
+    }
+}
+
+/// Encoded reply of a packet handed to a local node.
+pub type ReplyFuture = Box<dyn Future<Item = Value, Error = ()>>;
+
+fn call<T>(node: &SyncAddress<Node>, body: Value) -> Result<ReplyFuture, String>
+    where T: PacketData + Clone + Send + ResponseType + Serialize + DeserializeOwned + 'static + Debug,
+          T::Item: Send + Serialize + DeserializeOwned, T::Error: Send + Default + Serialize + DeserializeOwned {
+    let packet: Packet<T> = serde_json::from_value(body).map_err(|e| format!("cannot decode {} packet: {}", T::kind(), e))?;
+    Ok(Box::new(node.call_fut(packet).then(|r| {
+        // a node which went away answers like a lost packet
+        let reply = r.unwrap_or(Err(T::Error::default()));
+        Ok::<Value, ()>(serde_json::to_value(reply).unwrap_or(Value::Null))
+    })))
+}
+
+/// Hand an encoded packet of type `kind` to `node`.
+pub fn deliver(kind: &str, node: &SyncAddress<Node>, body: Value) -> Result<ReplyFuture, String> {
+    match kind {
+        "ping" => call::<Ping>(node, body),
+        "dht_lookup" => call::<DHTLookup>(node, body),
+        "dht_lookup_reply" => call::<DHTLookupReply>(node, body),
+        "dht_store" => call::<DHTStore>(node, body),
+        "dht_find_value" => call::<DHTFindValue>(node, body),
+        "nc_probe" => call::<NCProbe>(node, body),
+        "address_request" => call::<AddressRequest>(node, body),
+        "metric_advert" => call::<MetricAdvert>(node, body),
+        "metric_probe" => call::<MetricProbe>(node, body),
+        "invoice" => call::<Invoice>(node, body),
+        "onion" => call::<Onion>(node, body),
+        "data" => call::<Data>(node, body),
+        "baseline_update" => call::<BaselineUpdate>(node, body),
+        "baseline_data" => call::<BaselineData>(node, body),
+        _ => Err(format!("unknown packet type {}", kind))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use packet::PacketRouteData;
+
+    #[test]
+    fn frames_round_trip() {
+        let mut packet = Packet::new(PacketRouteData { from: 3, des: 9, route: vec![3, 5, 9] }, Data { flow: 7, seq: 2, size: 1200 });
+        packet.hops = vec![3, 5];
+        packet.sent_at = 1.5;
+        packet.elapsed = 0.25;
+        packet.trace = (4 << 40) | 11;
+        let frame = Frame::request(42, 9, &packet).unwrap();
+        let bytes = serde_json::to_vec(&frame).unwrap();
+        let decoded: Frame = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!((decoded.id, decoded.to, decoded.kind.as_str()), (42, 9, "data"));
+        let back: Packet<Data> = serde_json::from_value(decoded.body).unwrap();
+        assert_eq!((back.from, back.des, back.route), (3, 9, vec![3, 5, 9]));
+        assert_eq!((back.data.flow, back.data.seq, back.data.size), (7, 2, 1200));
+        assert_eq!((back.hops, back.sent_at, back.elapsed, back.trace), (vec![3, 5], 1.5, 0.25, (4 << 40) | 11));
+    }
+
+    #[test]
+    fn replies_which_do_not_decode_fail() {
+        let ok: Result<(), ()> = Ok(());
+        assert_eq!(decode_reply::<Data>(serde_json::to_value(ok).unwrap()), Ok(()));
+        assert_eq!(decode_reply::<Data>(Value::String(String::from("garbage"))), Err(()));
+    }
+}
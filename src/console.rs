@@ -0,0 +1,219 @@
+//! Queries of a paused run on stdin.
+//!
+//! With `--pause <epoch>` the world stops at the start of that epoch, once
+//! everything in flight has been processed, and reads commands from stdin.
+//! Every answer is printed as JSON. Queries about a node go to the node
+//! itself. A test lookup asks the nodes on its way for the closest entries of
+//! their DHT tables, as a lookup would, but sends no packet and changes no
+//! table, so the run carries on as if nothing had been asked. `pause` typed
+//! while the run goes on stops it at the next epoch.
+
+use actix::*;
+
+use serde::Serialize;
+use serde_json;
+
+use std::fmt;
+use std::io::{self, BufRead};
+use std::thread;
+
+use connection::Connection;
+use dht::DHTNode;
+use nc::NCNodeData;
+use node::Node;
+use world::{self, World};
+
+pub static HELP: &str = "commands:
+  node <n>            neighbours, DHT buckets and coordinates of a node
+  lookup <a> <b>      DHT lookup from node a for node b (or any ID), with the route packets take
+  stretch <a> <b>     latency and hops of the route packets take from a to b over the shortest path
+  failed              links taken down by the scenario and nodes down with churn
+  continue [<n>]      resume, pausing again after n epochs if given
+  pause               pause at the start of the next epoch
+  quit                finish the run as if the epoch limit was reached
+nodes are given by node ID, or by graph index as #<index>";
+
+/// A node as typed, `#<index>` for a graph index, a plain number for an ID.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Index(usize),
+    Id(u64),
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Index(i) => write!(f, "#{}", i),
+            Target::Id(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl Target {
+    fn parse(word: &str) -> Option<Target> {
+        if let Some(index) = word.strip_prefix('#') {
+            index.parse().ok().map(Target::Index)
+        } else {
+            word.parse().ok().map(Target::Id)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    Node(Target),
+    Lookup(Target, Target),
+    Stretch(Target, Target),
+    Failed,
+    Continue(Option<u64>),
+    Pause,
+    Help,
+    Quit,
+}
+
+impl Query {
+    pub fn parse(line: &str) -> Result<Query, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let argument = |i: usize| words.get(i).cloned().ok_or(format!("{} is missing an argument", words[0]));
+        let number = |i: usize| -> Result<u64, String> {
+            argument(i).and_then(|w| w.parse().map_err(|_| format!("not a number: {}", w)))
+        };
+        let node = |i: usize| -> Result<Target, String> {
+            argument(i).and_then(|w| Target::parse(w).ok_or(format!("not an ID or #<index>: {}", w)))
+        };
+        match words.first().cloned() {
+            Some("node") => Ok(Query::Node(node(1)?)),
+            Some("lookup") => Ok(Query::Lookup(node(1)?, node(2)?)),
+            Some("stretch") => Ok(Query::Stretch(node(1)?, node(2)?)),
+            Some("failed") => Ok(Query::Failed),
+            Some("continue") if words.len() > 1 => Ok(Query::Continue(Some(number(1)?))),
+            Some("continue") => Ok(Query::Continue(None)),
+            Some("pause") => Ok(Query::Pause),
+            Some("help") => Ok(Query::Help),
+            Some("quit") => Ok(Query::Quit),
+            Some(c) => Err(format!("unknown command {}, try help", c)),
+            None => Err(String::from("try help"))
+        }
+    }
+}
+
+/// Read commands from stdin for the world, the run carries on once stdin is closed.
+pub fn listen(world: SyncAddress<World>) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            match line.map_err(|e| e.to_string()).and_then(|l| Query::parse(&l)) {
+                Ok(query) => world.send(world::Console { query }),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        world.send(world::Console { query: Query::Continue(None) });
+    });
+}
+
+/// Print an answer, always whatever the log level.
+pub fn print<T: Serialize>(value: &T) {
+    match serde_json::to_string_pretty(value) {
+        Ok(s) => println!("{}", s),
+        Err(e) => eprintln!("cannot encode the answer: {}", e)
+    }
+}
+
+#[derive(Serialize)]
+pub struct NeighbourDump {
+    pub id: u64,
+    pub level: usize,
+    pub connection: Connection,
+    pub failed: bool,
+    // seconds of traffic queued on the link
+    pub backlog: f32,
+}
+
+#[derive(Serialize)]
+pub struct NodeDump {
+    pub id: u64,
+    pub graph_index: usize,
+    pub level: usize,
+    pub now: f32,
+    pub neighbours: Vec<NeighbourDump>,
+    // non empty k-buckets by index
+    pub buckets: Vec<(usize, Vec<DHTNode>)>,
+    pub records: usize,
+    pub nc: NCNodeData,
+    pub nc_error: f32,
+}
+
+// sent by the world, answered with the state of the node
+pub struct InspectNode;
+
+impl ResponseType for InspectNode {
+    type Item = NodeDump;
+    type Error = ();
+}
+
+impl Handler<InspectNode> for Node {
+    fn handle(&mut self, _msg: InspectNode, _ctx: &mut Context<Self>) -> Response<Self, InspectNode> {
+        Self::reply(NodeDump {
+            id: self.id,
+            graph_index: self.graph_index.index(),
+            level: self.level,
+            now: self.now,
+            neighbours: self.neighbours.iter().map(|n| NeighbourDump {
+                id: n.id,
+                level: n.level,
+                connection: n.connection.clone(),
+                failed: n.failed,
+                backlog: n.queue.backlog(),
+            }).collect(),
+            buckets: self.dht.table().buckets().iter().enumerate()
+                .filter(|&(_, b)| !b.data().is_empty())
+                .map(|(i, b)| (i, b.data().iter().cloned().collect()))
+                .collect(),
+            records: self.dht.value_count(),
+            nc: self.nc.clone(),
+            nc_error: self.nc_error,
+        })
+    }
+}
+
+// sent by the world, answered with the entries of the node's table closest to `goal`, as a lookup would be
+pub struct ClosestKnown {
+    pub goal: u64,
+}
+
+impl ResponseType for ClosestKnown {
+    type Item = Vec<DHTNode>;
+    type Error = ();
+}
+
+impl Handler<ClosestKnown> for Node {
+    fn handle(&mut self, msg: ClosestKnown, _ctx: &mut Context<Self>) -> Response<Self, ClosestKnown> {
+        Self::reply(self.dht.closest(&msg.goal))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nodes_are_ids_unless_prefixed() {
+        assert_eq!(Query::parse("node 12"), Ok(Query::Node(Target::Id(12))));
+        assert_eq!(Query::parse("node #12"), Ok(Query::Node(Target::Index(12))));
+        assert_eq!(Query::parse("stretch #0 7"), Ok(Query::Stretch(Target::Index(0), Target::Id(7))));
+        assert_eq!(Query::parse("  lookup 3   #4 "), Ok(Query::Lookup(Target::Id(3), Target::Index(4))));
+        assert_eq!(Query::parse("continue 5"), Ok(Query::Continue(Some(5))));
+        assert_eq!(Query::parse("continue"), Ok(Query::Continue(None)));
+    }
+
+    #[test]
+    fn bad_commands_are_rejected() {
+        assert!(Query::parse("node").is_err());
+        assert!(Query::parse("node #x").is_err());
+        assert!(Query::parse("node #-1").is_err());
+        assert!(Query::parse("lookup 1").is_err());
+        assert!(Query::parse("continue soon").is_err());
+        assert!(Query::parse("jump").is_err());
+        assert!(Query::parse("").is_err());
+    }
+}
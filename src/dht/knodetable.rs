@@ -11,8 +11,7 @@
 //!
 //! See [original paper](http://pdos.csail.mit.edu/%7Epetar/papers/maymounkov-kademlia-lncs.pdf)
 //! for details. The most essential difference is that when k-bucket is full,
-//! no RPC call is done. It is up to upper-level code to ensure proper clean up
-//! using `pop_oldest` call.
+//! no RPC call is done and the new node is dropped.
 
 use std::cmp;
 use std::fmt::Debug;
@@ -23,9 +22,8 @@ use super::GenericNodeTable;
 use super::DHTNode;
 
 
-// TODO(divius): make public?
-static BUCKET_SIZE: usize = 32;
-static DEFAULT_HASH_SIZE: usize = 64;
+pub static BUCKET_SIZE: usize = 32;
+pub static DEFAULT_HASH_SIZE: usize = 64;
 
 
 /// Kademlia node table.
@@ -36,7 +34,7 @@ static DEFAULT_HASH_SIZE: usize = 64;
 ///
 /// methods may panic if distance between two ids is greater than the
 /// `hash_size`.
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KNodeTable {
     this_id: u64,
     hash_size: usize,
@@ -45,7 +43,7 @@ pub struct KNodeTable {
 }
 
 /// K-bucket - structure for keeping last nodes in Kademlia.
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct KBucket {
     data: VecDeque<DHTNode>,
     size: usize,
@@ -53,18 +51,11 @@ pub struct KBucket {
 
 
 impl KNodeTable {
-    /// Create a new node table.
-    ///
-    /// `this_id` -- ID of the current node (used to calculate metrics).
-    pub fn new(this_id: u64) -> KNodeTable {
-        KNodeTable::new_with_details(this_id, BUCKET_SIZE, DEFAULT_HASH_SIZE)
-    }
-
     pub fn new_with_details(this_id: u64, bucket_size: usize,
                             hash_size: usize) -> KNodeTable {
         KNodeTable {
-            this_id: this_id,
-            hash_size: hash_size,
+            this_id,
+            hash_size,
             buckets: (0..hash_size).map(
                 |_| KBucket::new(bucket_size)).collect(),
         }
@@ -84,23 +75,15 @@ impl KNodeTable {
         debug_assert!(!diff.is_zero());
         let res = diff.bits() - 1;
         if res >= self.hash_size {
-            panic!(format!("Distance between IDs {:?} and {:?} is {:?}, which is \
+            panic!("Distance between IDs {:?} and {:?} is {:?}, which is \
                     greater than the hash size ({:?})",
-                           id, self.this_id, res, self.hash_size));
+                           id, self.this_id, res, self.hash_size);
         }
         res
     }
 }
 
 impl GenericNodeTable for KNodeTable {
-    fn new(this_id: u64) -> KNodeTable {
-        KNodeTable::new_with_details(this_id, BUCKET_SIZE, DEFAULT_HASH_SIZE)
-    }
-
-    fn random_id(&self) -> u64 {
-        u64::gen(self.hash_size)
-    }
-
     fn update(&mut self, node: &DHTNode) -> bool {
         assert!(node.id != self.this_id);
         let bucket = self.bucket_number(&node.id);
@@ -111,19 +94,11 @@ impl GenericNodeTable for KNodeTable {
         debug_assert!(count > 0);
         // assert!(*id != self.this_id);
 
-        let mut data_copy: Vec<_> = self.buckets.iter().flat_map(|b| &b.data).map(|n| n.clone()).collect();
+        let mut data_copy: Vec<_> = self.buckets.iter().flat_map(|b| &b.data).cloned().collect();
         data_copy.sort_by_key(|n| KNodeTable::distance(id, &n.id));
         data_copy[0..cmp::min(count, data_copy.len())].to_vec()
     }
 
-    fn pop_oldest(&mut self) -> Vec<DHTNode> {
-        // For every full k-bucket, pop the last.
-        // TODO(divius): TTL expiration?
-        self.buckets.iter_mut()
-            .filter(|b| { !b.data.is_empty() && b.size == b.data.len() })
-            .map(|b| b.data.pop_front().unwrap())
-            .collect()
-    }
 }
 
 impl KBucket {
@@ -147,25 +122,15 @@ impl KBucket {
         }
     }
 
-    pub fn find(&self, id: &u64, count: usize) -> Vec<DHTNode> {
-        let mut data_copy: Vec<_> = self.data.iter().map(|n| n.clone()).collect();
-        data_copy.sort_by_key(|n| KNodeTable::distance(id, &n.id));
-        data_copy[0..cmp::min(count, data_copy.len())].to_vec()
-    }
-
     pub fn data(&self) -> &VecDeque<DHTNode> {
         &self.data
     }
-    pub fn size(&self) -> usize {
-        self.size
-    }
 
     fn update_position(&mut self, node: DHTNode) {
         // TODO(divius): 1. optimize, 2. make it less ugly
         let mut new_data = VecDeque::with_capacity(self.data.len());
         new_data.extend(self.data.iter()
-            .filter(|x| x.id != node.id)
-            .map(|x| x.clone()));
+            .filter(|x| x.id != node.id).cloned());
         new_data.push_back(node.clone());
         self.data = new_data;
     }
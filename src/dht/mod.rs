@@ -1,10 +1,7 @@
 pub use self::base::GenericId;
 pub use self::base::GenericNodeTable;
 pub use self::base::DHTNode;
-pub use self::knodetable::KNodeTable;
-pub use self::service::GenDHT;
 
 mod base;
 mod knodetable;
-pub mod protocol;
 pub mod service;
\ No newline at end of file
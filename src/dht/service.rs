@@ -10,53 +10,92 @@
 //! Protocol-agnostic service implementation
 
 use actix::*;
-use actix::fut::result;
-use actix::fut::FutureResult;
-use std::any::Any;
 
-use std::marker;
 use std::collections::HashMap;
-use rand::{thread_rng, Rng};
+use std::mem;
+use rand::Rng;
 
-use super::{GenericId, GenericNodeTable, DHTNode};
-use dht::knodetable::KNodeTable;
+use super::{GenericNodeTable, DHTNode};
+use dht::knodetable::{self, KNodeTable};
 
-use node::Node;
+use node::{Node, NodeFuture};
+use directory::Record;
 
 use packet::*;
 
-static MAX_NODE_COUNT: usize = 16;
+pub static MAX_NODE_COUNT: usize = 16;
 
-pub type DHT = GenDHT<KNodeTable, ()>;
+#[allow(clippy::upper_case_acronyms)]
+pub type DHT = GenDHT<KNodeTable, Record>;
 
-/// Result of the find operations - either data or nodes closest to it.
-#[derive(Debug)]
-pub enum FindResult<TData> {
-    Value(TData),
-    ClosestNodes(Vec<DHTNode>),
-    Nothing
+/// Table sizes, the defaults are the original constants.
+#[derive(Clone, Copy, Debug)]
+pub struct DHTParams {
+    // nodes kept per k-bucket
+    pub bucket_size: usize,
+    // nodes returned for a find request
+    pub max_node_count: usize,
 }
 
-#[derive(Debug)]
+impl Default for DHTParams {
+    fn default() -> DHTParams {
+        DHTParams { bucket_size: knodetable::BUCKET_SIZE, max_node_count: MAX_NODE_COUNT }
+    }
+}
+
+impl DHT {
+    pub fn with_params(node_id: u64, params: DHTParams) -> DHT {
+        let table = KNodeTable::new_with_details(node_id, params.bucket_size, knodetable::DEFAULT_HASH_SIZE);
+        GenDHT::with_table(node_id, table, params.max_node_count)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GenDHT<TNodeTable, TData>
     where TNodeTable: GenericNodeTable,
           TData: Send + Sync + Clone {
     node_id: u64,
     table: TNodeTable,
     data: HashMap<u64, TData>,
+    max_node_count: usize,
 }
 
 impl<TNodeTable, TData> GenDHT<TNodeTable, TData>
     where TNodeTable: GenericNodeTable,
           TData: Send + Sync + Clone {
-    pub fn new(node_id: u64) -> Self {
+    pub fn with_table(node_id: u64, table: TNodeTable, max_node_count: usize) -> Self {
         GenDHT {
-            node_id: node_id.clone(),
-            table: TNodeTable::new(node_id),
+            node_id,
+            table,
             data: HashMap::new(),
+            max_node_count,
         }
     }
 
+    pub fn table(&self) -> &TNodeTable {
+        &self.table
+    }
+
+    /// Number of values stored on this node.
+    pub fn value_count(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Value stored locally under `id`.
+    pub fn get(&self, id: &u64) -> Option<&TData> {
+        self.data.get(id)
+    }
+
+    /// Store a value locally, replacing any previous one.
+    pub fn store(&mut self, id: u64, value: TData) {
+        self.data.insert(id, value);
+    }
+
+    /// Keep only the values for which `f` returns true.
+    pub fn retain<F: FnMut(&u64, &mut TData) -> bool>(&mut self, f: F) {
+        self.data.retain(f);
+    }
+
     /// Process the ping request.
     ///
     /// Essentially remembers the incoming node and returns true.
@@ -65,73 +104,84 @@ impl<TNodeTable, TData> GenDHT<TNodeTable, TData>
         true
     }
     /// Process the find request.
-    pub fn on_find_node(&mut self, sender: &DHTNode, id: &u64) -> Vec<DHTNode> {
-        let res = self.table.find(&id, MAX_NODE_COUNT);
-        self.update(sender);
-        res
+    /// The nodes closest to `id` a lookup would be told about, without learning of the sender.
+    pub fn closest(&self, id: &u64) -> Vec<DHTNode> {
+        self.table.find(id, self.max_node_count)
     }
-    /// Find a value or the closes nodes.
-    pub fn on_find_value(&mut self, sender: &DHTNode, id: &u64)
-                         -> FindResult<TData> {
+
+    pub fn on_find_node(&mut self, sender: &DHTNode, id: &u64) -> Vec<DHTNode> {
+        let res = self.closest(id);
         self.update(sender);
-        let data = &self.data;
-        let table = &self.table;
-        let res = match data.get(&id) {
-            Some(value) => FindResult::Value(value.clone()),
-            None => FindResult::ClosestNodes(table.find(&id, MAX_NODE_COUNT))
-        };
         res
     }
-
     fn update(&mut self, node: &DHTNode) {
         if node.id == self.node_id {
             return;
         }
 
-        if !self.table.update(&node) {}
+        if !self.table.update(node) {}
     }
 }
 
 impl Node {
-    fn dht_lookup(&self, goal: u64, current_nodes: Option<Vec<DHTNode>>, init: bool)
-                  -> Box<ActorFuture<Item=DHTNode, Error=(), Actor=Node>> {
-        println!("ID: {}, looking for goal {}", self.id, goal);
-        let mut closest = current_nodes.unwrap_or(self.dht.table.find(&(goal), 16));
+    pub fn dht_lookup(&mut self, goal: u64, current_nodes: Option<Vec<DHTNode>>, init: bool)
+                  -> NodeFuture<DHTNode> {
+        // the queries of every step are traced as part of the lookup
+        let lookup = self.next_trace();
+        trace!(Dht, Info, self.event("lookup").trace(lookup).goal(goal));
+        self.dht_lookup_step(goal, current_nodes, init, lookup, Vec::new())
+    }
+
+    // `asked` holds the nodes this lookup asked already, none is asked twice so it ends
+    fn dht_lookup_step(&mut self, goal: u64, current_nodes: Option<Vec<DHTNode>>, init: bool, lookup: u64, asked: Vec<u64>)
+                  -> NodeFuture<DHTNode> {
+        let mut closest = current_nodes.unwrap_or_else(|| self.dht.closest(&goal));
+        closest.retain(|n| !asked.contains(&n.id));
 
         // println!("c {:?} {}", closest, self.id);
 
-        if init && closest.len() > 0 {
+        if init && !closest.is_empty() {
             closest.remove(0);
         }
 
-        if closest.len() < 1 || closest[0].id == self.id {
-            println!("ID: {}, not found {}, returning", self.id, goal);
-            return Box::new(fut::err(()));
+        if closest.is_empty() || closest[0].id == self.id {
+            trace!(Dht, Info, self.event("lookup_failed").trace(lookup).goal(goal).detail(String::from("no closer node")));
+            return NodeFuture::new(fut::err(()));
         }
 
         if closest[0].id == goal {
             let mut r = closest[0].clone();
             r.route.push(self.id);
-            println!("ID: {}, found {}!", self.id, goal);
-            return Box::new(fut::ok(r));
+            trace!(Dht, Info, self.event("lookup_found").trace(lookup).goal(goal).route(&r.route));
+            return NodeFuture::new(fut::ok(r));
         }
 
-        let mut r = closest[0].route.clone();
+        let r = closest[0].route.clone();
+        let mut asked = asked;
+        asked.push(closest[0].id);
 
-        println!("ID: {}, sending packet to {}, looking for goal {}", self.id, closest[0].id, goal);
-        Box::new(self.send_packet(Packet {
+        let query = self.next_trace();
+        trace!(Dht, Debug, self.event("lookup_query").trace(query).parent(lookup).goal(goal).packet("dht_lookup").route(&r)
+            .detail(format!("asking {}", closest[0].id)));
+        NodeFuture::new(self.send_packet(Packet {
             from: self.id,
             des: closest[0].id,
             route: r.clone(),
             data: DHTLookup { goal, path_to: r },
-        }).then(move |item, actor, ctx| {
+            hops: Vec::new(),
+            sent_at: 0.,
+            elapsed: 0.,
+            trace: query,
+        }).then(move |item, actor, _ctx| {
+            // the node asked is not tried again
             let mut c = closest;
+            c.remove(0);
             match item.clone().unwrap() {
                 Ok(response) => {
                     for i in response.data.reply.clone().iter_mut() {
                         i.route.append(&mut item.clone().unwrap().unwrap().get_full_route());
                         // i.route = simplify_route(i.route.clone());
-                        actor.dht.update(&i)
+                        actor.dht.update(i)
                     }
 
                     let r = &response.data.reply[0];
@@ -140,20 +190,20 @@ impl Node {
                         let mut hop = response.clone().get_full_route();
                         hop.reverse();
                         r.prepend(&mut hop);
-                        println!("ID: {}, found {}, replying", actor.id, goal);
-                        return Box::new(fut::ok(DHTNode { id: 0u64, route: r.route })) as Box<ActorFuture<Item=DHTNode, Error=(), Actor=Node>>
+                        trace!(Dht, Info, actor.event("lookup_found").trace(lookup).goal(goal).route(&r.route));
+                        NodeFuture::new(fut::ok(DHTNode { id: 0u64, route: r.route }))
                     } else {
-                        println!("ID: {}, not found {}, recursing", actor.id, goal);
-                        return actor.dht_lookup(goal, Some(response.data.reply.clone()), init)
+                        trace!(Dht, Debug, actor.event("lookup_closer").trace(lookup).goal(goal).detail(format!("{} nodes", response.data.reply.len())));
+                        actor.dht_lookup_step(goal, Some(response.data.reply.clone()), init, lookup, asked)
                     }
                 }
-                Err(error) => {
-                    println!("ID: {}, err", actor.id);
-                    if c.len() > 0 {
-                        return actor.dht_lookup(goal, Some(c), init)
+                Err(_error) => {
+                    trace!(Dht, Debug, actor.event("lookup_lost").trace(query).parent(lookup).goal(goal).packet("dht_lookup"));
+                    if !c.is_empty() {
+                        actor.dht_lookup_step(goal, Some(c), init, lookup, asked)
                     } else {
-                        println!("ID: {}, not found {}, replying", actor.id, goal);
-                        return Box::new(fut::err(())) as Box<ActorFuture<Item=DHTNode, Error=(), Actor=Node>>
+                        trace!(Dht, Info, actor.event("lookup_failed").trace(lookup).goal(goal).detail(String::from("no node left")));
+                        NodeFuture::new(fut::err(()))
                     }
                 }
             }
@@ -180,19 +230,24 @@ impl Node {
     }
 
     pub fn dht_tick(&mut self, ctx: &mut Context<Self>) {
-        println!("ID: {}, Table {:?}", self.id, self.dht);
+        trace!(Dht, Debug, self.event("tick").detail(format!("{} nodes, {} records", self.dht.table().buckets().iter().map(|b| b.data().len()).sum::<usize>(), self.dht.value_count())));
         for n in &self.neighbours.clone() {
-            let mut r = self.send_packet(Packet {
+            let _r = self.send_packet(Packet {
                 from: self.id,
                 des: n.id,
                 route: vec![n.id],
                 data: Ping,
+                hops: Vec::new(),
+                sent_at: 0.,
+                elapsed: 0.,
+                trace: 0,
             });
 
             // ctx.spawn(self.dht_lookup(self.id, None, true).then(|item, ctx, context| {
             //     fut::ok::<(), (), Node>(())
             // }));
-            ctx.spawn(self.dht_lookup(thread_rng().next_u64(), None, false).then(|item, actor, context| {
+            let goal = self.rng.next_u64();
+            ctx.spawn(self.dht_lookup(goal, None, false).then(|_item, _actor, _context| {
                 fut::ok::<(), (), Node>(())
             }));
 
@@ -222,10 +277,20 @@ impl Node {
     }
 }
 
-#[derive(Clone, Debug, Message)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Ping;
 
+message!(Ping);
+
 impl PacketData for Ping {
+    fn kind() -> &'static str {
+        "ping"
+    }
+
+    fn size(&self) -> usize {
+        0
+    }
+
     fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Ping>> {
         let mut r = packet.get_full_route().clone();
         r.push(packet.from);
@@ -237,31 +302,50 @@ impl PacketData for Ping {
 
 type DHTLookupReplyPacket = Packet<DHTLookupReply>;
 
-#[derive(Clone, Debug, Message)]
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
 #[Message(DHTLookupReplyPacket)]
 pub struct DHTLookup {
     pub goal: u64,
     pub path_to: Vec<u64>,
 }
 
-#[derive(Clone, Debug, Message)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct DHTLookupReply {
     pub goal: u64,
     pub reply: Vec<DHTNode>,
 }
 
+message!(DHTLookupReply);
+
 
 impl PacketData for DHTLookup {
+    fn kind() -> &'static str {
+        "dht_lookup"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<u64>() * (1 + self.path_to.len())
+    }
+
     fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
-        println!("ID: {}, processed DHT lookup", node.id);
-        let r = node.dht.on_find_node(&DHTNode { id: packet.from, route: packet.route.reverse() }, &packet.data.goal);
-        let p = packet.reverse();
-        Node::reply(Packet::new(Packet { from: Node.id, des: packet.from }, DHTLookupReply { reply: r, goal: packet.data.goal }))
+        trace!(Dht, Debug, node.event("lookup_served").at(packet.sent_at + packet.elapsed).trace(packet.trace).goal(packet.data.goal).packet(Self::kind()).route(&packet.hops));
+        let mut route = packet.route.clone();
+        route.reverse();
+        let r = node.dht.on_find_node(&DHTNode { id: packet.from, route }, &packet.data.goal);
+        Node::reply(Packet::new(packet.reverse(), DHTLookupReply { reply: r, goal: packet.data.goal }))
     }
 }
 
 impl PacketData for DHTLookupReply {
-    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+    fn kind() -> &'static str {
+        "dht_lookup_reply"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<u64>() + self.reply.iter().map(|n| n.wire_size()).sum::<usize>()
+    }
+
+    fn process(_packet: &Packet<Self>, _node: &mut Node) -> Response<Node, Packet<Self>> {
         Node::reply(())
     }
 }
@@ -0,0 +1,337 @@
+//! Values published in the DHT.
+//!
+//! Every node periodically stores its coordinate under its own ID on the
+//! `REPLICAS` nodes closest to it, found by asking the closest ones in its
+//! table for closer ones. Fetches look for the same nodes. Records carry a
+//! version so replicas keep the newest one, and expire unless refreshed by a
+//! store of the same or a later version. A live record is only replaced by its owner. After every
+//! publication a node resolves a random node it knows, to measure how often
+//! lookups fail.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::mem;
+
+use address::Address;
+use dht::{DHTNode, GenericNodeTable};
+use dht::service::DHTLookup;
+use nc::NCNodeData;
+use node::{Node, NodeFuture};
+use onion::ReplyBlock;
+use packet::*;
+
+// nodes each record is stored on
+pub static REPLICAS: usize = 4;
+// simulated seconds between publications, and lifetime of a record
+static PUBLISH_INTERVAL: f32 = 10.;
+pub static RECORD_TTL: f32 = 30.;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Value {
+    Coordinates(NCNodeData),
+    // current addresses of a node, stored under `address::address_key` of its ID
+    Addresses(Vec<Address>),
+    // reply block from a rendezvous point to a hidden receiver, under `onion::rendezvous_key`
+    Rendezvous { point: u64, block: ReplyBlock },
+    // public key of a node, under `onion::key_record` of its ID
+    PublicKey(u64),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub owner: u64,
+    pub version: u64,
+    // simulated time after which the record is dropped
+    pub expires: f32,
+    pub value: Value,
+}
+
+impl Value {
+    /// Encoded size in bytes.
+    pub fn wire_size(&self) -> usize {
+        match *self {
+            Value::Coordinates(ref nc) => nc.wire_size(),
+            Value::Addresses(ref addresses) => addresses.iter().map(|a| a.0.len() * mem::size_of::<u16>()).sum(),
+            Value::Rendezvous { ref block, .. } => mem::size_of::<u64>() + block.wire_size(),
+            Value::PublicKey(_) => mem::size_of::<u64>(),
+        }
+    }
+}
+
+impl Record {
+    /// Encoded size in bytes, owner, version and expiry followed by the value.
+    pub fn wire_size(&self) -> usize {
+        2 * mem::size_of::<u64>() + mem::size_of::<f32>() + self.value.wire_size()
+    }
+
+    pub fn live(&self, now: f32) -> bool {
+        self.expires > now
+    }
+}
+
+/// Coordinate of a node together with a fresh route to it.
+#[derive(Clone, Debug)]
+pub struct Located {
+    pub id: u64,
+    pub coords: NCNodeData,
+    pub version: u64,
+    pub route: Vec<u64>,
+}
+
+pub type LookupFuture<T> = NodeFuture<T>;
+
+impl Node {
+    /// Publish our coordinate if it is due, and drop expired records held for others.
+    pub fn dht_publish(&mut self, ctx: &mut Context<Self>) {
+        let now = self.now;
+        self.dht.retain(|_, r| r.live(now));
+        if self.published.is_some_and(|t| now - t < PUBLISH_INTERVAL) {
+            return
+        }
+        self.published = Some(now);
+        self.publish_version += 1;
+        let record = Record {
+            owner: self.id,
+            version: self.publish_version,
+            expires: now + RECORD_TTL,
+            value: Value::Coordinates(self.advertised_coords(None)),
+        };
+        let key = self.id;
+        self.dht_store(key, record, ctx);
+        if self.config.private_routes.is_some() {
+            self.publish_key(ctx);
+        }
+        if self.config.private_routes.is_some_and(|c| c.rendezvous) {
+            self.publish_rendezvous(ctx);
+        }
+        self.resolve_sample(ctx);
+    }
+
+    /// Resolve the coordinate and addresses of a node from our table, counting the lookups which fail.
+    fn resolve_sample(&mut self, ctx: &mut Context<Self>) {
+        let key = self.rng.next_u64();
+        let id = match self.dht.table().find(&key, 1).pop() {
+            Some(n) => n.id,
+            None => return
+        };
+        self.lookups += 2;
+        let f = self.nc_lookup(id);
+        ctx.spawn(f.then(move |res, actor, _ctx| {
+            match res {
+                Ok(ref l) if l.id == id && l.route.last() == Some(&id) => {
+                    trace!(Dht, Debug, actor.event("resolved").goal(id).route(&l.route)
+                        .detail(format!("version {} predicted {:.4}", l.version, actor.nc_model.predict(&actor.nc, &l.coords))));
+                }
+                _ => actor.lookup_failures += 1
+            }
+            fut::ok(())
+        }));
+        let f = self.address_lookup(id);
+        ctx.spawn(f.then(|res, actor, _ctx| {
+            if res.is_err() {
+                actor.lookup_failures += 1;
+            }
+            fut::ok(())
+        }));
+    }
+
+    /// The `REPLICAS` nodes closest to `key`, asking the closest we know of for
+    /// closer ones until every node left was asked.
+    pub fn dht_closest(&mut self, key: u64) -> LookupFuture<Vec<DHTNode>> {
+        let shortlist = self.dht.table().find(&key, REPLICAS);
+        self.dht_closest_step(key, shortlist, Vec::new())
+    }
+
+    fn dht_closest_step(&mut self, key: u64, mut shortlist: Vec<DHTNode>, mut asked: Vec<u64>) -> LookupFuture<Vec<DHTNode>> {
+        let n = match shortlist.iter().find(|n| !asked.contains(&n.id)) {
+            Some(n) => n.clone(),
+            None => return NodeFuture::new(fut::ok(shortlist))
+        };
+        asked.push(n.id);
+        let route = match self.source_route(n.id, n.route.clone()) {
+            Some(r) => r,
+            None => {
+                shortlist.retain(|s| s.id != n.id);
+                return self.dht_closest_step(key, shortlist, asked)
+            }
+        };
+        NodeFuture::new(self.send_packet(Packet {
+            from: self.id,
+            des: n.id,
+            route: route.clone(),
+            data: DHTLookup { goal: key, path_to: route },
+            hops: Vec::new(),
+            sent_at: 0.,
+            elapsed: 0.,
+            trace: 0,
+        }).then(move |res, actor, _ctx| {
+            match res {
+                Ok(Ok(reply)) => {
+                    let back = reply.get_full_route();
+                    for mut i in reply.data.reply {
+                        if i.id != actor.id && shortlist.iter().all(|s| s.id != i.id) {
+                            i.route.extend(back.iter().cloned());
+                            shortlist.push(i);
+                        }
+                    }
+                }
+                // a node we cannot reach holds nothing for us
+                _ => shortlist.retain(|s| s.id != n.id)
+            }
+            // XOR distance, as in the table
+            shortlist.sort_by_key(|s| s.id ^ key);
+            shortlist.truncate(REPLICAS);
+            actor.dht_closest_step(key, shortlist, asked)
+        }))
+    }
+
+    /// Store `record` under `key` on the closest nodes.
+    pub fn dht_store(&mut self, key: u64, record: Record, ctx: &mut Context<Self>) {
+        ctx.spawn(self.dht_closest(key).then(move |res, actor, _ctx| {
+            for n in res.unwrap_or_default() {
+                let route = match actor.source_route(n.id, n.route.clone()) {
+                    Some(r) => r,
+                    None => continue
+                };
+                // fire and forget, the next publication repairs lost stores
+                let _ = actor.send_packet(Packet {
+                    from: actor.id,
+                    des: n.id,
+                    route,
+                    data: DHTStore { key, record: record.clone() },
+                    hops: Vec::new(),
+                    sent_at: 0.,
+                    elapsed: 0.,
+                    trace: 0,
+                });
+            }
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+
+    fn accept_record(&mut self, key: u64, record: Record) -> bool {
+        let newer = match self.dht.get(&key) {
+            // while a record lives only its owner may replace it
+            Some(old) if old.live(self.now) => old.owner == record.owner && record.version >= old.version,
+            Some(_) => true,
+            None => true
+        };
+        if newer && record.live(self.now) {
+            self.dht.store(key, record);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Fetch the record stored under `key` from the closest nodes.
+    pub fn dht_find_value(&mut self, key: u64) -> LookupFuture<Record> {
+        if let Some(r) = self.dht.get(&key) {
+            if r.live(self.now) {
+                return NodeFuture::new(fut::ok(r.clone()))
+            }
+        }
+        NodeFuture::new(self.dht_closest(key).then(move |res, actor, _ctx| {
+            actor.dht_ask_value(key, res.unwrap_or_default())
+        }))
+    }
+
+    // asks the candidates closest first
+    fn dht_ask_value(&mut self, key: u64, mut candidates: Vec<DHTNode>) -> LookupFuture<Record> {
+        while !candidates.is_empty() {
+            let n = candidates.remove(0);
+            let route = match self.source_route(n.id, n.route.clone()) {
+                Some(r) => r,
+                None => continue
+            };
+            return NodeFuture::new(self.send_packet(Packet {
+                from: self.id,
+                des: n.id,
+                route,
+                data: DHTFindValue { key },
+                hops: Vec::new(),
+                sent_at: 0.,
+                elapsed: 0.,
+                trace: 0,
+            }).then(move |res, actor, _ctx| {
+                if let Ok(Ok(DHTFindValueReply { record: Some(r) })) = res {
+                    if r.live(actor.now) {
+                        return NodeFuture::new(fut::ok(r))
+                    }
+                }
+                actor.dht_ask_value(key, candidates)
+            }))
+        }
+        NodeFuture::new(fut::err(()))
+    }
+
+    /// Coordinate of `id` from the DHT, together with a fresh route to it.
+    pub fn nc_lookup(&mut self, id: u64) -> LookupFuture<Located> {
+        NodeFuture::new(self.dht_find_value(id).then(move |res, actor, _ctx| {
+            let (coords, version) = match res {
+                Ok(Record { value: Value::Coordinates(c), version, .. }) => (c, version),
+                _ => return NodeFuture::new(fut::err(()))
+            };
+            NodeFuture::new(actor.dht_lookup(id, None, false).then(move |res, actor, _ctx| {
+                match res.ok().and_then(|n| actor.source_route(id, n.route)) {
+                    Some(route) => fut::ok(Located { id, coords, version, route }),
+                    None => fut::err(())
+                }
+            }))
+        }))
+    }
+}
+
+/// Store a record on a replica.
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
+#[Message(bool)]
+pub struct DHTStore {
+    pub key: u64,
+    pub record: Record,
+}
+
+impl PacketData for DHTStore {
+    fn kind() -> &'static str {
+        "dht_store"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<u64>() + self.record.wire_size()
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        let stored = node.accept_record(packet.data.key, packet.data.record.clone());
+        Node::reply(stored)
+    }
+}
+
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
+#[Message(DHTFindValueReply)]
+pub struct DHTFindValue {
+    pub key: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DHTFindValueReply {
+    pub record: Option<Record>,
+}
+
+impl PacketData for DHTFindValue {
+    fn kind() -> &'static str {
+        "dht_find_value"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<u64>()
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        let record = match node.dht.get(&packet.data.key) {
+            Some(r) if r.live(node.now) => Some(r.clone()),
+            _ => None
+        };
+        Node::reply(DHTFindValueReply { record })
+    }
+}
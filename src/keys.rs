@@ -0,0 +1,101 @@
+//! Per node key pairs.
+//!
+//! Discrete logarithms modulo the Mersenne prime 2^61 - 1 stand in for real
+//! public key cryptography: far too small to be secure, but a node's secret
+//! is not derived from its ID, so no other node can compute it. Keys are
+//! drawn from a stream of their own, apart from the node streams, so the
+//! world can register the public key of every node with the ledger,
+//! including those other processes run. Signatures are Schnorr's, keys shared
+//! for private routes come from Diffie-Hellman.
+
+use rand::Rng;
+
+use onion;
+use rng::SimRng;
+
+const P: u64 = (1 << 61) - 1;
+const G: u64 = 37;
+// keys are drawn from this stream of the run
+static KEY_SEED: u64 = 0x6b65_7973_0000_0000;
+
+fn mul(a: u64, b: u64) -> u64 {
+    (a as u128 * b as u128 % P as u128) as u64
+}
+
+fn pow(base: u64, exponent: u64) -> u64 {
+    let (mut b, mut e, mut r) = (base % P, exponent, 1);
+    while e > 0 {
+        if e & 1 == 1 {
+            r = mul(r, b);
+        }
+        b = mul(b, b);
+        e >>= 1;
+    }
+    r
+}
+
+// an exponent in 1..P - 1
+fn exponent<R: Rng>(rng: &mut R) -> u64 {
+    1 + rng.next_u64() % (P - 2)
+}
+
+// challenge of a signature, the MAC under a well known key serves as the hash
+fn challenge(r: u64, message: &[u8]) -> u64 {
+    let mut data: Vec<u8> = (0..8).map(|i| (r >> (8 * i)) as u8).collect();
+    data.extend_from_slice(message);
+    onion::mac(&[0u8; 32], &[0u8; 12], &data) % (P - 1)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    pub e: u64,
+    pub s: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyPair {
+    secret: u64,
+    pub public: u64,
+}
+
+impl KeyPair {
+    /// Key pair of the node at graph index `index` of the run seeded with `seed`.
+    pub fn of(seed: u64, index: usize) -> KeyPair {
+        let secret = exponent(&mut SimRng::derive(seed ^ KEY_SEED, index as u64));
+        KeyPair { secret, public: pow(G, secret) }
+    }
+
+    /// A throwaway key pair, for a single message.
+    pub fn ephemeral<R: Rng>(rng: &mut R) -> KeyPair {
+        let secret = exponent(rng);
+        KeyPair { secret, public: pow(G, secret) }
+    }
+
+    /// Key shared with the owner of `public`, who gets the same from our public key.
+    pub fn shared(&self, public: u64) -> [u8; 32] {
+        let mut seed = [0u8; 32];
+        let secret = pow(public, self.secret);
+        for (i, b) in seed.iter_mut().take(8).enumerate() {
+            *b = (secret >> (8 * i)) as u8;
+        }
+        let mut key = [0u8; 32];
+        onion::chacha20(&seed, &[0u8; 12], &mut key);
+        key
+    }
+
+    /// Sign `message`, the nonce is drawn from `rng`.
+    pub fn sign<R: Rng>(&self, message: &[u8], rng: &mut R) -> Signature {
+        let k = exponent(rng);
+        let e = challenge(pow(G, k), message);
+        // k - secret * e modulo the order of the group
+        let n = (P - 1) as u128;
+        let s = ((k as u128 + n - self.secret as u128 * e as u128 % n) % n) as u64;
+        Signature { e, s }
+    }
+}
+
+/// Whether `signature` over `message` was made with the secret of `public`.
+pub fn verify(public: u64, message: &[u8], signature: &Signature) -> bool {
+    let r = mul(pow(G, signature.s), pow(public, signature.e));
+    challenge(r, message) == signature.e
+}
@@ -0,0 +1,349 @@
+//! Payment for forwarding.
+//!
+//! Every node counts the bytes it forwards on behalf of each neighbour and the
+//! bytes it hands to each neighbour for transit. Each settlement round a
+//! node invoices its neighbours for the running total at its own price. The
+//! debtor checks the invoice against its own count and answers with a signed
+//! IOU for what it has not paid yet, which the creditor submits to the ledger
+//! service, standing in for a chain. The ledger checks the signature against
+//! the public key registered for the debtor when it started. Invoicing totals
+//! keeps a lost invoice or IOU from being paid twice. An invoice left
+//! unanswered goes again with the same total, which the debtor answers with
+//! the IOU it already issued for it, so a lost IOU is not lost money either.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use keys::{self, KeyPair, Signature};
+use node::Node;
+use packet::*;
+use stats::Distribution;
+use world;
+
+#[derive(Clone, Debug)]
+pub struct BillingConfig {
+    // price per forwarded byte, by the level of the forwarding node, the last one applies to lower levels
+    pub prices: Vec<f32>,
+    // simulated seconds between settlements
+    pub settle_interval: f32,
+    // money per second of latency, used to weigh prices against latency in route choice
+    pub latency_value: f32,
+}
+
+impl Default for BillingConfig {
+    fn default() -> BillingConfig {
+        BillingConfig {
+            prices: vec![1e-6, 4e-6],
+            settle_interval: 10.,
+            latency_value: 1.,
+        }
+    }
+}
+
+impl BillingConfig {
+    pub fn price(&self, level: usize) -> f32 {
+        match self.prices.get(level).or(self.prices.last()) {
+            Some(&p) => p,
+            None => 0.
+        }
+    }
+}
+
+/// Per neighbour byte counts kept by every node.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Accounts {
+    // forwarded on behalf of each neighbour, the total last acknowledged by it and one invoiced with no answer yet
+    pub forwarded: HashMap<u64, u64>,
+    pub invoiced: HashMap<u64, u64>,
+    pub pending: HashMap<u64, u64>,
+    // handed to, and already paid to, each neighbour for transit
+    pub handed: HashMap<u64, u64>,
+    pub paid: HashMap<u64, u64>,
+    // the total and IOU of the last payment to each neighbour
+    pub issued: HashMap<u64, (u64, Iou)>,
+    pub iou_seq: u64,
+    pub disputes: u64,
+    pub settle_round: Option<f32>,
+}
+
+/// Promise by `debtor` to pay `creditor`, signed with the debtor's key.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Iou {
+    pub debtor: u64,
+    pub creditor: u64,
+    pub amount: f64,
+    pub seq: u64,
+    pub signature: Signature,
+}
+
+impl Iou {
+    fn digest(debtor: u64, creditor: u64, amount: f64, seq: u64) -> Vec<u8> {
+        let mut data = Vec::new();
+        for x in &[debtor, creditor, amount.to_bits(), seq] {
+            for i in 0..8 {
+                data.push((x >> (8 * i)) as u8);
+            }
+        }
+        data
+    }
+
+    /// An IOU signed with `keys`, the debtor's.
+    pub fn new<R: Rng>(keys: &KeyPair, debtor: u64, creditor: u64, amount: f64, seq: u64, rng: &mut R) -> Iou {
+        let signature = keys.sign(&Iou::digest(debtor, creditor, amount, seq), rng);
+        Iou { debtor, creditor, amount, seq, signature }
+    }
+
+    /// Whether the debtor whose public key is `public` signed it.
+    pub fn verify(&self, public: u64) -> bool {
+        keys::verify(public, &Iou::digest(self.debtor, self.creditor, self.amount, self.seq), &self.signature)
+    }
+}
+
+impl Node {
+    /// Count a packet received from `from` which we forward onwards.
+    pub fn bill_forwarded(&mut self, from: u64, bytes: usize) {
+        if self.config.billing.is_some() {
+            *self.accounts.forwarded.entry(from).or_insert(0) += bytes as u64;
+        }
+    }
+
+    /// Count a packet handed to `to`, at every hop whether or not `to` forwards it.
+    pub fn bill_handed(&mut self, to: u64, bytes: usize) {
+        if self.config.billing.is_some() {
+            *self.accounts.handed.entry(to).or_insert(0) += bytes as u64;
+        }
+    }
+
+    /// Invoice every neighbour for what we forwarded since the last round.
+    pub fn settle_tick(&mut self, ctx: &mut Context<Self>) {
+        let price = match self.config.billing {
+            Some(ref b) if self.accounts.settle_round.is_none_or(|t| self.now - t >= b.settle_interval) => b.price(self.level),
+            _ => return
+        };
+        self.accounts.settle_round = Some(self.now);
+        let due: Vec<(u64, u64)> = self.accounts.forwarded.iter()
+            .filter(|&(n, _)| self.neighbours_map.contains_key(n))
+            .map(|(n, total)| (*n, self.accounts.pending.get(n).cloned().unwrap_or(*total)))
+            .filter(|&(n, total)| self.accounts.invoiced.get(&n) != Some(&total))
+            .collect();
+        for (n, total) in due {
+            self.accounts.pending.insert(n, total);
+            let f = self.send_packet(Packet {
+                from: self.id,
+                des: n,
+                route: vec![n],
+                data: Invoice { total, price },
+                hops: Vec::new(),
+                sent_at: 0.,
+                elapsed: 0.,
+                trace: 0,
+            });
+            ctx.spawn(ActorFuture::then(f, move |res, actor: &mut Node, _ctx| {
+                if let Ok(Ok(reply)) = res {
+                    actor.accounts.pending.remove(&n);
+                    actor.accounts.invoiced.insert(n, total);
+                    if let Some(iou) = reply.iou {
+                        Arbiter::system_registry().get::<LedgerService>().send(SubmitIou { iou });
+                    }
+                }
+                fut::ok::<(), (), Node>(())
+            }));
+        }
+    }
+
+    fn pay(&mut self, creditor: u64, total: u64, charged: f32) -> Option<Iou> {
+        let level = match self.neighbours_map.get(&creditor) {
+            Some(&i) => self.neighbours[i].level,
+            None => return None
+        };
+        let price = self.config.billing.as_ref().map_or(0., |b| b.price(level));
+        let handed = self.accounts.handed.get(&creditor).cloned().unwrap_or(0);
+        let paid = self.accounts.paid.get(&creditor).cloned().unwrap_or(0);
+        // more bytes than we handed over, or not at the price the creditor is known to charge.
+        // packets lost on the way or addressed to the creditor are counted by us but not by it, so honest invoices are never above our count
+        if total > handed || charged != price {
+            self.accounts.disputes += 1;
+            return None
+        }
+        // our answer to this total was lost, the ledger takes the same IOU only once
+        if let Some(&(t, ref iou)) = self.accounts.issued.get(&creditor) {
+            if t == total {
+                return Some(iou.clone())
+            }
+        }
+        if total <= paid {
+            return None
+        }
+        self.accounts.paid.insert(creditor, total);
+        self.accounts.iou_seq += 1;
+        let iou = Iou::new(&self.keys, self.id, creditor, (total - paid) as f64 * price as f64, self.accounts.iou_seq, &mut self.rng);
+        self.accounts.issued.insert(creditor, (total, iou.clone()));
+        Some(iou)
+    }
+}
+
+/// Bill for forwarding, sent to a neighbour.
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
+#[Message(InvoiceReply)]
+pub struct Invoice {
+    // bytes forwarded for the debtor since the start, and the price per byte
+    pub total: u64,
+    pub price: f32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InvoiceReply {
+    pub iou: Option<Iou>,
+}
+
+impl PacketData for Invoice {
+    fn kind() -> &'static str {
+        "invoice"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<u64>() + mem::size_of::<f32>()
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        let iou = node.pay(packet.from, packet.data.total, packet.data.price);
+        Node::reply(InvoiceReply { iou })
+    }
+}
+
+/// In process stand in for a chain, keeps the balance of every node.
+#[derive(Default)]
+pub struct LedgerService {
+    // public key of every node, as registered when it started
+    keys: HashMap<u64, u64>,
+    balances: HashMap<u64, f64>,
+    seen: HashSet<(u64, u64)>,
+    settled: u64,
+    rejected: u64,
+    volume: f64,
+}
+
+impl Actor for LedgerService {
+    type Context = Context<Self>;
+}
+
+impl Supervised for LedgerService {}
+
+impl SystemService for LedgerService {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {}
+}
+
+// sent by the world for every node it starts or stands in for, the first key registered for an ID stays
+pub struct RegisterKey {
+    pub id: u64,
+    pub public: u64,
+}
+
+message!(RegisterKey);
+
+impl Handler<RegisterKey> for LedgerService {
+    fn handle(&mut self, msg: RegisterKey, _ctx: &mut Context<Self>) -> Response<Self, RegisterKey> {
+        self.keys.entry(msg.id).or_insert(msg.public);
+        Self::reply(())
+    }
+}
+
+pub struct SubmitIou {
+    pub iou: Iou,
+}
+
+message!(SubmitIou);
+
+impl Handler<SubmitIou> for LedgerService {
+    fn handle(&mut self, msg: SubmitIou, _ctx: &mut Context<Self>) -> Response<Self, SubmitIou> {
+        let iou = msg.iou;
+        // forged, by an unknown debtor, or replayed
+        let signed = self.keys.get(&iou.debtor).is_some_and(|&k| iou.verify(k));
+        if !signed || !self.seen.insert((iou.debtor, iou.seq)) {
+            self.rejected += 1;
+            return Self::reply(())
+        }
+        *self.balances.entry(iou.debtor).or_insert(0.) -= iou.amount;
+        *self.balances.entry(iou.creditor).or_insert(0.) += iou.amount;
+        self.settled += 1;
+        self.volume += iou.amount;
+        Self::reply(())
+    }
+}
+
+// sent by the world, the ledger answers with a `world::LedgerReport`
+pub struct QueryLedger {
+    // output directory of the report, passed back
+    pub dir: Option<String>,
+    pub world: SyncAddress<world::World>,
+}
+
+message!(QueryLedger);
+
+impl Handler<QueryLedger> for LedgerService {
+    fn handle(&mut self, msg: QueryLedger, _ctx: &mut Context<Self>) -> Response<Self, QueryLedger> {
+        msg.world.send(world::LedgerReport {
+            dir: msg.dir,
+            settled: self.settled,
+            rejected: self.rejected,
+            volume: self.volume,
+            balances: self.balances.clone(),
+        });
+        Self::reply(())
+    }
+}
+
+/// Whether prices change the paths packets would take, from a sample of pairs.
+#[derive(Clone, Debug, Default)]
+pub struct RouteChoice {
+    pub pairs: usize,
+    // pairs whose price aware path differs from the fastest one
+    pub changed: usize,
+    // latency of the price aware over the fastest path, and transit price of the fastest over the price aware
+    pub latency_ratio: f64,
+    pub price_ratio: f64,
+}
+
+impl RouteChoice {
+    pub fn record(&mut self, changed: bool, latency_ratio: f32, price_ratio: f32) {
+        self.pairs += 1;
+        if changed {
+            self.changed += 1;
+        }
+        self.latency_ratio += latency_ratio as f64;
+        self.price_ratio += price_ratio as f64;
+    }
+
+    pub fn summary(&self, settled: u64, rejected: u64, volume: f64, balances: &HashMap<u64, f64>) -> String {
+        let n = self.pairs.max(1) as f64;
+        format!("billing: {} IOUs settled, {} rejected, volume {:.4}, balances {}\n  \
+                 route choice over {} pairs: {} changed by prices, price aware paths {:.3}x slower, fastest {:.3}x dearer",
+                settled, rejected, volume, Distribution::new(balances.values().map(|b| *b as f32).collect()),
+                self.pairs, self.changed, self.latency_ratio / n, self.price_ratio / n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rng::SimRng;
+
+    #[test]
+    fn ious_only_verify_under_the_debtor_key() {
+        let (debtor, other) = (KeyPair::of(1, 0), KeyPair::of(1, 1));
+        let mut rng = SimRng::derive(1, 0);
+        let iou = Iou::new(&debtor, 10, 11, 2.5, 1, &mut rng);
+        assert!(iou.verify(debtor.public));
+        assert!(!iou.verify(other.public));
+        // a creditor cannot raise the amount
+        let raised = Iou { amount: 25., ..iou.clone() };
+        assert!(!raised.verify(debtor.public));
+        // nor sign for the debtor with its own key
+        let forged = Iou::new(&other, 10, 11, 2.5, 1, &mut rng);
+        assert!(!forged.verify(debtor.public));
+    }
+}
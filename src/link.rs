@@ -0,0 +1,271 @@
+//! Finite capacity links.
+//!
+//! Every directed edge has a queue owned by the sending node. Packets carry
+//! the simulated time they left their source plus the time spent in transit,
+//! so a queue can be drained lazily whenever a packet arrives at it.
+
+use rand::Rng;
+
+use std::collections::HashMap;
+
+use connection::Connection;
+use node::Node;
+use packet::{Packet, PacketData};
+use stats::Distribution;
+
+use actix::ResponseType;
+
+/// Queue management used on every link.
+#[derive(Clone, Copy, Debug)]
+pub enum Discipline {
+    /// Plain FIFO with tail drops.
+    DropTail,
+    /// Random early detection, thresholds as a fraction of the capacity.
+    Red { min: f32, max: f32, max_p: f32 },
+    /// Controlled delay, drops while the standing delay stays above `target` for `interval`.
+    CoDel { target: f32, interval: f32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct QueueConfig {
+    pub discipline: Discipline,
+    // bytes
+    pub capacity: f32,
+    // simulated seconds between ticks
+    pub epoch_length: f32,
+    // drop packets with the link's loss rate
+    pub apply_loss: bool,
+}
+
+impl Default for QueueConfig {
+    fn default() -> QueueConfig {
+        QueueConfig {
+            discipline: Discipline::DropTail,
+            capacity: 64000.,
+            epoch_length: 1.,
+            apply_loss: true,
+        }
+    }
+}
+
+// weight of new samples in the RED average
+static RED_WEIGHT: f32 = 0.002;
+
+#[derive(Clone, Debug)]
+pub struct LinkQueue {
+    discipline: Discipline,
+    capacity: f32,
+    // bytes per simulated second
+    bandwidth: f32,
+    backlog: f32,
+    last: f32,
+    average: f32,
+    above_since: Option<f32>,
+    pub enqueued: u64,
+    pub dropped: u64,
+    pub max_backlog: f32,
+}
+
+impl LinkQueue {
+    pub fn new(connection: &Connection, config: &QueueConfig) -> LinkQueue {
+        LinkQueue {
+            discipline: config.discipline,
+            capacity: config.capacity,
+            bandwidth: connection.bandwidth,
+            backlog: 0.,
+            last: 0.,
+            average: 0.,
+            above_since: None,
+            enqueued: 0,
+            dropped: 0,
+            max_backlog: 0.,
+        }
+    }
+
+    pub fn backlog(&self) -> f32 {
+        self.backlog
+    }
+
+    fn drain(&mut self, now: f32) {
+        if now > self.last {
+            self.backlog = (self.backlog - (now - self.last) * self.bandwidth).max(0.);
+            self.last = now;
+        }
+    }
+
+    fn early_drop<R: Rng>(&mut self, now: f32, delay: f32, rng: &mut R) -> bool {
+        match self.discipline {
+            Discipline::DropTail => false,
+            Discipline::Red { min, max, max_p } => {
+                self.average = (1. - RED_WEIGHT) * self.average + RED_WEIGHT * self.backlog;
+                let (min, max) = (min * self.capacity, max * self.capacity);
+                if self.average < min {
+                    false
+                } else if self.average >= max {
+                    true
+                } else {
+                    rng.next_f32() < max_p * (self.average - min) / (max - min)
+                }
+            }
+            Discipline::CoDel { target, interval } => {
+                if delay < target {
+                    self.above_since = None;
+                    false
+                } else {
+                    let since = *self.above_since.get_or_insert(now);
+                    now - since >= interval
+                }
+            }
+        }
+    }
+
+    /// Queue a packet at time `now`, returns the queueing plus transmission delay or `None` if dropped.
+    ///
+    /// Early drops of RED are drawn from `rng`.
+    pub fn enqueue<R: Rng>(&mut self, now: f32, size: f32, rng: &mut R) -> Option<f32> {
+        self.drain(now);
+        // packets can arrive slightly out of order, they see the queue as it is now
+        let delay = self.backlog / self.bandwidth;
+        if self.backlog + size > self.capacity || self.early_drop(now, delay, rng) {
+            self.dropped += 1;
+            return None
+        }
+        self.backlog += size;
+        self.enqueued += 1;
+        self.max_backlog = self.max_backlog.max(self.backlog);
+        Some(delay + size / self.bandwidth)
+    }
+}
+
+/// Per packet type counters kept by each node.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct KindStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub queueing: f64,
+}
+
+/// Queue statistics for one node.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct QueueReport {
+    pub backlogs: Vec<f32>,
+    pub max_backlogs: Vec<f32>,
+    pub enqueued: u64,
+    pub dropped: u64,
+    #[serde(with = "::checkpoint::kind_map")]
+    pub kinds: HashMap<&'static str, KindStats>,
+}
+
+/// Network wide queue statistics kept by the world.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct QueueStats {
+    nodes: HashMap<u64, QueueReport>,
+}
+
+impl QueueStats {
+    pub fn record(&mut self, id: u64, report: QueueReport) {
+        self.nodes.insert(id, report);
+    }
+
+    /// Packets each node put on its links.
+    pub fn sent(&self) -> Vec<(u64, u64)> {
+        self.nodes.iter().map(|(&id, r)| (id, r.kinds.values().map(|k| k.sent).sum())).collect()
+    }
+
+    fn kinds(&self) -> HashMap<&'static str, KindStats> {
+        let mut kinds: HashMap<&'static str, KindStats> = HashMap::new();
+        for r in self.nodes.values() {
+            for (kind, k) in &r.kinds {
+                let e = kinds.entry(*kind).or_default();
+                e.sent += k.sent;
+                e.dropped += k.dropped;
+                e.queueing += k.queueing;
+            }
+        }
+        kinds
+    }
+
+    pub fn summary(&self) -> String {
+        let occupancy = Distribution::new(self.nodes.values().flat_map(|r| r.backlogs.iter().cloned()).collect());
+        let mut out = format!("link occupancy (bytes): {}", occupancy);
+        for (kind, k) in self.kinds() {
+            out.push_str(&format!("\n  {}: {} sent, {} dropped, mean queueing {:.4}s", kind, k.sent, k.dropped,
+                                  k.queueing / k.sent.max(1) as f64));
+        }
+        out
+    }
+
+    /// Fraction of the packets put on a link which were dropped, over every kind.
+    pub fn loss_ratio(&self) -> f64 {
+        let (sent, dropped) = self.kinds().values().fold((0, 0), |(s, d), k| (s + k.sent, d + k.dropped));
+        dropped as f64 / sent.max(1) as f64
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("kind,sent,dropped,mean_queueing\n");
+        for (kind, k) in self.kinds() {
+            out.push_str(&format!("{},{},{},{}\n", kind, k.sent, k.dropped, k.queueing / k.sent.max(1) as f64));
+        }
+        out
+    }
+}
+
+impl Node {
+    /// Put a packet on the link to neighbour `index`, returns false if it was dropped.
+    pub fn transmit<T: PacketData + Clone + Send + ResponseType>(&mut self, index: usize, packet: &mut Packet<T>) -> bool {
+        let now = packet.sent_at + packet.elapsed;
+        let size = packet.wire_size() as f32;
+        let next = self.neighbours[index].id;
+        self.bill_handed(next, size as usize);
+        let n = &mut self.neighbours[index];
+        let stats = self.link_stats.entry(T::kind()).or_default();
+        stats.sent += 1;
+        // the packet occupies the queue even if it is then lost on the wire
+        let queued = n.queue.enqueue(now, size, &mut self.rng);
+        let lost = n.failed || (self.config.queue.apply_loss && self.rng.next_f32() < n.connection.packet_loss);
+        match queued {
+            Some(delay) if !lost => {
+                packet.elapsed += delay + n.connection.latency;
+                stats.queueing += delay as f64;
+                true
+            }
+            _ => {
+                stats.dropped += 1;
+                false
+            }
+        }
+    }
+
+    pub fn queue_report(&self) -> QueueReport {
+        QueueReport {
+            backlogs: self.neighbours.iter().map(|n| n.queue.backlog()).collect(),
+            max_backlogs: self.neighbours.iter().map(|n| n.queue.max_backlog).collect(),
+            enqueued: self.neighbours.iter().map(|n| n.queue.enqueued).sum(),
+            dropped: self.neighbours.iter().map(|n| n.queue.dropped).sum(),
+            kinds: self.link_stats.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packet::PacketRouteData;
+    use rng::SimRng;
+    use traffic::Data;
+
+    #[test]
+    fn data_queues_for_its_size_over_bandwidth() {
+        let connection = Connection { latency: 0.01, bandwidth: 1000., packet_loss: 0. };
+        let mut queue = LinkQueue::new(&connection, &QueueConfig::default());
+        let mut rng = SimRng::derive(0, 0);
+        let data = Data { flow: 1, seq: 0, size: 500 };
+        assert_eq!(data.size(), 500);
+        assert_eq!(queue.enqueue(0., data.size() as f32, &mut rng), Some(0.5));
+        // the second packet waits for the first
+        assert_eq!(queue.enqueue(0., data.size() as f32, &mut rng), Some(1.));
+        // only the header is added on the wire
+        let packet = Packet::new(PacketRouteData { from: 1, des: 2, route: vec![2] }, data);
+        assert_eq!(packet.wire_size(), 500 + 3 * 8 + 2 * 4);
+    }
+}
@@ -0,0 +1,51 @@
+//! Verbosity of the console output.
+//!
+//! `info!` is for progress and reports, `debug!` for per packet chatter. Both
+//! take `println!` arguments and print nothing below the level set at startup.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Quiet,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn parse(s: &str) -> Option<Level> {
+        match s {
+            "quiet" => Some(Level::Quiet),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None
+        }
+    }
+}
+
+// shared by every arbiter thread
+static LEVEL: AtomicUsize = AtomicUsize::new(Level::Quiet as usize);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level as usize
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if ::logging::enabled(::logging::Level::Info) {
+            println!($($arg)*);
+        }
+    }
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if ::logging::enabled(::logging::Level::Debug) {
+            println!($($arg)*);
+        }
+    }
+}
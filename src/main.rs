@@ -1,31 +1,268 @@
-#![feature(conservative_impl_trait)]
-#![feature(box_syntax)]
-
 extern crate actix;
 extern crate petgraph;
-extern crate spade;
+extern crate rstar;
 extern crate tokio_core;
 extern crate futures;
 extern crate rand;
 #[macro_use]
 extern crate actix_derive;
 extern crate nalgebra;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 use actix::*;
 
+// `ResponseType` of a message answered with nothing, the `Message` derive of actix_derive 0.0.2 needs the types spelled out
+macro_rules! message {
+    ($name:ident) => {
+        impl ::actix::ResponseType for $name {
+            type Item = ();
+            type Error = ();
+        }
+    }
+}
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+#[macro_use]
+mod logging;
+#[macro_use]
+mod trace;
+mod cli;
 mod node;
 mod world;
 mod connection;
 mod nc;
 mod packet;
 mod dht;
+mod topology;
+mod oracle;
+mod baseline;
+mod stats;
+mod accounting;
+mod traffic;
+mod link;
+mod nceval;
+mod adversary;
+mod directory;
+mod address;
+mod onion;
+mod advert;
+mod ledger;
+mod keys;
+mod scenario;
+mod sweep;
+mod rng;
+mod checkpoint;
+mod partition;
+mod codec;
+mod shard;
+mod udp;
+mod console;
 
-fn main() {
-    let system = System::new("test");
+fn run(options: &cli::Options, generate_only: bool) -> i32 {
+    let scenario = match options.scenario {
+        Some(ref path) => match scenario::Scenario::load(path) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2
+            }
+        },
+        None => None
+    };
+    let restore = match options.restore {
+        Some(ref path) => match checkpoint::Checkpoint::load(path) {
+            Ok(c) => Some(c),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2
+            }
+        },
+        None => None
+    };
+    let mut setting = sweep::Setting::default();
+    for (key, value) in &options.set {
+        if let Err(e) = setting.apply(key, value) {
+            eprintln!("{}", e);
+            return 2
+        }
+    }
+    let link = match (options.shard, options.coordinator.as_ref()) {
+        (Some(index), Some(address)) => match shard::Link::connect(address, index) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2
+            }
+        },
+        _ => None
+    };
+    let udp = match (options.node, options.topology.as_ref()) {
+        (Some(index), Some(path)) => match udp::Topology::load(path) {
+            Ok(ref t) if index >= t.nodes.len() => {
+                eprintln!("node {} is not in {}", index, path);
+                return 2
+            }
+            Ok(t) => Some((index, t)),
+            Err(e) => {
+                eprintln!("{}", e);
+                return 2
+            }
+        },
+        _ => None
+    };
+    let system = System::new("routing-actor");
 
-    let addr = Arbiter::system_registry().get::<world::World>();
+    if trace::any_enabled() {
+        let dir = options.output_dir.clone().unwrap_or(String::from("."));
+        let path = Path::new(&dir).join("trace.jsonl").to_string_lossy().into_owned();
+        Arbiter::system_registry().get::<trace::Tracer>().send(trace::Open { path });
+    }
 
-    addr.send(world::Wake);
+    let addr: SyncAddress<world::World> = world::World::default().start();
 
-    system.run();
+    addr.send(world::Configure {
+        seed: options.seed,
+        threads: options.threads,
+        epochs: options.epochs,
+        output_dir: options.output_dir.clone(),
+        scenario,
+        generation: setting.generation,
+        churn: setting.churn,
+        node_config: setting.node_config,
+        checkpoint_interval: options.checkpoint_interval,
+        restore,
+        partitioner: options.partitioner,
+        generate_only,
+        shard: link.as_ref().map(|(l, _)| (l.shard, options.shards, l.clone())),
+        udp,
+        pause: options.pause,
+        require_connected: options.require_connected,
+    });
+    // only once the world knows it is a shard
+    if let Some((l, stream)) = link {
+        l.listen(stream, addr.clone());
+    }
+    if options.pause.is_some() {
+        console::listen(addr.clone());
+    }
+    if !generate_only {
+        addr.send(world::Wake);
+    }
+
+    system.run()
+}
+
+// every run in its own system, exporting to its own directory
+fn sweep(options: &cli::Options) -> i32 {
+    let dir = options.output_dir.clone().unwrap_or(String::from("sweep"));
+    let mut results = Vec::new();
+    let mut failed = 0;
+    for point in sweep::grid(&options.vary) {
+        let mut runs = Vec::new();
+        for seed in options.seed..options.seed + options.runs {
+            let run_dir = Path::new(&dir).join(sweep::label(&point)).join(format!("seed-{}", seed));
+            let mut run_options = options.clone();
+            run_options.seed = seed;
+            run_options.output_dir = Some(run_dir.to_string_lossy().into_owned());
+            run_options.set.extend(point.iter().cloned());
+            let code = run(&run_options, false);
+            match sweep::read_summary(&run_dir) {
+                Ok(row) if code == 0 => runs.push(row),
+                Ok(_) => {
+                    eprintln!("{} seed {} exited with {}", sweep::label(&point), seed, code);
+                    failed += 1;
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    failed += 1;
+                }
+            }
+        }
+        results.push((point, runs));
+    }
+
+    let table = sweep::table(&results);
+    println!("{}", table);
+    let path = Path::new(&dir).join("sweep.csv");
+    if let Err(e) = File::create(&path).and_then(|mut f| f.write_all(table.as_bytes())) {
+        eprintln!("failed to export {:?}: {}", path, e);
+    }
+    println!("sweep finished, {} runs failed", failed);
+    if failed > 0 { 1 } else { 0 }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let code = match cli::parse(&args) {
+        Ok(cli::Command::Run(o)) => {
+            logging::set_level(o.log_level);
+            trace::set_levels(&o.trace);
+            if o.shards > 1 && o.shard.is_none() {
+                shard::coordinate(&o, &args)
+            } else {
+                run(&o, false)
+            }
+        }
+        Ok(cli::Command::Generate(o)) => {
+            logging::set_level(o.log_level);
+            trace::set_levels(&o.trace);
+            run(&o, true)
+        }
+        Ok(cli::Command::Udp(o)) => {
+            logging::set_level(o.log_level);
+            trace::set_levels(&o.trace);
+            if o.node.is_none() {
+                udp::launch(&o, &args)
+            } else {
+                run(&o, false)
+            }
+        }
+        Ok(cli::Command::Sweep(o)) => {
+            logging::set_level(o.log_level);
+            trace::set_levels(&o.trace);
+            sweep(&o)
+        }
+        Ok(cli::Command::Inspect(dir)) => {
+            match cli::inspect(&dir) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            }
+        }
+        Ok(cli::Command::Trace(path, filter)) => {
+            match trace::replay(&path, &filter) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            }
+        }
+        Ok(cli::Command::Journey(path, id)) => {
+            match trace::print_journey(&path, id) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            }
+        }
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::usage());
+            0
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::usage());
+            2
+        }
+    };
+    std::process::exit(code);
 }
@@ -1,65 +1,442 @@
 use actix::*;
 
-use nalgebra::VectorN;
-use nalgebra::U10;
+use nalgebra::DVector;
 
 use rand::distributions::{IndependentSample, Range};
-use rand::thread_rng;
+use rand::Rng;
+use std::mem;
+use std::time::Instant;
 
-use world::World;
-use connection::Connection;
+use node::Node;
+use packet::*;
+use adversary::Witness;
+use rng::SimRng;
 
-pub type NC = VectorN<f32, U10>;
+pub type NC = DVector<f32>;
 
+/// How a node's learning rate changes as it takes measurements.
+#[derive(Clone, Copy, Debug)]
+pub enum LearnSchedule {
+    Constant,
+    /// Multiply by `factor` after every update, down to `min`.
+    Decay { factor: f32, min: f32 },
+    /// Bold driver, grow while the running error falls and halve when it rises.
+    Adaptive { min: f32, max: f32 },
+}
+
+/// Run time NC parameters, the defaults are the original hard coded values.
 #[derive(Clone, Debug)]
+pub struct NCParams {
+    pub dimension: usize,
+    pub learn_rate: f32,
+    pub schedule: LearnSchedule,
+    pub regularisation: f32,
+    // bound on the prediction error used in a single update
+    pub clamp: f32,
+    // probers take half the round trip in real time as the latency, set for nodes exchanging UDP datagrams
+    pub wall_clock: bool,
+}
+
+impl Default for NCParams {
+    fn default() -> NCParams {
+        NCParams {
+            dimension: 10,
+            learn_rate: 0.05,
+            schedule: LearnSchedule::Constant,
+            regularisation: 0.01,
+            clamp: 2.,
+            wall_clock: false,
+        }
+    }
+}
+
+impl LearnSchedule {
+    /// Learning rate after an update which moved the running error from `before` to `after`.
+    pub fn next(&self, learn_rate: f32, before: f32, after: f32) -> f32 {
+        match *self {
+            LearnSchedule::Constant => learn_rate,
+            LearnSchedule::Decay { factor, min } => (learn_rate * factor).max(min),
+            LearnSchedule::Adaptive { min, max } => {
+                if after <= before {
+                    (learn_rate * 1.05).min(max)
+                } else {
+                    (learn_rate * 0.5).max(min)
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NCNodeData {
     pub outgoing_vec: NC,
     pub incoming_vec: NC,
+    // only used by models with a height above the plane
+    pub height: f32,
+    // confidence in our own coordinate, used to weight vivaldi updates
+    pub error: f32,
     pub learn_rate: f32,
 }
 
 impl NCNodeData {
-    pub fn new() -> NCNodeData {
-        let mut rng = thread_rng();
+    /// Encoded size in bytes, both vectors and the three scalars.
+    pub fn wire_size(&self) -> usize {
+        mem::size_of::<f32>() * (self.outgoing_vec.len() + self.incoming_vec.len() + 3)
+    }
+
+    pub fn new<R: Rng>(params: &NCParams, rng: &mut R) -> NCNodeData {
         let between = Range::new(0., 1.);
         NCNodeData {
-            outgoing_vec: <NC>::from_fn(|_, _| between.ind_sample(&mut rng)),
-            incoming_vec: <NC>::from_fn(|_, _| between.ind_sample(&mut rng)),
-            learn_rate: 0.05
+            outgoing_vec: NC::from_fn(params.dimension, |_, _| between.ind_sample(&mut *rng)),
+            incoming_vec: NC::from_fn(params.dimension, |_, _| between.ind_sample(&mut *rng)),
+            height: 0.,
+            error: 1.,
+            learn_rate: params.learn_rate
         }
     }
 }
 
-pub fn calc_update(a: NC, b: NC, actual: f32, learn_rate: f32) -> (NC, NC) {
-    let diff = actual - a.dot(&b);
+pub fn calc_update(a: &NC, b: &NC, actual: f32, learn_rate: f32, regularisation: f32, clamp: f32) -> (NC, NC) {
+    let diff = actual - a.dot(b);
 
-    let mut a_d = b;
+    let mut a_d = b.clone();
 
     let mut n = 0;
 
-    let diff = (diff).min(2.).max(-2.);
+    let diff = (diff).min(clamp).max(-clamp);
 
     for i in a_d.iter_mut() {
-        *i = *i * learn_rate * diff - b[n].abs() * 0.01 * learn_rate;
+        *i = *i * learn_rate * diff - b[n].abs() * regularisation * learn_rate;
         n += 1;
     }
 
-    let mut b_d = a;
+    let mut b_d = a.clone();
 
     n = 0;
     for i in b_d.iter_mut() {
-        *i = *i * learn_rate * diff - a[n].abs() * 0.01 * learn_rate;
+        *i = *i * learn_rate * diff - a[n].abs() * regularisation * learn_rate;
         n += 1;
     }
 
     (a_d, b_d)
 }
 
-#[derive(Message)]
-struct MeasureMetric {
-    quality: Connection
+/// Which coordinate model nodes run.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ModelKind {
+    #[default]
+    Factorisation,
+    Vivaldi,
+    Dmfsgd,
+}
+
+impl ModelKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ModelKind::Factorisation => "factorisation",
+            ModelKind::Vivaldi => "vivaldi",
+            ModelKind::Dmfsgd => "dmfsgd",
+        }
+    }
+
+    pub fn create(&self, params: &NCParams) -> Box<dyn NCModel> {
+        match *self {
+            ModelKind::Factorisation => Box::new(Factorisation { regularisation: params.regularisation, clamp: params.clamp }),
+            ModelKind::Vivaldi => Box::new(Vivaldi { learn_rate: params.learn_rate, regularisation: params.regularisation, clamp: params.clamp }),
+            ModelKind::Dmfsgd => Box::new(Dmfsgd { regularisation: params.regularisation }),
+        }
+    }
+}
+
+/// A network coordinate model.
+pub trait NCModel {
+    /// Predicted latency from `from` to `to`.
+    fn predict(&self, from: &NCNodeData, to: &NCNodeData) -> f32;
+    /// Adjust `own` after measuring `latency` between it and `other`, `own_is_source` gives the direction.
+    ///
+    /// Any random choice is drawn from `rng`, the stream of the node.
+    fn update(&self, own: &mut NCNodeData, other: &NCNodeData, latency: f32, own_is_source: bool, rng: &mut SimRng);
+    /// A coordinate which predicts `factor` times the latency `own` does to and from `peer`.
+    fn scaled(&self, own: &NCNodeData, _peer: &NCNodeData, factor: f32) -> NCNodeData {
+        // a dot product takes one vector of each side, so scaling ours does for any peer
+        let mut c = own.clone();
+        c.outgoing_vec *= factor;
+        c.incoming_vec *= factor;
+        c.height *= factor;
+        c
+    }
+}
+
+/// The original dot product matrix factorisation.
+pub struct Factorisation {
+    pub regularisation: f32,
+    pub clamp: f32,
+}
+
+impl NCModel for Factorisation {
+    fn predict(&self, from: &NCNodeData, to: &NCNodeData) -> f32 {
+        from.outgoing_vec.dot(&to.incoming_vec)
+    }
+
+    fn update(&self, own: &mut NCNodeData, other: &NCNodeData, latency: f32, own_is_source: bool, _rng: &mut SimRng) {
+        if own_is_source {
+            let (a_d, _) = calc_update(&own.outgoing_vec, &other.incoming_vec, latency, own.learn_rate, self.regularisation, self.clamp);
+            own.outgoing_vec += a_d;
+        } else {
+            let (_, b_d) = calc_update(&other.outgoing_vec, &own.incoming_vec, latency, own.learn_rate, self.regularisation, self.clamp);
+            own.incoming_vec += b_d;
+        }
+    }
 }
 
-impl World {
-    fn send_nc(&mut self, ctx: &mut Context<Self>) {}
-}
\ No newline at end of file
+// vivaldi tuning constants from the original paper
+static VIVALDI_CE: f32 = 0.25;
+static VIVALDI_CC: f32 = 0.25;
+static MIN_HEIGHT: f32 = 1e-3;
+
+/// Vivaldi, euclidean coordinates plus a height for the access link.
+///
+/// Only `outgoing_vec` is used as the position, latencies are symmetric. The
+/// step of the paper shrinks with the node's learning rate over the one it
+/// started from, so the schedule and the trust in a peer apply, and the
+/// position is pulled towards the origin by the regularisation.
+pub struct Vivaldi {
+    pub learn_rate: f32,
+    pub regularisation: f32,
+    pub clamp: f32,
+}
+
+impl NCModel for Vivaldi {
+    fn predict(&self, from: &NCNodeData, to: &NCNodeData) -> f32 {
+        (&from.outgoing_vec - &to.outgoing_vec).norm() + from.height + to.height
+    }
+
+    fn scaled(&self, own: &NCNodeData, peer: &NCNodeData, factor: f32) -> NCNodeData {
+        // moved along the line from the peer, our height takes the peer's share of the change as far as it can
+        let mut c = own.clone();
+        c.outgoing_vec = &peer.outgoing_vec + (&own.outgoing_vec - &peer.outgoing_vec) * factor;
+        c.height = (factor * own.height + (factor - 1.) * peer.height).max(0.);
+        c
+    }
+
+    fn update(&self, own: &mut NCNodeData, other: &NCNodeData, latency: f32, _own_is_source: bool, rng: &mut SimRng) {
+        if latency <= 0. {
+            return
+        }
+        let predicted = self.predict(own, other);
+        let w = own.error / (own.error + other.error);
+        let sample_error = (predicted - latency).abs() / latency;
+        own.error = sample_error * VIVALDI_CE * w + own.error * (1. - VIVALDI_CE * w);
+
+        let rate = if self.learn_rate > 0. { own.learn_rate / self.learn_rate } else { 1. };
+        let force = VIVALDI_CC * w * rate * (latency - predicted).min(self.clamp).max(-self.clamp);
+        let mut direction = &own.outgoing_vec - &other.outgoing_vec;
+        let distance = direction.norm();
+        if distance > 0. {
+            direction /= distance;
+        } else {
+            // coincident nodes are pushed apart in a random direction
+            direction = NC::from_fn(direction.len(), |_, _| rng.next_f32() - 0.5);
+            direction /= direction.norm();
+        }
+        own.outgoing_vec += direction * force;
+        own.outgoing_vec *= 1. - (own.learn_rate * self.regularisation).min(1.);
+        if predicted > 0. {
+            own.height = (own.height + force * (own.height + other.height) / predicted).max(MIN_HEIGHT);
+        }
+    }
+}
+
+/// Decentralised matrix factorisation by stochastic gradient descent (Liao et al.).
+///
+/// Same prediction as `Factorisation`, but plain L2 loss and regularisation with
+/// coordinates kept non negative.
+pub struct Dmfsgd {
+    pub regularisation: f32,
+}
+
+impl NCModel for Dmfsgd {
+    fn predict(&self, from: &NCNodeData, to: &NCNodeData) -> f32 {
+        from.outgoing_vec.dot(&to.incoming_vec)
+    }
+
+    fn update(&self, own: &mut NCNodeData, other: &NCNodeData, latency: f32, own_is_source: bool, _rng: &mut SimRng) {
+        let lr = own.learn_rate;
+        let (own_vec, other_vec) = if own_is_source {
+            (&mut own.outgoing_vec, &other.incoming_vec)
+        } else {
+            (&mut own.incoming_vec, &other.outgoing_vec)
+        };
+        let diff = latency - own_vec.dot(other_vec);
+        for (x, y) in own_vec.iter_mut().zip(other_vec.iter()) {
+            *x = ((1. - lr * self.regularisation) * *x + lr * diff * *y).max(0.);
+        }
+    }
+}
+
+// weight of new samples in the running prediction error
+static ERROR_WEIGHT: f32 = 0.05;
+
+impl Node {
+    /// Measure the latency to a random neighbour and update our coordinate.
+    pub fn nc_tick(&mut self, ctx: &mut Context<Self>) {
+        if self.neighbours.is_empty() {
+            return
+        }
+        let n = self.neighbours[self.rng.gen_range(0, self.neighbours.len())].id;
+        let coords = self.advertised_coords(None);
+        let witnesses = self.witnesses(&coords);
+        let sent = Instant::now();
+        let f = self.send_packet(Packet {
+            from: self.id,
+            des: n,
+            route: vec![n],
+            data: NCProbe { coords, witnesses },
+            hops: Vec::new(),
+            sent_at: 0.,
+            elapsed: 0.,
+            trace: 0,
+        });
+        ctx.spawn(ActorFuture::then(f, move |res, actor: &mut Node, _ctx| {
+            match res {
+                Ok(Ok(ref reply)) if actor.config.nc.wall_clock => {
+                    let latency = sent.elapsed().as_secs_f32() / 2.;
+                    actor.nc_measured(n, &reply.coords, latency, true, &reply.witnesses);
+                    actor.witness(n, &reply.coords, latency);
+                }
+                Ok(Ok(reply)) => actor.nc_measured(n, &reply.coords, reply.latency, true, &reply.witnesses),
+                _ => {}
+            }
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+
+    fn nc_measured(&mut self, from: u64, other: &NCNodeData, latency: f32, own_is_source: bool, witnesses: &[Witness]) {
+        let predicted = if own_is_source {
+            self.nc_model.predict(&self.nc, other)
+        } else {
+            self.nc_model.predict(other, &self.nc)
+        };
+        let before = self.nc_error;
+        if latency > 0. {
+            let e = (predicted - latency).abs() / latency;
+            self.nc_error = (1. - ERROR_WEIGHT) * self.nc_error + ERROR_WEIGHT * e;
+        }
+        // would we have picked the truly closer of this and the previously measured neighbour
+        if let Some((p, l)) = self.nc_last {
+            self.nc_rank.1 += 1;
+            if (p < predicted) == (l < latency) {
+                self.nc_rank.0 += 1;
+            }
+        }
+        self.nc_last = Some((predicted, latency));
+
+        // liars only poison what they are allowed to
+        let weight = match self.nc_accept(from, other, latency, predicted, witnesses) {
+            Some(w) => w * self.metric_trust(from),
+            None => {
+                trace!(Nc, Info, self.event("rejected").detail(format!("peer {} latency {:.4} predicted {:.4}", from, latency, predicted)));
+                return
+            }
+        };
+        trace!(Nc, Debug, self.event("measured")
+            .detail(format!("peer {} latency {:.4} predicted {:.4} error {:.3} weight {:.2}", from, latency, predicted, self.nc_error, weight)));
+        let learn_rate = self.nc.learn_rate;
+        self.nc.learn_rate *= weight;
+        self.nc_model.update(&mut self.nc, other, latency, own_is_source, &mut self.rng);
+        self.nc.learn_rate = self.config.nc.schedule.next(learn_rate, before, self.nc_error);
+    }
+}
+
+/// Coordinate exchange with a neighbour, the one way latency is measured on arrival.
+#[derive(Clone, Debug, Message, Serialize, Deserialize)]
+#[Message(NCProbeReply)]
+pub struct NCProbe {
+    pub coords: NCNodeData,
+    pub witnesses: Vec<Witness>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NCProbeReply {
+    pub coords: NCNodeData,
+    pub latency: f32,
+    pub witnesses: Vec<Witness>,
+}
+
+impl PacketData for NCProbe {
+    fn kind() -> &'static str {
+        "nc_probe"
+    }
+
+    fn size(&self) -> usize {
+        self.coords.wire_size() + self.witnesses.iter().map(|w| w.wire_size()).sum::<usize>()
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        let coords = node.advertised_coords(Some(&packet.data.coords));
+        let witnesses = node.witnesses(&coords);
+        let latency = packet.elapsed;
+        // on the wall clock only the prober times the probe
+        if !node.config.nc.wall_clock {
+            node.nc_measured(packet.from, &packet.data.coords, latency, false, &packet.data.witnesses);
+            node.witness(packet.from, &packet.data.coords, latency);
+        }
+        Node::reply(NCProbeReply { coords, latency: node.reported_latency(latency), witnesses })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vivaldi(params: &NCParams) -> Box<dyn NCModel> {
+        ModelKind::Vivaldi.create(params)
+    }
+
+    fn at(x: f32, params: &NCParams) -> NCNodeData {
+        let mut rng = SimRng::derive(1, 0);
+        let mut c = NCNodeData::new(params, &mut rng);
+        c.outgoing_vec = NC::from_fn(params.dimension, |i, _| if i == 0 { x } else { 0. });
+        c
+    }
+
+    #[test]
+    fn vivaldi_moves_towards_the_measured_latency() {
+        let params = NCParams { dimension: 2, regularisation: 0., ..NCParams::default() };
+        let model = vivaldi(&params);
+        let mut rng = SimRng::derive(2, 0);
+        let other = at(0., &params);
+        let mut own = at(0.1, &params);
+        for _ in 0..200 {
+            model.update(&mut own, &other, 0.03, true, &mut rng);
+        }
+        assert!((model.predict(&own, &other) - 0.03).abs() < 1e-3, "{}", model.predict(&own, &other));
+    }
+
+    #[test]
+    fn vivaldi_follows_the_parameters() {
+        let params = NCParams { dimension: 2, regularisation: 0., ..NCParams::default() };
+        let mut rng = SimRng::derive(3, 0);
+        let other = at(0., &params);
+
+        // a node whose schedule ran down to nothing stays put
+        let mut stopped = at(0.1, &params);
+        stopped.learn_rate = 0.;
+        vivaldi(&params).update(&mut stopped, &other, 0.5, true, &mut rng);
+        assert_eq!(stopped.outgoing_vec[0], 0.1);
+
+        // the error of a single update is clamped
+        let clamped = NCParams { clamp: 0.01, ..params.clone() };
+        let mut own = at(0.1, &clamped);
+        vivaldi(&clamped).update(&mut own, &other, 10., true, &mut rng);
+        assert!(own.outgoing_vec[0] - 0.1 <= VIVALDI_CC * 0.01 + 1e-6);
+
+        // regularisation pulls towards the origin
+        let regularised = NCParams { regularisation: 1., ..params.clone() };
+        let mut own = at(0.1, &regularised);
+        let model = vivaldi(&regularised);
+        let exact = model.predict(&own, &other);
+        model.update(&mut own, &other, exact, true, &mut rng);
+        assert!(own.outgoing_vec[0] < 0.1);
+    }
+}
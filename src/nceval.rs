@@ -0,0 +1,153 @@
+//! NC accuracy against ground truth latencies.
+//!
+//! The world periodically collects the coordinates of a sample of nodes and
+//! compares their predictions with shortest path latencies from the oracle.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::collections::HashMap;
+
+use nc::{NCModel, NCNodeData};
+use node::Node;
+use stats::Distribution;
+use world;
+
+// nodes asked for their coordinates per evaluation
+pub static EVAL_NODES: usize = 64;
+// pairs and triples sampled per evaluation
+static EVAL_SAMPLES: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct NCEvaluation {
+    pub epoch: u64,
+    pub relative_error: Distribution,
+    // fraction of (source, a, b) where NC picks the truly closer of a and b
+    pub rank_accuracy: f32,
+    // fraction of triangles violating the triangle inequality, and mean severity of those
+    pub tiv_predicted: f32,
+    pub tiv_severity_predicted: f32,
+    pub tiv_truth: f32,
+}
+
+impl NCEvaluation {
+    pub fn csv_header() -> String {
+        format!("epoch,{},rank_accuracy,tiv_predicted,tiv_severity_predicted,tiv_truth\n",
+                Distribution::csv_header("relative_error"))
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{},{},{}\n", self.epoch, self.relative_error.to_csv(), self.rank_accuracy,
+                self.tiv_predicted, self.tiv_severity_predicted, self.tiv_truth)
+    }
+}
+
+#[derive(Default)]
+pub struct NCEvaluator {
+    coords: HashMap<u64, NCNodeData>,
+    pub history: Vec<NCEvaluation>,
+}
+
+// (violated, severity) for the triangle a -> b -> c against the direct a -> c
+fn tiv(ab: f32, bc: f32, ac: f32) -> (bool, f32) {
+    if ac > ab + bc && ac > 0. {
+        (true, (ac - ab - bc) / ac)
+    } else {
+        (false, 0.)
+    }
+}
+
+impl NCEvaluator {
+    pub fn record(&mut self, id: u64, coords: NCNodeData) {
+        self.coords.insert(id, coords);
+    }
+
+    /// Evaluate the collected coordinates, `truth` gives the shortest path latency between two ids.
+    ///
+    /// The pairs and triangles compared are drawn with `rng`.
+    pub fn evaluate<F, R>(&mut self, epoch: u64, model: &dyn NCModel, rng: &mut R, mut truth: F) -> Option<NCEvaluation>
+        where F: FnMut(u64, u64) -> Option<f32>, R: Rng {
+        let mut ids: Vec<u64> = self.coords.keys().cloned().collect();
+        if ids.len() < 3 {
+            return None
+        }
+        // the same draws pick the same nodes whatever the order of the map
+        ids.sort();
+        let mut errors = Vec::new();
+        let (mut rank_hits, mut rank_total) = (0, 0);
+        let (mut tiv_p, mut tiv_t, mut severity, mut triangles) = (0, 0, 0., 0);
+
+        for _ in 0..EVAL_SAMPLES {
+            let a = ids[rng.gen_range(0, ids.len())];
+            let b = ids[rng.gen_range(0, ids.len())];
+            let c = ids[rng.gen_range(0, ids.len())];
+            if a == b || b == c || a == c {
+                continue
+            }
+            let (ca, cb, cc) = (&self.coords[&a], &self.coords[&b], &self.coords[&c]);
+            let (pab, pbc, pac) = (model.predict(ca, cb), model.predict(cb, cc), model.predict(ca, cc));
+            let (tab, tbc, tac) = match (truth(a, b), truth(b, c), truth(a, c)) {
+                (Some(x), Some(y), Some(z)) => (x, y, z),
+                _ => continue
+            };
+
+            if tab > 0. {
+                errors.push((pab - tab).abs() / tab);
+            }
+
+            // from a, is b or c closer
+            if tab != tac {
+                rank_total += 1;
+                if (pab < pac) == (tab < tac) {
+                    rank_hits += 1;
+                }
+            }
+
+            triangles += 1;
+            let (v, s) = tiv(pab, pbc, pac);
+            if v {
+                tiv_p += 1;
+                severity += s;
+            }
+            if tiv(tab, tbc, tac).0 {
+                tiv_t += 1;
+            }
+        }
+
+        let eval = NCEvaluation {
+            epoch,
+            relative_error: Distribution::new(errors),
+            rank_accuracy: if rank_total > 0 { rank_hits as f32 / rank_total as f32 } else { 0. },
+            tiv_predicted: if triangles > 0 { tiv_p as f32 / triangles as f32 } else { 0. },
+            tiv_severity_predicted: if tiv_p > 0 { severity / tiv_p as f32 } else { 0. },
+            tiv_truth: if triangles > 0 { tiv_t as f32 / triangles as f32 } else { 0. },
+        };
+        self.history.push(eval.clone());
+        self.coords.clear();
+        Some(eval)
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = NCEvaluation::csv_header();
+        for e in &self.history {
+            out.push_str(&e.to_csv());
+        }
+        out
+    }
+}
+
+// sent by the world to the nodes sampled for evaluation
+pub struct ReportCoordinates;
+
+message!(ReportCoordinates);
+
+impl Handler<ReportCoordinates> for Node {
+    fn handle(&mut self, _msg: ReportCoordinates, _ctx: &mut Context<Self>) -> Response<Self, ReportCoordinates> {
+        // the accuracy of interest is that of the honest nodes
+        if self.malicious.is_none() {
+            self.world.send(world::CoordinateReport { id: self.id, coords: self.nc.clone() });
+        }
+        Self::reply(())
+    }
+}
@@ -2,13 +2,17 @@ use actix::*;
 use petgraph::graph::NodeIndex;
 use connection::Connection;
 
-use rand::{thread_rng, Rng};
+use rand::Rng;
 
 use std::collections::HashMap;
 use std::marker::Send;
 use std::fmt::Debug;
 
-use futures::Future;
+use futures::Poll;
+use futures::unsync::oneshot::Canceled;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 
 use world;
 use nc;
@@ -18,6 +22,61 @@ use packet::PacketData;
 use std::clone::Clone;
 
 use dht::service::*;
+use baseline::{self, RoutingProtocol};
+use link::{LinkQueue, QueueConfig, KindStats};
+use address::{Address, AddressScheme};
+use onion::OnionConfig;
+use advert::{AdvertConfig, LinkMetric};
+use keys::KeyPair;
+use ledger::{Accounts, BillingConfig};
+use adversary::{self, AdversaryConfig, Attack, Defence, Witnesses};
+use rng::SimRng;
+use codec::Forwarder;
+use trace::Event;
+
+/// Settings shared by every node in a run.
+#[derive(Clone, Debug, Default)]
+pub struct NodeConfig {
+    // every node derives its random stream from this
+    pub seed: u64,
+    pub baseline: Option<baseline::Protocol>,
+    pub nc_model: nc::ModelKind,
+    pub nc: nc::NCParams,
+    pub dht: DHTParams,
+    pub queue: QueueConfig,
+    pub adversary: Option<AdversaryConfig>,
+    pub defence: Defence,
+    pub addressing: AddressScheme,
+    // send traffic over private routes
+    pub private_routes: Option<OnionConfig>,
+    // advertise link metrics and keep reputations of the advertisers
+    pub adverts: Option<AdvertConfig>,
+    // charge for forwarding and settle with the ledger service
+    pub billing: Option<BillingConfig>,
+}
+
+/// Future of a packet sent with `Node::send_packet`, resolves immediately if the first link dropped it.
+pub type PacketFuture<T> = fut::Either<Request<Node, Packet<T>>,
+                                       fut::FutureResult<Result<<T as ResponseType>::Item, <T as ResponseType>::Error>, Canceled, Node>>;
+
+/// Future run by a node whose concrete type depends on the path taken, e.g. the next step of a lookup.
+pub struct NodeFuture<T>(Box<dyn ActorFuture<Item=T, Error=(), Actor=Node>>);
+
+impl<T> NodeFuture<T> {
+    pub fn new<F: ActorFuture<Item=T, Error=(), Actor=Node> + 'static>(f: F) -> NodeFuture<T> {
+        NodeFuture(Box::new(f))
+    }
+}
+
+impl<T> ActorFuture for NodeFuture<T> {
+    type Item = T;
+    type Error = ();
+    type Actor = Node;
+
+    fn poll(&mut self, srv: &mut Node, ctx: &mut Context<Node>) -> Poll<T, ()> {
+        self.0.poll(srv, ctx)
+    }
+}
 
 pub struct Node {
     // going to be bigger in the future
@@ -27,32 +86,170 @@ pub struct Node {
     pub neighbours: Vec<NeighbourData>,
     pub neighbours_map: HashMap<u64, usize>,
     pub nc: nc::NCNodeData,
+    pub nc_model: Box<dyn nc::NCModel>,
+    // running relative error of our latency predictions
+    pub nc_error: f32,
+    // last (predicted, measured) latency and (correct, total) pairwise neighbour rankings
+    pub nc_last: Option<(f32, f32)>,
+    pub nc_rank: (u64, u64),
+    // set if this node lies about its coordinate
+    pub malicious: Option<Attack>,
+    pub nc_reputation: HashMap<u64, f32>,
+    pub nc_witnesses: Witnesses,
+    // neighbour samples discarded by the defence
+    pub nc_rejected: u64,
     pub dht: DHT,
     pub dht_init: bool,
+    // simulated time and version of our last coordinate publication
+    pub published: Option<f32>,
+    pub publish_version: u64,
+    pub addresses: Vec<Address>,
+    // last offer of every upstream, and the rounds since it answered
+    pub address_offers: HashMap<u64, (Vec<Address>, u32)>,
+    // labels delegated to our downstream nodes
+    pub address_children: HashMap<u64, u16>,
+    pub address_version: u64,
+    pub address_round: Option<f32>,
+    pub readdressed: u64,
+    // coordinate and address lookups made, and those which failed
+    pub lookups: u64,
+    pub lookup_failures: u64,
+    // advertises better links than it has
+    pub metric_liar: bool,
+    pub adverts: HashMap<u64, Vec<LinkMetric>>,
+    pub metric_reputation: HashMap<u64, f32>,
+    pub advert_round: Option<f32>,
+    pub accounts: Accounts,
+    pub keys: KeyPair,
+    // public keys of other nodes found in the DHT
+    pub known_keys: HashMap<u64, u64>,
+    // hierarchy level in the world graph
+    pub level: usize,
+    pub baseline: Option<Box<dyn RoutingProtocol>>,
+    pub baseline_messages: u64,
+    pub baseline_failures: u64,
+    pub config: NodeConfig,
+    // simulated time of the last tick
+    pub now: f32,
+    pub link_stats: HashMap<&'static str, KindStats>,
+    pub rng: SimRng,
+    // packets and lookups given a trace ID so far
+    pub trace_seq: u64,
 }
 
 impl Node {
-    pub fn new(graph_index: NodeIndex) -> Node {
-        let id = thread_rng().next_u64();
+    pub fn new(graph_index: NodeIndex, level: usize, config: NodeConfig, world: SyncAddress<world::World>) -> Node {
+        let mut rng = SimRng::derive(config.seed, graph_index.index() as u64);
+        let id = rng.next_u64();
         Node {
-            world: Arbiter::system_registry().get::<world::World>(),
+            world,
             id,
             neighbours: Vec::new(),
             graph_index,
             neighbours_map: HashMap::new(),
-            nc: nc::NCNodeData::new(),
-            dht: DHT::new(id),
+            nc: nc::NCNodeData::new(&config.nc, &mut rng),
+            nc_model: config.nc_model.create(&config.nc),
+            nc_error: 1.,
+            nc_last: None,
+            nc_rank: (0, 0),
+            malicious: adversary::choose(&mut rng, &config.adversary),
+            nc_reputation: HashMap::new(),
+            nc_witnesses: Witnesses::new(),
+            nc_rejected: 0,
+            dht: DHT::with_params(id, config.dht),
             dht_init: false,
+            published: None,
+            publish_version: 0,
+            addresses: Vec::new(),
+            address_offers: HashMap::new(),
+            address_children: HashMap::new(),
+            address_version: 0,
+            address_round: None,
+            readdressed: 0,
+            lookups: 0,
+            lookup_failures: 0,
+            metric_liar: config.adverts.is_some_and(|a| rng.next_f32() < a.liars),
+            adverts: HashMap::new(),
+            metric_reputation: HashMap::new(),
+            advert_round: None,
+            accounts: Accounts::default(),
+            keys: KeyPair::of(config.seed, graph_index.index()),
+            known_keys: HashMap::new(),
+            level,
+            baseline: config.baseline.map(|b| b.create(&mut rng)),
+            baseline_messages: 0,
+            baseline_failures: 0,
+            config,
+            now: 0.,
+            link_stats: HashMap::new(),
+            rng,
+            trace_seq: 0,
         }
     }
 
-    pub fn fwd<T: PacketData + Clone + Send + ResponseType + 'static + Debug>(&self, msg: Packet<T>) -> Response<Self, Packet<T>>
-        where T::Item: Send, T::Error: Send {
+    /// ID of the node at `graph_index`, the first draw of its stream.
+    pub fn id_of(seed: u64, graph_index: NodeIndex) -> u64 {
+        SimRng::derive(seed, graph_index.index() as u64).next_u64()
+    }
+
+    /// Trace event of this node at the current time.
+    pub fn event(&self, kind: &str) -> Event {
+        let epoch = (self.now / self.config.queue.epoch_length).round() as u64;
+        Event::new(kind, self.id, epoch, self.now)
+    }
+
+    /// A new trace ID, the graph index above a sequence number of the node, so no two are the same.
+    pub fn next_trace(&mut self) -> u64 {
+        self.trace_seq += 1;
+        ((self.graph_index.index() as u64) << 40) | self.trace_seq
+    }
+
+    // transmit, tracing when the packet reached this node and when it left
+    fn transmit_traced<T: PacketData + Clone + Send + ResponseType>(&mut self, index: usize, msg: &mut Packet<T>) -> bool {
+        let arrived = msg.sent_at + msg.elapsed;
+        let next = self.neighbours[index].id;
+        if !self.transmit(index, msg) {
+            trace!(Forward, Info, self.event("dropped").at(arrived).trace(msg.trace).packet(T::kind()).route(&msg.hops)
+                .detail(format!("towards {}", next)));
+            return false
+        }
+        // the packet leaves once it is through the queue, and arrives a latency later
+        let departed = msg.sent_at + msg.elapsed - self.neighbours[index].connection.latency;
+        trace!(Forward, Debug, self.event("hop").at(arrived).departed(departed).trace(msg.trace).packet(T::kind()).route(&msg.hops)
+            .detail(format!("towards {}", next)));
+        true
+    }
+
+    // index of the neighbour the packet goes to next, None if the route does not lead on from here
+    fn next_hop<T: PacketData + Clone + Send + ResponseType>(&self, msg: &mut Packet<T>) -> Option<usize> {
+        let next = msg.route.pop();
+        let index = next.and_then(|next| self.neighbours_map.get(&next).cloned());
+        if index.is_none() {
+            let reason = if next.is_some() { "no link to the next hop" } else { "no hop left on the route" };
+            debug!("{} dropped a {} packet from {}: {}", self.id, T::kind(), msg.from, reason);
+            trace!(Forward, Info, self.event("dropped").at(msg.sent_at + msg.elapsed).trace(msg.trace).packet(T::kind())
+                .route(&msg.hops).detail(String::from(reason)));
+        }
+        index
+    }
+
+    pub fn fwd<T>(&mut self, msg: Packet<T>) -> Response<Self, Packet<T>>
+        where T: PacketData + Clone + Send + ResponseType + 'static + Debug + Serialize + DeserializeOwned, T::Item: Send + Serialize + DeserializeOwned, T::Error: Send + Default + Serialize + DeserializeOwned {
         let mut msg = msg.clone();
-        let next = msg.route.pop().expect(&format!("ID: {}, error while forwarding {:?}, nmap: {:?}", self.id, msg, self.neighbours_map));
-        let index = self.neighbours_map[&next];
+        if let Some(&prev) = msg.hops.last() {
+            self.bill_forwarded(prev, msg.wire_size());
+        }
+        msg.hops.push(self.id);
+        // a route through a link that is not there, e.g. one a liar advertised, ends here
+        let index = match self.next_hop(&mut msg) {
+            Some(index) => index,
+            None => return Node::reply_error(T::Error::default())
+        };
+        if !self.transmit_traced(index, &mut msg) {
+            return Node::reply_error(T::Error::default())
+        }
         let f = self.neighbours[index].address.call(self, msg);
-        Node::async_reply(ActorFuture::then(f, |item, actor, ctx| {
+        Node::async_reply(ActorFuture::then(f, |item, _actor, _ctx| {
             match item.unwrap() {
                 Ok(s) => fut::ok::<T::Item, T::Error, Node>(s),
                 Err(e) => fut::err::<T::Item, T::Error, Node>(e)
@@ -61,34 +258,92 @@ impl Node {
 
     }
 
-    pub fn send_packet<T: PacketData + Clone + Send + ResponseType + 'static + Debug>(&self, msg: Packet<T>) -> Request<Node, Packet<T>>
-        where T::Item: Send, T::Error: Send {
+    /// Turn a route found by the DHT (destination first) into one usable from this node.
+    pub fn source_route(&self, des: u64, route: Vec<u64>) -> Option<Vec<u64>> {
+        let mut forward = route;
+        forward.reverse();
+        let mut full = vec![self.id];
+        full.extend(forward.into_iter().skip_while(|h| *h == self.id));
+        let mut r = remove_loops(full);
+        r.remove(0);
+        if r.is_empty() || r.last() != Some(&des) || !self.neighbours_map.contains_key(&r[0]) {
+            return None
+        }
+        r.reverse();
+        self.trusted_first_hop(&mut r);
+        Some(r)
+    }
+
+    pub fn send_packet<T>(&mut self, msg: Packet<T>) -> PacketFuture<T>
+        where T: PacketData + Clone + Send + ResponseType + 'static + Debug + Serialize + DeserializeOwned, T::Item: Send + Serialize + DeserializeOwned, T::Error: Send + Default + Serialize + DeserializeOwned {
         let mut m = msg.clone();
-        let next = m.route.pop().expect(&format!("ID: {}, error while sending {:?}, nmap: {:?}", self.id, m, self.neighbours_map));
-        let index = self.neighbours_map.get(&next).expect("Key not in neighbour map");
-        self.neighbours[*index].address.call(self, m)
+        m.sent_at = self.now;
+        m.elapsed = 0.;
+        if m.trace == 0 {
+            m.trace = self.next_trace();
+        }
+        self.relay_packet(m)
+    }
+
+    /// Like `send_packet`, but keeps the timing of a packet continuing an earlier journey.
+    pub fn relay_packet<T>(&mut self, msg: Packet<T>) -> PacketFuture<T>
+        where T: PacketData + Clone + Send + ResponseType + 'static + Debug + Serialize + DeserializeOwned, T::Item: Send + Serialize + DeserializeOwned, T::Error: Send + Default + Serialize + DeserializeOwned {
+        let mut m = msg;
+        m.hops.push(self.id);
+        let index = match self.next_hop(&mut m) {
+            Some(index) => index,
+            None => return fut::Either::B(fut::ok(Err(T::Error::default())))
+        };
+        if !self.transmit_traced(index, &mut m) {
+            return fut::Either::B(fut::ok(Err(T::Error::default())))
+        }
+        fut::Either::A(self.neighbours[index].address.call(self, m))
     }
 }
 
 
+/// Where packets for a neighbour are handed, the node itself or the forwarder to the process running it.
+#[derive(Clone)]
+pub enum Pipe {
+    Node(SyncAddress<Node>),
+    Forwarder(SyncAddress<Forwarder>),
+}
+
+impl Pipe {
+    pub fn call<T>(&self, node: &Node, msg: Packet<T>) -> Request<Node, Packet<T>>
+        where T: PacketData + Clone + Send + ResponseType + 'static + Debug + Serialize + DeserializeOwned,
+              T::Item: Send + Serialize + DeserializeOwned, T::Error: Send + Default + Serialize + DeserializeOwned {
+        match *self {
+            Pipe::Node(ref a) => a.call(node, msg),
+            Pipe::Forwarder(ref a) => a.call(node, msg),
+        }
+    }
+
+    /// Introduce a neighbour, forwarders keep no neighbours and ignore it.
+    pub fn hello(&self, msg: HelloNode) {
+        if let Pipe::Node(ref a) = *self {
+            a.send(msg)
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct NeighbourData {
     pub id: u64,
+    pub level: usize,
     pub connection: Connection,
-    pub address: SyncAddress<Node>,
-}
-
-struct Quality {
-    latency: f32,
-    bandwidth: f32,
-    packet_loss: f32,
+    pub address: Pipe,
+    // queue of the link towards this neighbour
+    pub queue: LinkQueue,
+    // taken down by a scenario, everything sent over it is lost
+    pub failed: bool,
 }
 
 impl Actor for Node {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.world.send(world::HelloWorld { addr: ctx.address(), graph_index: self.graph_index, id: self.id });
+        self.world.send(world::HelloWorld { pipe: Pipe::Node(ctx.address()), graph_index: self.graph_index, id: self.id });
 
         // println!("Node started {:?}", self.graph_index);
         // Arbiter::system().send(msgs::SystemExit(0));
@@ -98,62 +353,82 @@ impl Actor for Node {
 // out of band messages
 
 // sent by node to another node to notify its presence
-#[derive(Message)]
 pub struct HelloNode {
     pub id: u64,
-    pub pipe: SyncAddress<Node>,
+    pub pipe: Pipe,
+    // link from the receiver to the sender
     pub connection: Connection,
+    // link the other way, for the reply
+    pub back: Connection,
+    pub level: usize,
     // if this is a reply
     pub reply: bool,
 }
 
+message!(HelloNode);
+
 impl Handler<HelloNode> for Node {
     fn handle(&mut self, msg: HelloNode, ctx: &mut Context<Self>) -> Response<Self, HelloNode> {
         if !self.neighbours_map.contains_key(&msg.id) {
             self.neighbours_map.insert(msg.id, self.neighbours.len());
-            self.neighbours.push(NeighbourData { address: msg.pipe.clone(), connection: msg.connection.clone(), id: msg.id });
+            self.neighbours.push(NeighbourData {
+                address: msg.pipe.clone(),
+                queue: LinkQueue::new(&msg.connection, &self.config.queue),
+                connection: msg.connection.clone(),
+                id: msg.id,
+                level: msg.level,
+                failed: false,
+            });
             // only send back message if message it did not originate to prevent loops
             if !msg.reply {
-                msg.pipe.send(HelloNode { pipe: ctx.address(), reply: true, id: self.id, ..msg })
+                msg.pipe.hello(HelloNode { pipe: Pipe::Node(ctx.address()), reply: true, id: self.id, level: self.level,
+                    connection: msg.back, back: msg.connection })
             }
         }
         Self::reply(())
     }
 }
 
-#[derive(Message)]
-pub struct Tick;
+pub struct Tick {
+    // simulated time
+    pub time: f32,
+}
+
+message!(Tick);
 
 impl Handler<Tick> for Node {
-    fn handle(&mut self, _msg: Tick, ctx: &mut Context<Self>) -> Response<Self, Tick> {
+    fn handle(&mut self, msg: Tick, ctx: &mut Context<Self>) -> Response<Self, Tick> {
+        self.now = msg.time;
         self.dht_tick(ctx);
+        self.dht_publish(ctx);
+        self.address_tick(ctx);
+        self.advert_tick(ctx);
+        self.settle_tick(ctx);
+        self.nc_tick(ctx);
+        self.baseline_tick();
 
         Self::reply(())
     }
 }
 
 // in band messages
-impl<T: PacketData + Clone + Send + ResponseType + 'static + Debug> Handler<Packet<T>> for Node where <T as ResponseType>::Item: Send, <T as ResponseType>::Error: Send {
-    fn handle(&mut self, msg: Packet<T>, ctx: &mut Context<Self>) -> Response<Self, Packet<T>> {
-        if thread_rng().next_f32() < 0.01 {
+impl<T: PacketData + Clone + Send + ResponseType + 'static + Debug> Handler<Packet<T>> for Node
+    where T: Serialize + DeserializeOwned, T::Item: Send + Serialize + DeserializeOwned, T::Error: Send + Default + Serialize + DeserializeOwned {
+    fn handle(&mut self, mut msg: Packet<T>, _ctx: &mut Context<Self>) -> Response<Self, Packet<T>> {
+        if self.rng.next_f32() < 0.01 {
             self.world.send(world::Sent);
         }
         if msg.des == self.id {
             assert_eq!(msg.route.len(), 0);
-            let r = T::process(&msg, self);
-            r
+            msg.hops.push(self.id);
+            self.world.send(world::Delivered { kind: T::kind(), hops: msg.hops.clone(), elapsed: msg.elapsed });
+            trace!(Forward, Info, self.event("delivered").at(msg.sent_at + msg.elapsed).trace(msg.trace).packet(T::kind()).route(&msg.hops)
+                .detail(format!("after {:.4}s", msg.elapsed)));
+
+            T::process(&msg, self)
         } else {
-            if msg.route.len() == 0 {
-                println!("RiP {:?}, {}", msg, self.id);
-                assert!(msg.route.len() > 0);
-            }
+            // a packet with no route left is dropped by `fwd` like one without a link
             self.fwd(msg)
         }
     }
-}
-
-impl<T: SearchPacketData + Clone + Send + ResponseType> Handler<SearchPacket<T>> for Node {
-    fn handle(&mut self, msg: SearchPacket<T>, ctx: &mut Context<Self>) -> Response<Self, SearchPacket<T>> {
-        T::process(&msg, self)
-    }
 }
\ No newline at end of file
@@ -0,0 +1,787 @@
+//! Private routes.
+//!
+//! The sender wraps the source route in one encrypted layer per hop, so each
+//! hop only learns who it got the packet from and who to pass it to. Layers
+//! are sealed with ChaCha20 and Poly1305 keyed from the same stream. The key
+//! of a layer comes from Diffie-Hellman between a throwaway key pair, whose
+//! public half leads the layer, and the key pair of the hop, whose public key
+//! is published in the DHT. The flow, sequence number and reply block are
+//! sealed the same way for the destination alone.
+//!
+//! Headers are laid out as in Sphinx so they are the same length at every
+//! hop and for every path: a hop opens the layer key, a tag and a fixed
+//! block of routing information, reads its slot at the front and shifts the
+//! rest forward, and the key stream fills the end back in. The sender works
+//! out that fill ahead of time, so every later tag still holds.
+//!
+//! A receiver can stay hidden behind a rendezvous point: it publishes a reply
+//! block from the point back to itself in the DHT, and senders route to the
+//! point, which splices the block onto the packet.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::mem;
+
+use directory::{self, Record, Value};
+use dht::GenericNodeTable;
+use keys::KeyPair;
+use node::{Node, NodeFuture};
+use packet::*;
+use traffic::FlowEvent;
+use world;
+
+#[derive(Clone, Copy, Debug)]
+pub struct OnionConfig {
+    // receivers hide behind a rendezvous point
+    pub rendezvous: bool,
+    // senders attach a reply block and receivers acknowledge through it
+    pub reply_blocks: bool,
+}
+
+// simulated time to peel one layer
+static CRYPTO_DELAY: f32 = 5e-5;
+
+const PUBLIC: usize = 8;
+const NONCE: usize = 12;
+const TAG: usize = 8;
+// command byte and next hop
+const ROUTING: usize = 9;
+// what a hop reads of the routing information: its routing, then the key, nonce and tag of the next layer
+const SLOT: usize = ROUTING + PUBLIC + NONCE + TAG;
+/// Longest path a header has room for.
+pub const MAX_HOPS: usize = 20;
+const ROUTING_INFO: usize = MAX_HOPS * SLOT;
+/// Length of every header, whatever the path and hop.
+pub const HEADER: usize = PUBLIC + NONCE + TAG + ROUTING_INFO;
+
+const DELIVER: u8 = 0;
+const FORWARD: u8 = 1;
+const SPLICE: u8 = 2;
+
+fn read_u32(b: &[u8]) -> u32 {
+    b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+
+fn read_u64(b: &[u8]) -> u64 {
+    read_u32(b) as u64 | (read_u32(&b[4..]) as u64) << 32
+}
+
+fn write_u64(out: &mut Vec<u8>, x: u64) {
+    for i in 0..8 {
+        out.push((x >> (8 * i)) as u8);
+    }
+}
+
+fn quarter(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha_block(key: &[u8; 32], counter: u32, nonce: &[u8]) -> [u8; 64] {
+    let mut s = [0u32; 16];
+    s[0] = 0x6170_7865;
+    s[1] = 0x3320_646e;
+    s[2] = 0x7962_2d32;
+    s[3] = 0x6b20_6574;
+    for i in 0..8 {
+        s[4 + i] = read_u32(&key[4 * i..]);
+    }
+    s[12] = counter;
+    for i in 0..3 {
+        s[13 + i] = read_u32(&nonce[4 * i..]);
+    }
+    let mut w = s;
+    for _ in 0..10 {
+        quarter(&mut w, 0, 4, 8, 12);
+        quarter(&mut w, 1, 5, 9, 13);
+        quarter(&mut w, 2, 6, 10, 14);
+        quarter(&mut w, 3, 7, 11, 15);
+        quarter(&mut w, 0, 5, 10, 15);
+        quarter(&mut w, 1, 6, 11, 12);
+        quarter(&mut w, 2, 7, 8, 13);
+        quarter(&mut w, 3, 4, 9, 14);
+    }
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        let x = w[i].wrapping_add(s[i]);
+        for j in 0..4 {
+            out[4 * i + j] = (x >> (8 * j)) as u8;
+        }
+    }
+    out
+}
+
+/// XOR `data` with the ChaCha20 key stream, block 0 is kept for the MAC key.
+pub fn chacha20(key: &[u8; 32], nonce: &[u8], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let block = chacha_block(key, 1 + i as u32, nonce);
+        for (d, k) in chunk.iter_mut().zip(block.iter()) {
+            *d ^= *k;
+        }
+    }
+}
+
+// Poly1305 over 26 bit limbs, the full 16 byte tag
+fn poly1305(key: &[u8; 32], data: &[u8]) -> [u8; 16] {
+    const M: u32 = 0x3ff_ffff;
+    let r = [
+        read_u32(key) & M,
+        (read_u32(&key[3..]) >> 2) & 0x3ff_ff03,
+        (read_u32(&key[6..]) >> 4) & 0x3ff_c0ff,
+        (read_u32(&key[9..]) >> 6) & 0x3f0_3fff,
+        (read_u32(&key[12..]) >> 8) & 0x00f_ffff,
+    ];
+    let r: Vec<u64> = r.iter().map(|&x| x as u64).collect();
+    let s: Vec<u64> = r[1..].iter().map(|&x| x * 5).collect();
+    let mut h = [0u32; 5];
+    for chunk in data.chunks(16) {
+        // a full block gets the 2^128 bit, a short one a 1 byte after it
+        let mut block = [0u8; 17];
+        block[..chunk.len()].copy_from_slice(chunk);
+        block[chunk.len()] = 1;
+        h[0] += read_u32(&block) & M;
+        h[1] += (read_u32(&block[3..]) >> 2) & M;
+        h[2] += (read_u32(&block[6..]) >> 4) & M;
+        h[3] += (read_u32(&block[9..]) >> 6) & M;
+        h[4] += (read_u32(&block[12..]) >> 8) | (block[16] as u32) << 24;
+        let x: Vec<u64> = h.iter().map(|&x| x as u64).collect();
+        let mut d = [
+            x[0] * r[0] + x[1] * s[3] + x[2] * s[2] + x[3] * s[1] + x[4] * s[0],
+            x[0] * r[1] + x[1] * r[0] + x[2] * s[3] + x[3] * s[2] + x[4] * s[1],
+            x[0] * r[2] + x[1] * r[1] + x[2] * r[0] + x[3] * s[3] + x[4] * s[2],
+            x[0] * r[3] + x[1] * r[2] + x[2] * r[1] + x[3] * r[0] + x[4] * s[3],
+            x[0] * r[4] + x[1] * r[3] + x[2] * r[2] + x[3] * r[1] + x[4] * r[0],
+        ];
+        for i in 0..4 {
+            d[i + 1] += d[i] >> 26;
+            h[i] = d[i] as u32 & M;
+        }
+        h[4] = d[4] as u32 & M;
+        h[0] += (d[4] >> 26) as u32 * 5;
+        h[1] += h[0] >> 26;
+        h[0] &= M;
+    }
+    for i in 1..5 {
+        h[(i + 1) % 5] += (h[i] >> 26) * if i == 4 { 5 } else { 1 };
+        h[i] &= M;
+    }
+    h[1] += h[0] >> 26;
+    h[0] &= M;
+    // h - p, kept if it did not go below zero
+    let mut g = [0u32; 5];
+    let mut c = 5;
+    for i in 0..5 {
+        g[i] = h[i] + c;
+        c = g[i] >> 26;
+        g[i] &= M;
+    }
+    if c == 1 {
+        h = g;
+    }
+    let words = [
+        h[0] | h[1] << 26,
+        h[1] >> 6 | h[2] << 20,
+        h[2] >> 12 | h[3] << 14,
+        h[3] >> 18 | h[4] << 8,
+    ];
+    let mut tag = [0u8; 16];
+    let mut carry = 0u64;
+    for i in 0..4 {
+        carry += words[i] as u64 + read_u32(&key[16 + 4 * i..]) as u64;
+        for j in 0..4 {
+            tag[4 * i + j] = (carry >> (8 * j)) as u8;
+        }
+        carry >>= 32;
+    }
+    tag
+}
+
+/// Tag of `data`, Poly1305 keyed from block 0 of the stream as in RFC 8439, cut to 8 bytes.
+pub fn mac(key: &[u8; 32], nonce: &[u8], data: &[u8]) -> u64 {
+    let block = chacha_block(key, 0, nonce);
+    let mut poly_key = [0u8; 32];
+    poly_key.copy_from_slice(&block[..32]);
+    read_u64(&poly1305(&poly_key, data))
+}
+
+/// Seal `plain` for the owner of `public`: a throwaway public key, then the layer sealed with the key it shares.
+pub fn seal_for<R: Rng>(public: u64, plain: Vec<u8>, rng: &mut R) -> Vec<u8> {
+    let ephemeral = KeyPair::ephemeral(rng);
+    let mut out = Vec::new();
+    write_u64(&mut out, ephemeral.public);
+    out.extend(seal(&ephemeral.shared(public), plain, rng));
+    out
+}
+
+/// Open what `seal_for` sealed for us.
+pub fn open_with(keys: &KeyPair, sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < PUBLIC {
+        return None
+    }
+    open(&keys.shared(read_u64(sealed)), &sealed[PUBLIC..])
+}
+
+/// Encrypt one layer: nonce, tag, then the ciphertext, the nonce is drawn from `rng`.
+pub fn seal<R: Rng>(key: &[u8; 32], plain: Vec<u8>, rng: &mut R) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE];
+    rng.fill_bytes(&mut nonce);
+    let mut body = plain;
+    chacha20(key, &nonce, &mut body);
+    let mut out = nonce.to_vec();
+    write_u64(&mut out, mac(key, &nonce, &body));
+    out.extend(body);
+    out
+}
+
+/// Decrypt one layer, `None` if it was not sealed with `key`.
+pub fn open(key: &[u8; 32], layer: &[u8]) -> Option<Vec<u8>> {
+    if layer.len() < NONCE + TAG {
+        return None
+    }
+    let (nonce, rest) = layer.split_at(NONCE);
+    let (tag, body) = rest.split_at(TAG);
+    if mac(key, nonce, body) != read_u64(tag) {
+        return None
+    }
+    let mut plain = body.to_vec();
+    chacha20(key, nonce, &mut plain);
+    Some(plain)
+}
+
+fn layer(cmd: u8, next: u64) -> Vec<u8> {
+    let mut plain = vec![cmd];
+    write_u64(&mut plain, next);
+    plain
+}
+
+// the first `len` bytes of the key stream
+fn stream(key: &[u8; 32], nonce: &[u8], len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    chacha20(key, nonce, &mut out);
+    out
+}
+
+fn xor(data: &mut [u8], stream: &[u8]) {
+    for (d, k) in data.iter_mut().zip(stream.iter()) {
+        *d ^= *k;
+    }
+}
+
+/// Wrap a path given in travel order, the last hop gets `last` (a command and next hop).
+///
+/// `keys` holds the public key of every hop, `None` if one is missing or the path is longer than `MAX_HOPS`.
+pub fn build<R: Rng>(path: &[u64], last: (u8, u64), keys: &HashMap<u64, u64>, rng: &mut R) -> Option<Vec<u8>> {
+    let n = path.len();
+    if n == 0 || n > MAX_HOPS {
+        return None
+    }
+    // a throwaway public key, the key it shares with the hop and a nonce, per layer
+    let mut layers = Vec::new();
+    for id in path {
+        let ephemeral = KeyPair::ephemeral(rng);
+        let mut nonce = [0u8; NONCE];
+        rng.fill_bytes(&mut nonce);
+        layers.push((ephemeral.public, ephemeral.shared(*keys.get(id)?), nonce));
+    }
+    // the end of the routing information as the last hop gets it, filled in by the streams of the hops before
+    let mut filler = Vec::new();
+    for (_, key, nonce) in &layers[..n - 1] {
+        filler.extend_from_slice(&[0u8; SLOT]);
+        let s = stream(key, nonce, ROUTING_INFO + SLOT);
+        let start = s.len() - filler.len();
+        xor(&mut filler, &s[start..]);
+    }
+    let (_, ref key, ref nonce) = layers[n - 1];
+    let mut info = layer(last.0, last.1);
+    info.resize(ROUTING_INFO - filler.len(), 0);
+    rng.fill_bytes(&mut info[ROUTING..]);
+    let s = stream(key, nonce, info.len());
+    xor(&mut info, &s);
+    info.extend(filler);
+    let mut tag = mac(key, nonce, &info);
+    // each hop before gets the next hop and layer at the front of the rest
+    for i in (0..n - 1).rev() {
+        let (public, _, nonce) = layers[i + 1];
+        let mut plain = layer(FORWARD, path[i + 1]);
+        write_u64(&mut plain, public);
+        plain.extend_from_slice(&nonce);
+        write_u64(&mut plain, tag);
+        plain.extend_from_slice(&info[..ROUTING_INFO - SLOT]);
+        let (_, ref key, ref nonce) = layers[i];
+        xor(&mut plain, &stream(key, nonce, ROUTING_INFO));
+        tag = mac(key, nonce, &plain);
+        info = plain;
+    }
+    let (public, _, nonce) = layers[0];
+    let mut header = Vec::with_capacity(HEADER);
+    write_u64(&mut header, public);
+    header.extend_from_slice(&nonce);
+    write_u64(&mut header, tag);
+    header.extend(info);
+    Some(header)
+}
+
+/// Open our layer of a header: the command, then the next hop and header from `ROUTING` on.
+pub fn peel(keys: &KeyPair, header: &[u8]) -> Option<Vec<u8>> {
+    if header.len() != HEADER {
+        return None
+    }
+    let key = keys.shared(read_u64(header));
+    let (nonce, rest) = header[PUBLIC..].split_at(NONCE);
+    let (tag, info) = rest.split_at(TAG);
+    if mac(&key, nonce, info) != read_u64(tag) {
+        return None
+    }
+    // shifting our slot out leaves room for as much of the stream
+    let mut plain = info.to_vec();
+    plain.extend_from_slice(&[0u8; SLOT]);
+    chacha20(&key, nonce, &mut plain);
+    Some(plain)
+}
+
+/// Header leading back to its creator, usable without knowing the path.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplyBlock {
+    pub first_hop: u64,
+    pub header: Vec<u8>,
+}
+
+impl ReplyBlock {
+    /// Block for the path in travel order, ending at its creator.
+    pub fn new<R: Rng>(path: &[u64], keys: &HashMap<u64, u64>, rng: &mut R) -> Option<ReplyBlock> {
+        Some(ReplyBlock { first_hop: path[0], header: build(path, (DELIVER, 0), keys, rng)? })
+    }
+
+    /// Encoded size in bytes, the first hop and the header.
+    pub fn wire_size(&self) -> usize {
+        mem::size_of::<u64>() + self.header.len()
+    }
+}
+
+// what only the destination reads, sealed for it apart from the header
+struct Payload {
+    flow: u64,
+    seq: u32,
+    reply: Option<ReplyBlock>,
+}
+
+impl Payload {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_u64(&mut out, self.flow);
+        out.extend((0..4).map(|i| (self.seq >> (8 * i)) as u8));
+        if let Some(ref block) = self.reply {
+            write_u64(&mut out, block.first_hop);
+            out.extend_from_slice(&block.header);
+        }
+        out
+    }
+
+    fn decode(b: &[u8]) -> Option<Payload> {
+        if b.len() < 12 {
+            return None
+        }
+        let reply = if b.len() > 20 {
+            Some(ReplyBlock { first_hop: read_u64(&b[12..]), header: b[20..].to_vec() })
+        } else {
+            None
+        };
+        Some(Payload { flow: read_u64(b), seq: read_u32(&b[8..]), reply })
+    }
+}
+
+/// DHT key of the rendezvous block of `id`.
+pub fn rendezvous_key(id: u64) -> u64 {
+    id.rotate_left(16) ^ 0xc2b2_ae3d_27d4_eb4f
+}
+
+/// DHT key of the public key of `id`.
+pub fn key_record(id: u64) -> u64 {
+    id.rotate_left(32) ^ 0x1656_67b1_9e37_79f9
+}
+
+// path in travel order from a route as used by `Packet`, which is popped from the end
+fn travel(route: &[u64]) -> Vec<u64> {
+    route.iter().rev().cloned().collect()
+}
+
+impl Node {
+    /// Publish our public key, senders need it to seal layers for us.
+    pub fn publish_key(&mut self, ctx: &mut Context<Self>) {
+        let record = Record {
+            owner: self.id,
+            version: self.publish_version,
+            expires: self.now + directory::RECORD_TTL,
+            value: Value::PublicKey(self.keys.public),
+        };
+        let key = key_record(self.id);
+        self.dht_store(key, record, ctx);
+    }
+
+    /// Public keys of `hops`, looked up in the DHT unless already known.
+    fn public_keys(&mut self, hops: Vec<u64>) -> NodeFuture<HashMap<u64, u64>> {
+        let missing = hops.iter().cloned().find(|h| *h != self.id && !self.known_keys.contains_key(h));
+        let id = match missing {
+            Some(id) => id,
+            None => {
+                let (me, public) = (self.id, self.keys.public);
+                let keys = hops.iter().map(|&h| (h, if h == me { public } else { self.known_keys[&h] })).collect();
+                return NodeFuture::new(fut::ok(keys))
+            }
+        };
+        let key = key_record(id);
+        NodeFuture::new(self.dht_find_value(key).then(move |res, actor, _ctx| {
+            match res {
+                Ok(Record { owner, value: Value::PublicKey(public), .. }) if owner == id => {
+                    actor.known_keys.insert(id, public);
+                    actor.public_keys(hops)
+                }
+                _ => NodeFuture::new(fut::err(()))
+            }
+        }))
+    }
+
+    /// Publish a reply block from a rendezvous point back to us.
+    pub fn publish_rendezvous(&mut self, ctx: &mut Context<Self>) {
+        let key = rendezvous_key(self.id);
+        let point = match self.dht.table().find(&key, 1).into_iter().next() {
+            Some(p) => p,
+            None => return
+        };
+        let route = match self.source_route(point.id, point.route.clone()) {
+            Some(r) => r,
+            None => return
+        };
+        // back along our route to the point, then us
+        let mut back: Vec<u64> = travel(&route);
+        back.pop();
+        back.reverse();
+        back.push(self.id);
+        let version = self.publish_version;
+        ctx.spawn(self.public_keys(back.clone()).then(move |res, actor, ctx| {
+            if let Some(block) = res.ok().and_then(|keys| ReplyBlock::new(&back, &keys, &mut actor.rng)) {
+                let record = Record {
+                    owner: actor.id,
+                    version,
+                    expires: actor.now + directory::RECORD_TTL,
+                    value: Value::Rendezvous { point: point.id, block },
+                };
+                actor.dht_store(key, record, ctx);
+            }
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+
+    /// Send a flow to `des` over a private route.
+    pub fn send_private(&mut self, ctx: &mut Context<Self>, flow: u64, des: u64, packets: usize, size: u32) {
+        let rendezvous = self.config.private_routes.is_some_and(|c| c.rendezvous);
+        let sent = if !rendezvous {
+            self.send_through(flow, des, des, (DELIVER, 0, Vec::new()), packets, size)
+        } else {
+            let key = rendezvous_key(des);
+            NodeFuture::new(self.dht_find_value(key).then(move |res, actor, _ctx| {
+                match res {
+                    Ok(Record { value: Value::Rendezvous { point, block }, .. }) =>
+                        actor.send_through(flow, des, point, (SPLICE, block.first_hop, block.header), packets, size),
+                    _ => NodeFuture::new(fut::err(()))
+                }
+            }))
+        };
+        ctx.spawn(sent.then(move |res, actor, _ctx| {
+            if res.is_err() {
+                actor.world.send(world::FlowReport { flow, event: FlowEvent::Unresolved });
+            }
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+
+    // route to `to`, the destination or its rendezvous point, and send the flow through it with `last` for the final layer
+    fn send_through(&mut self, flow: u64, des: u64, to: u64, last: (u8, u64, Vec<u8>), packets: usize, size: u32) -> NodeFuture<()> {
+        NodeFuture::new(self.dht_lookup(to, None, false).then(move |res, actor, _ctx| {
+            let path = match res.ok().and_then(|n| actor.source_route(to, n.route)) {
+                Some(route) => travel(&route),
+                None => return NodeFuture::new(fut::err(()))
+            };
+            let mut hops = path.clone();
+            hops.push(des);
+            NodeFuture::new(actor.public_keys(hops).map(move |keys, actor, ctx| {
+                actor.world.send(world::FlowReport { flow, event: FlowEvent::Resolved });
+                for seq in 0..packets {
+                    let payload = Payload { flow, seq: seq as u32, reply: None };
+                    if let Some((header, payload)) = actor.seal_onion(payload, &path, des, last.clone(), &keys) {
+                        actor.send_onion(ctx, flow, path[0], Onion { header, payload, size });
+                    }
+                }
+            }))
+        }))
+    }
+
+    // the header for `path` and the payload sealed for `des`, with a reply block if we attach them
+    // and sealed again with the block to splice on for a rendezvous point
+    fn seal_onion(&mut self, mut payload: Payload, path: &[u64], des: u64, last: (u8, u64, Vec<u8>), keys: &HashMap<u64, u64>) -> Option<(Vec<u8>, Vec<u8>)> {
+        let reply_blocks = self.config.private_routes.is_some_and(|c| c.reply_blocks);
+        // replies need the path to the receiver, so rendezvous flows go without
+        if reply_blocks && last.0 == DELIVER {
+            let mut back: Vec<u64> = path[..path.len() - 1].iter().rev().cloned().collect();
+            back.push(self.id);
+            payload.reply = ReplyBlock::new(&back, keys, &mut self.rng);
+        }
+        let (cmd, next, block) = last;
+        let header = build(path, (cmd, next), keys, &mut self.rng)?;
+        self.world.send(world::OnionReport { layers: path.len(), header_bytes: header.len() });
+        let mut sealed = seal_for(*keys.get(&des)?, payload.encode(), &mut self.rng);
+        if cmd == SPLICE {
+            let mut plain = block;
+            plain.extend(sealed);
+            sealed = seal_for(*keys.get(&path[path.len() - 1])?, plain, &mut self.rng);
+        }
+        Some((header, sealed))
+    }
+
+    fn send_onion(&mut self, ctx: &mut Context<Self>, flow: u64, first_hop: u64, onion: Onion) {
+        let f = self.send_packet(Packet {
+            from: self.id,
+            des: first_hop,
+            route: vec![first_hop],
+            data: onion,
+            hops: Vec::new(),
+            sent_at: 0.,
+            elapsed: 0.,
+            trace: 0,
+        });
+        ctx.spawn(ActorFuture::then(f, move |res, actor: &mut Node, _ctx| {
+            let event = match res {
+                Ok(Ok(_)) => FlowEvent::Delivered,
+                _ => FlowEvent::Lost
+            };
+            actor.world.send(world::FlowReport { flow, event });
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+}
+
+/// A packet on a private route, re-sent hop by hop as layers are peeled.
+///
+/// Which nodes it went through is only measured, with the `hops` of the packet
+/// carried over from one hop to the next like the trace ID.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Onion {
+    pub header: Vec<u8>,
+    // sealed for the destination, empty for acknowledgements sent through a reply block
+    pub payload: Vec<u8>,
+    pub size: u32,
+}
+
+message!(Onion);
+
+impl PacketData for Onion {
+    fn kind() -> &'static str {
+        "onion"
+    }
+
+    fn size(&self) -> usize {
+        // the application data is only accounted for, not actually encrypted
+        self.header.len() + self.payload.len() + mem::size_of::<u32>() + self.size as usize
+    }
+
+    fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> {
+        let plain = match peel(&node.keys, &packet.data.header) {
+            Some(p) => p,
+            None => return Node::reply_error(())
+        };
+        let (cmd, next) = (plain[0], read_u64(&plain[1..]));
+        let mut data = packet.data.clone();
+        data.header = plain[ROUTING..].to_vec();
+        let elapsed = packet.elapsed + CRYPTO_DELAY;
+
+        if cmd == DELIVER {
+            let payload = if data.payload.is_empty() {
+                None
+            } else {
+                match open_with(&node.keys, &data.payload).and_then(|p| Payload::decode(&p)) {
+                    Some(p) => Some(p),
+                    None => return Node::reply_error(())
+                }
+            };
+            let kind = if payload.is_some() { "onion_data" } else { "onion_reply" };
+            node.world.send(world::Delivered { kind, hops: packet.hops.clone(), elapsed });
+            if let Some(block) = payload.and_then(|p| p.reply) {
+                if node.neighbours_map.contains_key(&block.first_hop) {
+                    // fire and forget, the sender learns about delivery from the forward path
+                    let _ = node.send_packet(Packet {
+                        from: node.id,
+                        des: block.first_hop,
+                        route: vec![block.first_hop],
+                        data: Onion { header: block.header, payload: Vec::new(), size: 0 },
+                        hops: Vec::new(),
+                        sent_at: 0.,
+                        elapsed: 0.,
+                        trace: 0,
+                    });
+                }
+            }
+            return Node::reply(())
+        }
+        if (cmd != FORWARD && cmd != SPLICE) || !node.neighbours_map.contains_key(&next) {
+            return Node::reply_error(())
+        }
+        // the point takes the block to go on with out of the payload
+        if cmd == SPLICE {
+            match open_with(&node.keys, &data.payload) {
+                Some(ref p) if p.len() >= HEADER => {
+                    data.header = p[..HEADER].to_vec();
+                    data.payload = p[HEADER..].to_vec();
+                }
+                _ => return Node::reply_error(())
+            }
+        }
+        // relaying adds us again
+        let mut hops = packet.hops.clone();
+        hops.pop();
+        let f = node.relay_packet(Packet {
+            from: node.id,
+            des: next,
+            route: vec![next],
+            data,
+            hops,
+            sent_at: packet.sent_at,
+            elapsed,
+            trace: packet.trace,
+        });
+        Node::async_reply(ActorFuture::then(f, |res, _actor: &mut Node, _ctx| {
+            match res {
+                Ok(Ok(())) => fut::ok(()),
+                _ => fut::err(())
+            }
+        }))
+    }
+}
+
+/// Header sizes of private packets, kept by the world.
+#[derive(Clone, Debug, Default)]
+pub struct OnionStats {
+    pub packets: u64,
+    pub layers: u64,
+    pub header_bytes: u64,
+}
+
+impl OnionStats {
+    pub fn record(&mut self, layers: usize, header_bytes: usize) {
+        self.packets += 1;
+        self.layers += layers as u64;
+        self.header_bytes += header_bytes as u64;
+    }
+
+    pub fn mean_header(&self) -> f64 {
+        self.header_bytes as f64 / self.packets.max(1) as f64
+    }
+
+    pub fn bytes_per_layer(&self) -> f64 {
+        self.header_bytes as f64 / self.layers.max(1) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rng::SimRng;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    fn key(bytes: &[u8]) -> [u8; 32] {
+        let mut k = [0u8; 32];
+        k.copy_from_slice(bytes);
+        k
+    }
+
+    // RFC 8439 2.4.2
+    #[test]
+    fn chacha20_encrypts_the_rfc_vector() {
+        let k = key(&(0..32).collect::<Vec<u8>>());
+        let nonce = hex("00 00 00 00 00 00 00 4a 00 00 00 00");
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        chacha20(&k, &nonce, &mut data);
+        assert_eq!(data, hex("6e 2e 35 9a 25 68 f9 80 41 ba 07 28 dd 0d 69 81 e9 7e 7a ec 1d 43 60 c2 0a 27 af cc fd 9f ae 0b
+                              f9 1b 65 c5 52 47 33 ab 8f 59 3d ab cd 62 b3 57 16 39 d6 24 e6 51 52 ab 8f 53 0c 35 9f 08 61 d8
+                              07 ca 0d bf 50 0d 6a 61 56 a3 8e 08 8a 22 b6 5e 52 bc 51 4d 16 cc f8 06 81 8c e9 1a b7 79 37 36
+                              5a f9 0b bf 74 a3 5b e6 b4 0b 8e ed f2 78 5e 42 87 4d"));
+    }
+
+    // RFC 8439 2.5.2
+    #[test]
+    fn poly1305_tags_the_rfc_vector() {
+        let k = key(&hex("85 d6 be 78 57 55 6d 33 7f 44 52 fe 42 d5 06 a8 01 03 80 8a fb 0d b2 fd 4a bf f6 af 41 49 f5 1b"));
+        assert_eq!(poly1305(&k, b"Cryptographic Forum Research Group").to_vec(),
+                   hex("a8 06 1d c1 30 51 36 c6 c2 2b 8b af 0c 01 27 a9"));
+    }
+
+    // RFC 8439 2.6.2, the MAC is keyed from block 0
+    #[test]
+    fn mac_key_is_the_rfc_poly1305_key() {
+        let k = key(&(0x80..0xa0).collect::<Vec<u8>>());
+        let nonce = hex("00 00 00 00 00 01 02 03 04 05 06 07");
+        let poly_key = key(&hex("8a d5 a0 8b 90 5f 81 cc 81 50 40 27 4a b2 94 71 a8 33 b6 37 e3 fd 0d a5 08 db b8 e2 fd d1 a6 46"));
+        assert_eq!(chacha_block(&k, 0, &nonce)[..32], poly_key[..]);
+        assert_eq!(mac(&k, &nonce, b"data"), read_u64(&poly1305(&poly_key, b"data")));
+    }
+
+    #[test]
+    fn each_hop_peels_its_own_layer() {
+        let mut rng = SimRng::derive(3, 0);
+        let nodes: Vec<KeyPair> = (0..3).map(|i| KeyPair::of(3, i)).collect();
+        let path = [10, 11, 12];
+        let keys: HashMap<u64, u64> = path.iter().cloned().zip(nodes.iter().map(|k| k.public)).collect();
+        let mut header = build(&path, (DELIVER, 7), &keys, &mut rng).unwrap();
+        for (i, hop) in nodes.iter().enumerate() {
+            // nobody else can open the layer
+            assert!(peel(&nodes[(i + 1) % 3], &header).is_none());
+            let plain = peel(hop, &header).unwrap();
+            if i < 2 {
+                assert_eq!((plain[0], read_u64(&plain[1..])), (FORWARD, path[i + 1]));
+            } else {
+                assert_eq!((plain[0], read_u64(&plain[1..])), (DELIVER, 7));
+            }
+            header = plain[ROUTING..].to_vec();
+        }
+
+        let block = ReplyBlock::new(&path, &keys, &mut rng).unwrap();
+        let payload = Payload { flow: 7, seq: 2, reply: Some(block.clone()) };
+        let sealed = seal_for(nodes[2].public, payload.encode(), &mut rng);
+        let opened = Payload::decode(&open_with(&nodes[2], &sealed).unwrap()).unwrap();
+        assert_eq!((opened.flow, opened.seq), (7, 2));
+        assert_eq!(opened.reply.map(|r| (r.first_hop, r.header)), Some((block.first_hop, block.header)));
+        // a flipped bit is caught by the tag
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(open_with(&nodes[2], &tampered).is_none());
+    }
+
+    #[test]
+    fn headers_are_the_same_length_at_every_hop() {
+        let mut rng = SimRng::derive(5, 0);
+        let nodes: Vec<KeyPair> = (0..MAX_HOPS).map(|i| KeyPair::of(5, i)).collect();
+        let keys: HashMap<u64, u64> = nodes.iter().enumerate().map(|(i, k)| (i as u64, k.public)).collect();
+        for n in [1, 2, 7, MAX_HOPS] {
+            let path: Vec<u64> = (0..n as u64).collect();
+            let mut header = build(&path, (DELIVER, 0), &keys, &mut rng).unwrap();
+            for (i, hop) in nodes[..n].iter().enumerate() {
+                assert_eq!(header.len(), HEADER);
+                let plain = peel(hop, &header).unwrap();
+                assert_eq!(plain[0], if i + 1 < n { FORWARD } else { DELIVER });
+                header = plain[ROUTING..].to_vec();
+            }
+            assert_eq!(header.len(), HEADER);
+        }
+        let path: Vec<u64> = (0..MAX_HOPS as u64 + 1).collect();
+        assert!(build(&path, (DELIVER, 0), &keys, &mut rng).is_none());
+    }
+}
@@ -0,0 +1,198 @@
+use petgraph::stable_graph::StableDiGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::EdgeRef;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+use connection::Connection;
+
+/// Latency weighted shortest paths from a single source.
+#[derive(Debug)]
+pub struct ShortestPaths {
+    pub source: NodeIndex,
+    pub latency: HashMap<NodeIndex, f32>,
+    pub hops: HashMap<NodeIndex, usize>,
+    predecessor: HashMap<NodeIndex, NodeIndex>,
+}
+
+impl ShortestPaths {
+    /// Optimal path from the source to `target`, including both ends.
+    pub fn path(&self, target: NodeIndex) -> Option<Vec<NodeIndex>> {
+        if !self.latency.contains_key(&target) {
+            return None
+        }
+        let mut path = vec![target];
+        let mut current = target;
+        while current != self.source {
+            current = self.predecessor[&current];
+            path.push(current);
+        }
+        path.reverse();
+        Some(path)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+struct State {
+    cost: f32,
+    node: NodeIndex,
+}
+
+impl Eq for State {}
+
+// reversed so the binary heap pops the cheapest state first
+impl Ord for State {
+    fn cmp(&self, other: &State) -> Ordering {
+        other.cost.partial_cmp(&self.cost).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &State) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub fn shortest_paths<N>(graph: &StableDiGraph<N, Connection>, source: NodeIndex) -> ShortestPaths {
+    shortest_paths_by(graph, source, |_, c| c.latency)
+}
+
+/// Shortest paths under another edge cost, given the edge's target and weight. `latency` then holds the cost.
+pub fn shortest_paths_by<N, F>(graph: &StableDiGraph<N, Connection>, source: NodeIndex, edge_cost: F) -> ShortestPaths
+    where F: Fn(NodeIndex, &Connection) -> f32 {
+    let mut latency = HashMap::new();
+    let mut hops = HashMap::new();
+    let mut predecessor = HashMap::new();
+    let mut heap = BinaryHeap::new();
+
+    latency.insert(source, 0.);
+    hops.insert(source, 0);
+    heap.push(State { cost: 0., node: source });
+
+    while let Some(State { cost, node }) = heap.pop() {
+        if cost > latency[&node] {
+            continue
+        }
+        for e in graph.edges(node) {
+            let next = e.target();
+            let c = cost + edge_cost(next, e.weight());
+            if latency.get(&next).is_none_or(|&l| c < l) {
+                latency.insert(next, c);
+                hops.insert(next, hops[&node] + 1);
+                predecessor.insert(next, node);
+                heap.push(State { cost: c, node: next });
+            }
+        }
+    }
+
+    ShortestPaths { source, latency, hops, predecessor }
+}
+
+/// Ground truth for routing quality, caches single source shortest path trees.
+pub struct Oracle {
+    cache: HashMap<NodeIndex, ShortestPaths>,
+    order: VecDeque<NodeIndex>,
+    capacity: usize,
+}
+
+impl Oracle {
+    pub fn new(capacity: usize) -> Oracle {
+        assert!(capacity > 0);
+        Oracle {
+            cache: HashMap::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn from<N>(&mut self, graph: &StableDiGraph<N, Connection>, source: NodeIndex) -> &ShortestPaths {
+        if !self.cache.contains_key(&source) {
+            if self.order.len() == self.capacity {
+                let oldest = self.order.pop_front().unwrap();
+                self.cache.remove(&oldest);
+            }
+            self.cache.insert(source, shortest_paths(graph, source));
+            self.order.push_back(source);
+        }
+        &self.cache[&source]
+    }
+
+    /// Optimal (latency, hops) between two nodes, `None` if unreachable.
+    pub fn optimal<N>(&mut self, graph: &StableDiGraph<N, Connection>, a: NodeIndex, b: NodeIndex) -> Option<(f32, usize)> {
+        let paths = self.from(graph, a);
+        match (paths.latency.get(&b), paths.hops.get(&b)) {
+            (Some(&l), Some(&h)) => Some((l, h)),
+            _ => None
+        }
+    }
+
+    /// Must be called whenever links change.
+    pub fn invalidate(&mut self) {
+        self.cache.clear();
+        self.order.clear();
+    }
+}
+
+/// Actual latency of a hop sequence, `None` if two consecutive hops are not linked.
+pub fn path_latency<N>(graph: &StableDiGraph<N, Connection>, path: &[NodeIndex]) -> Option<f32> {
+    let mut total = 0.;
+    for w in path.windows(2) {
+        let e = graph.find_edge(w[0], w[1])?;
+        total += graph[e].latency;
+    }
+    Some(total)
+}
+
+/// Latency of a route over that of the shortest path.
+pub fn stretch(latency: f32, optimal_latency: f32) -> f32 {
+    if optimal_latency > 0. { latency / optimal_latency } else { 1. }
+}
+
+/// Route stretch for one packet type.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StretchStats {
+    pub delivered: u64,
+    pub total_stretch: f64,
+    pub max_stretch: f32,
+    pub total_hop_stretch: f64,
+    pub total_latency: f64,
+    pub total_optimal: f64,
+    // propagation plus queueing, as observed by the packets
+    pub total_elapsed: f64,
+}
+
+impl StretchStats {
+    pub fn record(&mut self, latency: f32, elapsed: f32, hops: usize, optimal_latency: f32, optimal_hops: usize) {
+        // packets to self have nothing to compare against
+        if optimal_hops == 0 {
+            return
+        }
+        let stretch = stretch(latency, optimal_latency);
+        self.delivered += 1;
+        self.total_stretch += stretch as f64;
+        self.max_stretch = self.max_stretch.max(stretch);
+        self.total_hop_stretch += hops as f64 / optimal_hops as f64;
+        self.total_latency += latency as f64;
+        self.total_optimal += optimal_latency as f64;
+        self.total_elapsed += elapsed as f64;
+    }
+
+    pub fn mean_stretch(&self) -> f64 {
+        if self.delivered == 0 { 0. } else { self.total_stretch / self.delivered as f64 }
+    }
+
+    pub fn mean_hop_stretch(&self) -> f64 {
+        if self.delivered == 0 { 0. } else { self.total_hop_stretch / self.delivered as f64 }
+    }
+
+    pub fn csv_header() -> &'static str {
+        "kind,delivered,mean_stretch,max_stretch,mean_hop_stretch,mean_latency,mean_optimal,mean_elapsed\n"
+    }
+
+    pub fn to_csv(&self, kind: &str) -> String {
+        let n = self.delivered.max(1) as f64;
+        format!("{},{},{},{},{},{},{},{}\n", kind, self.delivered, self.mean_stretch(), self.max_stretch,
+                self.mean_hop_stretch(), self.total_latency / n, self.total_optimal / n, self.total_elapsed / n)
+    }
+}
@@ -1,27 +1,33 @@
 use actix::*;
-use nc::NC;
 use node::Node;
 
 use std::fmt::Debug;
-use std::collections::HashMap;
-use std::iter;
+use std::mem;
 
 pub trait PacketData {
+    /// Name of the packet type, used to break down statistics.
+    fn kind() -> &'static str;
+    /// Payload size in bytes as encoded on the wire, used by the link model.
+    fn size(&self) -> usize;
     fn process(packet: &Packet<Self>, node: &mut Node) -> Response<Node, Packet<Self>> where Self: Sized + Clone + Send + ResponseType, <Self as ResponseType>::Item: Send, <Self as ResponseType>::Error: Send;
 }
 
-pub trait SearchPacketData {
-    fn process(packet: &SearchPacket<Self>, node: &mut Node) -> Response<Node, SearchPacket<Self>> where Self: Sized + Clone + Send + ResponseType;
-}
-
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Packet<T: PacketData + Clone + Send + ResponseType> {
     pub from: u64,
     pub des: u64,
     pub route: Vec<u64>,
     //list of hops
-    pub data: T
+    pub data: T,
+    // nodes this packet has actually visited, starting with the sender
+    pub hops: Vec<u64>,
+    // simulated time the packet left its source, and time spent in transit since
+    pub sent_at: f32,
+    pub elapsed: f32,
+    // identifies the journey in the trace, 0 until the source sends it
+    #[serde(default)]
+    pub trace: u64,
 }
 
 pub struct PacketRouteData {
@@ -46,52 +52,29 @@ impl<T: PacketData + Clone + Send + ResponseType> Packet<T> {
     }
 
     pub fn new(r: PacketRouteData, d: T) -> Packet<T> {
-        Packet { data: d, route: r.route, des: r.des, from: r.from }
+        Packet { data: d, route: r.route, des: r.des, from: r.from, hops: Vec::new(), sent_at: 0., elapsed: 0., trace: 0 }
     }
-}
-
-impl<T: PacketData + Clone + Send + ResponseType> ResponseType for Packet<T> where T::Error: Debug, T::Item: Send, T::Error: Send {
-    type Item = T::Item;
-    type Error = T::Error;
-}
 
-#[derive(Clone)]
-pub struct SearchPacket<T: SearchPacketData + Clone> {
-    pub from: u64,
-    des: u64,
-    nc: NC,
-    data: T
+    /// Size on the wire, measurement only fields are not counted.
+    pub fn wire_size(&self) -> usize {
+        mem::size_of::<u64>() * (2 + self.route.len()) + 2 * mem::size_of::<f32>() + self.data.size()
+    }
 }
 
-impl<T: SearchPacketData + Clone + Send + ResponseType> ResponseType for SearchPacket<T> {
+impl<T: PacketData + Clone + Send + ResponseType> ResponseType for Packet<T> where T::Item: Send, T::Error: Send {
     type Item = T::Item;
     type Error = T::Error;
 }
 
-pub fn simplify_route(input: Vec<u64>) -> Vec<u64> {
-    let mut m: HashMap<u64, usize> = HashMap::new();
-
-    let mut index = 0;
-    for j in input.iter() {
-        if !m.contains_key(&j) {
-            m.insert(*j, index);
-        }
-        index += 1;
-    }
-
-    for j in (0..input.len()).rev() {
-        if m.contains_key(&input[j]) {
-            let mut out = Vec::new();
-
-            for k in 0..m[&input[j]] {
-                out.push(input[k])
-            }
-
-            for k in j..input.len() {
-                out.push(input[k])
-            }
-            return out
+// remove loops from a route given in hop order, keeps the first and last hop
+pub fn remove_loops(path: Vec<u64>) -> Vec<u64> {
+    let mut out: Vec<u64> = Vec::new();
+    for hop in path {
+        if let Some(i) = out.iter().position(|h| *h == hop) {
+            out.truncate(i + 1);
+        } else {
+            out.push(hop);
         }
     }
-    input
-}
\ No newline at end of file
+    out
+}
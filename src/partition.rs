@@ -0,0 +1,214 @@
+//! Assignment of nodes to arbiter threads.
+//!
+//! Packets between nodes on different arbiters cross threads, so nodes which
+//! talk to each other should share one while every arbiter gets a similar
+//! number of nodes. The label propagation partitioner starts from contiguous
+//! breadth first chunks of the graph and then repeatedly moves nodes to the
+//! thread most of their neighbours are on, as long as the sizes stay within
+//! `IMBALANCE` of even. It is deterministic, so a seed always gives the same
+//! assignment.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use petgraph::graph::NodeIndex;
+use petgraph::stable_graph::StableDiGraph;
+use petgraph::visit::{EdgeRef, IntoEdgeReferences, NodeIndexable};
+
+/// How nodes are spread over the threads.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Partitioner {
+    /// Core nodes round robin, every other node on the thread of its first upstream.
+    Upstream,
+    #[default]
+    LabelPropagation,
+}
+
+impl Partitioner {
+    pub fn parse(s: &str) -> Option<Partitioner> {
+        match s {
+            "upstream" => Some(Partitioner::Upstream),
+            "label" => Some(Partitioner::LabelPropagation),
+            _ => None
+        }
+    }
+}
+
+// allowed deviation from an even number of nodes per thread
+static IMBALANCE: f32 = 0.05;
+// bound on the refinement passes over every node
+static ROUNDS: usize = 20;
+
+/// Thread of every node, by graph index.
+pub fn label_propagation<N, E>(graph: &StableDiGraph<N, E>, threads: usize) -> Vec<usize> {
+    let n = graph.node_bound();
+    let mut labels = vec![0; n];
+    if threads <= 1 || n == 0 {
+        return labels
+    }
+
+    // breadth first order keeps neighbourhoods together in the initial chunks
+    let mut order = Vec::with_capacity(n);
+    let mut seen = vec![false; n];
+    for start in graph.node_indices() {
+        if seen[start.index()] {
+            continue
+        }
+        seen[start.index()] = true;
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        while let Some(i) = queue.pop_front() {
+            order.push(i);
+            for j in graph.neighbors(i) {
+                if !seen[j.index()] {
+                    seen[j.index()] = true;
+                    queue.push_back(j);
+                }
+            }
+        }
+    }
+    let even = order.len() as f32 / threads as f32;
+    let mut sizes = vec![0usize; threads];
+    for (k, &i) in order.iter().enumerate() {
+        let t = ((k as f32 / even) as usize).min(threads - 1);
+        labels[i.index()] = t;
+        sizes[t] += 1;
+    }
+
+    let max = (even * (1. + IMBALANCE)).ceil() as usize;
+    let min = (even * (1. - IMBALANCE)).floor() as usize;
+    for _ in 0..ROUNDS {
+        let mut moved = 0;
+        for &i in &order {
+            let own = labels[i.index()];
+            if sizes[own] <= min {
+                continue
+            }
+            let mut counts: HashMap<usize, usize> = HashMap::new();
+            for j in graph.neighbors(i) {
+                *counts.entry(labels[j.index()]).or_insert(0) += 1;
+            }
+            let here = counts.get(&own).cloned().unwrap_or(0);
+            // ties go to the lowest thread so the result does not depend on the hash order
+            let best = counts.iter()
+                .filter(|&(&t, &c)| t != own && c > here && sizes[t] < max)
+                .max_by_key(|&(&t, &c)| (c, threads - t))
+                .map(|(&t, _)| t);
+            if let Some(t) = best {
+                labels[i.index()] = t;
+                sizes[own] -= 1;
+                sizes[t] += 1;
+                moved += 1;
+            }
+        }
+        if moved == 0 {
+            break
+        }
+    }
+    labels
+}
+
+/// Thread of every node by graph index, core nodes round robin and every other node on the thread of its first upstream.
+pub fn upstream<N, E, F: Fn(&N) -> usize>(graph: &StableDiGraph<N, E>, threads: usize, level: F) -> Vec<usize> {
+    let mut labels = vec![0; graph.node_bound()];
+    let mut order: Vec<NodeIndex> = graph.node_indices().collect();
+    // upstreams first
+    order.sort_by_key(|&i| (level(&graph[i]), i.index()));
+    let mut core = 0;
+    for i in order {
+        let l = level(&graph[i]);
+        let up = graph.neighbors(i).filter(|&j| level(&graph[j]) + 1 == l).min_by_key(|j| j.index());
+        labels[i.index()] = match up {
+            Some(j) => labels[j.index()],
+            None => {
+                core += 1;
+                (core - 1) % threads.max(1)
+            }
+        };
+    }
+    labels
+}
+
+/// Nodes and cut edges of an assignment.
+pub struct PartitionStats {
+    pub nodes: Vec<usize>,
+    pub edges: usize,
+    // edges between nodes on different threads
+    pub cut: usize,
+}
+
+impl PartitionStats {
+    pub fn new<N, E, F: Fn(NodeIndex) -> usize>(graph: &StableDiGraph<N, E>, threads: usize, thread: F) -> PartitionStats {
+        let mut nodes = vec![0; threads];
+        for i in graph.node_indices() {
+            nodes[thread(i)] += 1;
+        }
+        let mut cut = 0;
+        for e in graph.edge_references() {
+            if thread(e.source()) != thread(e.target()) {
+                cut += 1;
+            }
+        }
+        PartitionStats { nodes, edges: graph.edge_count(), cut }
+    }
+}
+
+impl fmt::Display for PartitionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} threads, nodes per thread {:?}, {}/{} edges cross threads ({:.1}%)", self.nodes.len(), self.nodes,
+               self.cut, self.edges, 100. * self.cut as f32 / self.edges.max(1) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `side` by `side` nodes, each linked both ways to the next in its row and column
+    fn grid(side: usize) -> StableDiGraph<usize, ()> {
+        let mut graph = StableDiGraph::new();
+        let nodes: Vec<NodeIndex> = (0..side * side).map(|i| graph.add_node(i / side)).collect();
+        for i in 0..side * side {
+            if i % side + 1 < side {
+                graph.add_edge(nodes[i], nodes[i + 1], ());
+                graph.add_edge(nodes[i + 1], nodes[i], ());
+            }
+            if i + side < side * side {
+                graph.add_edge(nodes[i], nodes[i + side], ());
+                graph.add_edge(nodes[i + side], nodes[i], ());
+            }
+        }
+        graph
+    }
+
+    #[test]
+    fn label_propagation_stays_balanced_and_cuts_few_edges() {
+        let graph = grid(20);
+        let threads = 4;
+        let labels = label_propagation(&graph, threads);
+        let stats = PartitionStats::new(&graph, threads, |i| labels[i.index()]);
+        let even = 400. / threads as f32;
+        for &n in &stats.nodes {
+            assert!(n as f32 <= (even * (1. + IMBALANCE)).ceil() && n as f32 >= (even * (1. - IMBALANCE)).floor(), "{:?}", stats.nodes);
+        }
+        // a random assignment would cut three quarters of them
+        assert!(stats.cut * 4 < stats.edges, "{}", stats);
+        assert_eq!(labels, label_propagation(&graph, threads));
+    }
+
+    #[test]
+    fn a_single_thread_takes_everything() {
+        assert!(label_propagation(&grid(5), 1).iter().all(|&t| t == 0));
+    }
+
+    #[test]
+    fn nodes_follow_their_upstream() {
+        // rows are levels, the first row the core
+        let graph = grid(6);
+        let labels = upstream(&graph, 4, |&level| level);
+        for i in 0..6 {
+            assert_eq!(labels[i], i % 4);
+            assert_eq!(labels[30 + i], labels[i]);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! Seeded random numbers for nodes.
+//!
+//! Every node draws from its own splitmix64 stream derived from the run's
+//! seed and its graph index, so a run is repeatable whichever thread a node
+//! lands on, and the stream can be saved with the rest of the node's state.
+
+use rand::Rng;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    pub fn new(seed: u64) -> SimRng {
+        SimRng { state: seed }
+    }
+
+    /// Stream `stream` of the run seeded with `seed`.
+    pub fn derive(seed: u64, stream: u64) -> SimRng {
+        let mut rng = SimRng::new(seed ^ stream.wrapping_mul(0xd1b5_4a32_d192_ed03));
+        rng.next_u64();
+        rng
+    }
+}
+
+impl Rng for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
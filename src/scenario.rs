@@ -0,0 +1,271 @@
+//! Scripted runs.
+//!
+//! A scenario is a plain text timeline the world works through, one event per
+//! line, `#` starts a comment:
+//!
+//! ```text
+//! epoch 0: add 50
+//! epoch 100: traffic gravity 8
+//! time 30: fail 3 17
+//! epoch 150: partition -1e8 -1e8 0 1e8
+//! epoch 180: heal
+//! epoch 200: adversary 0.1 inflate 2
+//! epoch 220: nc learn_rate 0.01
+//! epoch 250: snapshot attacked
+//! epoch 300: stop
+//! ```
+//!
+//! Events at a simulated time run at the first epoch starting at or after it.
+//! Links and regions are given by graph index and position, as exported by
+//! `generate`. A scenario with any `add` event takes over growing the network
+//! from the world.
+
+use actix::*;
+
+use std::fs::File;
+use std::io::Read;
+
+use adversary::{AdversaryConfig, Attack};
+use nc::NCParams;
+use node::Node;
+use traffic::{TrafficConfig, Workload};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum When {
+    Epoch(u64),
+    Time(f32),
+}
+
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Start the next `n` nodes of the graph.
+    AddNodes(usize),
+    /// Take down the link between two graph indices.
+    FailLink(usize, usize),
+    /// Take down every link crossing the border of the rectangle `[x0, y0, x1, y1]`.
+    Partition([f32; 4]),
+    /// Bring every failed link back up.
+    Heal,
+    Traffic(Option<TrafficConfig>),
+    /// Turn a fraction of the running nodes malicious, and as many of those started later.
+    Adversary(AdversaryConfig),
+    /// Change a run time NC parameter of every node.
+    NC(String, f32),
+    /// Export the statistics so far to a subdirectory of the output directory.
+    Snapshot(String),
+    Stop,
+}
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub when: When,
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Scenario {
+    // not yet run, in file order
+    pub events: Vec<Event>,
+}
+
+/// Word `i` of line `line` as a number, also used by the UDP topology format.
+pub fn number<T: ::std::str::FromStr>(words: &[&str], i: usize, line: usize) -> Result<T, String> {
+    match words.get(i) {
+        Some(w) => w.parse().map_err(|_| format!("line {}: invalid number {}", line, w)),
+        None => Err(format!("line {}: missing argument {}", line, i))
+    }
+}
+
+fn parse_action(words: &[&str], line: usize) -> Result<Action, String> {
+    let n = |i| number::<f32>(words, i, line);
+    Ok(match words[0] {
+        "add" => Action::AddNodes(number(words, 1, line)?),
+        "fail" => Action::FailLink(number(words, 1, line)?, number(words, 2, line)?),
+        "partition" => Action::Partition([n(1)?, n(2)?, n(3)?, n(4)?]),
+        "heal" => Action::Heal,
+        "traffic" if words.get(1) == Some(&"stop") => Action::Traffic(None),
+        "traffic" => {
+            let workload = match words.get(1) {
+                Some(&"uniform") => Workload::Uniform,
+                Some(&"gravity") => Workload::Gravity,
+                Some(&"hotspot") => Workload::HotSpot { spots: number(words, 3, line)?, fraction: n(4)? },
+                _ => return Err(format!("line {}: unknown workload", line))
+            };
+            Action::Traffic(Some(TrafficConfig { workload, flows_per_epoch: number(words, 2, line)?, ..TrafficConfig::default() }))
+        }
+        "adversary" => {
+            let attack = match words.get(2) {
+                Some(&"inflate") => Attack::Inflate { factor: n(3)? },
+                Some(&"deflate") => Attack::Deflate { factor: n(3)? },
+                Some(&"random") => Attack::Random,
+                Some(&"collude") => Attack::Collude { seed: number(words, 3, line)? },
+                Some(&"forge") => Attack::Forge { factor: n(3)? },
+                _ => return Err(format!("line {}: unknown attack", line))
+            };
+            Action::Adversary(AdversaryConfig { fraction: n(1)?, attack })
+        }
+        "nc" => {
+            let key = words.get(1).cloned().unwrap_or("");
+            if apply_nc(&mut NCParams::default(), key, 0.).is_none() {
+                return Err(format!("line {}: unknown nc parameter {}", line, key))
+            }
+            Action::NC(key.to_string(), n(2)?)
+        }
+        "snapshot" => match words.get(1) {
+            Some(name) => Action::Snapshot(name.to_string()),
+            None => return Err(format!("line {}: snapshot needs a name", line))
+        },
+        "stop" => Action::Stop,
+        w => return Err(format!("line {}: unknown event {}", line, w))
+    })
+}
+
+/// Set a parameter which can change while nodes run, the dimension can not.
+pub fn apply_nc(params: &mut NCParams, key: &str, value: f32) -> Option<()> {
+    match key {
+        "learn_rate" => params.learn_rate = value,
+        "regularisation" => params.regularisation = value,
+        "clamp" => params.clamp = value,
+        _ => return None
+    }
+    Some(())
+}
+
+impl Scenario {
+    pub fn parse(text: &str) -> Result<Scenario, String> {
+        let mut events = Vec::new();
+        for (i, raw) in text.lines().enumerate() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue
+            }
+            let colon = line.find(':').ok_or_else(|| format!("line {}: expected `epoch <n>:` or `time <t>:`", i + 1))?;
+            let head: Vec<&str> = line[..colon].split_whitespace().collect();
+            let when = match (head.first(), head.len()) {
+                (Some(&"epoch"), 2) => When::Epoch(number(&head, 1, i + 1)?),
+                (Some(&"time"), 2) => When::Time(number(&head, 1, i + 1)?),
+                _ => return Err(format!("line {}: expected `epoch <n>:` or `time <t>:`", i + 1))
+            };
+            let words: Vec<&str> = line[colon + 1..].split_whitespace().collect();
+            if words.is_empty() {
+                return Err(format!("line {}: missing event", i + 1))
+            }
+            events.push(Event { when, action: parse_action(&words, i + 1)? });
+        }
+        Ok(Scenario { events })
+    }
+
+    pub fn load(path: &str) -> Result<Scenario, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("cannot read scenario {}: {}", path, e))?;
+        Scenario::parse(&text)
+    }
+
+    pub fn grows(&self) -> bool {
+        self.events.iter().any(|e| matches!(e.action, Action::AddNodes(_)))
+    }
+
+    /// Remove and return the events due at the start of `epoch`, which starts at simulated time `time`.
+    pub fn due(&mut self, epoch: u64, time: f32) -> Vec<Action> {
+        let (due, rest): (Vec<Event>, Vec<Event>) = self.events.drain(..).partition(|e| match e.when {
+            When::Epoch(n) => n <= epoch,
+            When::Time(t) => t <= time,
+        });
+        self.events = rest;
+        due.into_iter().map(|e| e.action).collect()
+    }
+}
+
+// sent by the world to both ends of a link going down or coming back up
+pub struct SetLink {
+    pub neighbour: u64,
+    pub up: bool,
+}
+
+message!(SetLink);
+
+impl Handler<SetLink> for Node {
+    fn handle(&mut self, msg: SetLink, _ctx: &mut Context<Self>) -> Response<Self, SetLink> {
+        if let Some(&i) = self.neighbours_map.get(&msg.neighbour) {
+            self.neighbours[i].failed = !msg.up;
+        }
+        Self::reply(())
+    }
+}
+
+// sent by the world to the running nodes it turns malicious
+pub struct SetAttack {
+    pub attack: Attack,
+}
+
+message!(SetAttack);
+
+impl Handler<SetAttack> for Node {
+    fn handle(&mut self, msg: SetAttack, _ctx: &mut Context<Self>) -> Response<Self, SetAttack> {
+        self.malicious = Some(msg.attack);
+        Self::reply(())
+    }
+}
+
+// sent by the world to every node when a scenario changes the NC parameters
+pub struct SetNCParams {
+    pub params: NCParams,
+}
+
+message!(SetNCParams);
+
+impl Handler<SetNCParams> for Node {
+    fn handle(&mut self, msg: SetNCParams, _ctx: &mut Context<Self>) -> Response<Self, SetNCParams> {
+        self.nc.learn_rate = msg.params.learn_rate;
+        self.nc_model = self.config.nc_model.create(&msg.params);
+        self.config.nc = msg.params;
+        Self::reply(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static EXAMPLE: &str = "epoch 0: add 50
+epoch 100: traffic gravity 8
+time 30: fail 3 17
+epoch 150: partition -1e8 -1e8 0 1e8
+epoch 180: heal
+epoch 200: adversary 0.1 inflate 2
+epoch 220: nc learn_rate 0.01
+epoch 250: snapshot attacked # comment
+epoch 300: stop
+";
+
+    #[test]
+    fn the_documented_example_parses() {
+        let scenario = Scenario::parse(EXAMPLE).unwrap();
+        assert_eq!(scenario.events.len(), 9);
+        assert!(scenario.grows());
+        assert_eq!(scenario.events[2].when, When::Time(30.));
+        assert!(matches!(scenario.events[2].action, Action::FailLink(3, 17)));
+        assert!(matches!(scenario.events[5].action, Action::Adversary(AdversaryConfig { attack: Attack::Inflate { factor }, .. }) if factor == 2.));
+        assert!(matches!(scenario.events[7].action, Action::Snapshot(ref name) if name == "attacked"));
+    }
+
+    #[test]
+    fn events_run_once_when_due() {
+        let mut scenario = Scenario::parse(EXAMPLE).unwrap();
+        assert_eq!(scenario.due(0, 0.).len(), 1);
+        // the timed event is due at the first epoch starting at or after it
+        assert!(matches!(scenario.due(10, 30.)[..], [Action::FailLink(3, 17)]));
+        assert!(scenario.due(10, 30.).is_empty());
+        assert_eq!(scenario.due(300, 600.).len(), 7);
+        assert!(scenario.events.is_empty());
+    }
+
+    #[test]
+    fn broken_lines_are_rejected() {
+        for line in &["add 5", "epoch: add 5", "epoch x: add 5", "epoch 1:", "epoch 1: fly", "epoch 1: fail 3",
+                      "epoch 1: traffic sideways 8", "epoch 1: adversary 0.1 bribe", "epoch 1: nc dimension 3", "epoch 1: snapshot"] {
+            assert!(Scenario::parse(line).is_err(), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! One simulation spread over several processes.
+//!
+//! `run --shards N` makes this process the coordinator and starts N copies
+//! of the program as shards. Every shard generates the same graph from the
+//! seed and runs the nodes of its part of a label propagation partition over
+//! N. Nodes of other shards are stood in for by forwarders with the same ID,
+//! actors holding nothing else but the way to the owner, which hand every
+//! packet they receive to it as a `codec::Frame` and answer with the reply
+//! that comes back. Shards connect to the coordinator over localhost TCP and
+//! it relays the frames between them.
+//!
+//! Epochs are synchronised conservatively, no shard starts an epoch before
+//! every shard has finished the previous one and no frame is in flight. The
+//! coordinator polls idle shards for the frames they sent and received and
+//! the requests they have outstanding, and releases the next epoch once two
+//! consecutive waves agree, everything sent has been received and nothing is
+//! outstanding (the four counter method).
+//!
+//! The graph, node IDs, node random streams, churn and scenario events are
+//! those of the single process run with the same seed, and so are the flows,
+//! baseline probes and nodes sampled for the NC evaluation: every shard draws
+//! them alike over all nodes from the stream of the epoch and acts on those
+//! it runs. Frames from other shards are queued and served in the order of
+//! their shard and ID once the local nodes are idle. Within a shard the order
+//! packets are processed in is still up to the scheduler, as with several
+//! threads, so the measured statistics only agree with a single process run
+//! within the variation between runs of either. Checkpoints are not
+//! supported with shards.
+
+use actix::*;
+
+use std::env;
+use std::fs::File;
+use std::fmt::Debug;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{self, Child};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use cli::Options;
+use codec::{self, Forwarder, Frame, Waiting};
+use packet::{Packet, PacketData};
+use sweep;
+use world::{self, World};
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message {
+    // first message of a shard
+    Hello { shard: usize },
+    // a packet for a node of shard `to`, and its reply
+    Request { from: usize, to: usize, frame: Frame },
+    Reply { to: usize, frame: Frame },
+    // asks idle shards for their counters
+    Poll { epoch: u64, wave: u64 },
+    Counters { epoch: u64, wave: u64, sent: u64, received: u64, outstanding: u64 },
+    // every shard may start `epoch`
+    Release { epoch: u64 },
+}
+
+fn write(stream: &mut TcpStream, message: &Message) -> bool {
+    match serde_json::to_string(message) {
+        Ok(mut line) => {
+            line.push('\n');
+            stream.write_all(line.as_bytes()).is_ok()
+        }
+        Err(e) => {
+            eprintln!("cannot encode {:?}: {}", message, e);
+            false
+        }
+    }
+}
+
+fn read<R: BufRead>(reader: &mut R) -> Option<Message> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(n) if n > 0 => match serde_json::from_str(&line) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                eprintln!("cannot decode {}: {}", line.trim(), e);
+                None
+            }
+        },
+        _ => None
+    }
+}
+
+/// Connection of a shard to the coordinator, shared by the world and the forwarders.
+#[derive(Clone)]
+pub struct Link {
+    pub shard: usize,
+    stream: Arc<Mutex<TcpStream>>,
+    waiting: Waiting,
+    // requests received and not answered yet
+    serving: Arc<AtomicUsize>,
+    sent: Arc<AtomicUsize>,
+    received: Arc<AtomicUsize>,
+}
+
+impl Link {
+    /// Connect as shard `shard`, also returns the stream to hand to `listen`.
+    pub fn connect(address: &str, shard: usize) -> Result<(Link, TcpStream), String> {
+        let mut stream = TcpStream::connect(address).map_err(|e| format!("cannot connect to the coordinator at {}: {}", address, e))?;
+        let _ = stream.set_nodelay(true);
+        let reader = stream.try_clone().map_err(|e| e.to_string())?;
+        if !write(&mut stream, &Message::Hello { shard }) {
+            return Err(format!("cannot reach the coordinator at {}", address))
+        }
+        let link = Link {
+            shard,
+            stream: Arc::new(Mutex::new(stream)),
+            waiting: Waiting::default(),
+            serving: Arc::new(AtomicUsize::new(0)),
+            sent: Arc::new(AtomicUsize::new(0)),
+            received: Arc::new(AtomicUsize::new(0)),
+        };
+        Ok((link, reader))
+    }
+
+    fn send(&self, message: &Message) -> bool {
+        match *message {
+            Message::Request { .. } | Message::Reply { .. } => {
+                self.sent.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => {}
+        }
+        write(&mut self.stream.lock().unwrap(), message)
+    }
+
+    /// Read what the coordinator sends on another thread, and pass it on to the world.
+    pub fn listen(&self, stream: TcpStream, world: SyncAddress<World>) {
+        let link = self.clone();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            while let Some(message) = read(&mut reader) {
+                match message {
+                    Message::Request { from, frame, .. } => {
+                        link.received.fetch_add(1, Ordering::SeqCst);
+                        link.serving.fetch_add(1, Ordering::SeqCst);
+                        world.send(world::ShardRequest { from, frame });
+                    }
+                    Message::Reply { frame, .. } => {
+                        link.received.fetch_add(1, Ordering::SeqCst);
+                        link.waiting.resolve(frame.id, frame.body);
+                    }
+                    Message::Poll { epoch, wave } => world.send(world::ShardPoll { epoch, wave }),
+                    Message::Release { epoch } => world.send(world::ShardRelease { epoch }),
+                    _ => {}
+                }
+            }
+            // the run cannot go on without the others
+            eprintln!("shard {} lost the coordinator", link.shard);
+            process::exit(1);
+        });
+    }
+
+    /// Answer a request received from shard `to`.
+    pub fn reply(&self, to: usize, frame: Frame) {
+        self.send(&Message::Reply { to, frame });
+        self.serving.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn report(&self, epoch: u64, wave: u64) {
+        let outstanding = self.waiting.len() + self.serving.load(Ordering::SeqCst);
+        self.send(&Message::Counters {
+            epoch,
+            wave,
+            sent: self.sent.load(Ordering::SeqCst) as u64,
+            received: self.received.load(Ordering::SeqCst) as u64,
+            outstanding: outstanding as u64,
+        });
+    }
+}
+
+/// Set on forwarders, the shard running the real node.
+#[derive(Clone)]
+pub struct Remote {
+    pub shard: usize,
+    pub link: Link,
+}
+
+impl Remote {
+    /// Hand a packet to the real node, it resolves with the reply the owner sends back.
+    pub fn forward<T>(&self, to: usize, packet: Packet<T>) -> Response<Forwarder, Packet<T>>
+        where T: PacketData + Clone + Send + ResponseType + Serialize + 'static + Debug,
+              T::Item: Send + DeserializeOwned, T::Error: Send + Default + DeserializeOwned {
+        let (id, reply) = self.link.waiting.register();
+        let sent = Frame::request(id, to, &packet)
+            .map(|frame| self.link.send(&Message::Request { from: self.link.shard, to: self.shard, frame }));
+        match sent {
+            Ok(true) => codec::await_reply(reply),
+            Ok(false) => {
+                self.link.waiting.cancel(id);
+                Forwarder::reply_error(T::Error::default())
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                self.link.waiting.cancel(id);
+                Forwarder::reply_error(T::Error::default())
+            }
+        }
+    }
+}
+
+/// What the world of a shard needs to know about the others.
+pub struct Shard {
+    pub index: usize,
+    pub link: Link,
+    // shard of every node, by graph index
+    pub owner: Vec<usize>,
+    released: Option<u64>,
+    // poll to answer once idle
+    poll: Option<(u64, u64)>,
+    // packets from other shards not handed to our nodes yet, with the shard they came from
+    inbox: Vec<(usize, Frame)>,
+}
+
+impl Shard {
+    pub fn new(index: usize, link: Link, owner: Vec<usize>) -> Shard {
+        Shard { index, link, owner, released: None, poll: None, inbox: Vec::new() }
+    }
+
+    pub fn queue(&mut self, from: usize, frame: Frame) {
+        self.inbox.push((from, frame));
+    }
+
+    /// The queued packets by sending shard and in the order it sent them, whatever order they arrived in.
+    pub fn take_requests(&mut self) -> Vec<(usize, Frame)> {
+        let mut requests: Vec<(usize, Frame)> = self.inbox.drain(..).collect();
+        requests.sort_by_key(|&(from, ref frame)| (from, frame.id));
+        requests
+    }
+
+    /// Forwarder settings for node `i`, none if this shard runs it.
+    pub fn remote(&self, i: usize) -> Option<Remote> {
+        match self.owner[i] {
+            s if s == self.index => None,
+            s => Some(Remote { shard: s, link: self.link.clone() })
+        }
+    }
+
+    pub fn poll(&mut self, epoch: u64, wave: u64) {
+        self.poll = Some((epoch, wave));
+    }
+
+    pub fn release(&mut self, epoch: u64) {
+        self.released = Some(epoch);
+    }
+
+    /// Whether `epoch` may start, called while idle so a waiting poll is answered.
+    pub fn ready(&mut self, epoch: u64) -> bool {
+        if self.released == Some(epoch) {
+            return true
+        }
+        if let Some((e, wave)) = self.poll {
+            if e == epoch {
+                self.poll = None;
+                self.link.report(e, wave);
+            }
+        }
+        false
+    }
+}
+
+fn shard_dir(dir: &str, shard: usize) -> String {
+    Path::new(dir).join(format!("shard-{}", shard)).to_string_lossy().into_owned()
+}
+
+fn broadcast(streams: &mut [Option<TcpStream>], message: &Message) {
+    for s in streams.iter_mut() {
+        if let Some(ref mut s) = *s {
+            write(s, message);
+        }
+    }
+}
+
+// frames for a shard which has finished are dropped
+fn relay(streams: &mut [Option<TcpStream>], to: usize, message: &Message) {
+    if let Some(&mut Some(ref mut s)) = streams.get_mut(to) {
+        write(s, message);
+    }
+}
+
+/// Start `options.shards` shards running `args` and relay between them until they are done.
+pub fn coordinate(options: &Options, args: &[String]) -> i32 {
+    let n = options.shards;
+    let dir = options.output_dir.clone().unwrap_or(String::from("shards"));
+    let listener = match TcpListener::bind("127.0.0.1:0") {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("cannot listen for shards: {}", e);
+            return 1
+        }
+    };
+    let (exe, address) = match (env::current_exe(), listener.local_addr()) {
+        (Ok(exe), Ok(address)) => (exe, address.to_string()),
+        _ => {
+            eprintln!("cannot start shards");
+            return 1
+        }
+    };
+    let mut children: Vec<Child> = Vec::new();
+    for k in 0..n {
+        let child = process::Command::new(&exe).args(args)
+            .arg("--shard").arg(k.to_string())
+            .arg("--coordinator").arg(&address)
+            .arg("--output").arg(shard_dir(&dir, k))
+            .spawn();
+        match child {
+            Ok(c) => children.push(c),
+            Err(e) => {
+                eprintln!("cannot start shard {}: {}", k, e);
+                for c in &mut children {
+                    let _ = c.kill();
+                }
+                return 1
+            }
+        }
+    }
+    info!("coordinating {} shards on {}", n, address);
+
+    // every shard introduces itself, then a thread per shard reads what it sends
+    let (tx, rx) = mpsc::channel();
+    let mut streams: Vec<Option<TcpStream>> = (0..n).map(|_| None).collect();
+    for _ in 0..n {
+        let (stream, reader) = match listener.accept().and_then(|(s, _)| s.try_clone().map(|r| (s, r))) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("cannot accept a shard: {}", e);
+                return 1
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let mut reader = BufReader::new(reader);
+        let shard = match read(&mut reader) {
+            Some(Message::Hello { shard }) if shard < n && streams[shard].is_none() => shard,
+            m => {
+                eprintln!("unexpected first message from a shard: {:?}", m);
+                return 1
+            }
+        };
+        streams[shard] = Some(stream);
+        let tx = tx.clone();
+        thread::spawn(move || {
+            while let Some(m) = read(&mut reader) {
+                if tx.send((shard, Some(m))).is_err() {
+                    return
+                }
+            }
+            let _ = tx.send((shard, None));
+        });
+    }
+    drop(tx);
+
+    let mut epoch = 0;
+    let mut wave = 0;
+    let mut counters: Vec<Option<(u64, u64, u64)>> = vec![None; n];
+    let mut last: Option<Vec<(u64, u64, u64)>> = None;
+    let mut failed = false;
+    let mut running = n;
+    broadcast(&mut streams, &Message::Poll { epoch, wave });
+    while running > 0 {
+        let (from, message) = match rx.recv() {
+            Ok(m) => m,
+            Err(_) => break
+        };
+        match message {
+            Some(Message::Request { from: f, to, frame }) => relay(&mut streams, to, &Message::Request { from: f, to, frame }),
+            Some(Message::Reply { to, frame }) => relay(&mut streams, to, &Message::Reply { to, frame }),
+            Some(Message::Counters { epoch: e, wave: w, sent, received, outstanding }) => {
+                if e != epoch || w != wave {
+                    continue
+                }
+                counters[from] = Some((sent, received, outstanding));
+                if counters.iter().any(|c| c.is_none()) {
+                    continue
+                }
+                let now: Vec<(u64, u64, u64)> = counters.iter().map(|c| c.unwrap()).collect();
+                let (s, r, o) = now.iter().fold((0, 0, 0), |(s, r, o), c| (s + c.0, r + c.1, o + c.2));
+                if s == r && o == 0 && last.as_ref() == Some(&now) {
+                    broadcast(&mut streams, &Message::Release { epoch });
+                    epoch += 1;
+                    last = None;
+                } else {
+                    last = Some(now);
+                }
+                counters = vec![None; n];
+                wave += 1;
+                broadcast(&mut streams, &Message::Poll { epoch, wave });
+            }
+            Some(_) => {}
+            None => {
+                // shards finish together, one which fails stops the run
+                running -= 1;
+                streams[from] = None;
+                if !children[from].wait().map(|s| s.success()).unwrap_or(false) {
+                    eprintln!("shard {} failed, stopping the others", from);
+                    failed = true;
+                    for c in &mut children {
+                        let _ = c.kill();
+                    }
+                    break
+                }
+            }
+        }
+    }
+    for c in &mut children {
+        let _ = c.wait();
+    }
+    if failed {
+        return 1
+    }
+
+    let dirs: Vec<String> = (0..n).map(|k| shard_dir(&dir, k)).collect();
+    match combine_summaries(&dirs, &dir) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+/// Combine the summaries exported to `dirs` by the processes of one run, print it and export it to `dir`.
+pub fn combine_summaries(dirs: &[String], dir: &str) -> Result<(), String> {
+    let rows = dirs.iter().map(|d| sweep::read_summary(Path::new(d))).collect::<Result<Vec<_>, String>>()?;
+    let summary = combine(&rows);
+    let header: Vec<String> = summary.iter().map(|(k, _)| k.clone()).collect();
+    let values: Vec<String> = summary.iter().map(|&(_, v)| v.to_string()).collect();
+    println!("combined over {} processes:", rows.len());
+    for &(ref k, v) in &summary {
+        println!("  {} = {}", k, v);
+    }
+    let path = Path::new(dir).join("summary.csv");
+    File::create(&path).and_then(|mut f| f.write_all(format!("{}\n{}\n", header.join(","), values.join(",")).as_bytes()))
+        .map_err(|e| format!("failed to export {:?}: {}", path, e))
+}
+
+// one summary for the whole run, counts are added and the rest weighted by what they were measured over
+fn combine(rows: &[Vec<(String, f64)>]) -> Vec<(String, f64)> {
+    let get = |row: &Vec<(String, f64)>, key: &str| row.iter().find(|&(k, _)| k == key).map_or(0., |&(_, v)| v);
+    let weighted = |key: &str, weight: &str| {
+        let total: f64 = rows.iter().map(|r| get(r, weight)).sum();
+        if total > 0. { rows.iter().map(|r| get(r, key) * get(r, weight)).sum::<f64>() / total } else { 0. }
+    };
+    let keys: Vec<String> = rows.first().map_or(Vec::new(), |r| r.iter().map(|(k, _)| k.clone()).collect());
+    keys.into_iter().map(|k| {
+        let v = match k.as_str() {
+            "nodes" | "messages" | "delivered" => rows.iter().map(|r| get(r, &k)).sum(),
+            "stretch" => weighted(&k, "delivered"),
+            "loss" => weighted(&k, "messages"),
+            "nc_error" | "rank_accuracy" | "dht_entries" => weighted(&k, "nodes"),
+            // the same in every process
+            _ => rows.iter().map(|r| get(r, &k)).fold(0., f64::max),
+        };
+        (k, v)
+    }).collect()
+}
@@ -0,0 +1,46 @@
+use std::fmt;
+
+/// Summary of a sample of values.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Distribution {
+    pub count: usize,
+    pub mean: f32,
+    pub p50: f32,
+    pub p90: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl Distribution {
+    pub fn new(mut values: Vec<f32>) -> Distribution {
+        if values.is_empty() {
+            return Distribution::default()
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = values.len();
+        let percentile = |p: f32| values[((n - 1) as f32 * p).round() as usize];
+        Distribution {
+            count: n,
+            mean: values.iter().sum::<f32>() / n as f32,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: values[n - 1],
+        }
+    }
+
+    pub fn csv_header(prefix: &str) -> String {
+        format!("{0}_mean,{0}_p50,{0}_p90,{0}_p99,{0}_max", prefix)
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{},{}", self.mean, self.p50, self.p90, self.p99, self.max)
+    }
+}
+
+impl fmt::Display for Distribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mean {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}, max {:.2}",
+               self.mean, self.p50, self.p90, self.p99, self.max)
+    }
+}
@@ -0,0 +1,283 @@
+//! Parameter sweeps.
+//!
+//! A sweep runs every combination of the varied parameters for every seed,
+//! each run in its own system exporting to its own directory, then combines
+//! the `summary.csv` of the runs into one table with the mean and the 95%
+//! confidence interval of every statistic.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use address::{self, AddressScheme};
+use adversary::Defence;
+use advert::AdvertConfig;
+use baseline::Protocol;
+use ledger::BillingConfig;
+use link::Discipline;
+use nc::{LearnSchedule, ModelKind};
+use node::NodeConfig;
+use onion::OnionConfig;
+use world::GraphParams;
+
+/// Parameters which can be set for a run, by name.
+pub static KEYS: [&str; 15] = ["nodes", "bucket_size", "max_node_count", "dimension", "loss", "churn",
+                               "model", "schedule", "baseline", "queue", "defence", "addressing",
+                               "private_routes", "adverts", "billing"];
+
+// two sided 95% quantiles of the t distribution by degrees of freedom, the normal one beyond
+static T_95: [f64; 30] = [12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+                          2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+                          2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042];
+
+/// Everything a sweep can vary, handed to the world before it generates the graph.
+#[derive(Clone, Debug)]
+pub struct Setting {
+    pub generation: GraphParams,
+    pub churn: f32,
+    pub node_config: NodeConfig,
+}
+
+impl Default for Setting {
+    fn default() -> Setting {
+        Setting {
+            generation: GraphParams::default(),
+            churn: 0.,
+            node_config: NodeConfig::default(),
+        }
+    }
+}
+
+fn invalid(key: &str, value: &str) -> String {
+    format!("invalid value for {}: {}", key, value)
+}
+
+fn parse<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| invalid(key, value))
+}
+
+// `name:a:b`, the name of a variant and the numbers following it
+fn variant<'a>(key: &str, value: &'a str) -> Result<(&'a str, Vec<f32>), String> {
+    let mut parts = value.split(':');
+    let name = parts.next().unwrap_or("");
+    Ok((name, parts.map(|a| parse(key, a)).collect::<Result<Vec<f32>, String>>()?))
+}
+
+impl Setting {
+    pub fn apply(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "nodes" => {
+                let loss = self.generation.loss;
+                self.generation = GraphParams::sized(parse(key, value)?);
+                self.generation.loss = loss;
+            }
+            "bucket_size" => self.node_config.dht.bucket_size = parse(key, value)?,
+            "max_node_count" => self.node_config.dht.max_node_count = parse(key, value)?,
+            "dimension" => match parse(key, value)? {
+                0 => return Err(String::from("dimension must be at least 1")),
+                d => self.node_config.nc.dimension = d,
+            },
+            "loss" => self.generation.loss = parse(key, value)?,
+            "churn" => self.churn = parse(key, value)?,
+            "model" => self.node_config.nc_model = match value {
+                "factorisation" => ModelKind::Factorisation,
+                "vivaldi" => ModelKind::Vivaldi,
+                "dmfsgd" => ModelKind::Dmfsgd,
+                _ => return Err(invalid(key, value))
+            },
+            "private_routes" => self.node_config.private_routes = match value {
+                "off" => None,
+                "on" => Some(OnionConfig { rendezvous: false, reply_blocks: false }),
+                "rendezvous" => Some(OnionConfig { rendezvous: true, reply_blocks: true }),
+                _ => return Err(invalid(key, value))
+            },
+            "billing" => self.node_config.billing = match value {
+                "off" => None,
+                "on" => Some(BillingConfig::default()),
+                _ => return Err(invalid(key, value))
+            },
+            _ if !KEYS.contains(&key) => return Err(format!("unknown parameter {}, expected one of {}", key, KEYS.join(", "))),
+            _ => {
+                // the rest take a variant with optional numbers, defaults stand in for those left out
+                let (name, args) = variant(key, value)?;
+                let arg = |i: usize, default: f32| args.get(i).cloned().unwrap_or(default);
+                match (key, name) {
+                    ("schedule", "constant") => self.node_config.nc.schedule = LearnSchedule::Constant,
+                    ("schedule", "decay") => self.node_config.nc.schedule = LearnSchedule::Decay { factor: arg(0, 0.999), min: arg(1, 0.005) },
+                    ("schedule", "adaptive") => self.node_config.nc.schedule = LearnSchedule::Adaptive { min: arg(0, 0.005), max: arg(1, 0.2) },
+                    ("baseline", "none") => self.node_config.baseline = None,
+                    ("baseline", "link_state") => self.node_config.baseline = Some(Protocol::LinkState),
+                    ("baseline", "path_vector") => self.node_config.baseline = Some(Protocol::PathVector),
+                    ("baseline", "compact") => self.node_config.baseline = Some(Protocol::Compact { landmarks: arg(0, 0.1) }),
+                    ("queue", "drop_tail") => self.node_config.queue.discipline = Discipline::DropTail,
+                    ("queue", "red") => self.node_config.queue.discipline = Discipline::Red { min: arg(0, 0.25), max: arg(1, 0.75), max_p: arg(2, 0.1) },
+                    ("queue", "codel") => self.node_config.queue.discipline = Discipline::CoDel { target: arg(0, 0.005), interval: arg(1, 0.1) },
+                    ("defence", "none") => self.node_config.defence = Defence::None,
+                    ("defence", "outlier") => self.node_config.defence = Defence::OutlierRejection { threshold: arg(0, 1.) },
+                    ("defence", "reputation") => self.node_config.defence = Defence::Reputation { weight: arg(0, 1.) },
+                    ("defence", "veracity") => self.node_config.defence = Defence::Veracity { witnesses: arg(0, 5.) as usize, threshold: arg(1, 0.5) },
+                    ("addressing", "provider") => self.node_config.addressing = AddressScheme::Provider,
+                    ("addressing", "coordinates") => self.node_config.addressing = AddressScheme::Coordinates { depth: arg(0, 4.) as usize },
+                    ("addressing", "hybrid") => match arg(0, 4.) as usize {
+                        // deeper cells no longer fit in one label
+                        depth if depth <= address::HYBRID_DEPTH => self.node_config.addressing = AddressScheme::Hybrid { depth },
+                        _ => return Err(invalid(key, value))
+                    },
+                    ("adverts", "off") => self.node_config.adverts = None,
+                    ("adverts", "on") => self.node_config.adverts = Some(AdvertConfig { liars: arg(0, 0.), factor: arg(1, 0.5) }),
+                    _ => return Err(invalid(key, value))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Assignments of one data point, in the order the parameters were given.
+pub type Point = Vec<(String, String)>;
+
+/// Statistics of one run, as read from its summary.csv.
+pub type Summary = Vec<(String, f64)>;
+
+/// Every combination of the varied values.
+pub fn grid(vary: &[(String, Vec<String>)]) -> Vec<Point> {
+    let mut points: Vec<Point> = vec![Vec::new()];
+    for (key, values) in vary {
+        points = points.iter()
+            .flat_map(|p| values.iter().map(move |v| {
+                let mut q = p.clone();
+                q.push((key.clone(), v.clone()));
+                q
+            }))
+            .collect();
+    }
+    points
+}
+
+/// Directory name of a data point.
+pub fn label(point: &Point) -> String {
+    if point.is_empty() {
+        return String::from("default")
+    }
+    let parts: Vec<String> = point.iter().map(|(k, v)| format!("{}-{}", k, v)).collect();
+    parts.join("_")
+}
+
+/// The statistics a finished run exported to `dir`.
+pub fn read_summary(dir: &Path) -> Result<Summary, String> {
+    let path = dir.join("summary.csv");
+    let mut text = String::new();
+    File::open(&path).and_then(|mut f| f.read_to_string(&mut text))
+        .map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+    let mut lines = text.lines();
+    match (lines.next(), lines.next()) {
+        (Some(header), Some(row)) => header.split(',').zip(row.split(','))
+            .map(|(k, v)| v.parse().map(|v| (k.to_string(), v)).map_err(|_| format!("invalid value in {:?}: {}", path, v)))
+            .collect(),
+        _ => Err(format!("{:?} is incomplete", path))
+    }
+}
+
+/// Mean and half width of its 95% confidence interval, 0 for a single value.
+pub fn confidence(values: &[f64]) -> (f64, f64) {
+    let n = values.len();
+    if n == 0 {
+        return (0., 0.)
+    }
+    let mean = values.iter().sum::<f64>() / n as f64;
+    if n == 1 {
+        return (mean, 0.)
+    }
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+    let t = if n - 1 <= T_95.len() { T_95[n - 2] } else { 1.96 };
+    (mean, t * (variance / n as f64).sqrt())
+}
+
+/// One row per data point, with the mean and confidence interval of every statistic over its runs.
+pub fn table(results: &[(Point, Vec<Summary>)]) -> String {
+    let keys: Vec<String> = results.first().map_or(Vec::new(), |r| r.0.iter().map(|(k, _)| k.clone()).collect());
+    let stats: Vec<String> = results.iter().flat_map(|r| r.1.first())
+        .next().map_or(Vec::new(), |row| row.iter().map(|(k, _)| k.clone()).collect());
+
+    let mut header = keys.clone();
+    header.push(String::from("runs"));
+    for s in &stats {
+        header.push(format!("{}_mean", s));
+        header.push(format!("{}_ci95", s));
+    }
+    let mut out = header.join(",");
+    out.push('\n');
+    for (point, runs) in results {
+        let mut row: Vec<String> = point.iter().map(|(_, v)| v.clone()).collect();
+        row.push(runs.len().to_string());
+        for (i, _) in stats.iter().enumerate() {
+            let values: Vec<f64> = runs.iter().filter_map(|r| r.get(i).map(|&(_, v)| v)).collect();
+            let (mean, ci) = confidence(&values);
+            row.push(mean.to_string());
+            row.push(ci.to_string());
+        }
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confidence_uses_the_t_distribution() {
+        assert_eq!(confidence(&[]), (0., 0.));
+        assert_eq!(confidence(&[4.]), (4., 0.));
+        let (mean, half) = confidence(&[1., 2., 3.]);
+        assert_eq!(mean, 2.);
+        assert!((half - 4.303 * (1f64 / 3.).sqrt()).abs() < 1e-9);
+        // 30 degrees of freedom are the last in the table, the normal distribution after
+        let values: Vec<f64> = (0..31).map(|i| (i % 2) as f64).collect();
+        let (_, half) = confidence(&values);
+        let sd = (values.iter().map(|v| (v - 15. / 31.).powi(2)).sum::<f64>() / 30.).sqrt();
+        assert!((half - 2.042 * sd / 31f64.sqrt()).abs() < 1e-9);
+        let values: Vec<f64> = (0..100).map(|i| (i % 2) as f64).collect();
+        let (_, half) = confidence(&values);
+        let sd = (values.iter().map(|v| (v - 0.5).powi(2)).sum::<f64>() / 99.).sqrt();
+        assert!((half - 1.96 * sd / 10.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn the_grid_has_every_combination() {
+        let vary = vec![(String::from("nodes"), vec![String::from("10"), String::from("20")]),
+                        (String::from("loss"), vec![String::from("0"), String::from("0.1"), String::from("0.2")])];
+        let points = grid(&vary);
+        assert_eq!(points.len(), 6);
+        assert_eq!(label(&points[1]), "nodes-10_loss-0.1");
+        assert_eq!(label(&grid(&[])[0]), "default");
+    }
+
+    #[test]
+    fn settings_are_checked() {
+        let mut setting = Setting::default();
+        assert!(setting.apply("dimension", "3").is_ok());
+        assert_eq!(setting.node_config.nc.dimension, 3);
+        assert!(setting.apply("dimension", "0").is_err());
+        assert_eq!(setting.node_config.nc.dimension, 3);
+        assert!(setting.apply("dimension", "three").is_err());
+        assert!(setting.apply("colour", "3").is_err());
+    }
+
+    #[test]
+    fn variants_take_optional_numbers() {
+        let mut setting = Setting::default();
+        assert!(setting.apply("queue", "red:0.1").is_ok());
+        assert!(matches!(setting.node_config.queue.discipline, Discipline::Red { min, max, .. } if min == 0.1 && max == 0.75));
+        assert!(setting.apply("defence", "veracity:3").is_ok());
+        assert!(matches!(setting.node_config.defence, Defence::Veracity { witnesses: 3, .. }));
+        assert!(setting.apply("queue", "red:x").is_err());
+        assert!(setting.apply("queue", "blue").is_err());
+        assert!(setting.apply("baseline", "none").is_ok());
+        assert!(setting.node_config.baseline.is_none());
+        assert!(setting.apply("addressing", "hybrid:8").is_ok());
+        assert!(setting.apply("addressing", "hybrid:9").is_err());
+        assert!(matches!(setting.node_config.addressing, AddressScheme::Hybrid { depth: 8 }));
+    }
+}
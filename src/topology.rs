@@ -0,0 +1,227 @@
+use petgraph::stable_graph::StableDiGraph;
+use petgraph::graph::NodeIndex;
+use petgraph::algo::dijkstra;
+
+use rand::Rng;
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+use connection::Connection;
+
+// number of sources used to estimate path based statistics
+static PATH_SAMPLES: usize = 32;
+
+/// Summary of the generated world graph.
+///
+/// Path based statistics (diameter, average latency) are estimated from a
+/// random sample of sources, as all pairs is infeasible for the larger presets.
+#[derive(Clone, Debug)]
+pub struct TopologyReport {
+    pub node_count: usize,
+    pub link_count: usize,
+    pub components: usize,
+    pub largest_component: usize,
+    pub level_counts: Vec<usize>,
+    // index is the number of distinct neighbours, value is the number of nodes with that degree
+    pub degree_histogram: Vec<usize>,
+    pub mean_degree: f32,
+    pub diameter: f32,
+    pub hop_diameter: usize,
+    pub avg_path_latency: f32,
+    pub clustering: f32,
+}
+
+impl TopologyReport {
+    pub fn connected(&self) -> bool {
+        self.components <= 1
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("key,value\n");
+        out.push_str(&format!("nodes,{}\n", self.node_count));
+        out.push_str(&format!("links,{}\n", self.link_count));
+        out.push_str(&format!("components,{}\n", self.components));
+        out.push_str(&format!("largest_component,{}\n", self.largest_component));
+        for (level, count) in self.level_counts.iter().enumerate() {
+            out.push_str(&format!("level_{},{}\n", level, count));
+        }
+        for (degree, count) in self.degree_histogram.iter().enumerate() {
+            if *count > 0 {
+                out.push_str(&format!("degree_{},{}\n", degree, count));
+            }
+        }
+        out.push_str(&format!("mean_degree,{}\n", self.mean_degree));
+        out.push_str(&format!("diameter,{}\n", self.diameter));
+        out.push_str(&format!("hop_diameter,{}\n", self.hop_diameter));
+        out.push_str(&format!("avg_path_latency,{}\n", self.avg_path_latency));
+        out.push_str(&format!("clustering,{}\n", self.clustering));
+        out
+    }
+}
+
+impl fmt::Display for TopologyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "nodes: {}, links: {}", self.node_count, self.link_count)?;
+        writeln!(f, "components: {} (largest {})", self.components, self.largest_component)?;
+        writeln!(f, "nodes per level: {:?}", self.level_counts)?;
+        writeln!(f, "mean degree: {:.2}, max degree: {}", self.mean_degree, self.degree_histogram.len().saturating_sub(1))?;
+        writeln!(f, "diameter: {:.4}s ({} hops)", self.diameter, self.hop_diameter)?;
+        writeln!(f, "average shortest path latency: {:.4}s", self.avg_path_latency)?;
+        write!(f, "clustering coefficient: {:.4}", self.clustering)
+    }
+}
+
+/// Compute statistics over the world graph, `level` maps a node weight to its hierarchy level.
+///
+/// Path statistics are sampled from sources drawn with `rng`.
+pub fn analyse<N, F, R>(graph: &StableDiGraph<N, Connection>, level: F, rng: &mut R) -> TopologyReport
+    where F: Fn(&N) -> usize, R: Rng {
+    let nodes: Vec<NodeIndex> = graph.node_indices().collect();
+
+    let mut level_counts = Vec::new();
+    let mut degree_histogram = Vec::new();
+    // parallel edges to the same neighbour are one link
+    let mut link_count = 0;
+    for &n in &nodes {
+        let l = level(&graph[n]);
+        if level_counts.len() <= l {
+            level_counts.resize(l + 1, 0);
+        }
+        level_counts[l] += 1;
+
+        let d = graph.neighbors(n).filter(|m| *m != n).collect::<HashSet<_>>().len();
+        link_count += d;
+        if degree_histogram.len() <= d {
+            degree_histogram.resize(d + 1, 0);
+        }
+        degree_histogram[d] += 1;
+    }
+
+    let (components, largest_component) = components(graph);
+
+    let mut diameter = 0f32;
+    let mut hop_diameter = 0;
+    let mut total_latency = 0f64;
+    let mut paths = 0usize;
+
+    for _ in 0..PATH_SAMPLES.min(nodes.len()) {
+        let source = nodes[rng.gen_range(0, nodes.len())];
+        let scores = dijkstra(graph, source, None, |e| e.weight().latency);
+        for (&target, &latency) in &scores {
+            if target != source {
+                diameter = diameter.max(latency);
+                total_latency += latency as f64;
+                paths += 1;
+            }
+        }
+        hop_diameter = hop_diameter.max(eccentricity(graph, source));
+    }
+
+    TopologyReport {
+        node_count: nodes.len(),
+        link_count,
+        components,
+        largest_component,
+        level_counts,
+        degree_histogram,
+        mean_degree: if !nodes.is_empty() { link_count as f32 / nodes.len() as f32 } else { 0. },
+        diameter,
+        hop_diameter,
+        avg_path_latency: if paths > 0 { (total_latency / paths as f64) as f32 } else { 0. },
+        clustering: clustering(graph, &nodes),
+    }
+}
+
+// weakly connected components, returns (count, size of largest)
+fn components<N>(graph: &StableDiGraph<N, Connection>) -> (usize, usize) {
+    let mut seen = HashSet::new();
+    let mut count = 0;
+    let mut largest = 0;
+    for start in graph.node_indices() {
+        if !seen.insert(start) {
+            continue
+        }
+        count += 1;
+        let mut size = 0;
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        while let Some(n) = queue.pop_front() {
+            size += 1;
+            for m in graph.neighbors_undirected(n) {
+                if seen.insert(m) {
+                    queue.push_back(m);
+                }
+            }
+        }
+        largest = largest.max(size);
+    }
+    (count, largest)
+}
+
+// hop count to the furthest reachable node
+fn eccentricity<N>(graph: &StableDiGraph<N, Connection>, source: NodeIndex) -> usize {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    let mut furthest = 0;
+    seen.insert(source);
+    queue.push_back((source, 0));
+    while let Some((n, d)) = queue.pop_front() {
+        furthest = furthest.max(d);
+        for m in graph.neighbors(n) {
+            if seen.insert(m) {
+                queue.push_back((m, d + 1));
+            }
+        }
+    }
+    furthest
+}
+
+// average local clustering coefficient, treating links as undirected
+fn clustering<N>(graph: &StableDiGraph<N, Connection>, nodes: &Vec<NodeIndex>) -> f32 {
+    if nodes.is_empty() {
+        return 0.
+    }
+    let mut total = 0f64;
+    for &n in nodes {
+        let neighbours: HashSet<NodeIndex> = graph.neighbors_undirected(n).filter(|m| *m != n).collect();
+        let k = neighbours.len();
+        if k < 2 {
+            continue
+        }
+        // links are stored in both directions (and sometimes twice), so count unordered pairs
+        let mut links = HashSet::new();
+        for &a in &neighbours {
+            for b in graph.neighbors(a) {
+                if neighbours.contains(&b) && a != b {
+                    links.insert((a.min(b), a.max(b)));
+                }
+            }
+        }
+        total += links.len() as f64 / (k * (k - 1) / 2) as f64;
+    }
+    (total / nodes.len() as f64) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rng::SimRng;
+
+    #[test]
+    fn parallel_edges_count_once() {
+        let mut graph = StableDiGraph::new();
+        let n: Vec<NodeIndex> = (0..4).map(|i| graph.add_node(i)).collect();
+        let c = Connection { latency: 0.01, bandwidth: 1., packet_loss: 0. };
+        // a path 0 - 1 - 2 - 3 with 1 - 2 linked twice
+        for &(a, b) in &[(0, 1), (1, 2), (1, 2), (2, 3)] {
+            graph.add_edge(n[a], n[b], c.clone());
+            graph.add_edge(n[b], n[a], c.clone());
+        }
+        let report = analyse(&graph, |_| 0, &mut SimRng::derive(1, 0));
+        assert_eq!(report.degree_histogram, vec![0, 2, 2]);
+        assert_eq!(report.link_count, 6);
+        assert_eq!(report.mean_degree, 1.5);
+        assert_eq!(report.hop_diameter, 3);
+    }
+}
@@ -0,0 +1,439 @@
+//! Structured trace events.
+//!
+//! Nodes and the world record what they do as events tagged with the node,
+//! epoch, simulated time, subsystem and kind, plus the packet type and route
+//! where there is one. Every subsystem has its own level, all of them quiet
+//! unless set with `--trace`, so nothing is built or written by default. The
+//! events go to a `Tracer` service which appends them to the trace file, one
+//! JSON object per line with single letter keys, and `trace <file>` replays
+//! a file through a filter.
+//!
+//! Every packet carries a trace ID from its source, and every lookup gets one
+//! too. Hops record when the packet arrived and when it left, and the queries
+//! of a lookup name the lookup as their parent, so `trace <file> --journey
+//! <id>` can follow one packet, or one lookup with all its queries.
+
+use actix::*;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_json;
+
+use logging::Level;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Subsystem {
+    World,
+    Dht,
+    Nc,
+    Forward,
+}
+
+static SUBSYSTEMS: [Subsystem; 4] = [Subsystem::World, Subsystem::Dht, Subsystem::Nc, Subsystem::Forward];
+
+impl Subsystem {
+    pub fn parse(s: &str) -> Option<Subsystem> {
+        match s {
+            "world" => Some(Subsystem::World),
+            "dht" => Some(Subsystem::Dht),
+            "nc" => Some(Subsystem::Nc),
+            "forward" => Some(Subsystem::Forward),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Subsystem::World => "world",
+            Subsystem::Dht => "dht",
+            Subsystem::Nc => "nc",
+            Subsystem::Forward => "forward",
+        }
+    }
+}
+
+// by subsystem, shared by every arbiter thread
+static LEVELS: [AtomicUsize; 4] = [
+    AtomicUsize::new(Level::Quiet as usize),
+    AtomicUsize::new(Level::Quiet as usize),
+    AtomicUsize::new(Level::Quiet as usize),
+    AtomicUsize::new(Level::Quiet as usize),
+];
+
+/// `<level>` for every subsystem or `<subsystem>=<level>`, separated by commas.
+pub fn parse_levels(s: &str) -> Option<Vec<(Subsystem, Level)>> {
+    let mut levels = Vec::new();
+    for part in s.split(',') {
+        let mut kv = part.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some(l), None) => match Level::parse(l) {
+                Some(level) => levels.extend(SUBSYSTEMS.iter().map(|&s| (s, level))),
+                None => return None
+            },
+            (Some(s), Some(l)) => match (Subsystem::parse(s), Level::parse(l)) {
+                (Some(s), Some(l)) => levels.push((s, l)),
+                _ => return None
+            },
+            _ => return None
+        }
+    }
+    Some(levels)
+}
+
+pub fn set_levels(levels: &[(Subsystem, Level)]) {
+    for &(s, l) in levels {
+        LEVELS[s as usize].store(l as usize, Ordering::Relaxed);
+    }
+}
+
+pub fn enabled(subsystem: Subsystem, level: Level) -> bool {
+    LEVELS[subsystem as usize].load(Ordering::Relaxed) >= level as usize
+}
+
+/// Whether any subsystem traces at all, the trace file is only created then.
+pub fn any_enabled() -> bool {
+    SUBSYSTEMS.iter().any(|&s| enabled(s, Level::Info))
+}
+
+/// Record an event if its subsystem traces at the level, the event is only built then.
+macro_rules! trace {
+    ($subsystem:ident, $level:ident, $event:expr) => {
+        if ::trace::enabled(::trace::Subsystem::$subsystem, ::logging::Level::$level) {
+            ::trace::record(::trace::Subsystem::$subsystem, $event);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event {
+    // 0 for the world
+    #[serde(rename = "n")]
+    pub node: u64,
+    #[serde(rename = "e")]
+    pub epoch: u64,
+    // simulated time
+    #[serde(rename = "t")]
+    pub time: f32,
+    #[serde(rename = "s")]
+    pub subsystem: Subsystem,
+    #[serde(rename = "k")]
+    pub kind: String,
+    #[serde(rename = "p", default, skip_serializing_if = "Option::is_none")]
+    pub packet: Option<String>,
+    // node IDs in the order travelled
+    #[serde(rename = "r", default, skip_serializing_if = "Vec::is_empty")]
+    pub route: Vec<u64>,
+    // ID a lookup is after
+    #[serde(rename = "g", default, skip_serializing_if = "Option::is_none")]
+    pub goal: Option<u64>,
+    // trace ID of the packet or lookup, and of the lookup a packet was sent for
+    #[serde(rename = "i", default, skip_serializing_if = "Option::is_none")]
+    pub trace: Option<u64>,
+    #[serde(rename = "u", default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<u64>,
+    // simulated time a packet left the node, `time` being when it arrived
+    #[serde(rename = "l", default, skip_serializing_if = "Option::is_none")]
+    pub departed: Option<f32>,
+    #[serde(rename = "d", default, skip_serializing_if = "String::is_empty")]
+    pub detail: String,
+}
+
+message!(Event);
+
+impl Event {
+    pub fn new(kind: &str, node: u64, epoch: u64, time: f32) -> Event {
+        Event {
+            node,
+            epoch,
+            time,
+            subsystem: Subsystem::World,
+            kind: String::from(kind),
+            packet: None,
+            route: Vec::new(),
+            goal: None,
+            trace: None,
+            parent: None,
+            departed: None,
+            detail: String::new(),
+        }
+    }
+
+    pub fn packet(mut self, kind: &str) -> Event {
+        self.packet = Some(String::from(kind));
+        self
+    }
+
+    pub fn route(mut self, route: &[u64]) -> Event {
+        self.route = route.to_vec();
+        self
+    }
+
+    pub fn goal(mut self, goal: u64) -> Event {
+        self.goal = Some(goal);
+        self
+    }
+
+    pub fn at(mut self, time: f32) -> Event {
+        self.time = time;
+        self
+    }
+
+    pub fn trace(mut self, trace: u64) -> Event {
+        self.trace = Some(trace);
+        self
+    }
+
+    pub fn parent(mut self, parent: u64) -> Event {
+        self.parent = Some(parent);
+        self
+    }
+
+    pub fn departed(mut self, time: f32) -> Event {
+        self.departed = Some(time);
+        self
+    }
+
+    pub fn detail(mut self, detail: String) -> Event {
+        self.detail = detail;
+        self
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "epoch {} t {:.4} node {} {} {}", self.epoch, self.time, self.node, self.subsystem.name(), self.kind)?;
+        if let Some(ref p) = self.packet {
+            write!(f, " [{}]", p)?;
+        }
+        if let Some(t) = self.trace {
+            write!(f, " #{}", t)?;
+        }
+        if let Some(p) = self.parent {
+            write!(f, " for #{}", p)?;
+        }
+        if let Some(d) = self.departed {
+            write!(f, " left at {:.4}", d)?;
+        }
+        if let Some(g) = self.goal {
+            write!(f, " goal {}", g)?;
+        }
+        if !self.route.is_empty() {
+            let hops: Vec<String> = self.route.iter().map(|h| h.to_string()).collect();
+            write!(f, " route {}", hops.join(" > "))?;
+        }
+        if !self.detail.is_empty() {
+            write!(f, " ({})", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn record(subsystem: Subsystem, event: Event) {
+    Arbiter::system_registry().get::<Tracer>().send(Event { subsystem, ..event });
+}
+
+/// Appends the events of every node to the trace file.
+#[derive(Default)]
+pub struct Tracer {
+    out: Option<BufWriter<File>>,
+}
+
+impl Actor for Tracer {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Tracer {}
+
+impl SystemService for Tracer {
+    fn service_started(&mut self, _ctx: &mut Context<Self>) {}
+}
+
+// sent by main before the world is configured, events before it are dropped
+pub struct Open {
+    pub path: String,
+}
+
+message!(Open);
+
+impl Handler<Open> for Tracer {
+    fn handle(&mut self, msg: Open, _ctx: &mut Context<Self>) -> Response<Self, Open> {
+        if let Some(dir) = Path::new(&msg.path).parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        match File::create(&msg.path) {
+            Ok(f) => self.out = Some(BufWriter::new(f)),
+            Err(e) => eprintln!("cannot write trace {}: {}", msg.path, e)
+        }
+        Self::reply(())
+    }
+}
+
+impl Handler<Event> for Tracer {
+    fn handle(&mut self, msg: Event, _ctx: &mut Context<Self>) -> Response<Self, Event> {
+        if let Some(ref mut out) = self.out {
+            let line = serde_json::to_string(&msg).map_err(|e| e.to_string())
+                .and_then(|l| writeln!(out, "{}", l).map_err(|e| e.to_string()));
+            if let Err(e) = line {
+                eprintln!("cannot write trace event: {}", e);
+            }
+        }
+        Self::reply(())
+    }
+}
+
+// sent by the world before the system exits
+pub struct Flush;
+
+message!(Flush);
+
+impl Handler<Flush> for Tracer {
+    fn handle(&mut self, _msg: Flush, _ctx: &mut Context<Self>) -> Response<Self, Flush> {
+        if let Some(ref mut out) = self.out {
+            let _ = out.flush();
+        }
+        Self::reply(())
+    }
+}
+
+/// Which events of a trace file to replay, unset fields match everything.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    pub node: Option<u64>,
+    pub subsystem: Option<Subsystem>,
+    pub kind: Option<String>,
+    pub packet: Option<String>,
+    pub goal: Option<u64>,
+    // first and last epoch
+    pub epochs: Option<(u64, u64)>,
+}
+
+impl Filter {
+    pub fn matches(&self, e: &Event) -> bool {
+        self.node.is_none_or(|n| e.node == n || e.route.contains(&n))
+            && self.subsystem.is_none_or(|s| e.subsystem == s)
+            && self.kind.as_ref().is_none_or(|k| &e.kind == k)
+            && self.packet.as_ref().is_none_or(|p| e.packet.as_ref() == Some(p))
+            && self.goal.is_none_or(|g| e.goal == Some(g))
+            && self.epochs.is_none_or(|(a, b)| e.epoch >= a && e.epoch <= b)
+    }
+}
+
+// events matching `keep` in the order they were written, an event caused by another is always written after it
+fn read<F: Fn(&Event) -> bool>(path: &str, keep: F) -> Result<Vec<Event>, String> {
+    let f = File::open(path).map_err(|e| format!("cannot read trace {}: {}", path, e))?;
+    let mut events = Vec::new();
+    for (n, line) in BufReader::new(f).lines().enumerate() {
+        let line = line.map_err(|e| format!("cannot read trace {}: {}", path, e))?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let e: Event = serde_json::from_str(&line).map_err(|e| format!("{}:{}: {}", path, n + 1, e))?;
+        if keep(&e) {
+            events.push(e);
+        }
+    }
+    Ok(events)
+}
+
+/// Events of a trace file matching `filter`, in the order of simulated time.
+pub fn load(path: &str, filter: &Filter) -> Result<Vec<Event>, String> {
+    let mut events = read(path, |e| filter.matches(e))?;
+    // threads write in the order events arrive, which only roughly follows time
+    events.sort_by(|a, b| (a.epoch, a.time).partial_cmp(&(b.epoch, b.time)).unwrap_or(::std::cmp::Ordering::Equal));
+    Ok(events)
+}
+
+/// Print the events of a trace file matching `filter`.
+pub fn replay(path: &str, filter: &Filter) -> Result<(), String> {
+    let events = load(path, filter)?;
+    for e in &events {
+        println!("{}", e);
+    }
+    println!("{} events", events.len());
+    Ok(())
+}
+
+/// Events of packet or lookup `id` and of every packet sent for it, in the order they happened.
+pub fn journey(path: &str, id: u64) -> Result<Vec<Event>, String> {
+    let children: HashSet<u64> = read(path, |e| e.parent == Some(id))?.into_iter().filter_map(|e| e.trace).collect();
+    // replies travel back untimed, so a lookup's later steps carry the time it started, the written order is kept
+    read(path, |e| e.trace == Some(id) || e.trace.is_some_and(|t| children.contains(&t)))
+}
+
+/// Print the journey of packet or lookup `id` as a timeline, the packets sent for a lookup indented.
+pub fn print_journey(path: &str, id: u64) -> Result<(), String> {
+    let events = journey(path, id)?;
+    if events.is_empty() {
+        return Err(format!("no events of #{} in {}", id, path))
+    }
+    let start = events[0].time;
+    let mut packets = HashSet::new();
+    let mut hops = 0;
+    for e in &events {
+        let indent = if e.trace == Some(id) { "" } else { "  " };
+        println!("{:+10.4}s {}{}", e.time - start, indent, e);
+        if e.kind == "hop" {
+            hops += 1;
+        }
+        if e.trace != Some(id) {
+            packets.extend(e.trace);
+        }
+    }
+    let end = events.iter().map(|e| e.departed.unwrap_or(e.time)).fold(start, f32::max);
+    println!("#{}: {} events, {} packets sent for it, {} hops, {:.4}s of simulated time", id, events.len(), packets.len(), hops, end - start);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn write_trace(name: &str, events: &[Event]) -> String {
+        let path = env::temp_dir().join(format!("{}-{}.jsonl", name, process::id())).to_string_lossy().into_owned();
+        let mut f = File::create(&path).unwrap();
+        for e in events {
+            writeln!(f, "{}", serde_json::to_string(e).unwrap()).unwrap();
+        }
+        path
+    }
+
+    fn lookup_events() -> Vec<Event> {
+        let mut query = Event::new("lookup_query", 1, 3, 0.5).trace(11).parent(10).packet("dht_lookup").route(&[1, 2]);
+        query.subsystem = Subsystem::Dht;
+        let mut hop = Event::new("hop", 2, 3, 0.6).trace(11).packet("dht_lookup").route(&[1, 2]).departed(0.7);
+        hop.subsystem = Subsystem::Forward;
+        let other = Event::new("hop", 5, 4, 0.2).trace(12).packet("data");
+        vec![Event::new("lookup", 1, 3, 0.5).trace(10).goal(7), other, query, hop]
+    }
+
+    #[test]
+    fn filters_match_every_set_field() {
+        let path = write_trace("filter", &lookup_events());
+        let hops = load(&path, &Filter { kind: Some(String::from("hop")), ..Filter::default() }).unwrap();
+        // in the order of simulated time
+        assert_eq!(hops.iter().map(|e| e.node).collect::<Vec<_>>(), vec![2, 5]);
+        let through = load(&path, &Filter { node: Some(2), epochs: Some((3, 3)), ..Filter::default() }).unwrap();
+        assert_eq!(through.len(), 2);
+        let dht = load(&path, &Filter { subsystem: Some(Subsystem::Dht), goal: Some(7), ..Filter::default() }).unwrap();
+        assert!(dht.is_empty());
+        assert_eq!(load(&path, &Filter::default()).unwrap().len(), 4);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_journey_follows_the_packets_sent_for_it() {
+        let path = write_trace("journey", &lookup_events());
+        let events = journey(&path, 10).unwrap();
+        assert_eq!(events.iter().map(|e| e.kind.as_str()).collect::<Vec<_>>(), vec!["lookup", "lookup_query", "hop"]);
+        assert_eq!(journey(&path, 12).unwrap().len(), 1);
+        assert!(journey(&path, 13).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
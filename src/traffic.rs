@@ -0,0 +1,306 @@
+//! Application traffic between nodes.
+//!
+//! The world picks flows according to a workload model and asks the source to
+//! start them, the source resolves the destination through the DHT and then
+//! sends the flow's packets along the route it found.
+
+use actix::*;
+
+use rand::Rng;
+
+use std::collections::HashMap;
+
+use node::Node;
+use packet::*;
+use world;
+
+// upper bound on heavy tailed flow sizes, in packets
+static MAX_FLOW_PACKETS: usize = 1000;
+
+/// How sources and destinations are chosen.
+#[derive(Clone, Debug)]
+pub enum Workload {
+    /// Uniformly random pairs.
+    Uniform,
+    /// Both ends weighted by the population behind the node.
+    Gravity,
+    /// A fraction of flows go to a small set of popular destinations.
+    HotSpot { spots: usize, fraction: f32 },
+}
+
+#[derive(Clone, Debug)]
+pub struct TrafficConfig {
+    pub workload: Workload,
+    pub flows_per_epoch: usize,
+    // flow sizes are pareto distributed with this mean and shape
+    pub mean_flow_packets: f32,
+    pub pareto_shape: f32,
+    pub packet_size: u32,
+}
+
+impl Default for TrafficConfig {
+    fn default() -> TrafficConfig {
+        TrafficConfig {
+            workload: Workload::Uniform,
+            flows_per_epoch: 4,
+            mean_flow_packets: 10.,
+            pareto_shape: 1.5,
+            packet_size: 1000,
+        }
+    }
+}
+
+/// A candidate end point, weighted for the gravity model.
+pub struct Endpoint {
+    pub id: u64,
+    pub mass: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Flow {
+    pub id: u64,
+    pub from: u64,
+    pub des: u64,
+    pub packets: usize,
+}
+
+pub struct TrafficGenerator {
+    pub config: TrafficConfig,
+    hot_spots: Vec<u64>,
+    // ID of the next flow, carried over by checkpoints
+    pub next_flow: u64,
+}
+
+impl TrafficGenerator {
+    pub fn new(config: TrafficConfig) -> TrafficGenerator {
+        TrafficGenerator { config, hot_spots: Vec::new(), next_flow: 0 }
+    }
+
+    fn pick<R: Rng>(rng: &mut R, endpoints: &[Endpoint], weighted: bool) -> u64 {
+        if !weighted {
+            return endpoints[rng.gen_range(0, endpoints.len())].id
+        }
+        let total: f32 = endpoints.iter().map(|e| e.mass).sum();
+        let mut x = rng.next_f32() * total;
+        for e in endpoints {
+            if x < e.mass {
+                return e.id
+            }
+            x -= e.mass;
+        }
+        endpoints[endpoints.len() - 1].id
+    }
+
+    // pareto distributed, capped to keep single flows from dominating a run
+    fn flow_size<R: Rng>(&self, rng: &mut R) -> usize {
+        let a = self.config.pareto_shape;
+        let scale = self.config.mean_flow_packets * (a - 1.) / a;
+        let u = 1. - rng.next_f32();
+        ((scale / u.powf(1. / a)).ceil() as usize).max(1).min(MAX_FLOW_PACKETS)
+    }
+
+    /// Flows to start this epoch.
+    pub fn generate<R: Rng>(&mut self, endpoints: &[Endpoint], rng: &mut R) -> Vec<Flow> {
+        let mut flows = Vec::new();
+        if endpoints.len() < 2 {
+            return flows
+        }
+
+        if let Workload::HotSpot { spots, .. } = self.config.workload {
+            // hot spots are picked among the nodes active when traffic starts
+            while self.hot_spots.len() < spots.min(endpoints.len()) {
+                let s = TrafficGenerator::pick(rng, endpoints, false);
+                if !self.hot_spots.contains(&s) {
+                    self.hot_spots.push(s);
+                }
+            }
+        }
+
+        for _ in 0..self.config.flows_per_epoch {
+            let (from, des) = match self.config.workload {
+                Workload::Uniform => (TrafficGenerator::pick(rng, endpoints, false),
+                                      TrafficGenerator::pick(rng, endpoints, false)),
+                Workload::Gravity => (TrafficGenerator::pick(rng, endpoints, true),
+                                      TrafficGenerator::pick(rng, endpoints, true)),
+                Workload::HotSpot { fraction, .. } => {
+                    let from = TrafficGenerator::pick(rng, endpoints, false);
+                    if rng.next_f32() < fraction {
+                        (from, self.hot_spots[rng.gen_range(0, self.hot_spots.len())])
+                    } else {
+                        (from, TrafficGenerator::pick(rng, endpoints, false))
+                    }
+                }
+            };
+            if from == des {
+                continue
+            }
+            let packets = self.flow_size(rng);
+            flows.push(Flow { id: self.next_flow, from, des, packets });
+            self.next_flow += 1;
+        }
+        flows
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum FlowEvent {
+    Resolved,
+    Unresolved,
+    Delivered,
+    Lost,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct FlowProgress {
+    packets: usize,
+    delivered: usize,
+    lost: usize,
+}
+
+/// Delivery statistics for user traffic, latency and stretch are in the world's stretch table.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct TrafficStats {
+    flows: HashMap<u64, FlowProgress>,
+    pub started: u64,
+    pub resolved: u64,
+    pub unresolved: u64,
+    pub sent: u64,
+    pub delivered: u64,
+    pub lost: u64,
+    pub completed: u64,
+}
+
+impl TrafficStats {
+    pub fn start(&mut self, flow: &Flow) {
+        self.started += 1;
+        self.flows.insert(flow.id, FlowProgress { packets: flow.packets, ..FlowProgress::default() });
+    }
+
+    pub fn record(&mut self, flow: u64, event: FlowEvent) {
+        match event {
+            FlowEvent::Resolved => {
+                self.resolved += 1;
+                self.sent += self.flows.get(&flow).map_or(0, |f| f.packets as u64);
+            }
+            FlowEvent::Unresolved => {
+                self.unresolved += 1;
+                self.flows.remove(&flow);
+            }
+            FlowEvent::Delivered => {
+                self.delivered += 1;
+                if let Some(f) = self.flows.get_mut(&flow) {
+                    f.delivered += 1;
+                }
+            }
+            FlowEvent::Lost => {
+                self.lost += 1;
+                if let Some(f) = self.flows.get_mut(&flow) {
+                    f.lost += 1;
+                }
+            }
+        }
+        let done = self.flows.get(&flow).map(|f| (f.delivered + f.lost == f.packets, f.lost == 0));
+        if let Some((true, complete)) = done {
+            if complete {
+                self.completed += 1;
+            }
+            self.flows.remove(&flow);
+        }
+    }
+
+    pub fn delivery_ratio(&self) -> f32 {
+        if self.sent == 0 { 0. } else { self.delivered as f32 / self.sent as f32 }
+    }
+
+    pub fn summary(&self) -> String {
+        format!("traffic: {} flows, {} resolved, {} unresolved, {} completed, {}/{} packets delivered ({:.3}), {} lost",
+                self.started, self.resolved, self.unresolved, self.completed,
+                self.delivered, self.sent, self.delivery_ratio(), self.lost)
+    }
+
+    pub fn to_csv(&self) -> String {
+        format!("flows,resolved,unresolved,completed,sent,delivered,lost,delivery_ratio\n{},{},{},{},{},{},{},{}\n",
+                self.started, self.resolved, self.unresolved, self.completed,
+                self.sent, self.delivered, self.lost, self.delivery_ratio())
+    }
+}
+
+// sent by the world to the source of a new flow
+pub struct StartFlow {
+    pub flow: u64,
+    pub des: u64,
+    pub packets: usize,
+    pub size: u32,
+}
+
+message!(StartFlow);
+
+impl Handler<StartFlow> for Node {
+    fn handle(&mut self, msg: StartFlow, ctx: &mut Context<Self>) -> Response<Self, StartFlow> {
+        let StartFlow { flow, des, packets, size } = msg;
+        if self.config.private_routes.is_some() {
+            self.send_private(ctx, flow, des, packets, size);
+            return Self::reply(())
+        }
+        ctx.spawn(self.dht_lookup(des, None, false).then(move |res, actor, ctx| {
+            match res.ok().and_then(|n| actor.source_route(des, n.route)) {
+                Some(route) => {
+                    actor.world.send(world::FlowReport { flow, event: FlowEvent::Resolved });
+                    for seq in 0..packets {
+                        actor.send_data(ctx, flow, seq as u32, size, des, route.clone());
+                    }
+                }
+                None => actor.world.send(world::FlowReport { flow, event: FlowEvent::Unresolved })
+            }
+            fut::ok::<(), (), Node>(())
+        }));
+        Self::reply(())
+    }
+}
+
+impl Node {
+    fn send_data(&mut self, ctx: &mut Context<Self>, flow: u64, seq: u32, size: u32, des: u64, route: Vec<u64>) {
+        let f = self.send_packet(Packet {
+            from: self.id,
+            des,
+            route,
+            data: Data { flow, seq, size },
+            hops: Vec::new(),
+            sent_at: 0.,
+            elapsed: 0.,
+            trace: 0,
+        });
+        ctx.spawn(ActorFuture::then(f, move |res, actor: &mut Node, _ctx| {
+            let event = match res {
+                Ok(Ok(_)) => FlowEvent::Delivered,
+                _ => FlowEvent::Lost
+            };
+            actor.world.send(world::FlowReport { flow, event });
+            fut::ok::<(), (), Node>(())
+        }));
+    }
+}
+
+/// User data packet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Data {
+    pub flow: u64,
+    pub seq: u32,
+    pub size: u32,
+}
+
+message!(Data);
+
+impl PacketData for Data {
+    fn kind() -> &'static str {
+        "data"
+    }
+
+    fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    fn process(_packet: &Packet<Self>, _node: &mut Node) -> Response<Node, Packet<Self>> {
+        Node::reply(())
+    }
+}
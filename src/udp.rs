@@ -0,0 +1,394 @@
+//! Nodes as processes of their own, exchanging UDP datagrams.
+//!
+//! `udp --topology <file>` starts a process for every node of a topology,
+//! each running a world with just that node and forwarders for its neighbours,
+//! bound to the node's port on localhost. A forwarder sends the packets it gets
+//! to the neighbour's port as a `codec::Frame` and the reply comes back the
+//! same way. Links are emulated by the sender, which holds every datagram
+//! back for the delay of its link and drops it with the link's loss rate, so
+//! the nodes' own link model only queues. A request left without a reply for
+//! `REPLY_TIMEOUT` fails like a lost packet.
+//!
+//! The processes do not wait for each other, an epoch lasts its length in
+//! real seconds. Packets still carry the latency the link model gives them,
+//! but NC takes half the round trip of its probes, timed from the send to the
+//! reply, so it measures the emulated links and the processes alike.
+//! `generate` exports the topology of the graph it builds in this format:
+//!
+//! ```text
+//! # node <index> <port> <level> [<x> <y>]
+//! node 0 40000 0
+//! node 1 40001 1
+//! # link <a> <b> <delay in seconds> <loss> [<bandwidth>]
+//! link 0 1 0.012 0.01
+//! ```
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::process::{self, Child};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::fmt::Debug;
+
+use actix::*;
+
+use rand::Rng;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use cli::Options;
+use codec::{self, Forwarder, Frame, Waiting};
+use connection::Connection;
+use packet::{Packet, PacketData};
+use rng::SimRng;
+use scenario::number;
+use shard;
+use world::{self, World};
+
+/// Port of node 0 in exported topologies, node `i` listens on the next `i`th.
+pub static BASE_PORT: u16 = 40000;
+static REPLY_TIMEOUT: u64 = 2;
+// largest UDP payload over IPv4
+static MAX_DATAGRAM: usize = 65507;
+// how often the receiver gives up on requests which timed out
+static RECEIVE_TIMEOUT_MS: u64 = 100;
+// the emulated loss draws from its own stream for every node
+static LOSS_SEED: u64 = 0x6c6f_7373_0000_0000;
+
+#[derive(Clone, Debug)]
+pub struct TopologyNode {
+    pub port: u16,
+    pub level: usize,
+    pub position: [f32; 2],
+}
+
+/// Nodes by index, and the links between them.
+#[derive(Clone, Debug, Default)]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+    // both directions of a link behave alike
+    pub links: Vec<(usize, usize, Connection)>,
+}
+
+impl Topology {
+    pub fn parse(text: &str) -> Result<Topology, String> {
+        let mut nodes: HashMap<usize, TopologyNode> = HashMap::new();
+        let mut links = Vec::new();
+        for (i, raw) in text.lines().enumerate() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.first() {
+                None => continue,
+                Some(&"node") => {
+                    let position = if words.len() > 4 { [number(&words, 4, i + 1)?, number(&words, 5, i + 1)?] } else { [0., 0.] };
+                    let node = TopologyNode { port: number(&words, 2, i + 1)?, level: number(&words, 3, i + 1)?, position };
+                    if nodes.insert(number(&words, 1, i + 1)?, node).is_some() {
+                        return Err(format!("line {}: node {} given twice", i + 1, words[1]))
+                    }
+                }
+                Some(&"link") => {
+                    let bandwidth = if words.len() > 5 { number(&words, 5, i + 1)? } else { 10000. };
+                    let connection = Connection { latency: number(&words, 3, i + 1)?, packet_loss: number(&words, 4, i + 1)?, bandwidth };
+                    links.push((number(&words, 1, i + 1)?, number(&words, 2, i + 1)?, connection));
+                }
+                Some(w) => return Err(format!("line {}: expected node or link, got {}", i + 1, w))
+            }
+        }
+        let nodes = (0..nodes.len())
+            .map(|i| nodes.remove(&i).ok_or_else(|| format!("nodes are not numbered from 0, {} is missing", i)))
+            .collect::<Result<Vec<TopologyNode>, String>>()?;
+        if let Some(&(a, b, _)) = links.iter().find(|&&(a, b, _)| a >= nodes.len() || b >= nodes.len() || a == b) {
+            return Err(format!("invalid link between {} and {}", a, b))
+        }
+        Ok(Topology { nodes, links })
+    }
+
+    pub fn load(path: &str) -> Result<Topology, String> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| format!("cannot read topology {}: {}", path, e))?;
+        Topology::parse(&text)
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::from("# node <index> <port> <level> <x> <y>\n");
+        for (i, n) in self.nodes.iter().enumerate() {
+            out.push_str(&format!("node {} {} {} {} {}\n", i, n.port, n.level, n.position[0], n.position[1]));
+        }
+        out.push_str("# link <a> <b> <delay> <loss> <bandwidth>\n");
+        for &(a, b, ref c) in &self.links {
+            out.push_str(&format!("link {} {} {} {} {}\n", a, b, c.latency, c.packet_loss, c.bandwidth));
+        }
+        out
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Datagram {
+    // index of the sending node
+    from: usize,
+    reply: bool,
+    frame: Frame,
+}
+
+struct Peer {
+    address: SocketAddr,
+    delay: Duration,
+    loss: f32,
+}
+
+fn seconds(s: f32) -> Duration {
+    Duration::new(s as u64, (s.fract() * 1e9) as u32)
+}
+
+/// Socket of the node a process runs, shared by the world and the forwarders of its neighbours.
+#[derive(Clone)]
+pub struct Socket {
+    pub index: usize,
+    socket: Arc<UdpSocket>,
+    peers: Arc<HashMap<usize, Peer>>,
+    waiting: Waiting,
+    // datagrams held back until their link delay has passed
+    delayed: mpsc::Sender<(Instant, SocketAddr, Vec<u8>)>,
+    rng: Arc<Mutex<SimRng>>,
+}
+
+impl Socket {
+    /// Bind the port of node `index`, its neighbours are reached on theirs.
+    pub fn bind(index: usize, topology: &Topology, seed: u64) -> Result<Socket, String> {
+        let own = topology.nodes.get(index).ok_or_else(|| format!("no node {} in the topology", index))?;
+        let socket = UdpSocket::bind(("127.0.0.1", own.port)).map_err(|e| format!("cannot bind port {}: {}", own.port, e))?;
+        socket.set_read_timeout(Some(Duration::from_millis(RECEIVE_TIMEOUT_MS))).map_err(|e| e.to_string())?;
+        let mut peers = HashMap::new();
+        for &(a, b, ref c) in &topology.links {
+            let other = if a == index { b } else if b == index { a } else { continue };
+            let address = SocketAddr::from(([127, 0, 0, 1], topology.nodes[other].port));
+            peers.insert(other, Peer { address, delay: seconds(c.latency), loss: c.packet_loss });
+        }
+        let socket = Arc::new(socket);
+        let (delayed, rx) = mpsc::channel();
+        let sender = socket.clone();
+        thread::spawn(move || delay_line(sender, rx));
+        Ok(Socket {
+            index,
+            socket,
+            peers: Arc::new(peers),
+            waiting: Waiting::default(),
+            delayed,
+            rng: Arc::new(Mutex::new(SimRng::derive(seed ^ LOSS_SEED, index as u64))),
+        })
+    }
+
+    // send over the emulated link to neighbour `to`, false if there is none
+    fn emit(&self, to: usize, datagram: &Datagram) -> bool {
+        let peer = match self.peers.get(&to) {
+            Some(p) => p,
+            None => return false
+        };
+        if self.rng.lock().unwrap().next_f32() < peer.loss {
+            // lost on the way, the sender finds out when it times out
+            return true
+        }
+        match serde_json::to_vec(datagram) {
+            Ok(ref bytes) if bytes.len() > MAX_DATAGRAM => {
+                eprintln!("node {} cannot send {} bytes to node {}, a datagram holds {}", self.index, bytes.len(), to, MAX_DATAGRAM);
+                false
+            }
+            Ok(bytes) => self.delayed.send((Instant::now() + peer.delay, peer.address, bytes)).is_ok(),
+            Err(e) => {
+                eprintln!("cannot encode {:?}: {}", datagram, e);
+                false
+            }
+        }
+    }
+
+    /// Hand a packet to the process of neighbour `to`, it resolves with the reply that comes back.
+    pub fn forward<T>(&self, to: usize, packet: Packet<T>) -> Response<Forwarder, Packet<T>>
+        where T: PacketData + Clone + Send + ResponseType + Serialize + 'static + Debug,
+              T::Item: Send + DeserializeOwned, T::Error: Send + Default + DeserializeOwned {
+        let (id, reply) = self.waiting.register();
+        let sent = Frame::request(id, to, &packet).map(|frame| self.emit(to, &Datagram { from: self.index, reply: false, frame }));
+        match sent {
+            Ok(true) => codec::await_reply(reply),
+            Ok(false) => {
+                self.waiting.cancel(id);
+                Forwarder::reply_error(T::Error::default())
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                self.waiting.cancel(id);
+                Forwarder::reply_error(T::Error::default())
+            }
+        }
+    }
+
+    /// Answer a request from neighbour `to`.
+    pub fn reply(&self, to: usize, frame: Frame) {
+        self.emit(to, &Datagram { from: self.index, reply: true, frame });
+    }
+
+    /// Receive datagrams on another thread, requests go to the world.
+    pub fn listen(&self, world: SyncAddress<World>) {
+        let socket = self.clone();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                match socket.socket.recv_from(&mut buf) {
+                    Ok((n, _)) => match serde_json::from_slice::<Datagram>(&buf[..n]) {
+                        Ok(Datagram { reply: true, frame, .. }) => socket.waiting.resolve(frame.id, frame.body),
+                        Ok(Datagram { from, frame, .. }) => world.send(world::UdpRequest { from, frame }),
+                        Err(e) => eprintln!("node {} cannot decode a datagram: {}", socket.index, e),
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                    Err(e) => {
+                        eprintln!("node {} cannot receive: {}", socket.index, e);
+                        process::exit(1);
+                    }
+                }
+                socket.waiting.expire(Duration::from_secs(REPLY_TIMEOUT));
+            }
+        });
+    }
+}
+
+// send every datagram once it is due
+fn delay_line(socket: Arc<UdpSocket>, rx: mpsc::Receiver<(Instant, SocketAddr, Vec<u8>)>) {
+    // due times, the sequence number keeps datagrams due at the same time in order
+    let mut queue: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+    let mut datagrams: HashMap<u64, (SocketAddr, Vec<u8>)> = HashMap::new();
+    let mut seq = 0u64;
+    loop {
+        let now = Instant::now();
+        let next = match queue.peek().map(|r| r.0) {
+            Some((due, n)) if due <= now => {
+                queue.pop();
+                if let Some((address, bytes)) = datagrams.remove(&n) {
+                    // the request times out like a lost one
+                    if let Err(e) = socket.send_to(&bytes, address) {
+                        eprintln!("cannot send to {}: {}", address, e);
+                    }
+                }
+                continue
+            }
+            Some((due, _)) => rx.recv_timeout(due - now).map_err(|e| e == mpsc::RecvTimeoutError::Disconnected),
+            None => rx.recv().map_err(|_| true)
+        };
+        match next {
+            Ok((due, address, bytes)) => {
+                queue.push(Reverse((due, seq)));
+                datagrams.insert(seq, (address, bytes));
+                seq += 1;
+            }
+            Err(true) => return,
+            Err(false) => {}
+        }
+    }
+}
+
+fn node_dir(dir: &str, node: usize) -> String {
+    Path::new(dir).join(format!("node-{}", node)).to_string_lossy().into_owned()
+}
+
+/// Start a process for every node of the topology running `args`, and wait for them.
+pub fn launch(options: &Options, args: &[String]) -> i32 {
+    let topology = match options.topology.as_ref().map(|t| Topology::load(t)) {
+        Some(Ok(t)) => t,
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return 2
+        }
+        None => return 2
+    };
+    let dir = options.output_dir.clone().unwrap_or(String::from("udp"));
+    let exe = match ::std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            eprintln!("cannot start nodes: {}", e);
+            return 1
+        }
+    };
+    let mut children: Vec<Child> = Vec::new();
+    for i in 0..topology.nodes.len() {
+        let child = process::Command::new(&exe).args(args)
+            .arg("--node").arg(i.to_string())
+            .arg("--output").arg(node_dir(&dir, i))
+            .spawn();
+        match child {
+            Ok(c) => children.push(c),
+            Err(e) => {
+                eprintln!("cannot start node {}: {}", i, e);
+                for c in &mut children {
+                    let _ = c.kill();
+                }
+                return 1
+            }
+        }
+    }
+    info!("started {} node processes on ports {} to {}", children.len(),
+          topology.nodes.iter().map(|n| n.port).min().unwrap_or(0), topology.nodes.iter().map(|n| n.port).max().unwrap_or(0));
+
+    let mut failed = 0;
+    for (i, c) in children.iter_mut().enumerate() {
+        if !c.wait().map(|s| s.success()).unwrap_or(false) {
+            eprintln!("node {} failed", i);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return 1
+    }
+    let dirs: Vec<String> = (0..topology.nodes.len()).map(|i| node_dir(&dir, i)).collect();
+    match shard::combine_summaries(&dirs, &dir) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topologies_survive_the_text_format() {
+        let topology = Topology {
+            nodes: vec![
+                TopologyNode { port: BASE_PORT, level: 0, position: [0.5, -1.25] },
+                TopologyNode { port: BASE_PORT + 1, level: 2, position: [3., 4.] },
+            ],
+            links: vec![(0, 1, Connection { latency: 0.012, packet_loss: 0.01, bandwidth: 500. })],
+        };
+        let parsed = Topology::parse(&topology.to_text()).unwrap();
+        assert_eq!(parsed.to_text(), topology.to_text());
+        assert_eq!(parsed.nodes[1].port, BASE_PORT + 1);
+        assert_eq!(parsed.nodes[0].position, [0.5, -1.25]);
+        assert_eq!(parsed.links[0].2.latency, 0.012);
+    }
+
+    #[test]
+    fn the_documented_example_parses() {
+        let parsed = Topology::parse("# comment\nnode 1 40001 1\nnode 0 40000 0 # first\nlink 0 1 0.012 0.01\n").unwrap();
+        assert_eq!(parsed.nodes.len(), 2);
+        assert_eq!(parsed.nodes[0].port, 40000);
+        assert_eq!(parsed.links[0].2.bandwidth, 10000.);
+    }
+
+    #[test]
+    fn broken_topologies_are_rejected() {
+        assert!(Topology::parse("node 0 40000 0\nnode 0 40001 0\n").is_err());
+        assert!(Topology::parse("node 1 40001 0\n").is_err());
+        assert!(Topology::parse("node 0 40000 0\nlink 0 1 0.01 0\n").is_err());
+        assert!(Topology::parse("node 0 40000 0\nlink 0 0 0.01 0\n").is_err());
+        assert!(Topology::parse("node 0 port 0\n").is_err());
+        assert!(Topology::parse("edge 0 1\n").is_err());
+    }
+}
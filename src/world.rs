@@ -1,41 +1,210 @@
 use actix::*;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use std::time::Duration;
 
-use rand::thread_rng;
+use rand::{Rng, SeedableRng, StdRng};
 use rand::distributions::{Weighted, WeightedChoice, Sample, Range};
 
 use petgraph::stable_graph::StableDiGraph;
 use petgraph::graph::NodeIndex;
-use petgraph::visit::EdgeRef;
+use petgraph::visit::{EdgeRef, IntoEdgeReferences};
 
-use spade::HasPosition;
-use spade::rtree::RTree;
+use rstar::{PointDistance, RTree, RTreeObject, AABB};
 
-use node::{Node, HelloNode, Tick};
+use serde_json::Value;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use node::{Node, NodeConfig, HelloNode, Pipe, Tick};
+use packet::remove_loops;
 use connection::Connection;
+use topology::{self, TopologyReport};
+use oracle::{self, Oracle, StretchStats};
+use baseline::{self, BaselineStats, Label};
+use accounting::{NodeState, StateSummary, ReportState};
+use traffic::{TrafficGenerator, TrafficStats, Endpoint, FlowEvent, StartFlow};
+use link::{QueueReport, QueueStats};
+use stats::Distribution;
+use nc::NCNodeData;
+use nceval::{self, NCEvaluator, ReportCoordinates};
+use onion::OnionStats;
+use advert::ReputationStats;
+use keys::KeyPair;
+use ledger::{LedgerService, QueryLedger, RegisterKey, RouteChoice};
+use scenario::{self, Action, Scenario, SetAttack, SetLink, SetNCParams};
+use partition::{self, Partitioner, PartitionStats};
+use checkpoint::{Checkpoint, GraphNodeState, LinkState, NodeSnapshot, TakeSnapshot, WorldStats};
+use rng::SimRng;
+use codec::{self, Forwarder, Frame, Proxy};
+use shard::{Link, Shard};
+use udp::{self, Topology, TopologyNode};
+use trace::{Event, Flush, Tracer};
+use console::{self, ClosestKnown, InspectNode, Query, Target};
+
+static PROBES_PER_EPOCH: usize = 4;
+// NC is considered converged once the median relative error falls below this
+static NC_CONVERGED_ERROR: f32 = 0.2;
+// pairs sampled, and the size of the packet priced, when checking whether prices change route choice
+static ROUTE_CHOICE_SOURCES: usize = 8;
+static ROUTE_CHOICE_TARGETS: usize = 16;
+static PRICED_PACKET: f32 = 1000.;
+// epochs a node taken down by churn stays down
+static CHURN_DOWNTIME: u64 = 20;
+// churn draws from the stream of the epoch, apart from the node streams
+static CHURN_SEED: u64 = 0x6368_7572_6e00_0000;
+// likewise for the flows started and the baseline probes sent each epoch
+static TRAFFIC_SEED: u64 = 0x7472_6166_6600_0000;
+static PROBE_SEED: u64 = 0x7072_6f62_6500_0000;
+// and for sampling the topology, coordinates and route choices and for picking malicious nodes
+static TOPOLOGY_SEED: u64 = 0x746f_706f_0000_0000;
+static NC_EVAL_SEED: u64 = 0x6e63_6576_0000_0000;
+static NC_SAMPLE_SEED: u64 = 0x6e63_7361_6d00_0000;
+static ROUTE_SEED: u64 = 0x726f_7574_6500_0000;
+static ADVERSARY_SEED: u64 = 0x6164_7665_7200_0000;
+
+/// Shape of the generated hierarchy.
+#[derive(Clone, Debug)]
+pub struct GraphParams {
+    // number of fully connected core nodes
+    pub core: usize,
+    // number of nodes under another node on each level, on average, the length is the depth of the hierarchy
+    pub spread: Vec<usize>,
+    // number of connections on the same level
+    pub conn: Vec<usize>,
+    // packet loss of every link
+    pub loss: f32,
+}
+
+// 100 nodes (approx)
+impl Default for GraphParams {
+    fn default() -> GraphParams {
+        GraphParams { core: 8, spread: vec![1, 12], conn: vec![8, 2], loss: 0.01 }
+    }
+}
+
+impl GraphParams {
+    /// Roughly `nodes` nodes, two levels up to a few thousand and the deeper presets beyond.
+    pub fn sized(nodes: usize) -> GraphParams {
+        let core = 8;
+        let (spread, conn) = if nodes <= 4000 {
+            (vec![1, (nodes / core).max(2) - 1], vec![core, 2])
+        } else if nodes <= 100_000 {
+            // 40k nodes at spread [1, 20, 20, 10]
+            (vec![1, 20, 20, (nodes / (core * 400)).max(1)], vec![core, 2, 2, 2])
+        } else {
+            // 1 million nodes at spread [1, 20, 20, 5, 6, 10]
+            (vec![1, 20, 20, 5, 6, (nodes / (core * 12000)).max(1)], vec![core, 2, 2, 2, 2, 2])
+        };
+        GraphParams { core, spread, conn, ..GraphParams::default() }
+    }
+}
+
+// both directions of a link taken down, with their connections
+type FailedLink = (NodeIndex, NodeIndex, Connection, Connection);
 
 struct GraphNode {
-    address: Option<SyncAddress<Node>>,
+    // the node, or the forwarder standing in for a node of another process
+    pipe: Option<Pipe>,
+    // set once the node has started
+    id: Option<u64>,
     thread: usize,
+    level: usize,
+    position: [f32; 2],
+}
+
+impl GraphNode {
+    // the node if it runs in this process
+    fn address(&self) -> Option<&SyncAddress<Node>> {
+        match self.pipe {
+            Some(Pipe::Node(ref a)) => Some(a),
+            _ => None
+        }
+    }
 }
 
 pub struct World {
     graph: StableDiGraph<GraphNode, Connection>,
     rtrees: Vec<RTree<MapNode>>,
     threads: Vec<SyncAddress<Arbiter>>,
-    mapping: HashMap<u64, NodeIndex<u32>>,
+    mapping: BTreeMap<u64, NodeIndex<u32>>,
 
     active: usize,
     pending: usize,
     adding: bool,
+    // nodes started so far, in graph index order, including those of other shards
+    started: usize,
 
     epoch: u64,
+    // stop after this many epochs, run forever if unset
+    pub epochs: Option<u64>,
+    // seeds graph generation
+    pub seed: u64,
+    pub scenario: Option<Scenario>,
+    // links taken down by the scenario
+    failed_links: Vec<FailedLink>,
+    pub generation: GraphParams,
+    // probability a running node goes down each epoch
+    pub churn: f32,
+    // nodes down, with the epoch they come back and their links
+    down: BTreeMap<NodeIndex, (u64, Vec<FailedLink>)>,
+    churned: u64,
+    pub checkpoint_interval: Option<u64>,
+    pub partitioner: Partitioner,
+    // node states collected for a checkpoint in progress
+    snapshots: Option<Vec<NodeSnapshot>>,
+    last_checkpoint: Option<u64>,
+    // set when other processes run part of the nodes, with the forwarders standing in for them
+    shard: Option<Shard>,
+    remote_ids: HashMap<u64, NodeIndex>,
+    // set when this process runs a single node with its neighbours over UDP, epochs then follow the clock
+    udp: Option<udp::Socket>,
+    realtime: bool,
+    // our own address, handed to the nodes and forwarders we start
+    address: Option<SyncAddress<World>>,
+    // the epoch to stop at for console queries, and whether the run waits for the console now
+    pause_at: Option<u64>,
+    paused: bool,
 
     message_no: u64,
     last_seen_message: u64,
+
+    // refuse to start the simulation if the generated graph is disconnected
+    pub require_connected: bool,
+    // directory statistics are exported to, if any
+    pub output_dir: Option<String>,
+    topology: Option<TopologyReport>,
+
+    oracle: Oracle,
+    // route stretch of delivered packets, by packet type
+    stretch: BTreeMap<&'static str, StretchStats>,
+
+    // settings passed on to every node
+    pub node_config: NodeConfig,
+    baseline_stats: BaselineStats,
+
+    // latest routing state reported by each node
+    states: BTreeMap<u64, NodeState>,
+    // state summaries taken each time the network grows
+    state_history: Vec<StateSummary>,
+    // snapshot directories waiting for the state of this many nodes
+    snapshot_states: Vec<(String, usize)>,
+
+    // application traffic, disabled unless a generator is set
+    pub traffic: Option<TrafficGenerator>,
+    traffic_stats: TrafficStats,
+    queue_stats: QueueStats,
+    onion_stats: OnionStats,
+    reputation_stats: ReputationStats,
+
+    // latest NC report of each node
+    nc_reports: BTreeMap<u64, NCReport>,
+    nc_history: Vec<NCSummary>,
+    nc_converged: Option<u64>,
+    nc_eval: NCEvaluator,
 }
 
 #[derive(Clone, Debug)]
@@ -44,10 +213,16 @@ pub struct MapNode {
     graph_index: NodeIndex,
 }
 
-impl HasPosition for MapNode {
-    type Point = [f32; 2];
-    fn position(&self) -> [f32; 2] {
-        self.position
+impl RTreeObject for MapNode {
+    type Envelope = AABB<[f32; 2]>;
+    fn envelope(&self) -> AABB<[f32; 2]> {
+        AABB::from_point(self.position)
+    }
+}
+
+impl PointDistance for MapNode {
+    fn distance_2(&self, point: &[f32; 2]) -> f32 {
+        (self.position[0] - point[0]).powi(2) + (self.position[1] - point[1]).powi(2)
     }
 }
 
@@ -63,55 +238,335 @@ impl Default for World {
 }
 
 impl World {
-    pub fn new(threads: &Vec<SyncAddress<Arbiter>>) -> World {
+    pub fn new(threads: &[SyncAddress<Arbiter>]) -> World {
         World {
             graph: StableDiGraph::new(),
             rtrees: Vec::new(),
-            threads: threads.clone(),
-            mapping: HashMap::new(),
+            threads: threads.to_vec(),
+            mapping: BTreeMap::new(),
             active: 0,
             pending: 0,
             adding: true,
+            started: 0,
             epoch: 0,
+            epochs: None,
+            seed: 0,
+            scenario: None,
+            failed_links: Vec::new(),
+            generation: GraphParams::default(),
+            churn: 0.,
+            down: BTreeMap::new(),
+            churned: 0,
+            checkpoint_interval: None,
+            partitioner: Partitioner::default(),
+            snapshots: None,
+            last_checkpoint: None,
+            shard: None,
+            remote_ids: HashMap::new(),
+            udp: None,
+            realtime: false,
+            address: None,
+            pause_at: None,
+            paused: false,
             last_seen_message: 0,
             message_no: 0,
+            require_connected: false,
+            output_dir: None,
+            topology: None,
+            oracle: Oracle::new(1024),
+            stretch: BTreeMap::new(),
+            node_config: NodeConfig::default(),
+            baseline_stats: BaselineStats::default(),
+            states: BTreeMap::new(),
+            state_history: Vec::new(),
+            snapshot_states: Vec::new(),
+            traffic: None,
+            traffic_stats: TrafficStats::default(),
+            queue_stats: QueueStats::default(),
+            onion_stats: OnionStats::default(),
+            reputation_stats: ReputationStats::default(),
+            nc_reports: BTreeMap::new(),
+            nc_history: Vec::new(),
+            nc_converged: None,
+            nc_eval: NCEvaluator::default(),
         }
     }
 
-    fn activate_node(&mut self, i: NodeIndex) {
-        let core = &self.threads[self.graph[i].thread];
-        core.send::<msgs::Execute>(msgs::Execute::new(move || {
-            Node::new(i).start::<Address<Node>>();
-            Ok(())
-        }))
+    // write a statistics file to the output directory, if one is set
+    fn export(&self, name: &str, contents: &str) {
+        if let Some(ref dir) = self.output_dir {
+            export_to(dir, name, contents);
+        }
     }
 
-    // add 5% of new nodes per epoch
-    fn add_nodes(&mut self) -> bool {
-        for i in self.active..self.active + (1 + (self.active / 20)) {
-            if i < self.graph.node_count() {
-                self.activate_node(NodeIndex::new(i));
-                self.pending += 1;
-            } else {
-                return false
+    // compute and report graph statistics, returns false if the world should not be started
+    fn validate_topology(&mut self) -> bool {
+        let report = topology::analyse(&self.graph, |n| n.level, &mut SimRng::derive(self.seed ^ TOPOLOGY_SEED, 0));
+        info!("Topology report:\n{}", report);
+        self.export("topology.csv", &report.to_csv());
+
+        let ok = report.connected() || !self.require_connected;
+        if !report.connected() {
+            eprintln!("world graph is disconnected ({} components)", report.components);
+        }
+        self.topology = Some(report);
+        ok
+    }
+
+    fn report_stretch(&self) {
+        let mut csv = String::from(StretchStats::csv_header());
+        for (kind, s) in &self.stretch {
+            info!("{}: {} delivered, stretch {:.3} (max {:.3}), hop stretch {:.3}",
+                     kind, s.delivered, s.mean_stretch(), s.max_stretch, s.mean_hop_stretch());
+            csv.push_str(&s.to_csv(kind));
+        }
+        self.export("stretch.csv", &csv);
+    }
+
+    fn report_baseline(&mut self) {
+        if let Some(protocol) = self.node_config.baseline {
+            self.baseline_stats.check_converged(self.epoch, 20);
+            info!("{}", self.baseline_stats.summary(protocol));
+            self.export("baseline.csv", &self.baseline_stats.to_csv());
+        }
+    }
+
+    // ask every node for its routing state, returns the number of nodes asked
+    fn request_state(&self) -> usize {
+        let mut asked = 0;
+        for &v in self.mapping.values() {
+            if let Some(a) = self.graph[v].address() {
+                a.send(ReportState);
+                asked += 1;
             }
         }
-        return true
+        asked
     }
-}
 
-fn connection(a: &[f32; 2], b: &[f32; 2], level: usize) -> Connection {
-    let l = ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt() / 3e5;
-    Connection { latency: l, packet_loss: 0.01, bandwidth: 10000. }
-}
+    // a snapshot gets the state once every node it asked has answered
+    fn snapshot_state(&mut self) {
+        for s in &mut self.snapshot_states {
+            s.1 = s.1.saturating_sub(1);
+        }
+        let (done, waiting) = self.snapshot_states.drain(..).partition(|&(_, n)| n == 0);
+        self.snapshot_states = waiting;
+        for (dir, _) in done {
+            let summary = StateSummary::new(self.states.values());
+            export_to(&dir, "state.csv", &format!("{}{}", StateSummary::csv_header(), summary.to_csv()));
+        }
+    }
 
-impl Actor for World {
-    type Context = Context<Self>;
+    // summarise the last reported state of every node, before the network grows
+    fn record_state(&mut self) {
+        if self.states.is_empty() {
+            return
+        }
+        let summary = StateSummary::new(self.states.values());
+        info!("routing state over {} nodes: dht entries {}, addresses {}, bytes {}", summary.nodes, summary.dht_entries,
+                 summary.addresses, summary.bytes);
+        self.state_history.push(summary);
+
+        let mut csv = StateSummary::csv_header();
+        for s in &self.state_history {
+            csv.push_str(&s.to_csv());
+        }
+        self.export("state.csv", &csv);
+    }
+
+    // population behind a node, approximated by its number of downstream links
+    fn mass(&self, i: NodeIndex) -> f32 {
+        let level = self.graph[i].level;
+        1. + self.graph.neighbors(i).filter(|&j| self.graph[j].level > level).count() as f32
+    }
+
+    // IDs of every started node in graph order, including those of other shards, so every shard draws alike
+    fn started_ids(&self) -> Vec<(NodeIndex, u64)> {
+        self.graph.node_indices().filter_map(|i| self.graph[i].id.map(|id| (i, id))).collect()
+    }
+
+    // flows are drawn among all nodes, each shard starts those of its own nodes
+    fn start_flows(&mut self) {
+        let endpoints: Vec<Endpoint> = match self.traffic {
+            Some(_) => self.started_ids().into_iter().map(|(i, id)| Endpoint { id, mass: self.mass(i) }).collect(),
+            None => return
+        };
+        let mut rng = SimRng::derive(self.seed ^ TRAFFIC_SEED, self.epoch);
+        let (flows, size) = match self.traffic {
+            Some(ref mut t) => (t.generate(&endpoints, &mut rng), t.config.packet_size),
+            None => return
+        };
+        for flow in flows {
+            let from = match self.mapping.get(&flow.from) {
+                Some(&i) => i,
+                None => continue
+            };
+            if let Some(a) = self.graph[from].address() {
+                self.traffic_stats.start(&flow);
+                a.send(StartFlow { flow: flow.id, des: flow.des, packets: flow.packets, size });
+            }
+        }
+    }
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
-        println!("World started");
+    fn report_nc(&mut self) {
+        if self.nc_reports.is_empty() {
+            return
+        }
+        // liars are left out, what matters is how well the honest nodes pick their next hops
+        let honest: Vec<&NCReport> = self.nc_reports.values().filter(|r| !r.malicious).collect();
+        let errors = Distribution::new(honest.iter().map(|r| r.error).collect());
+        let learn_rates = Distribution::new(honest.iter().map(|r| r.learn_rate).collect());
+        let (hits, total) = honest.iter().fold((0, 0), |(h, t), r| (h + r.rank.0, t + r.rank.1));
+        let summary = NCSummary {
+            epoch: self.epoch,
+            median_error: errors.p50,
+            rank_accuracy: if total > 0 { hits as f32 / total as f32 } else { 0. },
+            mean_learn_rate: learn_rates.mean,
+            malicious: self.nc_reports.len() - honest.len(),
+            rejected: honest.iter().map(|r| r.rejected).sum(),
+        };
+        if self.nc_converged.is_none() && errors.p50 < NC_CONVERGED_ERROR {
+            self.nc_converged = Some(self.epoch);
+        }
+        info!("nc ({}, {} dimensions): relative error {}, next hop ranking {:.3}, converged at {:?}",
+                 self.node_config.nc_model.name(), self.node_config.nc.dimension, errors, summary.rank_accuracy, self.nc_converged);
+        if let Some(ref a) = self.node_config.adversary {
+            info!("nc under attack ({:?}, defence {}): {} malicious nodes, {} samples rejected",
+                     a.attack, self.node_config.defence.name(), summary.malicious, summary.rejected);
+        }
+        self.nc_history.push(summary);
+
+        let mut csv = String::from("model,dimension,defence,epoch,median_error,rank_accuracy,mean_learn_rate,malicious,rejected\n");
+        for s in &self.nc_history {
+            csv.push_str(&format!("{},{},{},{},{},{},{},{},{}\n", self.node_config.nc_model.name(), self.node_config.nc.dimension,
+                                  self.node_config.defence.name(), s.epoch, s.median_error, s.rank_accuracy,
+                                  s.mean_learn_rate, s.malicious, s.rejected));
+        }
+        self.export("nc.csv", &csv);
+    }
 
-        let mut rng = thread_rng();
+    // evaluate the coordinates collected since the last call, then sample new nodes
+    fn evaluate_nc(&mut self) {
+        let model = self.node_config.nc_model.create(&self.node_config.nc);
+        let eval = {
+            let oracle = &mut self.oracle;
+            let graph = &self.graph;
+            let mapping = &self.mapping;
+            let mut rng = SimRng::derive(self.seed ^ NC_EVAL_SEED, self.epoch);
+            self.nc_eval.evaluate(self.epoch, &*model, &mut rng, |a, b| {
+                match (mapping.get(&a), mapping.get(&b)) {
+                    (Some(&a), Some(&b)) => oracle.optimal(graph, a, b).map(|(l, _)| l),
+                    _ => None
+                }
+            })
+        };
+        if let Some(e) = eval {
+            info!("nc evaluation: relative error {}, rank accuracy {:.3}, tiv {:.3} (truth {:.3})",
+                     e.relative_error, e.rank_accuracy, e.tiv_predicted, e.tiv_truth);
+            self.export("nc_eval.csv", &self.nc_eval.to_csv());
+        }
+
+        // drawn among all nodes like the flows, each shard asks those it runs
+        let started = self.started_ids();
+        if started.is_empty() {
+            return
+        }
+        let mut rng = SimRng::derive(self.seed ^ NC_SAMPLE_SEED, self.epoch);
+        for _ in 0..nceval::EVAL_NODES.min(started.len()) {
+            let (i, _) = started[rng.gen_range(0, started.len())];
+            if let Some(a) = self.graph[i].address() {
+                a.send(ReportCoordinates);
+            }
+        }
+    }
+
+    // compare the paths packets would take when weighing transit prices against latency with the fastest ones
+    fn route_choice(&mut self) -> RouteChoice {
+        let mut choice = RouteChoice::default();
+        let billing = match self.node_config.billing {
+            Some(ref b) => b.clone(),
+            None => return choice
+        };
+        let mut nodes: Vec<NodeIndex> = self.mapping.values().cloned().collect();
+        if nodes.len() < 2 {
+            return choice
+        }
+        nodes.sort();
+        let mut rng = SimRng::derive(self.seed ^ ROUTE_SEED, self.epoch);
+        let graph = &self.graph;
+        let price = |i: NodeIndex| billing.price(graph[i].level) * PRICED_PACKET;
+        for _ in 0..ROUTE_CHOICE_SOURCES {
+            let a = nodes[rng.gen_range(0, nodes.len())];
+            let aware = oracle::shortest_paths_by(graph, a, |t, c| c.latency * billing.latency_value + price(t));
+            let fastest = self.oracle.from(graph, a);
+            for _ in 0..ROUTE_CHOICE_TARGETS {
+                let b = nodes[rng.gen_range(0, nodes.len())];
+                let (p, q) = match (aware.path(b), fastest.path(b)) {
+                    (Some(p), Some(q)) => (p, q),
+                    _ => continue
+                };
+                if p.len() < 2 {
+                    continue
+                }
+                // the destination does not charge
+                let transit = |path: &[NodeIndex]| path[1..path.len() - 1].iter().map(|&i| price(i)).sum::<f32>();
+                let (lp, lq) = match (oracle::path_latency(graph, &p), oracle::path_latency(graph, &q)) {
+                    (Some(lp), Some(lq)) if lq > 0. => (lp, lq),
+                    _ => continue
+                };
+                let (tp, tq) = (transit(&p), transit(&q));
+                choice.record(p != q, lp / lq, if tp > 0. { tq / tp } else { 1. });
+            }
+        }
+        choice
+    }
+
+    fn report_reputation(&self) {
+        if self.node_config.adverts.is_some() {
+            info!("{}", self.reputation_stats.summary());
+            self.export("reputation.csv", &self.reputation_stats.to_csv());
+        }
+    }
+
+    fn report_traffic(&self) {
+        if self.traffic.is_some() {
+            info!("{}", self.traffic_stats.summary());
+            self.export("traffic.csv", &self.traffic_stats.to_csv());
+        }
+        if self.node_config.private_routes.is_some() && self.onion_stats.packets > 0 {
+            // compared with what the same packets would have taken in the clear
+            let (elapsed, latency) = self.stretch.get("onion_data")
+                .map_or((0., 0.), |s| (s.total_elapsed / s.delivered.max(1) as f64, s.total_latency / s.delivered.max(1) as f64));
+            info!("private routes: {} packets, header {:.1} bytes ({:.1} per layer), elapsed {:.4}s over {:.4}s of propagation",
+                     self.onion_stats.packets, self.onion_stats.mean_header(), self.onion_stats.bytes_per_layer(), elapsed, latency);
+            self.export("onion.csv", &format!("packets,mean_header,bytes_per_layer,mean_elapsed,mean_latency\n{},{},{},{},{}\n",
+                                              self.onion_stats.packets, self.onion_stats.mean_header(),
+                                              self.onion_stats.bytes_per_layer(), elapsed, latency));
+        }
+    }
+
+    // route a few data packets between random pairs with the baseline protocol, sent by the shard of the source
+    fn send_probes(&self) {
+        let ids: Vec<u64> = self.started_ids().into_iter().map(|(_, id)| id).collect();
+        if self.node_config.baseline.is_none() || ids.len() < 2 {
+            return
+        }
+        let mut rng = SimRng::derive(self.seed ^ PROBE_SEED, self.epoch);
+        for _ in 0..PROBES_PER_EPOCH {
+            let a = ids[rng.gen_range(0, ids.len())];
+            let b = ids[rng.gen_range(0, ids.len())];
+            let from = match self.mapping.get(&a) {
+                Some(&i) if a != b => i,
+                _ => continue
+            };
+            if let Some(addr) = self.graph[from].address() {
+                addr.send(baseline::Probe { des: b, label: self.baseline_stats.labels.get(&b).cloned() });
+            }
+        }
+    }
+
+    // build the graph from `seed`, nodes are only started by `Wake`
+    fn generate(&mut self) {
+        let mut rng: StdRng = SeedableRng::from_seed(&[self.seed as usize][..]);
 
         let mut area = Range::new(-1e8, 1e8);
 
@@ -122,53 +577,36 @@ impl Actor for World {
             Weighted { weight: 1, item: 3 });
         let mut wc = WeightedChoice::new(&mut items);
 
-        // number of fully connected core nodes
-        let core = 8;
-
-        // // 1 million nodes (approx)
-        // // how deep the hierarchy is
-        // let levels = 6;
-        // // number of nodes under another node, on average
-        // let spread = [1, 20, 20, 5, 6, 10];
-        // // number of connections on the same level
-        // let conn = [core, 2, 2, 2, 2, 2];
-
-        // // 40k nodes (approx)
-        // let levels = 4;
-        // // number of nodes under another node, on average
-        // let spread = [1, 20, 20, 10];
-        // // number of connections on the same level
-        // let conn = [core, 2, 2, 2];
-
-        // 100 nodes (approx)
-        let levels = 2;
-        // number of nodes under another node, on average
-        let spread = [1, 12];
-        // number of connections on the same level
-        let conn = [core, 2];
+        let core = self.generation.core;
+        let levels = self.generation.spread.len();
+        let spread = self.generation.spread.clone();
+        let conn = self.generation.conn.clone();
+        let loss = self.generation.loss;
 
         let mut num_nodes = core;
         // add core nodes
 
         for level in 0..levels {
-            println!("Starting graph generation... (level {})", level);
+            info!("Starting graph generation... (level {})", level);
             num_nodes *= spread[level];
             self.rtrees.push(RTree::new());
 
             // add nodes
-            for i in 0..num_nodes {
+            for _ in 0..num_nodes {
                 let p = [area.sample(&mut rng), area.sample(&mut rng)];
 
-                let graph_index = self.graph.add_node(GraphNode { address: None, thread: 0 });
+                // threads are assigned once the graph is complete
+                let graph_index = self.graph.add_node(GraphNode { pipe: None, id: None, thread: 0, level, position: p });
 
                 self.rtrees[level].insert(MapNode { position: p, graph_index });
             }
 
             for i in self.rtrees[level].iter() {
                 // create same level connections
-                for j in self.rtrees[level].nearest_n_neighbors(&i.position, conn[level]) {
-                    if i.graph_index != j.graph_index {
-                        let connection = connection(&i.position, &j.position, 0);
+                for j in self.rtrees[level].nearest_neighbor_iter(&i.position).take(conn[level]) {
+                    // two nodes which are each among the other's nearest are linked once
+                    if i.graph_index != j.graph_index && self.graph.find_edge(i.graph_index, j.graph_index).is_none() {
+                        let connection = connection(&i.position, &j.position, loss);
                         self.graph.add_edge(i.graph_index, j.graph_index, connection.clone());
                         self.graph.add_edge(j.graph_index, i.graph_index, connection.clone());
                     }
@@ -177,78 +615,701 @@ impl Actor for World {
                 // create upstream connections
                 if level > 0 {
                     let upstreams = wc.sample(&mut rng);
-                    for j in self.rtrees[level - 1].nearest_n_neighbors(&i.position, upstreams) {
-                        let connection = connection(&i.position, &j.position, 0);
+                    for j in self.rtrees[level - 1].nearest_neighbor_iter(&i.position).take(upstreams) {
+                        let connection = connection(&i.position, &j.position, loss);
                         self.graph.add_edge(i.graph_index, j.graph_index, connection.clone());
                         self.graph.add_edge(j.graph_index, i.graph_index, connection.clone());
-                        // take on the core of upstream
-                        self.graph[i.graph_index].thread = self.graph[j.graph_index].thread;
                     }
                 }
             }
         }
 
-        println!("Completed graph generation...");
+        info!("Completed graph generation...");
 
-        println!("{} nodes Added", self.graph.node_count());
+        info!("{} nodes Added", self.graph.node_count());
+        self.assign_threads();
+    }
 
-        // self.add_nodes();
+    fn assign_threads(&mut self) {
+        let labels = match self.partitioner {
+            Partitioner::LabelPropagation => partition::label_propagation(&self.graph, self.threads.len()),
+            Partitioner::Upstream => partition::upstream(&self.graph, self.threads.len(), |n| n.level),
+        };
+        for i in self.graph.node_indices().collect::<Vec<NodeIndex>>() {
+            self.graph[i].thread = labels[i.index()];
+        }
+        let stats = PartitionStats::new(&self.graph, self.threads.len(), |i| self.graph[i].thread);
+        info!("partition ({:?}): {}", self.partitioner, stats);
+    }
 
-        // Arbiter::system().send(msgs::SystemExit(0));
+    // started nodes and packets sent on each thread
+    fn report_threads(&self) {
+        let threads = self.threads.len();
+        let mut nodes = vec![0; threads];
+        let mut packets = vec![0u64; threads];
+        for &i in self.mapping.values() {
+            nodes[self.graph[i].thread] += 1;
+        }
+        for (id, sent) in self.queue_stats.sent() {
+            if let Some(&i) = self.mapping.get(&id) {
+                packets[self.graph[i].thread] += sent;
+            }
+        }
+        info!("thread load: nodes {:?}, packets sent {:?}", nodes, packets);
+        let mut csv = String::from("thread,nodes,packets\n");
+        for t in 0..threads {
+            csv.push_str(&format!("{},{},{}\n", t, nodes[t], packets[t]));
+        }
+        self.export("threads.csv", &csv);
+    }
+
+    // log and export the statistics, returns the number of nodes asked for their state
+    fn report(&mut self) -> usize {
+        info!("sent {}", self.last_seen_message);
+        self.report_stretch();
+        self.report_baseline();
+        let asked = self.request_state();
+        self.report_traffic();
+        info!("{}", self.queue_stats.summary());
+        self.export("queues.csv", &self.queue_stats.to_csv());
+        self.report_threads();
+        self.report_nc();
+        self.evaluate_nc();
+        self.report_reputation();
+        if self.node_config.billing.is_some() {
+            Arbiter::system_registry().get::<LedgerService>().send(QueryLedger { dir: self.output_dir.clone(), world: self.address.clone().expect("the world runs") });
+        }
+        asked
+    }
+
+    // the generated graph, for `generate` runs and later inspection
+    fn export_graph(&self) {
+        let mut nodes = String::from("index,level,thread,x,y\n");
+        for i in self.graph.node_indices() {
+            let g = &self.graph[i];
+            nodes.push_str(&format!("{},{},{},{},{}\n", i.index(), g.level, g.thread, g.position[0], g.position[1]));
+        }
+        self.export("nodes.csv", &nodes);
+        let mut edges = String::from("from,to,latency,packet_loss,bandwidth\n");
+        for e in self.graph.edge_references() {
+            let c = e.weight();
+            edges.push_str(&format!("{},{},{},{},{}\n", e.source().index(), e.target().index(), c.latency, c.packet_loss, c.bandwidth));
+        }
+        self.export("edges.csv", &edges);
+        // ports run out for larger graphs
+        if self.graph.node_count() <= (u16::MAX - udp::BASE_PORT) as usize {
+            let topology = Topology {
+                nodes: self.graph.node_indices()
+                    .map(|i| TopologyNode { port: udp::BASE_PORT + i.index() as u16, level: self.graph[i].level, position: self.graph[i].position })
+                    .collect(),
+                links: self.graph.edge_references()
+                    .filter(|e| e.source() < e.target())
+                    .map(|e| (e.source().index(), e.target().index(), e.weight().clone()))
+                    .collect(),
+            };
+            self.export("topology.txt", &topology.to_text());
+        }
+    }
+
+    // run node `index` of a topology on its own, with its neighbours in their own processes
+    fn start_udp(&mut self, index: usize, topology: Topology, world: SyncAddress<World>) -> Result<(), String> {
+        let socket = udp::Socket::bind(index, &topology, self.seed)?;
+        socket.listen(world);
+        for n in &topology.nodes {
+            let i = self.graph.add_node(GraphNode { pipe: None, id: None, thread: 0, level: n.level, position: n.position });
+            // the IDs follow from the seed, so paths through nodes we never hear from can be measured
+            if i.index() != index {
+                self.remote_ids.insert(Node::id_of(self.seed, i), i);
+            }
+        }
+        for (a, b, c) in topology.links {
+            self.graph.add_edge(NodeIndex::new(a), NodeIndex::new(b), c.clone());
+            self.graph.add_edge(NodeIndex::new(b), NodeIndex::new(a), c);
+        }
+        // the socket drops datagrams with the loss of their link
+        self.node_config.queue.apply_loss = false;
+        // and delays them, so probes are timed for real
+        self.node_config.nc.wall_clock = true;
+        self.realtime = true;
+        self.adding = false;
+        self.udp = Some(socket);
+        let local = NodeIndex::new(index);
+        let mut nodes: Vec<NodeIndex> = self.graph.neighbors(local).collect();
+        nodes.push(local);
+        for i in nodes {
+            self.start_node(i, None);
+            self.pending += 1;
+        }
+        self.started = self.graph.node_count();
+        Ok(())
+    }
+
+    // trace event of the world at the current epoch
+    fn event(&self, kind: &str) -> Event {
+        Event::new(kind, 0, self.epoch, self.epoch as f32 * self.node_config.queue.epoch_length)
+    }
+
+    fn tick_nodes(&self) {
+        trace!(World, Info, self.event("epoch").detail(format!("{} nodes, {} messages", self.active, self.last_seen_message)));
+        for &v in self.mapping.values() {
+            if let Some(a) = self.graph[v].address() {
+                a.send(Tick { time: self.epoch as f32 * self.node_config.queue.epoch_length });
+            }
+        }
+    }
+
+    // run the scenario events due this epoch, false once the scenario stops the run
+    fn run_scenario(&mut self) -> bool {
+        let time = self.epoch as f32 * self.node_config.queue.epoch_length;
+        let due = match self.scenario {
+            Some(ref mut s) => s.due(self.epoch, time),
+            None => return true
+        };
+        for action in due {
+            trace!(World, Info, self.event("scenario").detail(format!("{:?}", action)));
+            match action {
+                Action::AddNodes(n) => {
+                    self.start_nodes(n);
+                }
+                Action::FailLink(a, b) => self.fail_link(NodeIndex::new(a), NodeIndex::new(b)),
+                Action::Partition(r) => {
+                    let inside = |p: [f32; 2]| p[0] >= r[0] && p[1] >= r[1] && p[0] < r[2] && p[1] < r[3];
+                    let cut: Vec<(NodeIndex, NodeIndex)> = self.graph.edge_references()
+                        .filter(|e| e.source() < e.target())
+                        .filter(|e| inside(self.graph[e.source()].position) != inside(self.graph[e.target()].position))
+                        .map(|e| (e.source(), e.target()))
+                        .collect();
+                    trace!(World, Info, self.event("partition").detail(format!("{} links cut", cut.len())));
+                    for (a, b) in cut {
+                        self.fail_link(a, b);
+                    }
+                }
+                Action::Heal => self.heal_links(),
+                Action::Traffic(config) => self.traffic = config.map(TrafficGenerator::new),
+                Action::Adversary(config) => {
+                    self.node_config.adversary = Some(config);
+                    // drawn over all nodes alike on every shard, each shard tells its own
+                    let mut rng = SimRng::derive(self.seed ^ ADVERSARY_SEED, self.epoch);
+                    for (v, _) in self.started_ids() {
+                        if rng.next_f32() < config.fraction {
+                            if let Some(a) = self.graph[v].address() {
+                                a.send(SetAttack { attack: config.attack });
+                            }
+                        }
+                    }
+                }
+                Action::NC(key, value) => {
+                    scenario::apply_nc(&mut self.node_config.nc, &key, value);
+                    for &v in self.mapping.values() {
+                        if let Some(a) = self.graph[v].address() {
+                            a.send(SetNCParams { params: self.node_config.nc.clone() });
+                        }
+                    }
+                }
+                Action::Snapshot(name) => {
+                    let dir = self.output_dir.clone();
+                    self.output_dir = dir.as_ref().map(|d| Path::new(d).join("snapshots").join(&name).to_string_lossy().into_owned());
+                    let asked = self.report();
+                    self.export_graph();
+                    // the state and the ledger answer later
+                    if let Some(d) = self.output_dir.take() {
+                        self.snapshot_states.push((d, asked));
+                    }
+                    self.output_dir = dir;
+                }
+                Action::Stop => return false,
+            }
+        }
+        true
+    }
+
+    // take down both directions of a link, the ends lose everything sent over it
+    fn take_link(&mut self, a: NodeIndex, b: NodeIndex) -> Option<FailedLink> {
+        let (ab, ba) = match (self.graph.find_edge(a, b), self.graph.find_edge(b, a)) {
+            (Some(ab), Some(ba)) => (ab, ba),
+            _ => return None
+        };
+        let (cab, cba) = match (self.graph.remove_edge(ab), self.graph.remove_edge(ba)) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return None
+        };
+        self.set_link(a, b, false);
+        self.oracle.invalidate();
+        Some((a, b, cab, cba))
+    }
+
+    fn restore_link(&mut self, link: FailedLink) {
+        let (a, b, cab, cba) = link;
+        self.graph.add_edge(a, b, cab.clone());
+        self.graph.add_edge(b, a, cba.clone());
+        self.set_link(a, b, true);
+        // nodes started while the link was down have never met, the end run here introduces itself
+        let (a, b, cab, cba) = if self.graph[a].address().is_some() { (a, b, cab, cba) } else { (b, a, cba, cab) };
+        let level = self.graph[b].level;
+        if let (Some(x), Some(y), Some(id)) = (self.graph[a].address().cloned(), self.graph[b].pipe.clone(), self.graph[b].id) {
+            x.send(HelloNode { pipe: y, reply: false, connection: cab, back: cba, id, level });
+        }
+        self.oracle.invalidate();
+    }
+
+    fn fail_link(&mut self, a: NodeIndex, b: NodeIndex) {
+        match self.take_link(a, b) {
+            Some(link) => self.failed_links.push(link),
+            None => info!("no link between {} and {}", a.index(), b.index())
+        }
+    }
+
+    fn heal_links(&mut self) {
+        for link in std::mem::take(&mut self.failed_links) {
+            self.restore_link(link);
+        }
+    }
+
+    // bring back the nodes whose downtime is over, then take down a fraction of the running ones
+    fn churn(&mut self) {
+        let epoch = self.epoch;
+        let back: Vec<NodeIndex> = self.down.iter().filter(|&(_, &(until, _))| until <= epoch).map(|(i, _)| *i).collect();
+        for i in back {
+            let links = self.down.remove(&i).map_or(Vec::new(), |(_, l)| l);
+            trace!(World, Info, self.event("node_up").detail(format!("node {}", i.index())));
+            for link in links {
+                // links to nodes which are still down come back with them
+                let other = if link.0 == i { link.1 } else { link.0 };
+                if self.down.contains_key(&other) {
+                    self.down.get_mut(&other).unwrap().1.push(link);
+                } else {
+                    self.restore_link(link);
+                }
+            }
+        }
+        if self.churn <= 0. {
+            return
+        }
+        // every shard takes down the same nodes
+        let mut rng = SimRng::derive(self.seed ^ CHURN_SEED, epoch);
+        let churn = self.churn;
+        let leaving: Vec<NodeIndex> = self.graph.node_indices()
+            .filter(|&i| self.graph[i].id.is_some() && !self.down.contains_key(&i))
+            .filter(|_| rng.next_f32() < churn)
+            .collect();
+        for i in leaving {
+            let neighbours: Vec<NodeIndex> = self.graph.neighbors(i).collect();
+            let links = neighbours.into_iter().filter_map(|j| self.take_link(i, j)).collect();
+            self.down.insert(i, (epoch + CHURN_DOWNTIME, links));
+            trace!(World, Info, self.event("node_down").detail(format!("node {} until epoch {}", i.index(), epoch + CHURN_DOWNTIME)));
+            self.churned += 1;
+        }
+    }
+
+    fn set_link(&self, a: NodeIndex, b: NodeIndex, up: bool) {
+        for &(x, y) in &[(a, b), (b, a)] {
+            if let (Some(ref addr), Some(neighbour)) = (self.graph[x].address().cloned(), self.graph[y].id) {
+                addr.send(SetLink { neighbour, up });
+            }
+        }
+    }
+
+    fn activate_node(&mut self, i: NodeIndex) {
+        self.start_node(i, None);
+    }
+
+    // start a new node, or one continuing from a checkpoint, or a forwarder if another process runs it
+    fn start_node(&mut self, i: NodeIndex, snapshot: Option<NodeSnapshot>) {
+        let core = &self.threads[self.graph[i].thread];
+        let level = self.graph[i].level;
+        let config = self.node_config.clone();
+        let world = self.address.clone().expect("the world starts nodes once it runs");
+        let remote = match self.udp {
+            Some(ref s) if s.index != i.index() => Some(Proxy::Udp(s.clone())),
+            Some(_) => None,
+            None => self.shard.as_ref().and_then(|s| s.remote(i.index())).map(Proxy::Shard)
+        };
+        // every process registers every key, standing in for keys published on the chain
+        if config.billing.is_some() {
+            let public = KeyPair::of(config.seed, i.index()).public;
+            Arbiter::system_registry().get::<LedgerService>().send(RegisterKey { id: Node::id_of(config.seed, i), public });
+        }
+        core.send::<msgs::Execute>(msgs::Execute::new(move || {
+            match remote {
+                Some(proxy) => {
+                    Forwarder { id: Node::id_of(config.seed, i), graph_index: i, proxy, world }.start::<Address<Forwarder>>();
+                }
+                None => {
+                    let node = match snapshot {
+                        Some(s) => Node::restore(s, config, world),
+                        None => Node::new(i, level, config, world)
+                    };
+                    node.start::<Address<Node>>();
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    // ask every started node for its state, the epoch waits until all have answered
+    fn request_snapshots(&mut self) {
+        self.snapshots = Some(Vec::new());
+        for &v in self.mapping.values() {
+            if let Some(a) = self.graph[v].address() {
+                a.send(TakeSnapshot);
+            }
+        }
+    }
+
+    fn write_checkpoint(&mut self) {
+        let mut states = self.snapshots.take().unwrap_or_default();
+        states.sort_by_key(|s| s.graph_index);
+        let link = |&(a, b, ref x, ref y): &FailedLink| (a.index(), b.index(), x.clone(), y.clone());
+        let links = self.graph.edge_references()
+            .filter(|e| e.source() < e.target())
+            .filter_map(|e| self.graph.find_edge(e.target(), e.source())
+                .map(|r| (e.source().index(), e.target().index(), e.weight().clone(), self.graph[r].clone())))
+            .collect();
+        let checkpoint = Checkpoint {
+            seed: self.seed,
+            epoch: self.epoch,
+            nodes: self.graph.node_indices()
+                .map(|i| GraphNodeState { level: self.graph[i].level, thread: self.graph[i].thread, position: self.graph[i].position })
+                .collect(),
+            links,
+            failed_links: self.failed_links.iter().map(&link).collect(),
+            down: self.down.iter().map(|(i, &(until, ref l))| (i.index(), until, l.iter().map(&link).collect())).collect(),
+            churned: self.churned,
+            states,
+            stats: WorldStats {
+                stretch: self.stretch.iter().map(|(&k, v)| (k, v.clone())).collect(),
+                nc_history: self.nc_history.clone(),
+                nc_converged: self.nc_converged,
+                traffic: self.traffic_stats.clone(),
+                next_flow: self.traffic.as_ref().map_or(0, |t| t.next_flow),
+                queues: self.queue_stats.clone(),
+                states: self.states.iter().map(|(&k, v)| (k, v.clone())).collect(),
+                state_history: self.state_history.clone(),
+            },
+        };
+        let dir = self.output_dir.clone().unwrap_or(String::from("."));
+        let path = Path::new(&dir).join(format!("checkpoint-{}.json", self.epoch));
+        match fs::create_dir_all(&dir).map_err(|e| e.to_string()).and_then(|_| checkpoint.save(&path)) {
+            Ok(()) => info!("checkpoint of {} nodes at epoch {} written to {:?}", checkpoint.states.len(), self.epoch, path),
+            Err(e) => eprintln!("{}", e)
+        }
+        self.last_checkpoint = Some(self.epoch);
+    }
+
+    // rebuild the graph of a checkpoint and start its nodes again, instead of generating one
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.seed = checkpoint.seed;
+        self.node_config.seed = checkpoint.seed;
+        self.epoch = checkpoint.epoch;
+        self.last_checkpoint = Some(checkpoint.epoch);
+        self.churned = checkpoint.churned;
+        let stats = checkpoint.stats;
+        self.stretch = stats.stretch.into_iter().collect();
+        self.nc_history = stats.nc_history;
+        self.nc_converged = stats.nc_converged;
+        self.traffic_stats = stats.traffic;
+        if let Some(ref mut t) = self.traffic {
+            t.next_flow = stats.next_flow;
+        }
+        self.queue_stats = stats.queues;
+        self.states = stats.states.into_iter().collect();
+        self.state_history = stats.state_history;
+        for n in &checkpoint.nodes {
+            let i = self.graph.add_node(GraphNode { pipe: None, id: None, thread: 0, level: n.level, position: n.position });
+            while self.rtrees.len() <= n.level {
+                self.rtrees.push(RTree::new());
+            }
+            self.rtrees[n.level].insert(MapNode { position: n.position, graph_index: i });
+        }
+        let link = |(a, b, x, y): LinkState| (NodeIndex::new(a), NodeIndex::new(b), x, y);
+        for (a, b, x, y) in checkpoint.links.into_iter().map(&link) {
+            self.graph.add_edge(a, b, x);
+            self.graph.add_edge(b, a, y);
+        }
+        self.failed_links = checkpoint.failed_links.into_iter().map(&link).collect();
+        self.down = checkpoint.down.into_iter()
+            .map(|(i, until, l)| (NodeIndex::new(i), (until, l.into_iter().map(&link).collect())))
+            .collect();
+        // the run may have other threads or another partitioner than the one which took the checkpoint
+        self.assign_threads();
+        info!("restored {} nodes at epoch {}", checkpoint.states.len(), self.epoch);
+        self.started = checkpoint.states.iter().map(|s| s.graph_index + 1).max().unwrap_or(0);
+        for s in checkpoint.states {
+            let i = NodeIndex::new(s.graph_index);
+            self.pending += 1;
+            self.start_node(i, Some(s));
+        }
+    }
+
+    // graph index of a started node, ours or a forwarder
+    fn index_of(&self, id: u64) -> Option<NodeIndex> {
+        self.mapping.get(&id).or_else(|| self.remote_ids.get(&id)).cloned()
+    }
+
+    // a node of the graph, by graph index or ID
+    fn find_node(&self, n: Target) -> Option<NodeIndex> {
+        match n {
+            Target::Index(i) if self.graph.contains_node(NodeIndex::new(i)) => Some(NodeIndex::new(i)),
+            Target::Index(_) => None,
+            Target::Id(id) => self.index_of(id),
+        }
+    }
+
+    // a node run by this process
+    fn local_node(&self, n: Target) -> Result<(NodeIndex, SyncAddress<Node>), String> {
+        let i = self.find_node(n).ok_or(format!("no node {}", n))?;
+        match (self.graph[i].id, self.graph[i].address().cloned()) {
+            (Some(id), Some(a)) if self.mapping.contains_key(&id) => Ok((i, a)),
+            _ => Err(format!("node {} is not running in this process", i.index()))
+        }
+    }
+
+    // walk a lookup from `a` for `b` through the DHT tables and print the route packets would take,
+    // compared with the shortest path if `b` is a node
+    fn test_lookup(&mut self, a: Target, b: Target, need_node: bool, ctx: &mut Context<Self>) -> Result<(), String> {
+        let (from, _) = self.local_node(a)?;
+        let to = self.find_node(b);
+        let goal = match (to, b) {
+            (Some(i), _) => self.graph[i].id.ok_or(format!("node {} has not started", i.index()))?,
+            (None, Target::Id(id)) if !need_node => id,
+            _ => return Err(format!("no node {}", b))
+        };
+        let from_id = self.graph[from].id.unwrap_or(0);
+        self.lookup_step(ConsoleLookup { from, to, goal, route: vec![from_id], asked: Vec::new() }, from_id, ctx);
+        Ok(())
+    }
+
+    // ask `at` for the entries closest to the goal, then the closest of those, until the goal is found
+    fn lookup_step(&mut self, mut lookup: ConsoleLookup, at: u64, ctx: &mut Context<Self>) {
+        let addr = match self.index_of(at).and_then(|i| self.graph[i].address().cloned()) {
+            Some(a) => a,
+            None => return self.print_lookup(lookup, false)
+        };
+        lookup.asked.push(at);
+        let goal = lookup.goal;
+        ctx.spawn(fut::wrap_future::<_, World>(addr.call_fut(ClosestKnown { goal })).then(move |res, world: &mut World, ctx| {
+            let closest = match res {
+                Ok(Ok(c)) => c.into_iter().next(),
+                _ => None
+            };
+            match closest {
+                // a lookup asks the closest node it was told about, and gives up on one it knows
+                Some(n) if !lookup.asked.contains(&n.id) => {
+                    // routes start at the destination
+                    lookup.route.extend(n.route.iter().rev().skip_while(|&&h| h == at));
+                    if lookup.route.last() != Some(&n.id) {
+                        lookup.route.push(n.id);
+                    }
+                    if n.id == goal {
+                        world.print_lookup(lookup, true);
+                    } else {
+                        world.lookup_step(lookup, n.id, ctx);
+                    }
+                }
+                _ => world.print_lookup(lookup, false)
+            }
+            fut::ok::<(), (), World>(())
+        }));
+    }
+
+    fn print_lookup(&mut self, lookup: ConsoleLookup, found: bool) {
+        // packets follow the route found without its loops
+        let route = if found { remove_loops(lookup.route) } else { Vec::new() };
+        let path: Option<Vec<NodeIndex>> = route.iter().map(|&id| self.index_of(id)).collect();
+        let latency = path.as_ref().and_then(|p| oracle::path_latency(&self.graph, p));
+        let from = lookup.from;
+        let optimal = lookup.to.and_then(|t| self.oracle.optimal(&self.graph, from, t));
+        let hops = route.len().saturating_sub(1);
+        console::print(&LookupResult {
+            from: self.graph[from].id.unwrap_or(0),
+            goal: lookup.goal,
+            found,
+            graph_route: path.map(|p| p.iter().map(|i| i.index()).collect()),
+            hops,
+            route,
+            latency,
+            optimal_latency: optimal.map(|o| o.0),
+            optimal_hops: optimal.map(|o| o.1),
+            stretch: match (latency, optimal) {
+                (Some(l), Some((o, h))) if h > 0 => Some(oracle::stretch(l, o)),
+                _ => None
+            },
+            hop_stretch: match optimal {
+                Some((_, h)) if found && h > 0 => Some(hops as f32 / h as f32),
+                _ => None
+            },
+        });
+    }
+
+    // add 5% of new nodes per epoch
+    fn add_nodes(&mut self) -> bool {
+        let n = 1 + self.started / 20;
+        self.start_nodes(n)
+    }
+
+    // start the next `n` nodes of the graph, false once every node is running
+    fn start_nodes(&mut self, n: usize) -> bool {
+        for i in self.started..self.started + n {
+            if i < self.graph.node_count() {
+                self.activate_node(NodeIndex::new(i));
+                self.pending += 1;
+                self.started += 1;
+            } else {
+                return false
+            }
+        }
+        true
     }
 }
 
-impl Supervised for World {}
+fn connection(a: &[f32; 2], b: &[f32; 2], loss: f32) -> Connection {
+    let l = ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)).sqrt() / 3e5;
+    Connection { latency: l, packet_loss: loss, bandwidth: 10000. }
+}
+
+impl Actor for World {
+    type Context = Context<Self>;
 
-impl SystemService for World {
-    fn service_started(&mut self, _ctx: &mut Context<Self>) {
-        println!("Service started");
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.address = Some(ctx.address());
+        info!("World started");
     }
 }
 
-// sent by node to world to notify that it has been initialised
-#[derive(Message)]
+// sent by a node or forwarder to world to notify that it has been initialised
 pub struct HelloWorld {
-    pub addr: SyncAddress<Node>,
+    pub pipe: Pipe,
     pub graph_index: NodeIndex,
     pub id: u64,
 }
 
+message!(HelloWorld);
+
 impl Handler<HelloWorld> for World {
     fn handle(&mut self, msg: HelloWorld, _ctx: &mut Context<Self>) -> Response<Self, HelloWorld> {
-        self.graph[msg.graph_index].address = Some(msg.addr.clone());
-        self.mapping.insert(msg.id, msg.graph_index);
-        self.active += 1;
+        let i = msg.graph_index;
+        self.graph[i].pipe = Some(msg.pipe.clone());
+        self.graph[i].id = Some(msg.id);
+        match msg.pipe {
+            Pipe::Node(_) => {
+                self.mapping.insert(msg.id, i);
+                self.active += 1;
+            }
+            Pipe::Forwarder(_) => {
+                self.remote_ids.insert(msg.id, i);
+            }
+        }
         self.pending -= 1;
-        for i in self.graph.edges(msg.graph_index) {
-            if let Some(ref addr) = self.graph[i.target()].address {
-                addr.send(HelloNode { pipe: msg.addr.clone(), reply: false, connection: i.weight().clone(), id: msg.id })
+        let level = self.graph[i].level;
+        for e in self.graph.edges(i) {
+            let t = e.target();
+            let back = match self.graph.find_edge(t, i) {
+                Some(b) => self.graph[b].clone(),
+                None => e.weight().clone()
+            };
+            if let (Some(pipe), Some(id)) = (self.graph[t].pipe.clone(), self.graph[t].id) {
+                pipe.hello(HelloNode { pipe: msg.pipe.clone(), reply: false, connection: back.clone(), back: e.weight().clone(), id: msg.id, level });
+                // forwarders do not answer, so the new node meets them here
+                if let Pipe::Forwarder(_) = pipe {
+                    msg.pipe.hello(HelloNode { pipe, reply: true, connection: e.weight().clone(), back, id, level: self.graph[t].level });
+                }
             }
         }
         Self::reply(())
     }
 }
 
-#[derive(Message)]
+// write a statistics file to `dir`
+fn export_to(dir: &str, name: &str, contents: &str) {
+    let path = Path::new(dir).join(name);
+    let res = fs::create_dir_all(dir)
+        .and_then(|_| File::create(&path))
+        .and_then(|mut f| f.write_all(contents.as_bytes()));
+    if let Err(e) = res {
+        eprintln!("failed to export {:?}: {}", path, e);
+    }
+}
+
 pub struct Wake;
 
+message!(Wake);
+
 impl Handler<Wake> for World {
     // runs every ms
     fn handle(&mut self, _msg: Wake, ctx: &mut Context<Self>) -> Response<Self, Wake> {
+        // other processes do not wait for us, an epoch lasts its length once the nodes have started
+        if self.realtime && self.pending == 0 {
+            if self.epochs.is_some_and(|e| self.epoch >= e) {
+                self.report();
+                ctx.notify(Finish, Duration::new(0, 100_000_000));
+                return Self::reply(())
+            }
+            if self.epoch.is_multiple_of(10) {
+                self.report();
+            }
+            self.tick_nodes();
+            self.epoch += 1;
+            let length = self.node_config.queue.epoch_length;
+            ctx.notify(Wake, Duration::new(length as u64, (length.fract() * 1e9) as u32));
+            return Self::reply(())
+        }
         // everything is done processing (in theory)
         if self.last_seen_message == self.message_no && self.pending == 0 {
-            if self.adding && self.epoch % 50 == 0 {
-                self.adding = self.add_nodes();
-                println!("added more nodes, total: {}", self.active);
+            // packets from other shards go in once our own are done, the epoch goes on with them
+            if self.serve_shard_requests(ctx) {
+                ctx.notify(Wake, Duration::new(0, 1_000_000));
+                return Self::reply(())
             }
-            if self.epoch % 10 == 0 {
-                println!("sent {}", self.last_seen_message);
+            // with shards, the epoch starts once the coordinator has seen all of them finish the last one
+            if let Some(ref mut s) = self.shard {
+                if !s.ready(self.epoch) {
+                    ctx.notify(Wake, Duration::new(0, 1_000_000));
+                    return Self::reply(())
+                }
             }
-            for (k, &v) in &self.mapping {
-                if let Some(ref a) = self.graph[v].address {
-                    a.send(Tick);
+            // a paused run waits for the console, with nothing in flight
+            if self.pause_at.is_some_and(|e| self.epoch >= e) {
+                if !self.paused {
+                    self.paused = true;
+                    println!("paused at the start of epoch {}, type help for the commands", self.epoch);
                 }
+                ctx.notify(Wake, Duration::new(0, 10_000_000));
+                return Self::reply(())
             }
+            // a checkpoint holds the state at the start of an epoch, nothing runs until every node has answered
+            if self.snapshots.is_some() {
+                if self.snapshots.as_ref().map_or(0, |s| s.len()) < self.active {
+                    ctx.notify(Wake, Duration::new(0, 1_000_000));
+                    return Self::reply(())
+                }
+                self.write_checkpoint();
+            } else if self.checkpoint_interval.is_some_and(|n| self.epoch.is_multiple_of(n)) && self.last_checkpoint != Some(self.epoch) {
+                self.request_snapshots();
+                ctx.notify(Wake, Duration::new(0, 1_000_000));
+                return Self::reply(())
+            }
+            let last = self.epochs.is_some_and(|e| self.epoch >= e);
+            if self.adding && self.epoch.is_multiple_of(50) {
+                self.record_state();
+                // nodes added in the last epoch would never run, but count in the summary
+                if !last {
+                    self.adding = self.add_nodes();
+                    trace!(World, Info, self.event("nodes_added").detail(format!("{} nodes", self.active)));
+                }
+            }
+            if last || !self.run_scenario() {
+                // give the last reports time to arrive, no more wakes
+                self.report();
+                ctx.notify(Finish, Duration::new(0, 100_000_000));
+                return Self::reply(())
+            }
+            self.churn();
+            if self.epoch.is_multiple_of(10) {
+                self.report();
+            }
+            self.send_probes();
+            self.start_flows();
+            self.tick_nodes();
             self.epoch += 1;
         }
         self.message_no = self.last_seen_message;
@@ -258,11 +1319,143 @@ impl Handler<Wake> for World {
     }
 }
 
-#[derive(Message)]
+// sent once by main before the first `Wake`
+pub struct Configure {
+    pub seed: u64,
+    pub threads: usize,
+    pub epochs: Option<u64>,
+    pub output_dir: Option<String>,
+    pub scenario: Option<Scenario>,
+    pub generation: GraphParams,
+    pub churn: f32,
+    pub node_config: NodeConfig,
+    // epochs between checkpoints, and a checkpoint to continue from instead of generating a graph
+    pub checkpoint_interval: Option<u64>,
+    pub restore: Option<Checkpoint>,
+    pub partitioner: Partitioner,
+    // export the graph and exit without starting any node
+    pub generate_only: bool,
+    // this process runs shard (index, count) of the nodes
+    pub shard: Option<(usize, usize, Link)>,
+    // this process runs one node of a topology over UDP
+    pub udp: Option<(usize, Topology)>,
+    // stop at the start of this epoch for console queries
+    pub pause: Option<u64>,
+    // do not start the nodes on a disconnected graph
+    pub require_connected: bool,
+}
+
+message!(Configure);
+
+impl Handler<Configure> for World {
+    fn handle(&mut self, msg: Configure, ctx: &mut Context<Self>) -> Response<Self, Configure> {
+        self.seed = msg.seed;
+        self.epochs = msg.epochs;
+        self.output_dir = msg.output_dir;
+        self.scenario = msg.scenario;
+        self.generation = msg.generation;
+        self.churn = msg.churn;
+        self.require_connected = msg.require_connected;
+        self.node_config = msg.node_config;
+        self.node_config.seed = msg.seed;
+        if self.scenario.as_ref().is_some_and(|s| s.grows()) {
+            self.adding = false;
+        }
+        for i in self.threads.len()..msg.threads {
+            self.threads.push(Arbiter::new(format!("Core {}", i)));
+        }
+
+        self.checkpoint_interval = msg.checkpoint_interval;
+        self.partitioner = msg.partitioner;
+        self.pause_at = msg.pause;
+        match (msg.restore, msg.udp) {
+            (_, Some((index, topology))) => {
+                if let Err(e) = self.start_udp(index, topology, ctx.address()) {
+                    eprintln!("{}", e);
+                    ctx.stop();
+                    Arbiter::system().send(msgs::SystemExit(1));
+                    return Self::reply(())
+                }
+            }
+            (Some(checkpoint), None) => {
+                // events before the checkpoint already happened
+                if checkpoint.epoch > 0 {
+                    let before = checkpoint.epoch - 1;
+                    let time = before as f32 * self.node_config.queue.epoch_length;
+                    if let Some(ref mut s) = self.scenario {
+                        s.due(before, time);
+                    }
+                }
+                self.restore(checkpoint);
+            }
+            (None, None) => self.generate()
+        }
+        if let Some((index, count, link)) = msg.shard {
+            let owner = partition::label_propagation(&self.graph, count);
+            info!("shard {}: {}", index, PartitionStats::new(&self.graph, count, |i| owner[i.index()]));
+            self.shard = Some(Shard::new(index, link, owner));
+        }
+        if !self.validate_topology() {
+            eprintln!("refusing to start");
+            ctx.stop();
+            Arbiter::system().send(msgs::SystemExit(1));
+        } else if msg.generate_only {
+            self.export_graph();
+            Arbiter::system().send(msgs::SystemExit(0));
+        }
+        Self::reply(())
+    }
+}
+
+pub struct Finish;
+
+message!(Finish);
+
+impl Handler<Finish> for World {
+    fn handle(&mut self, _msg: Finish, ctx: &mut Context<Self>) -> Response<Self, Finish> {
+        // printed whatever the log level
+        let delivered: u64 = self.stretch.values().map(|s| s.delivered).sum();
+        println!("finished after {} epochs (seed {}): {} nodes on {} threads, {} messages sent, {} packets delivered",
+                 self.epoch, self.seed, self.active, self.threads.len(), self.last_seen_message, delivered);
+        let mut kinds: Vec<(&&'static str, &StretchStats)> = self.stretch.iter().collect();
+        kinds.sort_by_key(|&(k, _)| *k);
+        for (kind, s) in kinds {
+            println!("  {}: {} delivered, stretch {:.3}", kind, s.delivered, s.mean_stretch());
+        }
+        if let Some(e) = self.nc_history.last() {
+            println!("  nc: median relative error {:.3}, converged at {:?}", e.median_error, self.nc_converged);
+        }
+        if self.churned > 0 {
+            println!("  churn: {} nodes taken down, {} down at the end", self.churned, self.down.len());
+        }
+
+        // one row per run, combined by sweeps
+        let stretch = self.stretch.values().map(|s| s.total_stretch).sum::<f64>() / delivered.max(1) as f64;
+        let (nc_error, rank_accuracy) = self.nc_history.last().map_or((0., 0.), |s| (s.median_error, s.rank_accuracy));
+        let dht_entries = if !self.states.is_empty() { StateSummary::new(self.states.values()).dht_entries.mean } else { 0. };
+        self.export("summary.csv", &format!(
+            "epochs,nodes,messages,delivered,stretch,nc_error,rank_accuracy,dht_entries,loss,churned\n{},{},{},{},{},{},{},{},{},{}\n",
+            self.epoch, self.active, self.last_seen_message, delivered, stretch, nc_error, rank_accuracy, dht_entries,
+            self.queue_stats.loss_ratio(), self.churned));
+        // the trace is buffered, exit once it is written out, stopping the node threads so a sweep does not pile them up
+        let flushed = Arbiter::system_registry().get::<Tracer>().call_fut(Flush);
+        ctx.spawn(fut::wrap_future::<_, World>(flushed).then(|_, world: &mut World, _| {
+            for thread in &world.threads {
+                thread.send(msgs::StopArbiter(0));
+            }
+            Arbiter::system().send(msgs::SystemExit(0));
+            fut::ok::<(), (), World>(())
+        }));
+        Self::reply(())
+    }
+}
+
 pub struct AddThread {
     pub thread: SyncAddress<Arbiter>,
 }
 
+message!(AddThread);
+
 impl Handler<AddThread> for World {
     fn handle(&mut self, msg: AddThread, _ctx: &mut Context<Self>) -> Response<Self, AddThread> {
         self.threads.push(msg.thread);
@@ -270,12 +1463,442 @@ impl Handler<AddThread> for World {
     }
 }
 
-#[derive(Message)]
 pub struct Sent;
 
+message!(Sent);
+
 impl Handler<Sent> for World {
-    fn handle(&mut self, msg: Sent, _ctx: &mut Context<Self>) -> Response<Self, Sent> {
+    fn handle(&mut self, _msg: Sent, _ctx: &mut Context<Self>) -> Response<Self, Sent> {
         self.last_seen_message += 1;
         Self::reply(())
     }
-}
\ No newline at end of file
+}
+// sent by a node when a packet reaches its destination
+pub struct Delivered {
+    pub kind: &'static str,
+    pub hops: Vec<u64>,
+    pub elapsed: f32,
+}
+
+message!(Delivered);
+
+impl Handler<Delivered> for World {
+    fn handle(&mut self, msg: Delivered, _ctx: &mut Context<Self>) -> Response<Self, Delivered> {
+        let path: Option<Vec<NodeIndex>> = msg.hops.iter().map(|&id| self.index_of(id)).collect();
+        if let Some(path) = path {
+            if let (Some(&a), Some(&b)) = (path.first(), path.last()) {
+                let latency = oracle::path_latency(&self.graph, &path);
+                let optimal = self.oracle.optimal(&self.graph, a, b);
+                if let (Some(latency), Some((optimal_latency, optimal_hops))) = (latency, optimal) {
+                    self.stretch.entry(msg.kind).or_default()
+                        .record(latency, msg.elapsed, path.len() - 1, optimal_latency, optimal_hops);
+                }
+            }
+        }
+        Self::reply(())
+    }
+}
+
+// sent by a node in response to `ReportState` when metric adverts are enabled
+pub struct ReputationReport {
+    pub id: u64,
+    pub liar: bool,
+    // our reputation of each neighbour whose adverts we checked
+    pub reputations: Vec<(u64, f32)>,
+}
+
+message!(ReputationReport);
+
+impl Handler<ReputationReport> for World {
+    fn handle(&mut self, msg: ReputationReport, _ctx: &mut Context<Self>) -> Response<Self, ReputationReport> {
+        self.reputation_stats.record(msg.id, msg.liar, msg.reputations);
+        Self::reply(())
+    }
+}
+
+// sent by the ledger service in response to `ledger::QueryLedger`
+pub struct LedgerReport {
+    // where to export the balances, as asked
+    pub dir: Option<String>,
+    pub settled: u64,
+    pub rejected: u64,
+    pub volume: f64,
+    pub balances: HashMap<u64, f64>,
+}
+
+message!(LedgerReport);
+
+impl Handler<LedgerReport> for World {
+    fn handle(&mut self, msg: LedgerReport, _ctx: &mut Context<Self>) -> Response<Self, LedgerReport> {
+        let choice = self.route_choice();
+        info!("{}", choice.summary(msg.settled, msg.rejected, msg.volume, &msg.balances));
+        let mut csv = String::from("id,balance\n");
+        for (id, b) in &msg.balances {
+            csv.push_str(&format!("{},{}\n", id, b));
+        }
+        if let Some(ref dir) = msg.dir {
+            export_to(dir, "balances.csv", &csv);
+        }
+        Self::reply(())
+    }
+}
+
+// sent by the source of every private packet
+pub struct OnionReport {
+    pub layers: usize,
+    pub header_bytes: usize,
+}
+
+message!(OnionReport);
+
+impl Handler<OnionReport> for World {
+    fn handle(&mut self, msg: OnionReport, _ctx: &mut Context<Self>) -> Response<Self, OnionReport> {
+        self.onion_stats.record(msg.layers, msg.header_bytes);
+        Self::reply(())
+    }
+}
+
+// sent by a node whenever its baseline routing state changes
+pub struct BaselineReport {
+    pub id: u64,
+    pub table_size: usize,
+    pub messages: u64,
+    pub failures: u64,
+    pub changed: bool,
+    pub label: Option<Label>,
+}
+
+message!(BaselineReport);
+
+impl Handler<BaselineReport> for World {
+    fn handle(&mut self, msg: BaselineReport, _ctx: &mut Context<Self>) -> Response<Self, BaselineReport> {
+        let epoch = self.epoch;
+        self.baseline_stats.record(msg, epoch);
+        Self::reply(())
+    }
+}
+
+// sent by a node in response to `checkpoint::TakeSnapshot`
+pub struct SnapshotReport {
+    pub snapshot: NodeSnapshot,
+}
+
+message!(SnapshotReport);
+
+impl Handler<SnapshotReport> for World {
+    fn handle(&mut self, msg: SnapshotReport, _ctx: &mut Context<Self>) -> Response<Self, SnapshotReport> {
+        if let Some(ref mut s) = self.snapshots {
+            s.push(msg.snapshot);
+        }
+        Self::reply(())
+    }
+}
+
+// sent by a node in response to `ReportState`
+pub struct StateReport {
+    pub id: u64,
+    pub state: NodeState,
+}
+
+message!(StateReport);
+
+impl Handler<StateReport> for World {
+    fn handle(&mut self, msg: StateReport, _ctx: &mut Context<Self>) -> Response<Self, StateReport> {
+        self.states.insert(msg.id, msg.state);
+        self.snapshot_state();
+        Self::reply(())
+    }
+}
+
+// sent by the source of a flow as it progresses
+pub struct FlowReport {
+    pub flow: u64,
+    pub event: FlowEvent,
+}
+
+message!(FlowReport);
+
+impl Handler<FlowReport> for World {
+    fn handle(&mut self, msg: FlowReport, _ctx: &mut Context<Self>) -> Response<Self, FlowReport> {
+        self.traffic_stats.record(msg.flow, msg.event);
+        Self::reply(())
+    }
+}
+
+// sent by a node in response to `ReportState`
+pub struct QueueStatsReport {
+    pub id: u64,
+    pub report: QueueReport,
+}
+
+message!(QueueStatsReport);
+
+impl Handler<QueueStatsReport> for World {
+    fn handle(&mut self, msg: QueueStatsReport, _ctx: &mut Context<Self>) -> Response<Self, QueueStatsReport> {
+        self.queue_stats.record(msg.id, msg.report);
+        Self::reply(())
+    }
+}
+
+// sent by a node in response to `ReportState`
+pub struct NCReport {
+    pub id: u64,
+    pub error: f32,
+    // (correct, total) pairwise neighbour rankings
+    pub rank: (u64, u64),
+    pub learn_rate: f32,
+    pub malicious: bool,
+    // neighbour samples discarded by the defence
+    pub rejected: u64,
+}
+
+message!(NCReport);
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NCSummary {
+    epoch: u64,
+    median_error: f32,
+    rank_accuracy: f32,
+    mean_learn_rate: f32,
+    malicious: usize,
+    rejected: u64,
+}
+
+impl Handler<NCReport> for World {
+    fn handle(&mut self, msg: NCReport, _ctx: &mut Context<Self>) -> Response<Self, NCReport> {
+        self.nc_reports.insert(msg.id, msg);
+        Self::reply(())
+    }
+}
+
+// sent by a node in response to `ReportCoordinates`
+pub struct CoordinateReport {
+    pub id: u64,
+    pub coords: NCNodeData,
+}
+
+message!(CoordinateReport);
+
+impl Handler<CoordinateReport> for World {
+    fn handle(&mut self, msg: CoordinateReport, _ctx: &mut Context<Self>) -> Response<Self, CoordinateReport> {
+        self.nc_eval.record(msg.id, msg.coords);
+        Self::reply(())
+    }
+}
+
+// sent by the shard link for a packet from a forwarder in another shard
+pub struct ShardRequest {
+    pub from: usize,
+    pub frame: Frame,
+}
+
+message!(ShardRequest);
+
+impl Handler<ShardRequest> for World {
+    fn handle(&mut self, msg: ShardRequest, _ctx: &mut Context<Self>) -> Response<Self, ShardRequest> {
+        // held until our nodes are idle, then handed over in a fixed order
+        if let Some(ref mut s) = self.shard {
+            s.queue(msg.from, msg.frame);
+        }
+        Self::reply(())
+    }
+}
+
+impl World {
+    // hand the packets queued by other shards to our nodes, ordered by sending shard and frame
+    fn serve_shard_requests(&mut self, ctx: &mut Context<Self>) -> bool {
+        let requests = match self.shard {
+            Some(ref mut s) => s.take_requests(),
+            None => return false
+        };
+        let served = !requests.is_empty();
+        for (from, frame) in requests {
+            self.serve_shard_request(ctx, from, frame);
+        }
+        served
+    }
+
+    fn serve_shard_request(&mut self, ctx: &mut Context<Self>, from: usize, frame: Frame) {
+        let (link, node) = match self.shard {
+            Some(ref s) => (s.link.clone(), match s.owner.get(frame.to) {
+                Some(&o) if o == s.index => self.graph[NodeIndex::new(frame.to)].address().cloned(),
+                _ => None
+            }),
+            None => return
+        };
+        let Frame { id, to, kind, body } = frame;
+        let reply = node.ok_or(format!("node {} is not running in shard {}", to, link.shard))
+            .and_then(|a| codec::deliver(&kind, &a, body));
+        match reply {
+            Ok(f) => {
+                ctx.spawn(fut::wrap_future::<_, World>(f).map(move |body, _, _| link.reply(from, Frame { id, to, kind, body })));
+            }
+            Err(e) => {
+                // answered like a lost packet
+                eprintln!("{}", e);
+                link.reply(from, Frame { id, to, kind, body: Value::Null });
+            }
+        }
+    }
+}
+
+// sent by the shard link when the coordinator asks for the counters
+pub struct ShardPoll {
+    pub epoch: u64,
+    pub wave: u64,
+}
+
+message!(ShardPoll);
+
+impl Handler<ShardPoll> for World {
+    fn handle(&mut self, msg: ShardPoll, _ctx: &mut Context<Self>) -> Response<Self, ShardPoll> {
+        if let Some(ref mut s) = self.shard {
+            s.poll(msg.epoch, msg.wave);
+        }
+        Self::reply(())
+    }
+}
+
+// sent by the shard link when every shard may start an epoch
+pub struct ShardRelease {
+    pub epoch: u64,
+}
+
+message!(ShardRelease);
+
+impl Handler<ShardRelease> for World {
+    fn handle(&mut self, msg: ShardRelease, _ctx: &mut Context<Self>) -> Response<Self, ShardRelease> {
+        if let Some(ref mut s) = self.shard {
+            s.release(msg.epoch);
+        }
+        Self::reply(())
+    }
+}
+
+// sent by the UDP socket for a packet from a neighbour's process
+pub struct UdpRequest {
+    pub from: usize,
+    pub frame: Frame,
+}
+
+message!(UdpRequest);
+
+impl Handler<UdpRequest> for World {
+    fn handle(&mut self, msg: UdpRequest, ctx: &mut Context<Self>) -> Response<Self, UdpRequest> {
+        let socket = match self.udp {
+            Some(ref s) => s.clone(),
+            None => return Self::reply(())
+        };
+        let Frame { id, to, kind, body } = msg.frame;
+        let from = msg.from;
+        let node = if to == socket.index { self.graph[NodeIndex::new(to)].address().cloned() } else { None };
+        let reply = node.ok_or(format!("node {} is not running in this process", to))
+            .and_then(|a| codec::deliver(&kind, &a, body));
+        match reply {
+            Ok(f) => {
+                ctx.spawn(fut::wrap_future::<_, World>(f).map(move |body, _, _| socket.reply(from, Frame { id, to, kind, body })));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                socket.reply(from, Frame { id, to, kind, body: Value::Null });
+            }
+        }
+        Self::reply(())
+    }
+}
+
+#[derive(Serialize)]
+struct LookupResult {
+    from: u64,
+    goal: u64,
+    found: bool,
+    route: Vec<u64>,
+    graph_route: Option<Vec<usize>>,
+    hops: usize,
+    latency: Option<f32>,
+    optimal_latency: Option<f32>,
+    optimal_hops: Option<usize>,
+    // as measured for delivered packets
+    stretch: Option<f32>,
+    hop_stretch: Option<f32>,
+}
+
+// a lookup of the console, walked from node to node
+struct ConsoleLookup {
+    from: NodeIndex,
+    to: Option<NodeIndex>,
+    goal: u64,
+    // IDs from the origin to the last node asked
+    route: Vec<u64>,
+    asked: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct FailedNodes {
+    // graph indices of links taken down by the scenario
+    links: Vec<(usize, usize)>,
+    // graph index and ID of the nodes down with churn, with the epoch they come back
+    down: Vec<(usize, Option<u64>, u64)>,
+}
+
+// a command typed on the console
+pub struct Console {
+    pub query: Query,
+}
+
+message!(Console);
+
+impl Handler<Console> for World {
+    fn handle(&mut self, msg: Console, ctx: &mut Context<Self>) -> Response<Self, Console> {
+        let answer = match msg.query {
+            Query::Help => {
+                println!("{}", console::HELP);
+                Ok(())
+            }
+            Query::Pause => {
+                self.pause_at = Some(self.epoch);
+                Ok(())
+            }
+            Query::Continue(n) => {
+                self.pause_at = n.map(|n| self.epoch + n.max(1));
+                if self.paused {
+                    self.paused = false;
+                    println!("continuing from epoch {}", self.epoch);
+                }
+                Ok(())
+            }
+            Query::Quit => {
+                self.pause_at = None;
+                self.paused = false;
+                self.epochs = Some(self.epoch);
+                Ok(())
+            }
+            // the rest only makes sense with nothing in flight
+            _ if !self.paused => Err(String::from("the run is not paused, type pause first")),
+            Query::Failed => {
+                let mut down: Vec<(usize, Option<u64>, u64)> = self.down.iter().map(|(&i, &(until, _))| (i.index(), self.graph[i].id, until)).collect();
+                down.sort();
+                console::print(&FailedNodes {
+                    links: self.failed_links.iter().map(|l| (l.0.index(), l.1.index())).collect(),
+                    down,
+                });
+                Ok(())
+            }
+            Query::Node(n) => self.local_node(n).map(|(_, addr)| {
+                ctx.spawn(fut::wrap_future::<_, World>(addr.call_fut(InspectNode)).then(|res, _, _| {
+                    match res {
+                        Ok(Ok(dump)) => console::print(&dump),
+                        _ => eprintln!("the node did not answer")
+                    }
+                    fut::ok::<(), (), World>(())
+                }));
+            }),
+            Query::Lookup(a, b) => self.test_lookup(a, b, false, ctx),
+            Query::Stretch(a, b) => self.test_lookup(a, b, true, ctx),
+        };
+        if let Err(e) = answer {
+            eprintln!("{}", e);
+        }
+        Self::reply(())
+    }
+}
@@ -0,0 +1,43 @@
+//! A run split over shards against the single process run with the same seed.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Command;
+
+// runs the program with `args` and returns the values of summary.csv by column
+fn summary(name: &str, args: &[&str]) -> Vec<(String, f64)> {
+    let dir: PathBuf = env::temp_dir().join(format!("routing-actor-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let status = Command::new(env!("CARGO_BIN_EXE_routing-actor"))
+        .args(["run", "--seed", "4", "--epochs", "150", "--set", "churn=0.01", "--output"])
+        .arg(&dir)
+        .args(args)
+        .status()
+        .expect("cannot start the program");
+    assert!(status.success(), "{} run failed", name);
+    let mut text = String::new();
+    File::open(dir.join("summary.csv")).expect("no summary.csv").read_to_string(&mut text).unwrap();
+    let _ = fs::remove_dir_all(&dir);
+    let mut lines = text.lines();
+    let header = lines.next().unwrap().split(',');
+    let values = lines.next().unwrap().split(',').map(|v| v.parse::<f64>().unwrap());
+    header.map(String::from).zip(values).collect()
+}
+
+#[test]
+fn two_shards_match_one() {
+    let single = summary("single", &[]);
+    let sharded = summary("sharded", &["--shards", "2"]);
+    for ((key, a), (_, b)) in single.iter().zip(sharded.iter()) {
+        match key.as_str() {
+            // drawn from the seed
+            "epochs" | "nodes" | "churned" => assert_eq!(a, b, "{}", key),
+            // measured, these vary between runs of either
+            "messages" => {}
+            "rank_accuracy" | "loss" => assert!((a - b).abs() < 0.3, "{}: {} and {}", key, a, b),
+            _ => assert!((a - b).abs() <= 0.25 * a.max(*b), "{}: {} and {}", key, a, b),
+        }
+    }
+}